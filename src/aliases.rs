@@ -0,0 +1,209 @@
+//! Songs MPD lists under several paths.
+//!
+//! MPD follows symbolic links, so a file can be listed both under its real
+//! path and through a link, and the audio file of a CUE sheet is listed
+//! along with the sheet's tracks. Analyzing all of them would store the same
+//! audio several times, and playlists would be full of "duplicates".
+//!
+//! Files are told apart by device and inode. Of the paths of a same file, the
+//! one that doesn't go through a link below the music directory is kept, see
+//! [direct_path](crate::Config::direct_path), so that MPD paths going through
+//! links can be mapped back to it.
+use crate::{cue_sheet_path, Config, MPDLibrary};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// The device and inode of the file at `path`, following links.
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// The audio files the `FILE` lines of the CUE sheet at `cue_path` point to.
+fn cue_audio_files(cue_path: &Path) -> Vec<PathBuf> {
+    let directory = cue_path.parent().unwrap_or(Path::new(""));
+    // CUE sheets are often not in UTF-8.
+    let cue_sheet = match fs::read(cue_path) {
+        Ok(contents) => String::from_utf8_lossy(&contents).to_string(),
+        Err(_) => return vec![],
+    };
+    cue_sheet
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("FILE "))
+        .filter_map(|file| match file.strip_prefix('"') {
+            Some(quoted) => quoted.split('"').next(),
+            // Unquoted names are followed by the file's type.
+            None => file.rsplit_once(' ').map(|(name, _)| name),
+        })
+        .map(|file| directory.join(file))
+        .collect()
+}
+
+/// Whether `path` is a CUE sheet.
+fn is_cue_sheet(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+}
+
+impl Config {
+    /// `path`, with the links below the base path it is in resolved, if the
+    /// file they point to is in that base path too. CUE tracks are resolved
+    /// through their CUE sheet.
+    pub(crate) fn direct_path(&self, path: &Path) -> PathBuf {
+        let file = cue_sheet_path(path).unwrap_or(path);
+        let direct_file = || -> Option<PathBuf> {
+            let base_path = self.base_paths().find(|b| file.starts_with(b))?;
+            let real_path = fs::canonicalize(file).ok()?;
+            let real_base_path = fs::canonicalize(base_path).ok()?;
+            Some(base_path.join(real_path.strip_prefix(real_base_path).ok()?))
+        };
+        match (direct_file(), path.strip_prefix(file)) {
+            (Some(direct_file), Ok(track)) if track.as_os_str().is_empty() => direct_file,
+            (Some(direct_file), Ok(track)) => direct_file.join(track),
+            _ => path.to_owned(),
+        }
+    }
+}
+
+impl MPDLibrary {
+    /// Leave out of `paths`, as returned by
+    /// [get_songs_paths](MPDLibrary::get_songs_paths), the files that are
+    /// also listed under another path, and the audio files of the CUE sheets
+    /// listed.
+    pub(crate) fn remove_aliases(&self, paths: Vec<String>) -> Vec<String> {
+        let cue_audio_files = paths
+            .iter()
+            .map(Path::new)
+            .filter(|p| is_cue_sheet(p))
+            .flat_map(cue_audio_files)
+            .filter_map(|p| file_id(&p))
+            .collect::<HashSet<_>>();
+        let ids = paths
+            .iter()
+            .map(|p| file_id(Path::new(p)))
+            .collect::<Vec<_>>();
+        // The index of the path each file is kept under, the first direct
+        // one or else the first one, and whether it is direct.
+        let mut kept: HashMap<(u64, u64), (usize, bool)> = HashMap::new();
+        for (i, (path, id)) in paths.iter().zip(&ids).enumerate() {
+            let id = match id {
+                Some(id) => id,
+                None => continue,
+            };
+            let path = Path::new(path);
+            let direct = self.library.config.direct_path(path) == path;
+            match kept.get(id) {
+                Some((_, true)) => (),
+                Some((_, false)) if !direct => (),
+                _ => {
+                    kept.insert(*id, (i, direct));
+                }
+            }
+        }
+        let number_paths = paths.len();
+        let paths = paths
+            .into_iter()
+            .zip(ids)
+            .enumerate()
+            .filter(|(i, (path, id))| match id {
+                // Files that can't be read are left for bliss to report.
+                None => true,
+                Some(id) => {
+                    kept[id].0 == *i
+                        && (is_cue_sheet(Path::new(path)) || !cue_audio_files.contains(id))
+                }
+            })
+            .map(|(_, (path, _))| path)
+            .collect::<Vec<_>>();
+        if paths.len() < number_paths {
+            info!(
+                "Leaving out {} file(s) MPD also lists under another path, or through a CUE sheet.",
+                number_paths - paths.len()
+            );
+        }
+        paths
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use mpd::song::Song as MPDSong;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_remove_aliases() {
+        let (mut library, tempdir) = setup_library();
+        let base_path = tempdir.path().join("music");
+        library.library.config.mpd_base_path = base_path.to_owned();
+        let album = base_path.join("Albums/album");
+        fs::create_dir_all(&album).unwrap();
+        fs::create_dir_all(base_path.join("Artists")).unwrap();
+        fs::write(album.join("song.flac"), b"song").unwrap();
+        fs::write(album.join("other.flac"), b"other").unwrap();
+        fs::write(album.join("whole.flac"), b"whole").unwrap();
+        fs::write(
+            album.join("whole.cue"),
+            "FILE \"whole.flac\" WAVE\n  TRACK 01 AUDIO\n",
+        )
+        .unwrap();
+        symlink("../Albums/album", base_path.join("Artists/album")).unwrap();
+
+        let path = |p: &str| base_path.join(p).to_string_lossy().to_string();
+        let paths = [
+            "Albums/album/other.flac",
+            "Albums/album/song.flac",
+            "Albums/album/whole.cue",
+            "Albums/album/whole.flac",
+            "Artists/album/song.flac",
+            "Artists/album/whole.cue",
+            "missing.flac",
+        ];
+        assert_eq!(
+            library.remove_aliases(paths.iter().map(|p| path(p)).collect()),
+            vec![
+                path("Albums/album/other.flac"),
+                path("Albums/album/song.flac"),
+                path("Albums/album/whole.cue"),
+                path("missing.flac"),
+            ],
+        );
+        // The direct path is kept even if it is listed last.
+        assert_eq!(
+            library.remove_aliases(vec![
+                path("Artists/album/song.flac"),
+                path("Albums/album/song.flac"),
+            ]),
+            vec![path("Albums/album/song.flac")],
+        );
+
+        // Songs played through links are found under their direct path.
+        let mpd_song = |file: &str| MPDSong {
+            file: String::from(file),
+            ..Default::default()
+        };
+        assert_eq!(
+            library
+                .mpd_to_bliss_path(&mpd_song("Artists/album/song.flac"))
+                .unwrap(),
+            album.join("song.flac"),
+        );
+        assert_eq!(
+            library
+                .mpd_to_bliss_path(&mpd_song("Artists/album/whole.cue/track0001"))
+                .unwrap(),
+            album.join("whole.cue/CUE_TRACK001"),
+        );
+        assert_eq!(
+            library
+                .mpd_to_bliss_path(&mpd_song("missing.flac"))
+                .unwrap(),
+            base_path.join("missing.flac"),
+        );
+    }
+}
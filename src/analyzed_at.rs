@@ -0,0 +1,160 @@
+//! When songs were analyzed, see `update --older-than`.
+//!
+//! bliss stores the songs it analyzes itself, so the time is set by triggers
+//! whenever a song is stored, rather than by each place songs are stored
+//! from. Songs analyzed before blissify recorded it have no time, and count
+//! as older than all the others.
+use crate::MPDLibrary;
+use anyhow::Result;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+impl MPDLibrary {
+    /// Add the column storing when each song was analyzed, in seconds since
+    /// the epoch, and the triggers setting it, if they don't exist yet.
+    pub(crate) fn create_analyzed_at_column(&self) -> Result<()> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare("select name from pragma_table_info('song')")?;
+        let columns = query
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if !columns.iter().any(|c| c == "analyzed_at") {
+            sqlite_conn.execute("alter table song add column analyzed_at integer", [])?;
+        }
+        // Songs analyzed again can also be updated in place, getting a new
+        // features version.
+        sqlite_conn.execute_batch(
+            "create trigger if not exists song_analyzed_at_insert
+            after insert on song when new.analyzed_at is null
+            begin
+                update song set analyzed_at = cast(strftime('%s', 'now') as integer)
+                where id = new.id;
+            end;
+            create trigger if not exists song_analyzed_at_update
+            after update of version on song
+            begin
+                update song set analyzed_at = cast(strftime('%s', 'now') as integer)
+                where id = new.id;
+            end;",
+        )?;
+        Ok(())
+    }
+
+    /// When the song at `path` was analyzed, as a local date and time like
+    /// "2024-10-17 20:15:03", if it is known.
+    pub(crate) fn analyzed_at(&self, path: &str) -> Result<Option<String>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select datetime(analyzed_at, 'unixepoch', 'localtime') from song where path = ?1",
+        )?;
+        let analyzed_at = query
+            .query_map([path], |row| row.get::<_, Option<String>>(0))?
+            .next()
+            .transpose()?;
+        Ok(analyzed_at.flatten())
+    }
+
+    /// The paths of the analyzed songs that were analyzed more than `age`
+    /// ago, or at an unknown time.
+    pub(crate) fn songs_analyzed_before(&self, age: Duration) -> Result<Vec<PathBuf>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let before = now.saturating_sub(age).as_secs() as i64;
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select path from song
+            where analyzed = true and (analyzed_at is null or analyzed_at < ?1)
+            order by path",
+        )?;
+        let paths = query
+            .query_map([before], |row| row.get::<_, String>(0))?
+            .map(|path| path.map(PathBuf::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::{insert_songs, setup_library};
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_analyzed_at() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/new.flac", None, 0.),
+                (2, "path/old.flac", None, 0.1),
+                (3, "path/unknown.flac", None, 0.2),
+            ],
+        );
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            let analyzed_at = |path: &str| {
+                sqlite_conn
+                    .query_row(
+                        "select analyzed_at from song where path = ?1",
+                        [path],
+                        |row| row.get::<_, Option<i64>>(0),
+                    )
+                    .unwrap()
+            };
+            assert!(analyzed_at("path/new.flac").is_some_and(|t| (t - now).abs() < 60));
+            sqlite_conn
+                .execute(
+                    "update song set analyzed_at = ?1 where path = 'path/old.flac'",
+                    [now - 2 * 365 * 24 * 60 * 60],
+                )
+                .unwrap();
+            sqlite_conn
+                .execute(
+                    "update song set analyzed_at = null where path = 'path/unknown.flac'",
+                    [],
+                )
+                .unwrap();
+            // Songs analyzed again in place get a new time.
+            sqlite_conn
+                .execute(
+                    "update song set version = 2 where path = 'path/unknown.flac'",
+                    [],
+                )
+                .unwrap();
+            assert!(analyzed_at("path/unknown.flac").is_some());
+            sqlite_conn
+                .execute(
+                    "update song set analyzed_at = null where path = 'path/unknown.flac'",
+                    [],
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            library
+                .songs_analyzed_before(Duration::from_secs(365 * 24 * 60 * 60))
+                .unwrap(),
+            vec![
+                PathBuf::from("path/old.flac"),
+                PathBuf::from("path/unknown.flac")
+            ],
+        );
+        assert_eq!(
+            library
+                .songs_analyzed_before(Duration::from_secs(3 * 365 * 24 * 60 * 60))
+                .unwrap(),
+            vec![PathBuf::from("path/unknown.flac")],
+        );
+        assert!(library
+            .analyzed_at("path/old.flac")
+            .unwrap()
+            .is_some_and(|t| t.len() == "2024-10-17 20:15:03".len()));
+        assert_eq!(library.analyzed_at("path/unknown.flac").unwrap(), None);
+        assert_eq!(library.analyzed_at("path/missing.flac").unwrap(), None);
+    }
+}
@@ -0,0 +1,178 @@
+//! Telling other MPD clients how a queued playlist was made, see
+//! `playlist --announce`.
+//!
+//! MPD lets clients send each other messages on channels they subscribe to.
+//! After queueing a playlist, blissify sends a [PlaylistAnnouncement] as
+//! JSON on the [ANNOUNCE_CHANNEL] channel, like
+//!
+//! ```text
+//! {"mode":"song","from":"album/song.flac","distance":"euclidean","algorithm":"closest","number_songs":20,"user":null}
+//! ```
+//!
+//! so that cooperating clients can display "generated from X with metric Y".
+use crate::{Algorithm, MPDLibrary, PlaylistDefaults};
+use anyhow::Result;
+use clap::ArgMatches;
+use log::warn;
+use mpd::Channel;
+use serde::{Deserialize, Serialize};
+
+/// The MPD channel playlists are announced on.
+pub(crate) const ANNOUNCE_CHANNEL: &str = "blissify";
+
+/// How a playlist was made.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct PlaylistAnnouncement {
+    /// What the playlist was made from: "song", "album", "one-album",
+    /// "file", "stdin", "log", "entire" or "priorities".
+    pub mode: String,
+    /// The MPD path of the song, the file, or the time range the playlist was
+    /// made from, if it was made from one.
+    pub from: Option<String>,
+    /// The distance metric, as passed to `--distance`, for playlists that
+    /// are not album playlists.
+    pub distance: Option<String>,
+    /// The algorithm, as passed to `--algorithm`, for playlists that are not
+    /// album playlists.
+    pub algorithm: Option<String>,
+    pub number_songs: usize,
+    /// The user selected with `--user`, if any.
+    pub user: Option<String>,
+}
+
+impl PlaylistAnnouncement {
+    /// Describe the playlist `playlist`'s arguments `sub_m` make, with
+    /// `defaults` for the arguments that are not given.
+    pub fn from_matches(
+        sub_m: &ArgMatches,
+        defaults: &PlaylistDefaults,
+        number_songs: usize,
+        user: Option<String>,
+    ) -> Result<Self> {
+        let mode = [
+            ("album", "album"),
+            ("one-album", "one-album"),
+            ("from-file", "file"),
+            ("from-stdin", "stdin"),
+            ("from-log", "log"),
+            ("entire", "entire"),
+            ("use-priorities", "priorities"),
+        ]
+        .into_iter()
+        .find(|(argument, _)| sub_m.is_present(argument))
+        .map_or("song", |(_, mode)| mode);
+        let from = sub_m
+            .value_of("from-file")
+            .or_else(|| sub_m.value_of("from-log"))
+            .or_else(|| sub_m.value_of("from-song"))
+            .map(String::from);
+        let (distance, algorithm) = match mode {
+            "album" | "one-album" => (None, None),
+            _ => {
+                let distance = sub_m
+                    .value_of("distance")
+                    .or(defaults.distance.as_deref())
+                    .unwrap_or(match mode {
+                        // Playlists made from several songs default to the
+                        // forest.
+                        "stdin" | "log" | "entire" => "extended_isolation_forest",
+                        _ => "euclidean",
+                    });
+                let algorithm = match Algorithm::from_options(
+                    sub_m.value_of("algorithm"),
+                    sub_m.is_present("seed"),
+                    defaults,
+                )? {
+                    Algorithm::Closest => "closest",
+                    Algorithm::Chained => "chained",
+                };
+                (Some(distance.to_owned()), Some(algorithm.to_owned()))
+            }
+        };
+        Ok(Self {
+            mode: mode.to_owned(),
+            from,
+            distance,
+            algorithm,
+            number_songs,
+            user,
+        })
+    }
+}
+
+impl MPDLibrary {
+    /// Send `announcement` to the clients subscribed to [ANNOUNCE_CHANNEL].
+    /// Playlists made from the current song are announced as made from it.
+    ///
+    /// MPD refuses messages nobody is subscribed to, so failing to send it
+    /// only warns.
+    pub(crate) fn announce_playlist(&self, mut announcement: PlaylistAnnouncement) -> Result<()> {
+        let mut mpd_conn = self.mpd_conn()?;
+        if announcement.from.is_none()
+            && ["song", "album", "one-album", "priorities"].contains(&announcement.mode.as_str())
+        {
+            announcement.from = mpd_conn.currentsong()?.map(|s| s.file);
+        }
+        let message = serde_json::to_string(&announcement)?;
+        let channel = Channel::new(ANNOUNCE_CHANNEL).unwrap();
+        if let Err(e) = mpd_conn.sendmessage(channel, &message) {
+            warn!(
+                "Could not announce the playlist on MPD's \"{}\" channel, maybe no client is subscribed to it: {}",
+                ANNOUNCE_CHANNEL, e
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use mpd::song::Song as MPDSong;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_announce_playlist() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("album/current.flac"),
+            ..Default::default()
+        }];
+        let announcement = |mode: &str, from: Option<&str>| PlaylistAnnouncement {
+            mode: mode.to_owned(),
+            from: from.map(String::from),
+            distance: Some(String::from("cosine")),
+            algorithm: Some(String::from("closest")),
+            number_songs: 20,
+            user: None,
+        };
+        library
+            .announce_playlist(announcement("song", None))
+            .unwrap();
+        library
+            .announce_playlist(announcement("stdin", None))
+            .unwrap();
+        library
+            .announce_playlist(announcement("file", Some("/tmp/song.flac")))
+            .unwrap();
+        let messages = library
+            .mpd_conn()
+            .unwrap()
+            .messages
+            .iter()
+            .map(|(channel, message)| {
+                assert_eq!(*channel, Channel::new(ANNOUNCE_CHANNEL).unwrap());
+                serde_json::from_str::<PlaylistAnnouncement>(message).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                announcement("song", Some("album/current.flac")),
+                announcement("stdin", None),
+                announcement("file", Some("/tmp/song.flac")),
+            ],
+        );
+    }
+}
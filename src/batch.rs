@@ -0,0 +1,364 @@
+//! Changing MPD's queue in as few steps as possible.
+//!
+//! The MPD client blissify uses sends one command per song added to the
+//! queue, which is slow over high-latency connections, and makes the other
+//! clients refresh the queue after each song. Songs are instead added with
+//! command lists, that MPD runs in one go, through a separate connection.
+//!
+//! Since queueing a playlist takes several commands, and MPD can't undo them,
+//! the queue is put back as it was when one of them fails, see
+//! [change_queue](MPDLibrary::change_queue).
+use crate::{MPDClient, MPDLibrary};
+use anyhow::{bail, Result};
+use log::warn;
+use mpd::song::{Range, Song as MPDSong};
+use std::io::BufRead;
+
+/// The maximum number of songs added by a single command list, to stay well
+/// under MPD's default `max_command_list_size`.
+const BATCH_SIZE: usize = 500;
+
+/// Quote `argument` for MPD's protocol.
+fn quote(argument: &str) -> String {
+    format!(
+        "\"{}\"",
+        argument.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// The command lists adding `songs` to the queue from `position` on, or at
+/// the end of the queue, [BATCH_SIZE] songs at a time.
+fn command_lists(songs: &[MPDSong], position: Option<u32>) -> impl Iterator<Item = String> + '_ {
+    songs
+        .chunks(BATCH_SIZE)
+        .enumerate()
+        .map(move |(i, batch)| command_list(batch, position.map(|p| p + (i * BATCH_SIZE) as u32)))
+}
+
+/// The command list adding `songs` to the queue from `position` on, or at the
+/// end of the queue.
+fn command_list(songs: &[MPDSong], position: Option<u32>) -> String {
+    let mut commands = String::from("command_list_begin\n");
+    for (i, song) in songs.iter().enumerate() {
+        match position {
+            Some(position) => commands.push_str(&format!(
+                "addid {} {}\n",
+                quote(&song.file),
+                position + i as u32
+            )),
+            None => commands.push_str(&format!("addid {}\n", quote(&song.file))),
+        }
+    }
+    commands.push_str("command_list_end\n");
+    commands
+}
+
+/// The command list restricting the songs among `songs` that have a range,
+/// like the tracks of sidecar CUE sheets, to it, given the ids MPD gave
+/// them when adding them. Returns `None` if no song has a range.
+fn range_command_list(songs: &[MPDSong], ids: &[u32]) -> Option<String> {
+    let mut commands = String::from("command_list_begin\n");
+    let mut has_range = false;
+    for (song, id) in songs.iter().zip(ids) {
+        if let Some(Range(start, end)) = song.range {
+            has_range = true;
+            commands.push_str(&format!(
+                "rangeid {} {:.3}:{}\n",
+                id,
+                start.as_secs_f64(),
+                end.map(|e| format!("{:.3}", e.as_secs_f64()))
+                    .unwrap_or_default(),
+            ));
+        }
+    }
+    commands.push_str("command_list_end\n");
+    has_range.then_some(commands)
+}
+
+/// Read MPD's response to a command, up to its final `OK`, failing if MPD
+/// answered with an error.
+///
+/// Returns the ids of the songs added by the command, if any.
+fn read_response(reader: &mut impl BufRead) -> Result<Vec<u32>> {
+    let mut line = String::new();
+    let mut ids = vec![];
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("MPD closed the connection.");
+        }
+        let line = line.trim_end();
+        // Greetings are "OK MPD <version>".
+        if line == "OK" || line.starts_with("OK MPD ") {
+            return Ok(ids);
+        }
+        if let Some(error) = line.strip_prefix("ACK ") {
+            bail!("MPD refused to queue the songs: {}", error);
+        }
+        if let Some(id) = line.strip_prefix("Id: ") {
+            ids.push(id.parse()?);
+        }
+    }
+}
+
+impl MPDLibrary {
+    /// Add `songs` to the queue, from `position` on, or at the end of the
+    /// queue, [BATCH_SIZE] songs at a time.
+    ///
+    /// The songs are sent through another connection to the same server as
+    /// `mpd_conn`, see [mpd_address](Self::mpd_address). Falls back to
+    /// adding the songs one by one through `mpd_conn` if it can't be opened,
+    /// in which case songs with a range, like the tracks of sidecar CUE
+    /// sheets, are queued whole.
+    pub(crate) fn add_to_queue(
+        &self,
+        mpd_conn: &mut MPDClient,
+        songs: &[MPDSong],
+        position: Option<u32>,
+    ) -> Result<()> {
+        if songs.is_empty() {
+            return Ok(());
+        }
+        #[cfg(not(test))]
+        match self
+            .mpd_address()
+            .and_then(|address| Ok((Self::mpd_stream(&address)?, address.password)))
+        {
+            Ok((stream, password)) => {
+                return Self::send_command_lists(stream, password, songs, position)
+            }
+            Err(e) => warn!(
+                "Could not open another connection to MPD ({}), queueing the songs one by one.",
+                e
+            ),
+        }
+        for (i, song) in songs.iter().enumerate() {
+            match position {
+                Some(position) => {
+                    mpd_conn.insert(song.to_owned(), (position + i as u32).try_into()?)?;
+                }
+                None => {
+                    mpd_conn.push(song.to_owned())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `change` on the queue, putting the queue back as it was if it
+    /// fails, so that errors don't leave it half-modified. The song playing
+    /// keeps playing.
+    ///
+    /// `change` should only talk to MPD: anything that can fail otherwise,
+    /// like converting paths, is better done before.
+    pub(crate) fn change_queue(
+        &self,
+        mpd_conn: &mut MPDClient,
+        change: impl FnOnce(&mut MPDClient) -> Result<()>,
+    ) -> Result<()> {
+        let previous = mpd_conn.queue()?;
+        let current = mpd_conn
+            .currentsong()?
+            .and_then(|s| s.place)
+            .map(|p| p.pos as usize);
+        let error = match change(mpd_conn) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        warn!("Changing the queue failed, putting it back as it was.");
+        if let Err(e) = self.restore_queue(mpd_conn, &previous, current) {
+            warn!("Could not put the queue back as it was: {}", e);
+        }
+        Err(error)
+    }
+
+    /// Replace the queue by `previous`, where `current` was the position of
+    /// the song playing, without interrupting the song playing.
+    fn restore_queue(
+        &self,
+        mpd_conn: &mut MPDClient,
+        previous: &[MPDSong],
+        current: Option<usize>,
+    ) -> Result<()> {
+        let playing = mpd_conn.currentsong()?.and_then(|s| s.place);
+        let playing = match playing {
+            Some(playing) => mpd_conn
+                .queue()?
+                .iter()
+                .position(|s| s.place.is_some_and(|p| p.id == playing.id)),
+            None => None,
+        };
+        match (playing, current) {
+            (Some(playing), Some(current)) => {
+                mpd_conn.delete(0..playing as u32)?;
+                if mpd_conn.queue()?.len() > 1 {
+                    mpd_conn.delete(1..)?;
+                }
+                self.add_to_queue(mpd_conn, &previous[..current], Some(0))?;
+                self.add_to_queue(mpd_conn, &previous[current + 1..], None)?;
+            }
+            _ => {
+                mpd_conn.clear()?;
+                self.add_to_queue(mpd_conn, previous, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(test))]
+    fn send_command_lists(
+        stream: crate::MPDStream,
+        password: Option<String>,
+        songs: &[MPDSong],
+        position: Option<u32>,
+    ) -> Result<()> {
+        use std::io::{BufReader, Write};
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        read_response(&mut reader)?;
+        if let Some(password) = password {
+            writer.write_all(format!("password {}\n", quote(&password)).as_bytes())?;
+            read_response(&mut reader)?;
+        }
+        for (batch, commands) in songs.chunks(BATCH_SIZE).zip(command_lists(songs, position)) {
+            writer.write_all(commands.as_bytes())?;
+            let ids = read_response(&mut reader)?;
+            if let Some(commands) = range_command_list(batch, &ids) {
+                writer.write_all(commands.as_bytes())?;
+                read_response(&mut reader)?;
+            }
+        }
+        writer.write_all(b"close\n")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use mpd::song::{Id, QueuePlace};
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    #[test]
+    fn test_command_list() {
+        let songs = ["first.flac", "album/\"quoted\" \\ song.flac"]
+            .iter()
+            .map(|f| MPDSong {
+                file: f.to_string(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            command_list(&songs, None),
+            "command_list_begin\n\
+            addid \"first.flac\"\n\
+            addid \"album/\\\"quoted\\\" \\\\ song.flac\"\n\
+            command_list_end\n",
+        );
+        assert_eq!(
+            command_list(&songs, Some(3)),
+            "command_list_begin\n\
+            addid \"first.flac\" 3\n\
+            addid \"album/\\\"quoted\\\" \\\\ song.flac\" 4\n\
+            command_list_end\n",
+        );
+
+        let songs = vec![songs[0].to_owned(); BATCH_SIZE + 1];
+        let lists = command_lists(&songs, Some(3)).collect::<Vec<_>>();
+        assert_eq!(lists.len(), 2);
+        assert_eq!(
+            lists[1],
+            format!(
+                "command_list_begin\naddid \"first.flac\" {}\ncommand_list_end\n",
+                BATCH_SIZE + 3
+            ),
+        );
+    }
+
+    #[test]
+    fn test_range_command_list() {
+        let songs = [
+            MPDSong {
+                file: String::from("mix.mp3"),
+                range: Some(Range(
+                    Duration::from_millis(302_400),
+                    Some(Duration::from_secs(600)),
+                )),
+                ..Default::default()
+            },
+            MPDSong {
+                file: String::from("song.flac"),
+                ..Default::default()
+            },
+            MPDSong {
+                file: String::from("mix.mp3"),
+                range: Some(Range(Duration::from_secs(600), None)),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            range_command_list(&songs, &[4, 5, 6]).unwrap(),
+            "command_list_begin\n\
+            rangeid 4 302.400:600.000\n\
+            rangeid 6 600.000:\n\
+            command_list_end\n",
+        );
+        assert_eq!(range_command_list(&songs[1..2], &[5]), None);
+    }
+
+    #[test]
+    fn test_change_queue() {
+        let (library, _tempdir) = setup_library();
+        let song = |file: &str, pos: Option<u32>| MPDSong {
+            file: String::from(file),
+            place: pos.map(|pos| QueuePlace {
+                id: Id(pos + 1),
+                pos,
+                prio: 0,
+            }),
+            ..Default::default()
+        };
+        let previous = vec![
+            song("before.flac", None),
+            song("current.flac", Some(1)),
+            song("after.flac", None),
+        ];
+        let mut mpd_conn = library.mpd_conn().unwrap();
+        mpd_conn.mpd_queue = previous.to_owned();
+
+        let error = library
+            .change_queue(&mut mpd_conn, |mpd_conn| {
+                mpd_conn.delete(0..1)?;
+                library.add_to_queue(mpd_conn, &[song("new.flac", None)], None)?;
+                bail!("No such song")
+            })
+            .unwrap_err();
+        assert_eq!(error.to_string(), "No such song");
+        assert_eq!(mpd_conn.mpd_queue, previous);
+
+        library
+            .change_queue(&mut mpd_conn, |mpd_conn| {
+                library.add_to_queue(mpd_conn, &[song("new.flac", None)], None)
+            })
+            .unwrap();
+        assert_eq!(mpd_conn.mpd_queue.len(), 4);
+    }
+
+    #[test]
+    fn test_read_response() {
+        let mut response = Cursor::new("OK MPD 0.23.5\nId: 1\nId: 2\nOK\n");
+        read_response(&mut response).unwrap();
+        assert_eq!(read_response(&mut response).unwrap(), vec![1, 2]);
+        assert!(read_response(&mut response).is_err());
+
+        let mut response = Cursor::new("Id: 1\nACK [50@1] {addid} No such song\n");
+        assert_eq!(
+            read_response(&mut response).unwrap_err().to_string(),
+            "MPD refused to queue the songs: [50@1] {addid} No such song",
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Checking that blissify can run, see `blissify check`.
+//!
+//! Each check prints a line, and the exit code is the one of the first check
+//! that failed, so that scripts (or a systemd `ExecStartPre`) can tell what
+//! went wrong:
+//!
+//! | Code | Check                                                    |
+//! |------|----------------------------------------------------------|
+//! | 0    | Everything is fine                                       |
+//! | 2    | The configuration file can't be read, or points nowhere  |
+//! | 3    | The database can't be read                               |
+//! | 4    | Songs were analyzed with another version of the features |
+//! | 5    | The songs of the library can't be decoded                |
+//! | 6    | MPD can't be reached                                     |
+use crate::hybrid::parse_distance;
+use crate::{Config, MPDAddress, MPDLibrary};
+use anyhow::{bail, Context, Result};
+use bliss_audio::decoder::ffmpeg::FFmpeg as Decoder;
+use bliss_audio::decoder::Decoder as _;
+use bliss_audio::library::{AppConfigTrait, BaseConfig, Library};
+use bliss_audio::FEATURES_VERSION;
+use std::path::{Path, PathBuf};
+
+/// How many analyzed songs are looked at to find one to decode.
+const DECODER_SAMPLE_SIZE: usize = 100;
+
+/// What `blissify check` checks, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Check {
+    Config,
+    Database,
+    FeaturesVersion,
+    Decoder,
+    Mpd,
+}
+
+impl Check {
+    /// The exit code of `blissify check` when this check fails.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Check::Config => 2,
+            Check::Database => 3,
+            Check::FeaturesVersion => 4,
+            Check::Decoder => 5,
+            Check::Mpd => 6,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Check::Config => "configuration",
+            Check::Database => "database",
+            Check::FeaturesVersion => "features version",
+            Check::Decoder => "decoder",
+            Check::Mpd => "MPD connection",
+        }
+    }
+}
+
+/// How a [Check] went, with the details printed next to it.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Passed(String),
+    Failed(String),
+    /// The check needs an earlier one to pass.
+    Skipped,
+}
+
+impl From<Result<String>> for Outcome {
+    fn from(result: Result<String>) -> Self {
+        match result {
+            Ok(details) => Outcome::Passed(details),
+            Err(e) => Outcome::Failed(format!("{:#}", e)),
+        }
+    }
+}
+
+/// Read and sanity-check the configuration at `config_path`.
+fn check_config(config_path: &Path) -> Result<Config> {
+    let config = Config::from_path(&config_path.to_string_lossy())?;
+    for base_path in config.base_paths().chain(config.mounts.values()) {
+        if !base_path.is_dir() {
+            bail!(
+                "the music directory {} is not a directory.",
+                base_path.display()
+            );
+        }
+    }
+    let distances = [(String::from("playlist"), &config.playlist)]
+        .into_iter()
+        .chain(config.profiles.iter().filter_map(|(name, profile)| {
+            Some((
+                format!("profiles.{}.playlist", name),
+                profile.playlist.as_ref()?,
+            ))
+        }));
+    for (section, defaults) in distances {
+        if let Some(distance) = &defaults.distance {
+            parse_distance(distance)
+                .with_context(|| format!("the \"distance\" of \"{}\" is invalid", section))?;
+        }
+    }
+    if config.base_config.features_version != FEATURES_VERSION {
+        bail!(
+            "it was written for version {} of bliss' features, but blissify uses version {}.",
+            config.base_config.features_version,
+            FEATURES_VERSION
+        );
+    }
+    Ok(config)
+}
+
+/// Check that the database can be read, and count its songs.
+fn check_database(library: &Library<Config, Decoder>) -> Result<String> {
+    let sqlite_conn = library.sqlite_conn.lock().unwrap();
+    let integrity: String = sqlite_conn.query_row("pragma quick_check", [], |row| row.get(0))?;
+    if integrity != "ok" {
+        bail!("the database is corrupted: {}.", integrity);
+    }
+    let songs: i64 = sqlite_conn.query_row(
+        "select count(*) from song where analyzed = true",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(format!("{} analyzed song(s)", songs))
+}
+
+/// Check that all the songs were analyzed with blissify's version of the
+/// features.
+fn check_features_version(library: &Library<Config, Decoder>) -> Result<String> {
+    let outdated: i64 = library.sqlite_conn.lock().unwrap().query_row(
+        "select count(*) from song where analyzed = true and version != ?1",
+        [FEATURES_VERSION],
+        |row| row.get(0),
+    )?;
+    if outdated > 0 {
+        bail!(
+            "{} song(s) were analyzed with another version of bliss' features, run `blissify rescan` to analyze them again.",
+            outdated
+        );
+    }
+    Ok(format!("version {}", FEATURES_VERSION))
+}
+
+/// Check that the decoder works, by decoding one of the analyzed songs that
+/// is still on disk.
+fn check_decoder(library: &Library<Config, Decoder>) -> Result<String> {
+    let paths = {
+        let sqlite_conn = library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn
+            .prepare("select path from song where analyzed = true and cue_path is null limit ?1")?;
+        let paths = query
+            .query_map([DECODER_SAMPLE_SIZE], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        paths
+    };
+    let path = match paths.iter().map(PathBuf::from).find(|p| p.exists()) {
+        Some(path) => path,
+        None => return Ok(String::from("no analyzed song to decode, skipped")),
+    };
+    Decoder::song_from_path(&path)?;
+    Ok(format!("decoded {}", path.display()))
+}
+
+/// Check that MPD can be reached with MPD_HOST and MPD_PORT.
+fn check_mpd() -> Result<String> {
+    let mut mpd_conn = MPDLibrary::get_mpd_conn(&MPDAddress::from_env()?)?;
+    mpd_conn.status()?;
+    Ok(String::from("connected"))
+}
+
+/// Open the database of `config`, without creating it if it doesn't exist.
+fn open_database(config: &Config, config_path: PathBuf) -> Result<Library<Config, Decoder>> {
+    let database_path = &config.base_config.database_path;
+    if !database_path.exists() {
+        bail!(
+            "{} does not exist, run `blissify init` first.",
+            database_path.display()
+        );
+    }
+    Library::from_config_path(Some(config_path))
+}
+
+/// Run all the checks, for the configuration at `config_path`, or the
+/// default one.
+pub(crate) fn run_checks(config_path: Option<PathBuf>) -> Vec<(Check, Outcome)> {
+    let mut outcomes = vec![];
+    let config = config_path
+        .map_or_else(
+            || BaseConfig::new(None, None, None).map(|c| c.config_path),
+            Ok,
+        )
+        .and_then(|path| Ok((check_config(&path)?, path)));
+    let library = match config {
+        Ok((config, config_path)) => {
+            let details = config_path.display().to_string();
+            outcomes.push((Check::Config, Outcome::Passed(details)));
+            let database = open_database(&config, config_path)
+                .and_then(|library| Ok((check_database(&library)?, library)));
+            match database {
+                Ok((details, library)) => {
+                    outcomes.push((Check::Database, Outcome::Passed(details)));
+                    Some(library)
+                }
+                Err(e) => {
+                    outcomes.push((Check::Database, Outcome::from(Err(e))));
+                    None
+                }
+            }
+        }
+        Err(e) => {
+            outcomes.push((Check::Config, Outcome::from(Err(e))));
+            outcomes.push((Check::Database, Outcome::Skipped));
+            None
+        }
+    };
+    match library {
+        Some(library) => {
+            outcomes.push((
+                Check::FeaturesVersion,
+                check_features_version(&library).into(),
+            ));
+            outcomes.push((Check::Decoder, check_decoder(&library).into()));
+        }
+        None => {
+            outcomes.push((Check::FeaturesVersion, Outcome::Skipped));
+            outcomes.push((Check::Decoder, Outcome::Skipped));
+        }
+    }
+    outcomes.push((Check::Mpd, check_mpd().into()));
+    outcomes
+}
+
+/// Print `outcomes`, and return the exit code of the first failed check, if
+/// any.
+pub(crate) fn report(outcomes: &[(Check, Outcome)]) -> Option<i32> {
+    for (check, outcome) in outcomes {
+        match outcome {
+            Outcome::Passed(details) => println!("ok      {}: {}", check.description(), details),
+            Outcome::Failed(error) => println!("FAILED  {}: {}", check.description(), error),
+            Outcome::Skipped => println!("skipped {}", check.description()),
+        }
+    }
+    outcomes
+        .iter()
+        .find(|(_, outcome)| matches!(outcome, Outcome::Failed(_)))
+        .map(|(check, _)| check.exit_code())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use crate::PlaylistDefaults;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_run_checks() {
+        let (library, tempdir) = setup_library();
+        let config_path = library.library.config.base_config.config_path.to_owned();
+        let outcomes = run_checks(Some(tempdir.path().join("missing.json")));
+        assert!(matches!(outcomes[0], (Check::Config, Outcome::Failed(_))));
+        assert_eq!(outcomes[1], (Check::Database, Outcome::Skipped));
+        assert_eq!(
+            outcomes[4],
+            (Check::Mpd, Outcome::from(Ok(String::from("connected"))))
+        );
+        assert_eq!(report(&outcomes), Some(2));
+
+        // The music directory of setup_library, "path", doesn't exist.
+        let mut config = library.library.config.to_owned();
+        config.mpd_base_path = tempdir.path().to_owned();
+        config.write().unwrap();
+        let song_path = tempdir.path().join("song.flac");
+        std::fs::copy("data/s16_mono_22_5kHz.flac", &song_path).unwrap();
+        insert_songs(
+            &library,
+            &[
+                (1, &song_path.to_string_lossy(), None, 0.),
+                (2, "/not/on/disk.flac", None, 0.),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute("update song set version = ?1", [FEATURES_VERSION + 1])
+            .unwrap();
+        let outcomes = run_checks(Some(config_path.to_owned()));
+        assert_eq!(
+            outcomes.iter().map(|(check, _)| *check).collect::<Vec<_>>(),
+            vec![
+                Check::Config,
+                Check::Database,
+                Check::FeaturesVersion,
+                Check::Decoder,
+                Check::Mpd,
+            ],
+        );
+        assert_eq!(
+            outcomes[1].1,
+            Outcome::Passed(String::from("2 analyzed song(s)"))
+        );
+        assert!(matches!(outcomes[2].1, Outcome::Failed(_)));
+        assert_eq!(
+            outcomes[3].1,
+            Outcome::Passed(format!("decoded {}", song_path.display()))
+        );
+        assert_eq!(report(&outcomes), Some(4));
+
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute("update song set version = ?1", [FEATURES_VERSION])
+            .unwrap();
+        assert_eq!(report(&run_checks(Some(config_path.to_owned()))), None);
+
+        config
+            .profiles
+            .entry(String::from("alice"))
+            .or_default()
+            .playlist = Some(PlaylistDefaults {
+            distance: Some(String::from("0.5*euclidean + 0.5*manhattan")),
+            ..Default::default()
+        });
+        config.write().unwrap();
+        let outcomes = run_checks(Some(config_path));
+        match &outcomes[0].1 {
+            Outcome::Failed(e) => assert!(
+                e.contains("\"profiles.alice.playlist\" is invalid: Unknown distance 'manhattan'")
+            ),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Detecting the songs whose file changed since they were analyzed, see
+//! [Config::content_hash](crate::Config::content_hash).
+//!
+//! Updates only analyze the songs that are not in the database yet, so a file
+//! whose audio was replaced in place, e.g. by re-ripping an album, keeps its
+//! old analysis. With `content_hash` set, an xxhash of each file is stored in
+//! the `content_hash` column of bliss' `song` table the first time an update
+//! sees it, and the songs whose file hashes differently on later updates are
+//! removed, to be analyzed again. Only the contents count, so restoring a
+//! backup or copying files with `cp -a` doesn't trigger anything.
+//!
+//! Hashing reads the files, which is slow on network drives: `"partial"` only
+//! reads their first [PARTIAL_HASH_SIZE] bytes, along with their size, while
+//! `"full"` reads them entirely.
+use crate::MPDLibrary;
+use anyhow::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use xxhash_rust::xxh3::Xxh3;
+
+/// How much of the files to hash.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentHash {
+    /// Their first [PARTIAL_HASH_SIZE] bytes, and their size.
+    Partial,
+    /// Their whole contents.
+    Full,
+}
+
+/// How many bytes of each file [ContentHash::Partial] hashes.
+pub const PARTIAL_HASH_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Hash the file at `path`.
+fn hash_file(path: &Path, content_hash: ContentHash) -> io::Result<i64> {
+    let file = File::open(path)?;
+    let mut hasher = Xxh3::new();
+    hasher.update(&file.metadata()?.len().to_le_bytes());
+    let mut reader: Box<dyn Read> = match content_hash {
+        ContentHash::Partial => Box::new(file.take(PARTIAL_HASH_SIZE)),
+        ContentHash::Full => Box::new(file),
+    };
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    // SQLite only has signed integers.
+    Ok(hasher.digest() as i64)
+}
+
+impl MPDLibrary {
+    /// Add the `content_hash` column to bliss' `song` table, if it isn't
+    /// there yet. It is null for songs that were not hashed yet.
+    pub(crate) fn create_content_hash_column(&self) -> Result<()> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare("select name from pragma_table_info('song')")?;
+        let columns = query
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if !columns.iter().any(|c| c == "content_hash") {
+            sqlite_conn.execute("alter table song add column content_hash integer", [])?;
+        }
+        Ok(())
+    }
+
+    /// Hash the files of the songs listed in `paths` if
+    /// [content_hash](crate::Config::content_hash) is set, and remove the
+    /// songs whose file changed since it was last hashed, so that they are
+    /// analyzed again. Returns the number of files that changed.
+    ///
+    /// `paths` are listed as in [get_songs_paths](Self::get_songs_paths).
+    /// Missing files are left alone.
+    pub(crate) fn remove_changed_songs(&mut self, paths: &[String]) -> Result<usize> {
+        let content_hash = match self.library.config.content_hash {
+            Some(content_hash) => content_hash,
+            None => return Ok(0),
+        };
+        let paths = paths.iter().collect::<HashSet<_>>();
+        // The songs' ids, the path they are listed by, the file they are
+        // decoded from, and their stored hash.
+        let songs = {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut query = sqlite_conn.prepare(
+                "select id, coalesce(cue_path, path), coalesce(audio_file_path, path),
+                content_hash from song",
+            )?;
+            let songs = query
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, Option<i64>>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            songs
+        };
+        let mut hashes = HashMap::new();
+        let mut new_hashes = vec![];
+        let mut changed = HashSet::new();
+        for (id, listed_path, file, stored_hash) in songs {
+            if !paths.contains(&listed_path) {
+                continue;
+            }
+            let hash = match hashes.get(&file) {
+                Some(hash) => *hash,
+                None => match hash_file(Path::new(&file), content_hash) {
+                    Ok(hash) => *hashes.entry(file).or_insert(hash),
+                    Err(_) => continue,
+                },
+            };
+            match stored_hash {
+                None => new_hashes.push((id, hash)),
+                Some(stored_hash) if stored_hash != hash => {
+                    changed.insert(listed_path);
+                }
+                Some(_) => (),
+            }
+        }
+
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let transaction = sqlite_conn.transaction()?;
+        for (id, hash) in new_hashes {
+            transaction.execute(
+                "update song set content_hash = ?1 where id = ?2",
+                [hash, id],
+            )?;
+        }
+        for path in &changed {
+            transaction.execute(
+                "delete from song where coalesce(cue_path, path) = ?1",
+                [path],
+            )?;
+        }
+        transaction.commit()?;
+        if !changed.is_empty() {
+            info!(
+                "{} file(s) changed since they were analyzed, analyzing them again.",
+                changed.len()
+            );
+        }
+        Ok(changed.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    #[test]
+    fn test_remove_changed_songs() {
+        let (mut library, tempdir) = setup_library();
+        let song = tempdir.path().join("song.flac");
+        let other = tempdir.path().join("other.flac");
+        fs::write(&song, "some audio").unwrap();
+        fs::write(&other, "other audio").unwrap();
+        let song = song.to_string_lossy().to_string();
+        let other = other.to_string_lossy().to_string();
+        insert_songs(&library, &[(1, &song, None, 0.), (2, &other, None, 0.)]);
+        let paths = vec![song.to_owned(), other.to_owned()];
+        let songs = |library: &MPDLibrary| {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            let mut query = sqlite_conn
+                .prepare("select path, content_hash is not null from song order by id")
+                .unwrap();
+            let songs = query
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<Result<Vec<(String, bool)>, _>>()
+                .unwrap();
+            songs
+        };
+
+        // Disabled by default.
+        assert_eq!(library.remove_changed_songs(&paths).unwrap(), 0);
+        assert_eq!(
+            songs(&library),
+            vec![(song.to_owned(), false), (other.to_owned(), false)],
+        );
+
+        library.library.config.content_hash = Some(ContentHash::Partial);
+        assert_eq!(library.remove_changed_songs(&paths).unwrap(), 0);
+        assert_eq!(
+            songs(&library),
+            vec![(song.to_owned(), true), (other.to_owned(), true)],
+        );
+
+        // Copying the file over doesn't count, changing it does.
+        fs::copy(&song, tempdir.path().join("copy.flac")).unwrap();
+        fs::rename(tempdir.path().join("copy.flac"), &song).unwrap();
+        fs::write(&other, "other audio, re-ripped").unwrap();
+        assert_eq!(library.remove_changed_songs(&paths).unwrap(), 1);
+        assert_eq!(songs(&library), vec![(song.to_owned(), true)]);
+    }
+}
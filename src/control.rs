@@ -0,0 +1,146 @@
+//! Control socket, used by `blissify ctl` to drive a running daemon.
+//!
+//! The protocol is line-based: clients send a command on a single line, and
+//! get a single line back, starting with "error: " if the command failed.
+use anyhow::{bail, Context, Result};
+use log::warn;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+/// The default path of the control socket, in `XDG_RUNTIME_DIR` if it is set,
+/// or else in the temporary directory, named after the user.
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("blissify.sock"),
+        // SAFETY: geteuid can't fail.
+        None => env::temp_dir().join(format!("blissify-{}.sock", unsafe { libc::geteuid() })),
+    }
+}
+
+/// Listen on `path` from a background thread, answering each command with
+/// `handler`, from a thread of its own so that slow commands don't hold up
+/// the others.
+pub fn serve<F>(path: &Path, handler: F) -> Result<()>
+where
+    F: Fn(&str) -> Result<String> + Send + Sync + 'static,
+{
+    if UnixStream::connect(path).is_ok() {
+        bail!(
+            "Another blissify daemon is already listening on {}.",
+            path.display()
+        );
+    }
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        // SAFETY: geteuid can't fail.
+        if metadata.uid() != unsafe { libc::geteuid() } {
+            bail!(
+                "{} belongs to another user, please remove it or choose another control socket.",
+                path.display()
+            );
+        }
+        // Leftover from a daemon that didn't exit cleanly.
+        fs::remove_file(path)
+            .with_context(|| format!("while removing the old control socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("while binding the control socket {}", path.display()))?;
+    // Only the daemon's user can drive it.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("while restricting the control socket {}", path.display()))?;
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Could not answer a control command: {}", e);
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            thread::spawn(move || {
+                let result = (|| -> Result<()> {
+                    let mut command = String::new();
+                    BufReader::new(&stream).read_line(&mut command)?;
+                    let response = match handler(command.trim()) {
+                        Ok(r) => r,
+                        Err(e) => format!("error: {}", e),
+                    };
+                    writeln!(&stream, "{}", response)?;
+                    Ok(())
+                })();
+                if let Err(e) = result {
+                    warn!("Could not answer a control command: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Send `command` to the daemon listening on `path`, and return its response.
+pub fn send(path: &Path, command: &str) -> Result<String> {
+    let stream = UnixStream::connect(path).with_context(|| {
+        format!(
+            "while connecting to {}. Is `blissify daemon` running?",
+            path.display()
+        )
+    })?;
+    writeln!(&stream, "{}", command)?;
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response)?;
+    let response = response.trim_end();
+    if let Some(e) = response.strip_prefix("error: ") {
+        bail!("{}", e);
+    }
+    Ok(response.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::{mpsc, Mutex};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_control_socket() {
+        let dir = TempDir::new("control").unwrap();
+        let path = dir.path().join("blissify.sock");
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Mutex::new(receiver);
+        serve(&path, move |command| match command {
+            "status" => Ok(String::from("running")),
+            "wait" => {
+                receiver.lock().unwrap().recv()?;
+                Ok(String::from("done"))
+            }
+            _ => bail!("Unknown command '{}'.", command),
+        })
+        .unwrap();
+
+        let waiting = thread::spawn({
+            let path = path.clone();
+            move || send(&path, "wait").unwrap()
+        });
+        // Answered while "wait" is still waiting.
+        assert_eq!(send(&path, "status").unwrap(), "running");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        sender.send(()).unwrap();
+        assert_eq!(waiting.join().unwrap(), "done");
+        assert_eq!(
+            send(&path, "foo").unwrap_err().to_string(),
+            "Unknown command 'foo'."
+        );
+        assert!(serve(&path, |_| Ok(String::new())).is_err());
+    }
+}
@@ -0,0 +1,593 @@
+//! CUE tracks, including the ones of FLAC files with an embedded CUE sheet,
+//! and of files with a sidecar CUE sheet.
+//!
+//! MPD exposes the tracks of a CUE sheet as `album.cue/track0003`, and the
+//! tracks of a FLAC file with an embedded CUE sheet as `album.flac/track0003`.
+//! bliss only analyzes standalone CUE sheets, so embedded ones are written to
+//! a temporary CUE sheet pointing to the FLAC file, and the resulting tracks
+//! are stored as `album.flac/CUE_TRACK003`, like the tracks of a CUE sheet.
+//!
+//! Long files like DJ mixes can also have a sidecar CUE sheet that MPD doesn't
+//! read, `mix.mp3.cue` or `mix.cue` next to `mix.mp3`. Their tracks are
+//! analyzed and stored the same way, as `mix.mp3/CUE_TRACK003`, but since MPD
+//! only knows about the whole file, they are queued as `mix.mp3` restricted to
+//! the track's time range.
+use crate::{Decoder, MPDLibrary};
+use anyhow::{bail, Context, Result};
+use bliss_audio::cue::CueInfo;
+use bliss_audio::decoder::Decoder as _;
+use bliss_audio::{BlissResult, Song};
+use log::info;
+use mpd::song::Range;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempdir::TempDir;
+
+/// Split the path of an MPD CUE track, like `album.cue/track0003` or
+/// `album.flac/track0003`, into the file the track comes from and its track
+/// number. Returns `None` if `file` is not a CUE track.
+pub fn split_cue_track(file: &str) -> Option<(&str, usize)> {
+    let (parent, name) = file.rsplit_once('/')?;
+    let number = name.strip_prefix("track")?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // Only CUE sheets and files that can embed one have tracks, which rules
+    // out directories that happen to contain files named "trackNNNN".
+    match Path::new(parent)
+        .extension()?
+        .to_str()?
+        .to_lowercase()
+        .as_str()
+    {
+        "cue" | "flac" => Some((parent, number.parse().ok()?)),
+        _ => None,
+    }
+}
+
+fn is_flac(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("flac"))
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64_be(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// The `CUESHEET` tag of a FLAC file's Vorbis comment block, if any.
+fn vorbis_cue_sheet(block: &[u8]) -> Option<String> {
+    let vendor_length = read_u32_le(block, 0)? as usize;
+    let mut offset = 4 + vendor_length;
+    let number_comments = read_u32_le(block, offset)?;
+    offset += 4;
+    for _ in 0..number_comments {
+        let length = read_u32_le(block, offset)? as usize;
+        let comment = block.get(offset + 4..offset + 4 + length)?;
+        offset += 4 + length;
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            if key.eq_ignore_ascii_case("CUESHEET") {
+                return Some(value.to_owned());
+            }
+        }
+    }
+    None
+}
+
+/// Turn a FLAC `CUESHEET` metadata block into a CUE sheet, given the sample
+/// rate of the file.
+fn binary_cue_sheet(block: &[u8], sample_rate: u64) -> Option<String> {
+    if sample_rate == 0 {
+        return None;
+    }
+    // Media catalog number, lead-in samples, CD flag and reserved bytes.
+    let mut offset = 128 + 8 + 259;
+    let number_tracks = *block.get(offset)?;
+    offset += 1;
+    let mut cue_sheet = String::new();
+    for _ in 0..number_tracks {
+        let track_offset = read_u64_be(block, offset)?;
+        let track_number = *block.get(offset + 8)?;
+        // ISRC, flags and reserved bytes.
+        let number_indices = *block.get(offset + 8 + 1 + 12 + 14)?;
+        offset += 8 + 1 + 12 + 14 + 1;
+        let mut indices = vec![];
+        for _ in 0..number_indices {
+            indices.push((read_u64_be(block, offset)?, *block.get(offset + 8)?));
+            offset += 12;
+        }
+        // Lead-out track.
+        if track_number == 170 || track_number == 255 {
+            continue;
+        }
+        cue_sheet.push_str(&format!("  TRACK {:02} AUDIO\n", track_number));
+        for (index_offset, index_number) in indices {
+            let frames = (track_offset + index_offset) * 75 / sample_rate;
+            cue_sheet.push_str(&format!(
+                "    INDEX {:02} {:02}:{:02}:{:02}\n",
+                index_number,
+                frames / 75 / 60,
+                frames / 75 % 60,
+                frames % 75,
+            ));
+        }
+    }
+    Some(cue_sheet)
+}
+
+/// Read the CUE sheet embedded in the FLAC file at `path`, from its `CUESHEET`
+/// tag, or from its CUESHEET metadata block otherwise.
+///
+/// The `FILE` lines of the sheet are left as they are.
+pub fn embedded_cue_sheet(path: &Path) -> Result<Option<String>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Ok(None);
+    }
+    let (mut sample_rate, mut binary_block) = (0, None);
+    loop {
+        let mut header = [0; 4];
+        file.read_exact(&mut header)?;
+        let (last, block_type) = (header[0] & 0x80 != 0, header[0] & 0x7f);
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        match block_type {
+            // STREAMINFO, CUESHEET and VORBIS_COMMENT.
+            0 | 4 | 5 => {
+                let mut block = vec![0; length as usize];
+                file.read_exact(&mut block)?;
+                match block_type {
+                    0 if block.len() >= 13 => {
+                        sample_rate = (u64::from(block[10]) << 12)
+                            | (u64::from(block[11]) << 4)
+                            | (u64::from(block[12]) >> 4);
+                    }
+                    4 => {
+                        if let Some(cue_sheet) = vorbis_cue_sheet(&block) {
+                            return Ok(Some(cue_sheet));
+                        }
+                    }
+                    5 => binary_block = Some(block),
+                    _ => (),
+                }
+            }
+            _ => {
+                file.seek(SeekFrom::Current(length.into()))?;
+            }
+        }
+        if last {
+            break;
+        }
+    }
+    Ok(binary_block.and_then(|b| binary_cue_sheet(&b, sample_rate)))
+}
+
+/// The sidecar CUE sheet of the audio file at `file`, `mix.mp3.cue` or
+/// `mix.cue` for `mix.mp3`, if there is one.
+pub fn sidecar_cue_sheet(file: &Path) -> Option<PathBuf> {
+    if file
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+    {
+        return None;
+    }
+    let mut name = file.file_name()?.to_owned();
+    name.push(".cue");
+    [file.with_file_name(name), file.with_extension("cue")]
+        .into_iter()
+        .find(|p| p.is_file())
+}
+
+/// Parse a CUE sheet time, `mm:ss:ff` with 75 frames per second.
+fn cue_time(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':').map(|p| p.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || frames >= 75 {
+        return None;
+    }
+    Some(
+        Duration::from_secs(minutes * 60 + seconds)
+            + Duration::from_nanos(frames * 1_000_000_000 / 75),
+    )
+}
+
+/// The start of each track of `cue_sheet` in its audio file, from their
+/// `INDEX 01` lines, as (track number, start) pairs.
+fn track_starts(cue_sheet: &str) -> Vec<(usize, Duration)> {
+    let mut starts = vec![];
+    let mut track = None;
+    for line in cue_sheet.lines() {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("TRACK"), Some(number), _) => track = number.parse().ok(),
+            (Some("INDEX"), Some("01"), Some(time)) => {
+                if let (Some(track), Some(start)) = (track.take(), cue_time(time)) {
+                    starts.push((track, start));
+                }
+            }
+            _ => (),
+        }
+    }
+    starts
+}
+
+/// The time ranges of the tracks of the sidecar CUE sheet of `file`, as
+/// (track number, range) pairs, or `None` if `file` has no sidecar CUE sheet.
+///
+/// Tracks end where the next one starts, and the last one at the end of the
+/// file.
+fn sidecar_track_ranges(file: &Path) -> Result<Option<Vec<(usize, Range)>>> {
+    let cue_path = match sidecar_cue_sheet(file) {
+        Some(cue_path) => cue_path,
+        None => return Ok(None),
+    };
+    let cue_sheet = fs::read_to_string(&cue_path)
+        .with_context(|| format!("while reading {}", cue_path.display()))?;
+    let starts = track_starts(&cue_sheet);
+    let ends = starts.iter().skip(1).map(|(_, start)| Some(*start));
+    Ok(Some(
+        starts
+            .iter()
+            .zip(ends.chain([None]))
+            .map(|(&(track, start), end)| (track, Range(start, end)))
+            .collect(),
+    ))
+}
+
+/// The time range of the track `track_number` of `file` in its sidecar CUE
+/// sheet, or `None` if `file` has no sidecar CUE sheet.
+pub fn sidecar_track_range(file: &Path, track_number: usize) -> Result<Option<Range>> {
+    let ranges = match sidecar_track_ranges(file)? {
+        Some(ranges) => ranges,
+        None => return Ok(None),
+    };
+    match ranges.into_iter().find(|(track, _)| *track == track_number) {
+        Some((_, range)) => Ok(Some(range)),
+        None => bail!(
+            "The sidecar CUE sheet of {} has no track {}.",
+            file.display(),
+            track_number
+        ),
+    }
+}
+
+/// The number of the track of `file`'s sidecar CUE sheet starting at `start`,
+/// if any.
+pub fn sidecar_track_at(file: &Path, start: Duration) -> Option<usize> {
+    // MPD rounds ranges to the millisecond.
+    sidecar_track_ranges(file)
+        .ok()??
+        .into_iter()
+        .find(|(_, range)| range.0.abs_diff(start) < Duration::from_millis(1))
+        .map(|(track, _)| track)
+}
+
+/// Point all the `FILE` lines of `cue_sheet` to `audio_file`, adding one if
+/// there is none, as with sheets made from CUESHEET metadata blocks.
+fn with_audio_file(cue_sheet: &str, audio_file: &Path) -> String {
+    let file_line = format!("FILE \"{}\" WAVE", audio_file.display());
+    let mut has_file = false;
+    let mut lines = cue_sheet
+        .lines()
+        .map(|line| {
+            if line.trim_start().starts_with("FILE ") {
+                has_file = true;
+                file_line.to_owned()
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>();
+    if !has_file {
+        let first_track = lines
+            .iter()
+            .position(|l| l.trim_start().starts_with("TRACK "))
+            .unwrap_or(0);
+        lines.insert(first_track, file_line);
+    }
+    lines.join("\n")
+}
+
+impl MPDLibrary {
+    /// Analyze the tracks of the audio file at `file`, using its sidecar CUE
+    /// sheet if it has one, or its embedded CUE sheet otherwise, returning
+    /// each track's path, like `album.flac/CUE_TRACK003`, and its analysis.
+    pub(crate) fn analyze_file_tracks(
+        &self,
+        file: &Path,
+    ) -> Result<Vec<(PathBuf, BlissResult<Song>)>> {
+        let cue_sheet = match sidecar_cue_sheet(file) {
+            Some(cue_path) => Some(
+                fs::read_to_string(&cue_path)
+                    .with_context(|| format!("while reading {}", cue_path.display()))?,
+            ),
+            None => embedded_cue_sheet(file)
+                .with_context(|| format!("while reading {}", file.display()))?,
+        };
+        let cue_sheet = match cue_sheet {
+            Some(c) => c,
+            None => bail!("{} does not have a CUE sheet.", file.display()),
+        };
+        let temp_dir = TempDir::new("blissify-cue")?;
+        let cue_path = temp_dir.path().join(format!(
+            "{}.cue",
+            file.file_stem().unwrap_or_default().to_string_lossy()
+        ));
+        fs::write(&cue_path, with_audio_file(&cue_sheet, file))?;
+
+        // Move the tracks from the temporary CUE sheet to the audio file.
+        let track_path = |path: &Path| match path.file_name() {
+            Some(name) if path != cue_path => file.join(name),
+            _ => file.to_owned(),
+        };
+        let number_cores = self.library.config.base_config.number_cores;
+        Ok(Decoder::analyze_paths_with_cores([&cue_path], number_cores)
+            .map(|(path, result)| {
+                let result = result.map(|mut song| {
+                    song.path = track_path(&song.path);
+                    song.cue_info = Some(CueInfo {
+                        cue_path: file.to_owned(),
+                        audio_file_path: file.to_owned(),
+                    });
+                    song
+                });
+                (track_path(&path), result)
+            })
+            .collect())
+    }
+
+    /// Analyze the FLAC files with an embedded CUE sheet and the files with a
+    /// sidecar CUE sheet among `paths` that are not analyzed yet, track by
+    /// track, since bliss would analyze each of them as a single song.
+    ///
+    /// Their tracks are stored with the audio file as their CUE path, so bliss
+    /// considers these files as analyzed afterwards. Sidecar CUE sheets that
+    /// MPD reads itself are left alone, since their tracks are analyzed
+    /// through them.
+    pub(crate) fn analyze_embedded_cues<P: AsRef<str>>(&mut self, paths: &[P]) -> Result<()> {
+        let analyzed = self.analyzed_paths()?;
+        let read_by_mpd = |cue_path: &Path| {
+            let cue_path = cue_path.to_string_lossy();
+            analyzed.contains(cue_path.as_ref()) || paths.iter().any(|p| p.as_ref() == cue_path)
+        };
+        for path in paths {
+            let path = path.as_ref();
+            let file = Path::new(path);
+            if analyzed.contains(path) {
+                continue;
+            }
+            // Files that can't be read are left for bliss to report.
+            let has_cue_sheet = match sidecar_cue_sheet(file) {
+                Some(cue_path) => !read_by_mpd(&cue_path),
+                None => is_flac(file) && embedded_cue_sheet(file).is_ok_and(|c| c.is_some()),
+            };
+            if !has_cue_sheet {
+                continue;
+            }
+            info!("Analyzing the tracks of {}.", file.display());
+            for (track, result) in self.analyze_file_tracks(file)? {
+                match result {
+                    Ok(song) => self.store_analyzed_song(song)?,
+                    Err(e) => self.store_failed_analysis(&track, e)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use bliss_audio::library::LibrarySong;
+    use mpd::song::Song as MPDSong;
+    use pretty_assertions::assert_eq;
+    use std::num::NonZeroUsize;
+
+    /// Make a FLAC file header with the given metadata blocks, as
+    /// (block type, block) pairs, after a STREAMINFO block.
+    fn flac(blocks: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut streaminfo = vec![0; 34];
+        // 44100Hz.
+        streaminfo[10..13].copy_from_slice(&[0x0a, 0xc4, 0x42]);
+        let blocks = [&[(0, streaminfo)], blocks].concat();
+        let mut data = b"fLaC".to_vec();
+        for (i, (block_type, block)) in blocks.iter().enumerate() {
+            let last = if i == blocks.len() - 1 { 0x80 } else { 0 };
+            data.push(last | block_type);
+            data.extend(&(block.len() as u32).to_be_bytes()[1..]);
+            data.extend(block);
+        }
+        data
+    }
+
+    #[test]
+    fn test_split_cue_track() {
+        assert_eq!(
+            split_cue_track("Albums/album.cue/track0003"),
+            Some(("Albums/album.cue", 3))
+        );
+        assert_eq!(
+            split_cue_track("tracks/album.FLAC/track0012"),
+            Some(("tracks/album.FLAC", 12))
+        );
+        assert_eq!(split_cue_track("Albums/Vol.1/track0001"), None);
+        assert_eq!(split_cue_track("Albums/album.cue/tracklist"), None);
+        assert_eq!(split_cue_track("Albums/song.flac"), None);
+    }
+
+    #[test]
+    fn test_mpd_to_bliss_path() {
+        let (library, _tempdir) = setup_library();
+        for (file, expected) in [
+            (
+                "Albums/album.cue/track0003",
+                "path/Albums/album.cue/CUE_TRACK003",
+            ),
+            (
+                "Albums/album.flac/track0012",
+                "path/Albums/album.flac/CUE_TRACK012",
+            ),
+            ("Albums/Vol.1/track0001", "path/Albums/Vol.1/track0001"),
+            ("Albums/tracks/song.flac", "path/Albums/tracks/song.flac"),
+        ] {
+            let mpd_song = MPDSong {
+                file: file.to_owned(),
+                ..Default::default()
+            };
+            assert_eq!(
+                library.mpd_to_bliss_path(&mpd_song).unwrap(),
+                PathBuf::from(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_vorbis_cue_sheet() {
+        let cue_sheet = "FILE \"CDImage.wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00";
+        let mut block = 6u32.to_le_bytes().to_vec();
+        block.extend(b"vendor");
+        let comments = ["TITLE=Album".to_owned(), format!("cuesheet={}", cue_sheet)];
+        block.extend((comments.len() as u32).to_le_bytes());
+        for c in comments {
+            block.extend((c.len() as u32).to_le_bytes());
+            block.extend(c.as_bytes());
+        }
+        let temp_dir = TempDir::new("cue").unwrap();
+        let path = temp_dir.path().join("album.flac");
+        fs::write(&path, flac(&[(1, vec![0; 16]), (4, block)])).unwrap();
+
+        let embedded = embedded_cue_sheet(&path).unwrap().unwrap();
+        assert_eq!(embedded, cue_sheet);
+        assert_eq!(
+            with_audio_file(&embedded, Path::new("/music/album.flac")),
+            "FILE \"/music/album.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00",
+        );
+    }
+
+    #[test]
+    fn test_binary_cue_sheet() {
+        let mut block = vec![0; 128 + 8 + 259];
+        block.push(3);
+        // Tracks at 0s and 62.5s (with a pregap at 60s), and the lead-out.
+        for (offset, number, indices) in [
+            (0u64, 1u8, vec![(0u64, 1u8)]),
+            (2_646_000, 2, vec![(0, 0), (110_250, 1)]),
+            (13_230_000, 170, vec![]),
+        ] {
+            block.extend(offset.to_be_bytes());
+            block.push(number);
+            block.extend([0; 12 + 14]);
+            block.push(indices.len() as u8);
+            for (offset, number) in indices {
+                block.extend(offset.to_be_bytes());
+                block.push(number);
+                block.extend([0; 3]);
+            }
+        }
+        let temp_dir = TempDir::new("cue").unwrap();
+        let path = temp_dir.path().join("album.flac");
+        fs::write(&path, flac(&[(5, block)])).unwrap();
+
+        let embedded = embedded_cue_sheet(&path).unwrap().unwrap();
+        assert_eq!(
+            with_audio_file(&embedded, Path::new("/music/album.flac")),
+            "FILE \"/music/album.flac\" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 01:00:00
+    INDEX 01 01:02:37",
+        );
+
+        fs::write(&path, flac(&[])).unwrap();
+        assert_eq!(embedded_cue_sheet(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sidecar_cue_sheet() {
+        let music_dir = TempDir::new("music").unwrap();
+        let mix = music_dir.path().join("mixes/mix.mp3");
+        fs::create_dir(mix.parent().unwrap()).unwrap();
+        fs::write(&mix, b"").unwrap();
+        assert_eq!(sidecar_cue_sheet(&mix), None);
+        assert_eq!(sidecar_track_range(&mix, 1).unwrap(), None);
+        fs::write(
+            mix.with_extension("mp3.cue"),
+            "FILE \"mix.mp3\" MP3
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 04:58:00
+    INDEX 01 05:02:30
+  TRACK 03 AUDIO
+    INDEX 01 61:00:74",
+        )
+        .unwrap();
+        assert_eq!(
+            sidecar_cue_sheet(&mix),
+            Some(music_dir.path().join("mixes/mix.mp3.cue"))
+        );
+        let start = Duration::from_millis(302_400);
+        let end = Duration::from_secs(3660) + Duration::from_nanos(74_000_000_000 / 75);
+        assert_eq!(
+            sidecar_track_range(&mix, 2).unwrap(),
+            Some(Range(start, Some(end)))
+        );
+        assert_eq!(
+            sidecar_track_range(&mix, 3).unwrap(),
+            Some(Range(end, None))
+        );
+        assert!(sidecar_track_range(&mix, 4).is_err());
+        assert_eq!(sidecar_track_at(&mix, start), Some(2));
+        assert_eq!(sidecar_track_at(&mix, Duration::from_secs(1)), None);
+
+        // Tracks are queued as the whole file restricted to their range, and
+        // map back to the track when they come back from MPD.
+        let config_dir = TempDir::new("config").unwrap();
+        let library = MPDLibrary::new(
+            music_dir.path().to_owned(),
+            Some(config_dir.path().join("config.json")),
+            Some(config_dir.path().join("bliss.db")),
+            Some(NonZeroUsize::new(1).unwrap()),
+        )
+        .unwrap();
+        let track = LibrarySong {
+            bliss_song: Song {
+                path: mix.join("CUE_TRACK002"),
+                track_number: Some(2),
+                cue_info: Some(CueInfo {
+                    cue_path: mix.to_owned(),
+                    audio_file_path: mix.to_owned(),
+                }),
+                ..Default::default()
+            },
+            extra_info: (),
+        };
+        let mpd_song = library.bliss_song_to_mpd(&track).unwrap();
+        assert_eq!(mpd_song.file, "mixes/mix.mp3");
+        assert_eq!(mpd_song.range, Some(Range(start, Some(end))));
+        assert_eq!(
+            library.mpd_to_bliss_path(&mpd_song).unwrap(),
+            track.bliss_song.path
+        );
+        let whole_mix = MPDSong {
+            file: String::from("mixes/mix.mp3"),
+            ..Default::default()
+        };
+        assert_eq!(library.mpd_to_bliss_path(&whole_mix).unwrap(), mix);
+    }
+}
@@ -0,0 +1,638 @@
+//! Long-running mode, keeping blissify's database in sync with MPD's.
+//!
+//! The daemon polls MPD for database updates, and analyzes new songs as
+//! they come. It is meant to be run as a systemd service of `Type=notify`:
+//! it signals its readiness through `sd_notify`, reloads its configuration
+//! on SIGHUP, and shuts down cleanly on SIGTERM / SIGINT, after the songs
+//! being analyzed have been stored in the database.
+//!
+//! The analysis can also be paused with SIGUSR1 or `blissify ctl pause`, and
+//! resumed with SIGUSR2 or `blissify ctl resume`. `blissify ctl status` shows
+//! its progress, see [ScanProgress].
+//!
+//! While running, the daemon also watches MPD's player, records the songs
+//! played, see [crate::history], and records songs from blissify's playlists
+//! skipped in their first [SKIP_THRESHOLD] as implicit negative feedback, see
+//! [crate::feedback].
+//!
+//! Finally, it queues the playlists of profiles asked for with
+//! `blissify ctl run-profile`, or whose schedule is due, see
+//! [crate::schedule].
+use crate::control;
+use crate::metrics::{self, Metrics};
+use crate::schedule;
+use crate::MPDLibrary;
+use anyhow::{bail, Result};
+use log::{debug, info, warn};
+use mpd::song::Song as MPDSong;
+use mpd::State;
+use std::env;
+use std::fmt;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A song changed before having played that long counts as skipped.
+const SKIP_THRESHOLD: Duration = Duration::from_secs(30);
+/// How often MPD's player is checked for plays and skips.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set by SIGTERM / SIGINT to ask the daemon to stop.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+/// Set by SIGHUP to ask the daemon to reload its configuration.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+/// Set by SIGUSR1 or `blissify ctl pause` to pause the analysis, and unset by
+/// SIGUSR2 or `blissify ctl resume` to resume it.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// A profile `blissify ctl run-profile` asked for, with where to send the
+/// outcome of queueing its playlist.
+type ProfileRequest = (String, Sender<Result<(), String>>);
+/// The profiles asked for and not run yet.
+static PROFILE_REQUESTS: Mutex<Vec<ProfileRequest>> = Mutex::new(vec![]);
+/// How long `blissify ctl run-profile` waits for the daemon, which only
+/// handles requests between the songs it analyzes.
+const PROFILE_TIMEOUT: Duration = Duration::from_secs(120);
+/// The progress of the new songs being analyzed, if any.
+static SCAN_PROGRESS: Mutex<Option<ScanProgress>> = Mutex::new(None);
+
+/// The progress of the analysis of the new songs found in MPD's database,
+/// shown by `blissify ctl status`.
+#[derive(Debug, Clone, PartialEq)]
+struct ScanProgress {
+    /// The number of new songs to analyze.
+    total: usize,
+    /// The number of songs analyzed so far, successfully or not.
+    done: usize,
+    /// The songs being analyzed, one per worker.
+    in_flight: Vec<String>,
+    started: Instant,
+}
+
+impl ScanProgress {
+    fn new(total: usize) -> Self {
+        Self {
+            total,
+            done: 0,
+            in_flight: vec![],
+            started: Instant::now(),
+        }
+    }
+
+    /// The number of songs waiting for a worker.
+    fn pending(&self) -> usize {
+        self.total - self.done - self.in_flight.len()
+    }
+
+    /// The number of songs analyzed per minute since the analysis started.
+    fn songs_per_minute(&self) -> f64 {
+        let minutes = self.started.elapsed().as_secs_f64() / 60.;
+        if minutes > 0. {
+            self.done as f64 / minutes
+        } else {
+            0.
+        }
+    }
+}
+
+impl fmt::Display for ScanProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "analyzed {}/{} new song(s), {:.1} song(s)/minute, {} pending",
+            self.done,
+            self.total,
+            self.songs_per_minute(),
+            self.pending(),
+        )?;
+        for (worker, path) in self.in_flight.iter().enumerate() {
+            write!(f, "; worker {}: {}", worker + 1, path)?;
+        }
+        Ok(())
+    }
+}
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        libc::SIGUSR1 => PAUSED.store(true, Ordering::SeqCst),
+        libc::SIGUSR2 => PAUSED.store(false, Ordering::SeqCst),
+        _ => SHUTDOWN.store(true, Ordering::SeqCst),
+    }
+}
+
+fn register_signals() {
+    for signal in [
+        libc::SIGTERM,
+        libc::SIGINT,
+        libc::SIGHUP,
+        libc::SIGUSR1,
+        libc::SIGUSR2,
+    ] {
+        // SAFETY: the handler only stores into atomics, which is async-signal-safe.
+        unsafe {
+            libc::signal(signal, handle_signal as *const () as libc::sighandler_t);
+        }
+    }
+}
+
+/// Send `state` to systemd's notification socket, see sd_notify(3).
+///
+/// Does nothing if blissify wasn't started by systemd.
+fn notify(state: &str) {
+    let socket_path = match env::var_os("NOTIFY_SOCKET") {
+        Some(p) => p,
+        None => return,
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let socket_path = socket_path.to_string_lossy();
+        #[cfg(target_os = "linux")]
+        if let Some(name) = socket_path.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            let addr = SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &*socket_path)
+    });
+    if let Err(e) = result {
+        warn!("Could not notify systemd: {}", e);
+    }
+}
+
+/// Options of the `daemon` subcommand.
+pub struct DaemonOptions {
+    /// The configuration path, read again when the configuration is reloaded.
+    pub config_path: Option<PathBuf>,
+    /// How often MPD is polled for database updates.
+    pub poll_interval: Duration,
+    /// Trim MPD's queue down to this many songs, instead of the
+    /// configuration file's `max_queue_length`.
+    pub max_queue_length: Option<usize>,
+    /// The address to serve Prometheus metrics on, if any.
+    pub metrics_address: Option<String>,
+    /// The path of the control socket `blissify ctl` talks to.
+    pub control_socket: PathBuf,
+}
+
+/// Answer a command sent by `blissify ctl` on the control socket.
+fn handle_command(command: &str) -> Result<String> {
+    if let Some(profile) = command.strip_prefix("run-profile ") {
+        let (sender, receiver) = mpsc::channel();
+        PROFILE_REQUESTS
+            .lock()
+            .unwrap()
+            .push((profile.to_owned(), sender));
+        return match receiver.recv_timeout(PROFILE_TIMEOUT) {
+            Ok(Ok(())) => Ok(format!("Queued the playlist of profile '{}'.", profile)),
+            Ok(Err(e)) => bail!("{}", e),
+            Err(_) => bail!("The daemon didn't queue the playlist in time, it may still do so."),
+        };
+    }
+    match command {
+        "pause" => PAUSED.store(true, Ordering::SeqCst),
+        "resume" => PAUSED.store(false, Ordering::SeqCst),
+        "status" => (),
+        _ => bail!("Unknown command '{}'.", command),
+    }
+    let state = if PAUSED.load(Ordering::SeqCst) {
+        "paused"
+    } else {
+        "running"
+    };
+    Ok(match &*SCAN_PROGRESS.lock().unwrap() {
+        Some(progress) => format!("{}, {}", state, progress),
+        None => String::from(state),
+    })
+}
+
+/// Block while the analysis is paused, unless a shutdown is requested.
+fn wait_while_paused() {
+    if !PAUSED.load(Ordering::SeqCst) {
+        return;
+    }
+    info!("Pausing the analysis.");
+    notify("STATUS=Analysis paused");
+    while PAUSED.load(Ordering::SeqCst) && !SHUTDOWN.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(200));
+    }
+    info!("Resuming the analysis.");
+}
+
+/// The song MPD was playing the last time the daemon checked.
+#[derive(Debug, Clone, PartialEq)]
+struct Playing {
+    /// The song's file, as returned by MPD.
+    file: String,
+    elapsed: Duration,
+    duration: Option<Duration>,
+}
+
+impl Playing {
+    /// Whether MPD going from this song to `next` means this song was skipped.
+    ///
+    /// Songs ending by themselves, even if shorter than [SKIP_THRESHOLD],
+    /// don't count.
+    fn skipped_for(&self, next: &Playing) -> bool {
+        next.file != self.file
+            && self.elapsed < SKIP_THRESHOLD
+            && self
+                .duration
+                .is_some_and(|d| self.elapsed + 2 * WATCH_INTERVAL < d)
+    }
+}
+
+/// The daemon's state, kept across reloads.
+struct Daemon {
+    library: MPDLibrary,
+    /// MPD's last database update, to only list songs when something changed.
+    last_db_update: Option<Duration>,
+    metrics: Arc<Metrics>,
+    playing: Option<Playing>,
+    last_watch: Instant,
+    /// The maximum queue length given on the command-line.
+    max_queue_length: Option<usize>,
+    /// When the profiles' schedules were last checked, in seconds since the
+    /// epoch.
+    last_schedule_check: i64,
+}
+
+impl Daemon {
+    /// Check which song MPD is playing, and record the previous one as
+    /// played when it changed, and as skipped if it was changed early, see
+    /// [Playing::skipped_for].
+    ///
+    /// Does nothing if the player was checked less than [WATCH_INTERVAL] ago.
+    fn watch_player(&mut self) {
+        if self.last_watch.elapsed() < WATCH_INTERVAL {
+            return;
+        }
+        self.last_watch = Instant::now();
+        if let Err(e) = self.check_player() {
+            debug!("Could not check for played songs: {}", e);
+        }
+    }
+
+    /// Queue the playlist of `profile`, counting it in the metrics.
+    fn queue_profile(&mut self, profile: &str) -> Result<()> {
+        self.library.queue_profile(profile)?;
+        self.metrics
+            .playlist_generations
+            .fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Queue the playlists of the profiles asked for with `blissify ctl
+    /// run-profile`, and of the profiles whose schedule is due.
+    fn run_profiles(&mut self) {
+        let requests = std::mem::take(&mut *PROFILE_REQUESTS.lock().unwrap());
+        for (profile, sender) in requests {
+            let result = self.queue_profile(&profile);
+            let _ = sender.send(result.map_err(|e| e.to_string()));
+        }
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now.as_secs() as i64,
+            Err(_) => return,
+        };
+        let since = std::mem::replace(&mut self.last_schedule_check, now);
+        let due = match schedule::due_profiles(&self.library.library.config.profiles, since, now) {
+            Ok(due) => due,
+            Err(e) => {
+                warn!("Could not check the profiles' schedules: {}", e);
+                return;
+            }
+        };
+        for profile in due {
+            match self.queue_profile(&profile) {
+                Ok(()) => info!("Queued the scheduled playlist of profile '{}'.", profile),
+                Err(e) => warn!(
+                    "Could not queue the scheduled playlist of profile '{}': {}",
+                    profile, e
+                ),
+            }
+        }
+    }
+
+    fn check_player(&mut self) -> Result<()> {
+        let mut mpd_conn = self.library.mpd_conn()?;
+        let status = mpd_conn.status()?;
+        let playing = match (status.state, mpd_conn.currentsong()?) {
+            (State::Play | State::Pause, Some(song)) => Some(Playing {
+                file: song.file,
+                elapsed: status.elapsed.unwrap_or_default(),
+                duration: status.duration,
+            }),
+            _ => None,
+        };
+        drop(mpd_conn);
+
+        let previous = std::mem::replace(&mut self.playing, playing);
+        let (previous, current) = match (previous, &self.playing) {
+            (Some(previous), Some(current)) => (previous, current),
+            _ => return Ok(()),
+        };
+        if previous.file == current.file {
+            return Ok(());
+        }
+        let skipped = previous.skipped_for(current);
+        let path = self.library.mpd_to_bliss_path(&MPDSong {
+            file: previous.file,
+            ..Default::default()
+        })?;
+        let path = path.to_string_lossy();
+        self.library.record_play(&path, skipped)?;
+        if skipped && self.library.record_skip(&path)? {
+            info!("Recorded {} as skipped.", path);
+        }
+        Ok(())
+    }
+
+    /// Analyze the songs that were added to MPD's database since the last
+    /// update, and remove the ones that were deleted from it, after trimming
+    /// the queue if it is longer than the maximum queue length.
+    ///
+    /// Songs are analyzed in chunks of `number_cores` songs, so a shutdown
+    /// request only waits for the songs currently being analyzed. Returns
+    /// false if the update was interrupted that way.
+    fn update(&mut self) -> Result<bool> {
+        let mut mpd_conn = self.library.mpd_conn()?;
+        let max_queue_length = self
+            .max_queue_length
+            .or(self.library.playlist_defaults().max_queue_length);
+        if let Some(max_queue_length) = max_queue_length {
+            let removed = MPDLibrary::trim_queue(&mut mpd_conn, max_queue_length)?;
+            if removed > 0 {
+                info!("Removed {} song(s) from the queue.", removed);
+            }
+        }
+        self.metrics
+            .queue_length
+            .store(mpd_conn.status()?.queue_len.into(), Ordering::Relaxed);
+        let db_update = mpd_conn.stats()?.db_update;
+        drop(mpd_conn);
+        if self.last_db_update == Some(db_update) {
+            return Ok(true);
+        }
+
+        let paths = self.library.get_songs_paths()?;
+        self.library.remove_changed_songs(&paths)?;
+        let analyzed = self.library.analyzed_paths()?;
+        let new_paths = paths
+            .iter()
+            .filter(|p| !analyzed.contains(*p))
+            .collect::<Vec<_>>();
+        if !new_paths.is_empty() {
+            info!("Analyzing {} new song(s).", new_paths.len());
+            *SCAN_PROGRESS.lock().unwrap() = Some(ScanProgress::new(new_paths.len()));
+        }
+        let analyzed = self.analyze_new_songs(&new_paths);
+        *SCAN_PROGRESS.lock().unwrap() = None;
+        self.metrics.analysis_pending.store(0, Ordering::Relaxed);
+        self.metrics.analysis_in_flight.store(0, Ordering::Relaxed);
+        if !analyzed? {
+            return Ok(false);
+        }
+        // Everything is analyzed by now, this only removes deleted songs.
+        self.library.update_library_paths(paths, true, false)?;
+        self.library.mark_degenerate_songs()?;
+        self.library.sync_genres()?;
+        self.metrics
+            .library_size
+            .store(self.library.count_songs()?.0, Ordering::Relaxed);
+        self.last_db_update = Some(db_update);
+        notify("STATUS=Watching the MPD database");
+        Ok(true)
+    }
+
+    /// Analyze `new_paths`, in chunks of `number_cores` songs, updating
+    /// [SCAN_PROGRESS] and the metrics as they are analyzed.
+    ///
+    /// Returns false if a shutdown was requested before all of them were
+    /// analyzed.
+    fn analyze_new_songs(&mut self, new_paths: &[&String]) -> Result<bool> {
+        let chunk_size = self.library.library.config.base_config.number_cores.get();
+        let set_progress = |metrics: &Metrics, update: &dyn Fn(&mut ScanProgress)| {
+            if let Some(progress) = &mut *SCAN_PROGRESS.lock().unwrap() {
+                update(progress);
+                metrics
+                    .analysis_pending
+                    .store(progress.pending() as u64, Ordering::Relaxed);
+                metrics
+                    .analysis_in_flight
+                    .store(progress.in_flight.len() as u64, Ordering::Relaxed);
+            }
+        };
+        for (i, chunk) in new_paths.chunks(chunk_size).enumerate() {
+            wait_while_paused();
+            self.watch_player();
+            self.run_profiles();
+            if SHUTDOWN.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            notify(&format!(
+                "STATUS=Analyzing new songs ({}/{})",
+                i * chunk_size,
+                new_paths.len(),
+            ));
+            set_progress(&self.metrics, &|p| {
+                p.in_flight = chunk.iter().map(|p| p.to_string()).collect()
+            });
+            let (analyzed_before, failed_before) = self.library.count_songs()?;
+            self.library.analyze_embedded_cues(chunk)?;
+            self.library.update_library_paths(
+                chunk.iter().map(|p| p.to_string()).collect(),
+                false,
+                false,
+            )?;
+            self.library.retry_failed_songs()?;
+            let (analyzed, failed) = self.library.count_songs()?;
+            self.metrics
+                .songs_analyzed
+                .fetch_add(analyzed.saturating_sub(analyzed_before), Ordering::Relaxed);
+            self.metrics
+                .analysis_errors
+                .fetch_add(failed.saturating_sub(failed_before), Ordering::Relaxed);
+            self.metrics.library_size.store(analyzed, Ordering::Relaxed);
+            set_progress(&self.metrics, &|p| {
+                p.done += p.in_flight.len();
+                p.in_flight.clear();
+            });
+        }
+        Ok(true)
+    }
+}
+
+/// Run the daemon until SIGTERM or SIGINT is received.
+pub fn run(options: DaemonOptions) -> Result<()> {
+    register_signals();
+    let mut daemon = Daemon {
+        library: MPDLibrary::from_config_path(options.config_path.to_owned())?,
+        last_db_update: None,
+        metrics: Arc::new(Metrics::default()),
+        playing: None,
+        last_watch: Instant::now(),
+        max_queue_length: options.max_queue_length,
+        last_schedule_check: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+    };
+    if let Some(address) = &options.metrics_address {
+        metrics::serve(address, daemon.metrics.clone())?;
+    }
+    control::serve(&options.control_socket, handle_command)?;
+    notify("READY=1\nSTATUS=Watching the MPD database");
+
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            notify("RELOADING=1");
+            info!("Reloading the configuration.");
+            match MPDLibrary::from_config_path(options.config_path.to_owned()) {
+                Ok(library) => {
+                    daemon.library = library;
+                    daemon.last_db_update = None;
+                }
+                Err(e) => warn!(
+                    "Could not reload the configuration, keeping the old one: {}",
+                    e
+                ),
+            }
+            notify("READY=1");
+        }
+        if let Err(e) = daemon.update() {
+            warn!("Could not update the library, retrying later: {}", e);
+            if let Err(e) = daemon.library.connect() {
+                warn!("Could not reconnect to MPD: {}", e);
+            }
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < options.poll_interval
+            && !SHUTDOWN.load(Ordering::SeqCst)
+            && !RELOAD.load(Ordering::SeqCst)
+        {
+            daemon.watch_player();
+            daemon.run_profiles();
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+    notify("STOPPING=1");
+    info!("Shutting down.");
+    let _ = std::fs::remove_file(&options.control_socket);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use crate::{PlaylistDefaults, Profile};
+    use mpd::song::{Id, QueuePlace};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_notify() {
+        let dir = TempDir::new("notify").unwrap();
+        let socket_path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&socket_path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &socket_path);
+        notify("READY=1");
+        env::remove_var("NOTIFY_SOCKET");
+
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(handle_command("status").unwrap(), "running");
+        let mut progress = ScanProgress::new(10);
+        progress.done = 4;
+        progress.in_flight = vec![String::from("a.flac"), String::from("b.flac")];
+        progress.started -= Duration::from_secs(120);
+        *SCAN_PROGRESS.lock().unwrap() = Some(progress);
+        let status = handle_command("status");
+        *SCAN_PROGRESS.lock().unwrap() = None;
+        assert_eq!(
+            status.unwrap(),
+            "running, analyzed 4/10 new song(s), 2.0 song(s)/minute, 4 pending; \
+            worker 1: a.flac; worker 2: b.flac"
+        );
+    }
+
+    #[test]
+    fn test_skipped_for() {
+        let playing = |file: &str, elapsed, duration: Option<u64>| Playing {
+            file: file.to_owned(),
+            elapsed: Duration::from_secs(elapsed),
+            duration: duration.map(Duration::from_secs),
+        };
+        let next = playing("next.flac", 0, Some(200));
+        assert!(playing("song.flac", 10, Some(200)).skipped_for(&next));
+        // Played for long enough.
+        assert!(!playing("song.flac", 40, Some(200)).skipped_for(&next));
+        // Short songs ending by themselves.
+        assert!(!playing("song.flac", 19, Some(20)).skipped_for(&next));
+        assert!(!playing("song.flac", 10, None).skipped_for(&next));
+        // Still the same song, e.g. seeking back to its start.
+        assert!(!playing("next.flac", 10, Some(200)).skipped_for(&next));
+    }
+
+    #[test]
+    fn test_run_profiles() {
+        let (mut library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/second.flac", None, 0.1),
+            ],
+        );
+        library.library.config.profiles.insert(
+            String::from("dinner"),
+            Profile {
+                playlist: Some(PlaylistDefaults {
+                    number_songs: Some(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        let mut daemon = Daemon {
+            library,
+            last_db_update: None,
+            metrics: Arc::new(Metrics::default()),
+            playing: None,
+            last_watch: Instant::now(),
+            max_queue_length: None,
+            last_schedule_check: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        PROFILE_REQUESTS.lock().unwrap().extend([
+            (String::from("dinner"), sender.clone()),
+            (String::from("breakfast"), sender),
+        ]);
+        daemon.run_profiles();
+        assert_eq!(receiver.recv().unwrap(), Ok(()));
+        assert!(receiver.recv().unwrap().is_err());
+        assert_eq!(
+            daemon.metrics.playlist_generations.load(Ordering::Relaxed),
+            1
+        );
+    }
+}
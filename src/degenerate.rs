@@ -0,0 +1,109 @@
+//! Analyses that can't be trusted, see [is_degenerate].
+//!
+//! Some corrupted files decode to (near-)silence, and end up with the same
+//! value for every feature, which puts them close to everything: every
+//! playlist would gravitate towards them. They are turned into analysis
+//! errors after scans, and left out of playlists if they are still in the
+//! database.
+use crate::MPDLibrary;
+use anyhow::Result;
+use bliss_audio::{Analysis, BlissError};
+use log::warn;
+
+/// The error degenerate analyses are stored with, which
+/// [ErrorKind::classify](crate::errors::ErrorKind::classify) recognizes.
+pub const DEGENERATE_ERROR: &str = "degenerate analysis";
+
+/// Whether `analysis` has features that are not numbers, or the same value
+/// for every feature, as silent or empty files get.
+pub(crate) fn is_degenerate(analysis: &Analysis) -> bool {
+    let features = analysis.as_vec();
+    features.iter().any(|f| !f.is_finite())
+        || features
+            .iter()
+            .all(|f| (f - features[0]).abs() < f32::EPSILON)
+}
+
+impl MPDLibrary {
+    /// Store the analyzed songs with a degenerate analysis as songs that
+    /// failed to be analyzed. Meant to be called after scans.
+    ///
+    /// Returns the number of songs that had a degenerate analysis.
+    pub(crate) fn mark_degenerate_songs(&mut self) -> Result<usize> {
+        let degenerate = self
+            .library
+            .songs_from_library::<()>()?
+            .into_iter()
+            .filter(|s| is_degenerate(&s.bliss_song.analysis))
+            .map(|s| s.bliss_song.path)
+            .collect::<Vec<_>>();
+        for path in &degenerate {
+            warn!(
+                "Analysis of {} failed: all its features have the same value, it is most likely silent or corrupted.",
+                path.display()
+            );
+            self.store_failed_analysis(
+                path,
+                BlissError::AnalysisError(DEGENERATE_ERROR.to_string()),
+            )?;
+        }
+        Ok(degenerate.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library, test_analysis};
+    use bliss_audio::NUMBER_FEATURES;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_is_degenerate() {
+        assert!(!is_degenerate(&test_analysis(0.)));
+        assert!(is_degenerate(&Analysis::new([0.; NUMBER_FEATURES])));
+        assert!(is_degenerate(&Analysis::new([-1.; NUMBER_FEATURES])));
+        let mut features = [0.; NUMBER_FEATURES];
+        features[0] = 0.5;
+        features[1] = f32::NAN;
+        assert!(is_degenerate(&Analysis::new(features)));
+    }
+
+    #[test]
+    fn test_mark_degenerate_songs() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/fine.flac", None, 0.),
+                (2, "path/silent.flac", None, 0.),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute("update feature set feature = -1 where song_id = 2", [])
+            .unwrap();
+        assert_eq!(library.mark_degenerate_songs().unwrap(), 1);
+        assert_eq!(library.mark_degenerate_songs().unwrap(), 0);
+        let songs = library
+            .library
+            .songs_from_library::<()>()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.bliss_song.path)
+            .collect::<Vec<_>>();
+        assert_eq!(songs, vec![PathBuf::from("path/fine.flac")]);
+        assert_eq!(
+            library
+                .error_counts()
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![(crate::errors::ErrorKind::DegenerateAnalysis, 1)],
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Describing songs in human terms, see `blissify describe`.
+//!
+//! bliss scales each feature to [-1, 1] from a fixed range, so the features
+//! can be scaled back to their units (beats per minute, decibels, hertz)
+//! before being put in buckets. The buckets are rough, and meant to give an
+//! idea of what the numbers mean rather than to classify songs.
+use bliss_audio::{Analysis, AnalysisIndex, Song};
+use serde::Serialize;
+use std::fmt;
+
+/// Upper bounds, in beats per minute, of the tempo buckets.
+const TEMPO_BUCKETS: [(f32, &str); 4] = [
+    (80., "slow"),
+    (110., "moderate"),
+    (140., "upbeat"),
+    (f32::INFINITY, "fast"),
+];
+/// Upper bounds, in decibels, of the loudness buckets.
+const LOUDNESS_BUCKETS: [(f32, &str); 4] = [
+    (-30., "very quiet"),
+    (-20., "quiet"),
+    (-10., "moderately loud"),
+    (f32::INFINITY, "loud"),
+];
+/// Upper bounds, in hertz, of the brightness buckets.
+const BRIGHTNESS_BUCKETS: [(f32, &str); 4] = [
+    (1000., "dark"),
+    (2000., "warm"),
+    (3500., "bright"),
+    (f32::INFINITY, "very bright"),
+];
+/// Upper bounds of the spectral flatness buckets, from 0 for pure tones to 1
+/// for white noise.
+const TEXTURE_BUCKETS: [(f32, &str); 3] = [
+    (0.05, "tonal, more acoustic than electronic"),
+    (0.15, "balanced between tonal and noisy"),
+    (
+        f32::INFINITY,
+        "noisy, more electronic or distorted than acoustic",
+    ),
+];
+
+/// Scale a feature from [-1, 1] back to [min, max], as bliss scaled it.
+fn denormalize(value: f32, min: f32, max: f32) -> f32 {
+    (value + 1.) / 2. * (max - min) + min
+}
+
+/// The tempo of `analysis`, in beats per minute. It is a rough estimate,
+/// which can be off by a factor of two.
+pub(crate) fn tempo_bpm(analysis: &Analysis) -> f32 {
+    denormalize(analysis[AnalysisIndex::Tempo], 0., 206.)
+}
+
+/// The mean loudness of `song`, in decibels.
+pub(crate) fn loudness_db(song: &Song) -> f32 {
+    denormalize(song.analysis[AnalysisIndex::MeanLoudness], -90., 0.)
+}
+
+/// The name of the bucket of `buckets` `value` is in.
+fn bucket(value: f32, buckets: &[(f32, &'static str)]) -> &'static str {
+    buckets
+        .iter()
+        .find(|(upper_bound, _)| value < *upper_bound)
+        .map_or(buckets[buckets.len() - 1].1, |(_, name)| name)
+}
+
+/// A song's features, in human terms.
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct Description {
+    pub path: String,
+    pub tempo_bpm: f32,
+    pub tempo: &'static str,
+    pub loudness_db: f32,
+    pub loudness: &'static str,
+    /// The mean spectral centroid, in hertz.
+    pub brightness_hz: f32,
+    pub brightness: &'static str,
+    /// The mean spectral flatness, between 0 and 1.
+    pub flatness: f32,
+    pub texture: &'static str,
+    /// When the song was analyzed, as a local date and time, if it is in
+    /// blissify's database and it is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub analyzed_at: Option<String>,
+}
+
+impl Description {
+    pub fn new(song: &Song) -> Self {
+        let analysis = &song.analysis;
+        let tempo_bpm = tempo_bpm(analysis);
+        let loudness_db = loudness_db(song);
+        let brightness_hz = denormalize(
+            analysis[AnalysisIndex::MeanSpectralCentroid],
+            0.,
+            22050. / 2.,
+        );
+        let flatness = denormalize(analysis[AnalysisIndex::MeanSpectralFlatness], 0., 1.);
+        Self {
+            path: song.path.to_string_lossy().to_string(),
+            tempo_bpm,
+            tempo: bucket(tempo_bpm, &TEMPO_BUCKETS),
+            loudness_db,
+            loudness: bucket(loudness_db, &LOUDNESS_BUCKETS),
+            brightness_hz,
+            brightness: bucket(brightness_hz, &BRIGHTNESS_BUCKETS),
+            flatness,
+            texture: bucket(flatness, &TEXTURE_BUCKETS),
+            analyzed_at: None,
+        }
+    }
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.path)?;
+        writeln!(f, "Tempo: {} (~{:.0} BPM)", self.tempo, self.tempo_bpm)?;
+        writeln!(
+            f,
+            "Loudness: {} (~{:.0} dB)",
+            self.loudness, self.loudness_db
+        )?;
+        writeln!(
+            f,
+            "Brightness: {} (~{:.0} Hz)",
+            self.brightness, self.brightness_hz
+        )?;
+        write!(f, "Texture: {}", self.texture)?;
+        if let Some(analyzed_at) = &self.analyzed_at {
+            write!(f, "\nAnalyzed: {}", analyzed_at)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bliss_audio::{Analysis, NUMBER_FEATURES};
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_describe() {
+        let mut features = [0.; NUMBER_FEATURES];
+        // 123.6 BPM, -9 dB, 2205 Hz and a flatness of 0.01.
+        features[AnalysisIndex::Tempo as usize] = 0.2;
+        features[AnalysisIndex::MeanLoudness as usize] = 0.8;
+        features[AnalysisIndex::MeanSpectralCentroid as usize] = -0.6;
+        features[AnalysisIndex::MeanSpectralFlatness as usize] = -0.98;
+        let song = Song {
+            path: PathBuf::from("/music/song.flac"),
+            analysis: Analysis::new(features),
+            ..Default::default()
+        };
+        let description = Description::new(&song);
+        assert_eq!(
+            (
+                description.tempo,
+                description.loudness,
+                description.brightness,
+                description.texture
+            ),
+            (
+                "upbeat",
+                "loud",
+                "bright",
+                "tonal, more acoustic than electronic"
+            )
+        );
+        assert_eq!(
+            description.to_string(),
+            "/music/song.flac\n\
+            Tempo: upbeat (~124 BPM)\n\
+            Loudness: loud (~-9 dB)\n\
+            Brightness: bright (~2205 Hz)\n\
+            Texture: tonal, more acoustic than electronic"
+        );
+        let description = Description {
+            analyzed_at: Some(String::from("2024-10-17 20:15:03")),
+            ..description
+        };
+        assert!(description
+            .to_string()
+            .ends_with("electronic\nAnalyzed: 2024-10-17 20:15:03"));
+    }
+}
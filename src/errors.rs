@@ -0,0 +1,262 @@
+//! Categories of analysis errors.
+//!
+//! bliss stores the raw decoder error of songs that failed to be analyzed,
+//! which is hard to act on with thousands of them. This sorts them into an
+//! `error_kind` column of the `song` table, and summarizes them with a hint
+//! for each kind at the end of scans.
+use crate::degenerate::DEGENERATE_ERROR;
+use crate::MPDLibrary;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Why a song could not be analyzed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    /// The file does not exist anymore.
+    MissingFile,
+    /// The decoder doesn't support the file's format or codec.
+    UnsupportedCodec,
+    /// The file's tags could not be read.
+    TagError,
+    /// The file could not be decoded, most likely because it is corrupted.
+    DecodeError,
+    /// The file decoded to silence, or to features that can't be trusted,
+    /// see [degenerate](crate::degenerate).
+    DegenerateAnalysis,
+    /// Anything else.
+    Other,
+}
+
+impl ErrorKind {
+    /// Guess the kind of an error from bliss' or ffmpeg's error message.
+    pub fn classify(error: &str) -> Self {
+        let error = error.to_lowercase();
+        let contains_any = |patterns: &[&str]| patterns.iter().any(|p| error.contains(p));
+        if error.contains(DEGENERATE_ERROR) {
+            ErrorKind::DegenerateAnalysis
+        } else if contains_any(&[
+            "codec not found",
+            "decoder not found",
+            "unsupported codec",
+            "unsupported format",
+            "unsupported feature",
+            "could not find codec",
+            "no decoder",
+        ]) {
+            ErrorKind::UnsupportedCodec
+        } else if contains_any(&["no such file", "file not found", "does not exist"]) {
+            ErrorKind::MissingFile
+        } else if contains_any(&["tag", "metadata", "id3"]) {
+            ErrorKind::TagError
+        } else if contains_any(&["decod", "invalid data", "corrupt", "end of file"]) {
+            ErrorKind::DecodeError
+        } else {
+            ErrorKind::Other
+        }
+    }
+
+    /// What can be done about songs failing with this kind of error.
+    pub fn hint(self) -> &'static str {
+        match self {
+            ErrorKind::MissingFile => {
+                "These files were moved or deleted. Update MPD's database, then run `blissify update`."
+            }
+            ErrorKind::UnsupportedCodec => {
+                "Your ffmpeg doesn't support these formats. Install an ffmpeg build supporting them (e.g. with opus support), or build blissify with `--features symphonia`, then run `blissify update --song` on them."
+            }
+            ErrorKind::TagError => {
+                "The tags of these files could not be read. Fix them with a tag editor, then run `blissify update --song` on them."
+            }
+            ErrorKind::DecodeError => {
+                "These files could not be decoded, and might be corrupted. Check that they play correctly, or re-rip them."
+            }
+            ErrorKind::DegenerateAnalysis => {
+                "These files decoded to silence, or to something that would end up in every playlist. Check that they play correctly, then run `blissify update --song` on them."
+            }
+            ErrorKind::Other => {
+                "See `blissify list-db --pending-only --detailed` for the details of each error."
+            }
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ErrorKind::MissingFile => "missing_file",
+            ErrorKind::UnsupportedCodec => "unsupported_codec",
+            ErrorKind::TagError => "tag_error",
+            ErrorKind::DecodeError => "decode_error",
+            ErrorKind::DegenerateAnalysis => "degenerate_analysis",
+            ErrorKind::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for ErrorKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "missing_file" => Ok(ErrorKind::MissingFile),
+            "unsupported_codec" => Ok(ErrorKind::UnsupportedCodec),
+            "tag_error" => Ok(ErrorKind::TagError),
+            "decode_error" => Ok(ErrorKind::DecodeError),
+            "degenerate_analysis" => Ok(ErrorKind::DegenerateAnalysis),
+            "other" => Ok(ErrorKind::Other),
+            _ => Err(()),
+        }
+    }
+}
+
+impl MPDLibrary {
+    /// Add the `error_kind` column to bliss' `song` table, if it isn't there
+    /// yet.
+    pub(crate) fn create_error_kind_column(&self) -> Result<()> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare("select name from pragma_table_info('song')")?;
+        let columns = query
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if !columns.iter().any(|c| c == "error_kind") {
+            sqlite_conn.execute("alter table song add column error_kind text", [])?;
+        }
+        Ok(())
+    }
+
+    /// Fill the `error_kind` column of the songs that failed to be analyzed
+    /// since the last time.
+    pub(crate) fn classify_errors(&self) -> Result<()> {
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let tx = sqlite_conn.transaction()?;
+        {
+            let mut query = tx.prepare(
+                "select id, coalesce(error, '') from song
+                where analyzed = false and error_kind is null",
+            )?;
+            let errors = query
+                .query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut update = tx.prepare("update song set error_kind = ?1 where id = ?2")?;
+            for (id, error) in errors {
+                update.execute((ErrorKind::classify(&error).to_string(), id))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The number of songs that failed to be analyzed, by kind of error.
+    pub(crate) fn error_counts(&self) -> Result<BTreeMap<ErrorKind, usize>> {
+        self.classify_errors()?;
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select error_kind, count(*) from song where analyzed = false group by error_kind",
+        )?;
+        let counts = query
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(counts
+            .into_iter()
+            .map(|(kind, count)| (kind.parse().unwrap_or(ErrorKind::Other), count))
+            .fold(BTreeMap::new(), |mut counts, (kind, count)| {
+                *counts.entry(kind).or_default() += count;
+                counts
+            }))
+    }
+
+    /// Print how many songs failed to be analyzed, by kind of error, with
+    /// what can be done about each kind, and how many were skipped for being
+    /// too large. Meant to be called after scans.
+    pub(crate) fn print_error_summary(&self) -> Result<()> {
+        let skipped = self.skipped_songs()?;
+        if !skipped.is_empty() {
+            println!(
+                "{} song(s) were skipped for being larger than the maximum file size. Raise or remove \"max_file_size\" in the configuration file, or pass a larger --max-file-size, to analyze them.",
+                skipped.len()
+            );
+        }
+        let counts = self.error_counts()?;
+        if counts.is_empty() {
+            return Ok(());
+        }
+        println!(
+            "{} song(s) could not be analyzed:",
+            counts.values().sum::<usize>()
+        );
+        for (kind, count) in counts {
+            println!("  {} {}: {}", count, kind, kind.hint());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            ErrorKind::classify(
+                "error happened while decoding file – while opening format for file 'a.flac': No such file or directory (os error 2)."
+            ),
+            ErrorKind::MissingFile
+        );
+        assert_eq!(
+            ErrorKind::classify("error happened while decoding file – Decoder not found"),
+            ErrorKind::UnsupportedCodec
+        );
+        assert_eq!(
+            ErrorKind::classify(
+                "error happened while decoding file – Invalid data found when processing input"
+            ),
+            ErrorKind::DecodeError
+        );
+        assert_eq!(
+            ErrorKind::classify("could not read the ID3 tag"),
+            ErrorKind::TagError
+        );
+        assert_eq!(
+            ErrorKind::classify("error happened while analyzing file - degenerate analysis"),
+            ErrorKind::DegenerateAnalysis
+        );
+        assert_eq!(ErrorKind::classify("something else"), ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_error_counts() {
+        let (library, _tempdir) = setup_library();
+        // Creating the column twice is fine.
+        library.create_error_kind_column().unwrap();
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            sqlite_conn
+                .execute_batch(
+                    "insert into song (id, path, analyzed, error) values
+                    (1, 'path/first.flac', false, 'No such file or directory'),
+                    (2, 'path/second.opus', false, 'Decoder not found'),
+                    (3, 'path/third.opus', false, 'Decoder not found'),
+                    (4, 'path/fourth.flac', true, null),
+                    (5, 'path/fifth.flac', false, null);",
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            library.error_counts().unwrap(),
+            BTreeMap::from([
+                (ErrorKind::MissingFile, 1),
+                (ErrorKind::UnsupportedCodec, 2),
+                (ErrorKind::Other, 1),
+            ]),
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Songs that are never suggested, see `blissify exclude`.
+//!
+//! Unlike the files left out of the analysis, excluded songs stay analyzed:
+//! they can still be used to make playlists from, and show up in `list-db`,
+//! but are never queued as part of a playlist. Their paths are kept in an
+//! `excluded_song` table, so that they stay excluded when they are analyzed
+//! again.
+//!
+//! Artists can be excluded too, with `exclude --artist`: their names are kept
+//! in an `excluded_artist` table, lowercased, so that their songs analyzed
+//! later are excluded as well.
+use crate::MPDLibrary;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+impl MPDLibrary {
+    /// Create the table storing the excluded songs, if it doesn't exist yet.
+    pub(crate) fn create_excluded_song_table(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "create table if not exists excluded_song (path text primary key)",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Create the table storing the excluded artists, if it doesn't exist
+    /// yet.
+    pub(crate) fn create_excluded_artist_table(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "create table if not exists excluded_artist (name text primary key)",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Exclude the songs of `artist`, ignoring case, from playlists.
+    ///
+    /// Returns whether the artist was not excluded yet.
+    pub(crate) fn exclude_artist(&self, artist: &str) -> Result<bool> {
+        let excluded = self.library.sqlite_conn.lock().unwrap().execute(
+            "insert or ignore into excluded_artist (name) values (?1)",
+            [artist.to_lowercase()],
+        )?;
+        Ok(excluded > 0)
+    }
+
+    /// Suggest the songs of `artist`, ignoring case, in playlists again, see
+    /// [exclude_artist](Self::exclude_artist).
+    ///
+    /// Returns whether the artist was excluded.
+    pub(crate) fn include_artist(&self, artist: &str) -> Result<bool> {
+        let included = self.library.sqlite_conn.lock().unwrap().execute(
+            "delete from excluded_artist where name = ?1",
+            [artist.to_lowercase()],
+        )?;
+        Ok(included > 0)
+    }
+
+    /// The excluded artists, lowercased, in alphabetical order.
+    pub(crate) fn excluded_artists(&self) -> Result<Vec<String>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare("select name from excluded_artist order by name")?;
+        let artists = query
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(artists)
+    }
+
+    /// Exclude the analyzed songs matching `pattern`, a bliss path or a glob
+    /// like `/home/user/Music/Interludes/*`, from playlists. A directory
+    /// excludes every song in it.
+    ///
+    /// Returns the number of songs that were not excluded yet.
+    pub(crate) fn exclude_songs(&self, pattern: &str) -> Result<usize> {
+        let excluded = self.library.sqlite_conn.lock().unwrap().execute(
+            "insert or ignore into excluded_song (path)
+            select path from song
+            where analyzed = true and (path glob ?1 or path glob ?1 || '/*')",
+            [pattern],
+        )?;
+        Ok(excluded)
+    }
+
+    /// Suggest the excluded songs matching `pattern` in playlists again, see
+    /// [exclude_songs](Self::exclude_songs).
+    ///
+    /// Returns the number of songs that were excluded.
+    pub(crate) fn include_songs(&self, pattern: &str) -> Result<usize> {
+        let included = self.library.sqlite_conn.lock().unwrap().execute(
+            "delete from excluded_song where path glob ?1 or path glob ?1 || '/*'",
+            [pattern],
+        )?;
+        Ok(included)
+    }
+
+    /// The paths of the excluded songs, including the songs of the excluded
+    /// artists.
+    pub(crate) fn excluded_songs(&self) -> Result<HashSet<PathBuf>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select path from excluded_song
+            union select path from song
+            where lower(artist) in (select name from excluded_artist)",
+        )?;
+        let paths = query
+            .query_map([], |row| row.get::<_, String>(0).map(PathBuf::from))?
+            .collect::<Result<HashSet<_>, _>>()?;
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::{insert_songs, setup_library};
+    use crate::PlaylistOptions;
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_exclude_songs() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "/music/album/song.flac", None, 0.),
+                (2, "/music/album/interlude.flac", None, 0.),
+                (3, "/music/skits/skit1.flac", None, 0.),
+                (4, "/music/skits/skit2.flac", None, 0.),
+            ],
+        );
+        let excluded = || {
+            let mut paths = library
+                .excluded_songs()
+                .unwrap()
+                .into_iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(
+            library
+                .exclude_songs("/music/album/interlude.flac")
+                .unwrap(),
+            1
+        );
+        assert_eq!(library.exclude_songs("/music/skits").unwrap(), 2);
+        assert_eq!(library.exclude_songs("/music/*/skit?.flac").unwrap(), 0);
+        assert_eq!(library.exclude_songs("/music/nothing/*").unwrap(), 0);
+        assert_eq!(
+            excluded(),
+            vec![
+                "/music/album/interlude.flac",
+                "/music/skits/skit1.flac",
+                "/music/skits/skit2.flac",
+            ],
+        );
+
+        assert_eq!(library.include_songs("/music/skits/skit1.flac").unwrap(), 1);
+        assert_eq!(
+            excluded(),
+            vec!["/music/album/interlude.flac", "/music/skits/skit2.flac"],
+        );
+        assert_eq!(library.include_songs("/music/*").unwrap(), 2);
+        assert!(excluded().is_empty());
+    }
+
+    #[test]
+    fn test_exclude_artists() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "/music/song.flac", None, 0.),
+                (2, "/music/annoying.flac", None, 0.),
+                (3, "/music/other.flac", None, 0.),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute("update song set artist = 'Annoying Band' where id = 2", [])
+            .unwrap();
+        library.exclude_songs("/music/other.flac").unwrap();
+
+        assert!(library.exclude_artist("annoying BAND").unwrap());
+        assert!(!library.exclude_artist("Annoying Band").unwrap());
+        assert_eq!(library.excluded_artists().unwrap(), vec!["annoying band"]);
+        let mut excluded = library
+            .excluded_songs()
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        excluded.sort();
+        assert_eq!(
+            excluded,
+            vec![
+                PathBuf::from("/music/annoying.flac"),
+                PathBuf::from("/music/other.flac"),
+            ],
+        );
+
+        assert!(library.include_artist("Annoying Band").unwrap());
+        assert!(!library.include_artist("Annoying Band").unwrap());
+        assert_eq!(
+            library.excluded_songs().unwrap(),
+            [PathBuf::from("/music/other.flac")].into(),
+        );
+    }
+
+    #[test]
+    fn test_playlist_excluded_songs() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first_song.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", None, 0.),
+                (2, "path/interlude.flac", None, 0.1),
+                (3, "path/third_song.flac", None, 0.2),
+            ],
+        );
+        library.exclude_songs("path/first_song.flac").unwrap();
+        library.exclude_songs("path/interlude*").unwrap();
+        let options = PlaylistOptions {
+            dedup: None,
+            excluded_songs: library.excluded_songs().unwrap(),
+            ..Default::default()
+        };
+        library
+            .queue_from_song(None, 20, &euclidean_distance, closest_to_songs, &options)
+            .unwrap();
+
+        // Excluded songs can still be played from.
+        let playlist = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|x| x.file.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(playlist, vec!["first_song.flac", "third_song.flac"]);
+    }
+}
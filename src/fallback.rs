@@ -0,0 +1,317 @@
+//! Retrying songs that failed to be analyzed with another decoder.
+//!
+//! Some files can't be decoded by ffmpeg, but can by symphonia, and the other
+//! way around. When blissify is built with the `symphonia` feature, songs
+//! that ffmpeg failed to analyze are analyzed again with symphonia before
+//! their error is reported, and the decoder that analyzed them is stored in a
+//! `decoder` column of the `song` table.
+//!
+//! bliss 0.9 only decodes with ffmpeg, so the symphonia decoder is
+//! [SymphoniaDecoder], implementing bliss' [Decoder](bliss_audio::decoder::Decoder)
+//! trait.
+use crate::MPDLibrary;
+use anyhow::Result;
+
+/// The name of the decoder songs are retried with, as stored in the
+/// `decoder` column.
+#[cfg(feature = "symphonia")]
+pub const FALLBACK_DECODER: &str = "symphonia";
+
+#[cfg(feature = "symphonia")]
+pub use self::symphonia_decoder::SymphoniaDecoder;
+
+#[cfg(feature = "symphonia")]
+mod symphonia_decoder {
+    use bliss_audio::decoder::{Decoder, PreAnalyzedSong};
+    use bliss_audio::{BlissError, BlissResult};
+    use rubato::{FftFixedIn, Resampler};
+    use std::fs::File;
+    use std::path::Path;
+    use std::time::Duration;
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::errors::Error as SymphoniaError;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+    use symphonia::core::probe::Hint;
+
+    /// The sample rate bliss analyzes songs at.
+    const SAMPLE_RATE: u32 = 22050;
+    /// The number of samples the resampler takes at once.
+    const CHUNK_SIZE: usize = 1024;
+
+    /// Decodes songs with symphonia, for the songs ffmpeg can't decode.
+    pub struct SymphoniaDecoder;
+
+    fn decoding_error(path: &Path, error: impl std::fmt::Display) -> BlissError {
+        BlissError::DecodingError(format!("while decoding {}: {}", path.display(), error))
+    }
+
+    /// Fill the tags of `song` from `metadata`.
+    fn read_tags(song: &mut PreAnalyzedSong, metadata: &MetadataRevision) {
+        for tag in metadata.tags() {
+            let value = tag.value.to_string();
+            match tag.std_key {
+                Some(StandardTagKey::Artist) => song.artist = Some(value),
+                Some(StandardTagKey::AlbumArtist) => song.album_artist = Some(value),
+                Some(StandardTagKey::TrackTitle) => song.title = Some(value),
+                Some(StandardTagKey::Album) => song.album = Some(value),
+                Some(StandardTagKey::Genre) => song.genre = Some(value),
+                // Like "3/12".
+                Some(StandardTagKey::TrackNumber) => {
+                    song.track_number = value.split('/').next().and_then(|n| n.parse().ok())
+                }
+                Some(StandardTagKey::DiscNumber) => {
+                    song.disc_number = value.split('/').next().and_then(|n| n.parse().ok())
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Resample the mono `samples`, at `sample_rate`, to [SAMPLE_RATE].
+    fn resample(path: &Path, samples: Vec<f32>, sample_rate: u32) -> BlissResult<Vec<f32>> {
+        if sample_rate == SAMPLE_RATE {
+            return Ok(samples);
+        }
+        let mut resampler =
+            FftFixedIn::<f32>::new(sample_rate as usize, SAMPLE_RATE as usize, CHUNK_SIZE, 2, 1)
+                .map_err(|e| decoding_error(path, e))?;
+        let expected = samples.len() * SAMPLE_RATE as usize / sample_rate as usize;
+        let mut resampled = Vec::with_capacity(expected + CHUNK_SIZE);
+        let mut chunks = samples.chunks_exact(CHUNK_SIZE);
+        for chunk in chunks.by_ref() {
+            let output = resampler
+                .process(&[chunk], None)
+                .map_err(|e| decoding_error(path, e))?;
+            resampled.extend_from_slice(&output[0]);
+        }
+        let output = resampler
+            .process_partial(Some(&[chunks.remainder()]), None)
+            .map_err(|e| decoding_error(path, e))?;
+        resampled.extend_from_slice(&output[0]);
+        // The resampler delays its output, and pads the last chunk.
+        let delay = resampler.output_delay().min(resampled.len());
+        resampled.drain(..delay);
+        resampled.truncate(expected);
+        Ok(resampled)
+    }
+
+    impl Decoder for SymphoniaDecoder {
+        fn decode(path: &Path) -> BlissResult<PreAnalyzedSong> {
+            let file = File::open(path).map_err(|e| decoding_error(path, e))?;
+            let source = MediaSourceStream::new(Box::new(file), Default::default());
+            let mut hint = Hint::new();
+            if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+                hint.with_extension(extension);
+            }
+            let mut probed = symphonia::default::get_probe()
+                .format(
+                    &hint,
+                    source,
+                    &FormatOptions::default(),
+                    &MetadataOptions::default(),
+                )
+                .map_err(|e| decoding_error(path, e))?;
+            let mut song = PreAnalyzedSong {
+                path: path.to_owned(),
+                ..Default::default()
+            };
+            // Tags can be in the container, or found while probing it.
+            if let Some(metadata) = probed.metadata.get() {
+                if let Some(revision) = metadata.current() {
+                    read_tags(&mut song, revision);
+                }
+            }
+            let mut format = probed.format;
+            if let Some(revision) = format.metadata().current() {
+                read_tags(&mut song, revision);
+            }
+            let track = format
+                .default_track()
+                .ok_or_else(|| decoding_error(path, "no audio stream"))?;
+            let track_id = track.id;
+            let mut decoder = symphonia::default::get_codecs()
+                .make(&track.codec_params, &DecoderOptions::default())
+                .map_err(|e| decoding_error(path, e))?;
+            let mut sample_rate = track.codec_params.sample_rate;
+            let mut samples = vec![];
+            loop {
+                let packet = match format.next_packet() {
+                    Ok(packet) => packet,
+                    // The end of the stream.
+                    Err(SymphoniaError::IoError(e))
+                        if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                    {
+                        break
+                    }
+                    Err(e) => return Err(decoding_error(path, e)),
+                };
+                if packet.track_id() != track_id {
+                    continue;
+                }
+                let decoded = match decoder.decode(&packet) {
+                    Ok(decoded) => decoded,
+                    // Corrupted packets are skipped, as ffmpeg does.
+                    Err(SymphoniaError::DecodeError(_)) => continue,
+                    Err(e) => return Err(decoding_error(path, e)),
+                };
+                let spec = *decoded.spec();
+                sample_rate = Some(spec.rate);
+                let channels = spec.channels.count();
+                let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+                buffer.copy_interleaved_ref(decoded);
+                samples.extend(
+                    buffer
+                        .samples()
+                        .chunks_exact(channels)
+                        .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+                );
+            }
+            let sample_rate = match sample_rate {
+                Some(sample_rate) if !samples.is_empty() => sample_rate,
+                _ => return Err(decoding_error(path, "no audio could be decoded")),
+            };
+            song.duration = Duration::from_secs_f64(samples.len() as f64 / sample_rate as f64);
+            song.sample_array = resample(path, samples, sample_rate)?;
+            Ok(song)
+        }
+    }
+}
+
+impl MPDLibrary {
+    /// Add the `decoder` column to bliss' `song` table, if it isn't there
+    /// yet. It is null for songs analyzed by the default decoder.
+    pub(crate) fn create_decoder_column(&self) -> Result<()> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare("select name from pragma_table_info('song')")?;
+        let columns = query
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if !columns.iter().any(|c| c == "decoder") {
+            sqlite_conn.execute("alter table song add column decoder text", [])?;
+        }
+        Ok(())
+    }
+
+    /// Analyze the songs that failed to be analyzed, and were not retried
+    /// yet, with the fallback decoder. Songs failing again keep both errors.
+    ///
+    /// Returns the number of songs that were analyzed this time.
+    #[cfg(feature = "symphonia")]
+    pub(crate) fn retry_failed_songs(&mut self) -> Result<usize> {
+        use bliss_audio::decoder::Decoder as _;
+        use log::{info, warn};
+
+        let paths = {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut query = sqlite_conn
+                .prepare("select path from song where analyzed = false and decoder is null")?;
+            let paths = query
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            paths
+        };
+        if paths.is_empty() {
+            return Ok(0);
+        }
+        info!(
+            "Analyzing {} song(s) that failed again with {}.",
+            paths.len(),
+            FALLBACK_DECODER,
+        );
+        let number_cores = self.library.config.base_config.number_cores;
+        let mut analyzed = 0;
+        for (path, result) in SymphoniaDecoder::analyze_paths_with_cores(paths, number_cores) {
+            let error = match result {
+                Ok(song) => {
+                    self.store_analyzed_song(song)?;
+                    analyzed += 1;
+                    None
+                }
+                Err(e) => {
+                    warn!(
+                        "Analysis of {} with {} failed too: {}",
+                        path.display(),
+                        FALLBACK_DECODER,
+                        e
+                    );
+                    Some(format!("{}: {}", FALLBACK_DECODER, e))
+                }
+            };
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            sqlite_conn.execute(
+                "update song set decoder = ?1, error_kind = null,
+                error = case when ?2 is null then null else coalesce(error || '; ', '') || ?2 end
+                where path = ?3",
+                (FALLBACK_DECODER, error, path.to_string_lossy()),
+            )?;
+        }
+        Ok(analyzed)
+    }
+
+    /// Without the `symphonia` feature, there is no decoder to fall back to.
+    #[cfg(not(feature = "symphonia"))]
+    pub(crate) fn retry_failed_songs(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+#[cfg(all(test, feature = "symphonia"))]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    #[test]
+    fn test_retry_failed_songs() {
+        let (mut library, tempdir) = setup_library();
+        let existing = tempdir.path().join("existing.flac");
+        fs::copy("data/s16_mono_22_5kHz.flac", &existing).unwrap();
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            sqlite_conn
+                .execute(
+                    "insert into song (id, path, analyzed, error) values
+                    (1, ?1, false, 'Decoder not found'),
+                    (2, 'path/missing.opus', false, 'No such file or directory')",
+                    [existing.to_string_lossy()],
+                )
+                .unwrap();
+        }
+        assert_eq!(library.retry_failed_songs().unwrap(), 1);
+        // Songs are only retried once.
+        assert_eq!(library.retry_failed_songs().unwrap(), 0);
+
+        let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn
+            .prepare("select path, analyzed, decoder, error from song order by path")
+            .unwrap();
+        let songs = query
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, bool>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(songs.len(), 2);
+        assert_eq!(
+            (songs[0].1, songs[0].2.as_deref(), songs[0].3.as_deref()),
+            (true, Some(FALLBACK_DECODER), None)
+        );
+        assert_eq!(songs[1].0, "path/missing.opus");
+        assert!(!songs[1].1);
+        assert!(songs[1]
+            .3
+            .as_deref()
+            .unwrap()
+            .starts_with("No such file or directory; symphonia: "));
+    }
+}
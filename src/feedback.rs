@@ -0,0 +1,440 @@
+//! Likes and dislikes, given with `blissify like` and `blissify dislike`.
+//!
+//! Playlists made with feedback lean towards the regions of the feature space
+//! where liked songs are, and away from the ones where disliked songs are:
+//! the distance of a candidate song is scaled by `exp(weight * (d - l))`,
+//! `l` and `d` being its proximity to the closest liked and disliked songs,
+//! between 0 (far away) and 1 (same features).
+//!
+//! Songs skipped early in playlists blissify queued count as implicit
+//! feedback, tied to the song the playlist was made from: when making a
+//! playlist from that song again, each skip scales the skipped song's
+//! distance by `exp(weight * SKIP_PENALTY)`.
+//!
+//! All of it is stored per user, see `--user`; the default user is stored
+//! as an empty name.
+use crate::MPDLibrary;
+use anyhow::{bail, Result};
+use bliss_audio::library::LibrarySong;
+use bliss_audio::playlist::{DistanceMetric, DistanceMetricBuilder};
+use ndarray::Array1;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How much each skip penalizes a song, relative to an explicit dislike.
+const SKIP_PENALTY: f32 = 0.5;
+/// How long after being queued by blissify a skipped song still counts as
+/// skipped from the playlist it was queued in.
+const SKIP_WINDOW: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// The start of the skip window, in seconds since the epoch.
+pub(crate) fn skip_window_start() -> Result<i64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    Ok(now.saturating_sub(SKIP_WINDOW).as_secs() as i64)
+}
+
+/// Feedback given on a song.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rating {
+    Like,
+    Dislike,
+}
+
+impl Rating {
+    /// The value stored in the `feedback` table.
+    fn value(self) -> i32 {
+        match self {
+            Rating::Like => 1,
+            Rating::Dislike => -1,
+        }
+    }
+}
+
+/// The analyses of the songs that were liked and disliked, used to bias
+/// playlists with a [FeedbackDistance].
+#[derive(Debug, Default)]
+pub struct Feedback {
+    liked: Vec<Array1<f32>>,
+    disliked: Vec<Array1<f32>>,
+    /// The analyses of the songs playlists were made from, the analyses of
+    /// the songs skipped in these playlists, and the number of skips.
+    skips: Vec<(Array1<f32>, Array1<f32>, u32)>,
+    /// How much feedback weighs on the distance. 0 disables it.
+    weight: f32,
+}
+
+impl Feedback {
+    pub fn is_empty(&self) -> bool {
+        self.weight == 0.
+            || (self.liked.is_empty() && self.disliked.is_empty() && self.skips.is_empty())
+    }
+
+    /// Wrap `inner` so that the distances it computes take feedback into account.
+    pub fn distance<'a>(&'a self, inner: &'a dyn DistanceMetricBuilder) -> FeedbackDistance<'a> {
+        FeedbackDistance {
+            inner,
+            feedback: self,
+        }
+    }
+}
+
+/// A distance metric taking likes and dislikes into account, see the
+/// [module](self) documentation.
+pub struct FeedbackDistance<'a> {
+    inner: &'a dyn DistanceMetricBuilder,
+    feedback: &'a Feedback,
+}
+
+impl DistanceMetricBuilder for FeedbackDistance<'_> {
+    fn build<'a>(&'a self, vectors: &[Array1<f32>]) -> Box<dyn DistanceMetric + 'a> {
+        let inner = self.inner.build(vectors);
+        let skipped = self
+            .feedback
+            .skips
+            .iter()
+            .filter(|(seed, _, _)| vectors.contains(seed))
+            .map(|(_, song, count)| (song, *count))
+            .collect::<Vec<_>>();
+        if self.feedback.is_empty()
+            || (self.feedback.liked.is_empty()
+                && self.feedback.disliked.is_empty()
+                && skipped.is_empty())
+        {
+            return inner;
+        }
+        Box::new(FeedbackMetric {
+            inner,
+            feedback: self.feedback,
+            skipped,
+        })
+    }
+}
+
+struct FeedbackMetric<'a> {
+    inner: Box<dyn DistanceMetric + 'a>,
+    feedback: &'a Feedback,
+    /// The songs skipped in playlists made from the songs the metric was built from.
+    skipped: Vec<(&'a Array1<f32>, u32)>,
+}
+
+impl DistanceMetric for FeedbackMetric<'_> {
+    fn distance(&self, vector: &Array1<f32>) -> f32 {
+        let skips: u32 = self
+            .skipped
+            .iter()
+            .filter(|(song, _)| *song == vector)
+            .map(|(_, count)| count)
+            .sum();
+        let bias = proximity(vector, &self.feedback.disliked)
+            - proximity(vector, &self.feedback.liked)
+            + SKIP_PENALTY * skips as f32;
+        self.inner.distance(vector) * (self.feedback.weight * bias).exp()
+    }
+}
+
+/// How close `vector` is to the closest of `vectors`, from 0 (far away, or
+/// no vectors at all) to 1 (same vector).
+fn proximity(vector: &Array1<f32>, vectors: &[Array1<f32>]) -> f32 {
+    vectors
+        .iter()
+        .map(|v| (-(v - vector).mapv(|x| x * x).sum().sqrt()).exp())
+        .fold(0., f32::max)
+}
+
+impl MPDLibrary {
+    /// The name feedback of the current user is stored under.
+    fn feedback_user(&self) -> &str {
+        self.user.as_deref().unwrap_or("")
+    }
+
+    /// Create the tables storing likes, dislikes and skips, if they don't
+    /// exist yet.
+    pub(crate) fn create_feedback_tables(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute_batch(
+            "create table if not exists feedback (
+                user text not null,
+                path text not null,
+                rating integer not null,
+                primary key (user, path)
+            );
+            create table if not exists queued_song (
+                path text primary key,
+                user text not null,
+                seed text not null,
+                queued_at integer not null
+            );
+            create table if not exists skip (
+                user text not null,
+                seed text not null,
+                path text not null,
+                count integer not null,
+                primary key (user, seed, path)
+            );",
+        )?;
+        Ok(())
+    }
+
+    /// Remember that the songs of `playlist` were queued in a playlist made
+    /// from `seed`, so skipping them can be tied back to it.
+    pub(crate) fn record_queued(&self, seed: &str, playlist: &[LibrarySong<()>]) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let tx = sqlite_conn.transaction()?;
+        tx.execute(
+            "delete from queued_song where queued_at < ?1",
+            [skip_window_start()?],
+        )?;
+        for song in playlist {
+            let path = song.bliss_song.path.to_string_lossy();
+            if path == seed {
+                continue;
+            }
+            tx.execute(
+                "insert or replace into queued_song (path, user, seed, queued_at)
+                values (?1, ?2, ?3, ?4)",
+                (&path, self.feedback_user(), seed, now),
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Record that the song at `path` was skipped, if it was recently queued
+    /// by blissify, for the user who queued it. Returns whether the skip was
+    /// recorded.
+    pub(crate) fn record_skip(&self, path: &str) -> Result<bool> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let recorded = sqlite_conn.execute(
+            "insert into skip (user, seed, path, count)
+                select user, seed, path, 1 from queued_song
+                where path = ?1 and queued_at >= ?2
+            on conflict (user, seed, path) do update set count = count + 1",
+            (path, skip_window_start()?),
+        )?;
+        Ok(recorded > 0)
+    }
+
+    /// Like or dislike the song at `path`, replacing any previous feedback on it,
+    /// or forget about the feedback given on it if `rating` is `None`.
+    pub(crate) fn set_rating(&self, path: &str, rating: Option<Rating>) -> Result<()> {
+        if self.library.song_from_path::<()>(path).is_err() {
+            bail!("Song '{}' has not been analyzed by blissify.", path);
+        }
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        match rating {
+            Some(rating) => sqlite_conn.execute(
+                "insert or replace into feedback (user, path, rating) values (?1, ?2, ?3)",
+                (self.feedback_user(), path, rating.value()),
+            )?,
+            None => sqlite_conn.execute(
+                "delete from feedback where user = ?1 and path = ?2",
+                [self.feedback_user(), path],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Load the analyses of the songs the current user liked, disliked and
+    /// skipped, ignoring the songs that are not in the database anymore.
+    pub(crate) fn feedback(&self, weight: f32) -> Result<Feedback> {
+        let ratings = {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut query =
+                sqlite_conn.prepare("select path, rating from feedback where user = ?1")?;
+            let ratings = query
+                .query_map([self.feedback_user()], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            ratings
+        };
+        let skips = {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut query =
+                sqlite_conn.prepare("select seed, path, count from skip where user = ?1")?;
+            let skips = query
+                .query_map([self.feedback_user()], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, u32>(2)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            skips
+        };
+        let mut feedback = Feedback {
+            weight,
+            ..Default::default()
+        };
+        for (path, rating) in ratings {
+            let analysis = match self.library.song_from_path::<()>(&path) {
+                Ok(song) => song.bliss_song.analysis.as_arr1(),
+                Err(_) => continue,
+            };
+            if rating == Rating::Like.value() {
+                feedback.liked.push(analysis);
+            } else {
+                feedback.disliked.push(analysis);
+            }
+        }
+        for (seed, path, count) in skips {
+            if let (Ok(seed), Ok(song)) = (
+                self.library.song_from_path::<()>(&seed),
+                self.library.song_from_path::<()>(&path),
+            ) {
+                feedback.skips.push((
+                    seed.bliss_song.analysis.as_arr1(),
+                    song.bliss_song.analysis.as_arr1(),
+                    count,
+                ));
+            }
+        }
+        Ok(feedback)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::library::LibrarySong;
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_feedback_distance() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/seed.flac", None, 0.),
+                (2, "path/close.flac", None, 0.1),
+                (3, "path/further.flac", None, -0.15),
+                (4, "path/liked.flac", None, -0.2),
+            ],
+        );
+        let songs: Vec<LibrarySong<()>> = library.library.songs_from_library().unwrap();
+        let seed = songs
+            .iter()
+            .find(|s| s.bliss_song.path.ends_with("seed.flac"))
+            .unwrap()
+            .to_owned();
+        let playlist = |feedback: &Feedback| {
+            closest_to_songs(
+                &[seed.to_owned()],
+                &songs,
+                &feedback.distance(&euclidean_distance),
+            )
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+        };
+
+        assert!(library
+            .set_rating("path/unknown.flac", Some(Rating::Like))
+            .is_err());
+        let feedback = library.feedback(1.).unwrap();
+        assert!(feedback.is_empty());
+        assert_eq!(
+            playlist(&feedback),
+            vec![
+                "path/seed.flac",
+                "path/close.flac",
+                "path/further.flac",
+                "path/liked.flac"
+            ],
+        );
+
+        library
+            .set_rating("path/liked.flac", Some(Rating::Like))
+            .unwrap();
+        library
+            .set_rating("path/close.flac", Some(Rating::Dislike))
+            .unwrap();
+        assert_eq!(
+            playlist(&library.feedback(1.).unwrap()),
+            vec![
+                "path/seed.flac",
+                "path/further.flac",
+                "path/liked.flac",
+                "path/close.flac"
+            ],
+        );
+        // A weight of 0 disables feedback.
+        assert!(library.feedback(0.).unwrap().is_empty());
+        // Other users have their own feedback.
+        library.user = Some(String::from("other"));
+        assert!(library.feedback(1.).unwrap().is_empty());
+        library.user = None;
+
+        library.set_rating("path/close.flac", None).unwrap();
+        library.set_rating("path/liked.flac", None).unwrap();
+        assert!(library.feedback(1.).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_skips() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/seed.flac", None, 0.),
+                (2, "path/skipped.flac", None, 0.1),
+                (3, "path/further.flac", None, 0.15),
+                (4, "path/other_seed.flac", None, 0.12),
+            ],
+        );
+        let songs: Vec<LibrarySong<()>> = library.library.songs_from_library().unwrap();
+        let song = |name: &str| {
+            songs
+                .iter()
+                .find(|s| s.bliss_song.path.ends_with(name))
+                .unwrap()
+                .to_owned()
+        };
+        let playlist = |seed: &str| {
+            let feedback = library.feedback(1.).unwrap();
+            closest_to_songs(
+                &[song(seed)],
+                &songs,
+                &feedback.distance(&euclidean_distance),
+            )
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .take(3)
+            .collect::<Vec<_>>()
+        };
+
+        // Only songs queued by blissify can be skipped.
+        assert!(!library.record_skip("path/skipped.flac").unwrap());
+        library
+            .record_queued(
+                "path/seed.flac",
+                &[
+                    song("seed.flac"),
+                    song("skipped.flac"),
+                    song("further.flac"),
+                ],
+            )
+            .unwrap();
+        assert!(library.record_skip("path/skipped.flac").unwrap());
+        assert!(library.record_skip("path/skipped.flac").unwrap());
+        assert!(!library.record_skip("path/seed.flac").unwrap());
+
+        assert_eq!(
+            playlist("seed.flac"),
+            vec![
+                "path/seed.flac",
+                "path/other_seed.flac",
+                "path/further.flac"
+            ],
+        );
+        // Skips only count for playlists made from the same song.
+        assert_eq!(
+            playlist("other_seed.flac"),
+            vec![
+                "path/other_seed.flac",
+                "path/skipped.flac",
+                "path/further.flac"
+            ],
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Leaving huge files out of scans, see
+//! [Config::max_file_size](crate::Config::max_file_size).
+//!
+//! Decoding a multi-gigabyte lossless rip or DJ mix can take hours on weak
+//! hardware. With a maximum file size set, scans don't analyze the files
+//! larger than it: they are stored in a `skipped_song` table instead of
+//! failing, and summarized apart from the errors. Raising or removing the
+//! limit gets them analyzed on the next update. Songs that are already
+//! analyzed are kept whatever their size, and CUE tracks are not checked.
+use crate::{cue_sheet_path, MPDLibrary};
+use anyhow::Result;
+use bliss_audio::BlissError;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A number of bytes, written with an optional K, M or G suffix (powers of
+/// 1024), like "500M".
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct FileSize(pub u64);
+
+impl std::str::FromStr for FileSize {
+    type Err = BlissError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+            Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+            Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+            _ => (s, 1),
+        };
+        number
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .map(FileSize)
+            .ok_or_else(|| {
+                BlissError::ProviderError(format!(
+                    "File size '{}' is not valid. Use a number of bytes, optionally followed by K, M or G, like \"500M\".",
+                    s
+                ))
+            })
+    }
+}
+
+impl fmt::Display for FileSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            0 => write!(f, "0"),
+            n if n % (1 << 30) == 0 => write!(f, "{}G", n >> 30),
+            n if n % (1 << 20) == 0 => write!(f, "{}M", n >> 20),
+            n if n % (1 << 10) == 0 => write!(f, "{}K", n >> 10),
+            n => write!(f, "{}", n),
+        }
+    }
+}
+
+impl TryFrom<String> for FileSize {
+    type Error = BlissError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<FileSize> for String {
+    fn from(size: FileSize) -> Self {
+        size.to_string()
+    }
+}
+
+impl MPDLibrary {
+    /// Create the table storing the songs left out of scans for being too
+    /// large, if it doesn't exist yet.
+    pub(crate) fn create_skipped_song_table(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "create table if not exists skipped_song (
+                path text primary key,
+                size integer not null
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// `paths`, listed as in [get_songs_paths](Self::get_songs_paths),
+    /// without the files larger than
+    /// [max_file_size](crate::Config::max_file_size) that are not analyzed
+    /// yet. Those are stored as skipped, and the other ones are not skipped
+    /// anymore.
+    pub(crate) fn skip_large_files(&self, paths: Vec<String>) -> Result<Vec<String>> {
+        let Some(max_file_size) = self.library.config.max_file_size else {
+            self.library
+                .sqlite_conn
+                .lock()
+                .unwrap()
+                .execute("delete from skipped_song", [])?;
+            return Ok(paths);
+        };
+        let analyzed = self.analyzed_paths()?;
+        let mut kept = Vec::with_capacity(paths.len());
+        let mut skipped = 0;
+        {
+            let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let tx = sqlite_conn.transaction()?;
+            {
+                let mut insert =
+                    tx.prepare("insert or replace into skipped_song (path, size) values (?1, ?2)")?;
+                let mut delete = tx.prepare("delete from skipped_song where path = ?1")?;
+                for path in paths {
+                    let size =
+                        if analyzed.contains(&path) || cue_sheet_path(Path::new(&path)).is_some() {
+                            None
+                        } else {
+                            // Missing files are left to the analysis to report.
+                            fs::metadata(&path).ok().map(|m| m.len())
+                        };
+                    match size {
+                        Some(size) if size > max_file_size.0 => {
+                            insert.execute((&path, size as i64))?;
+                            skipped += 1;
+                        }
+                        _ => {
+                            delete.execute([&path])?;
+                            kept.push(path);
+                        }
+                    }
+                }
+            }
+            tx.commit()?;
+        }
+        if skipped > 0 {
+            info!(
+                "Skipping {} file(s) larger than {}.",
+                skipped, max_file_size
+            );
+        }
+        Ok(kept)
+    }
+
+    /// The paths of the songs left out of scans for being too large, with
+    /// their size in bytes.
+    pub(crate) fn skipped_songs(&self) -> Result<Vec<(String, u64)>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare("select path, size from skipped_song order by path")?;
+        let songs = query
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(songs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_file_size() {
+        assert_eq!("500M".parse::<FileSize>().unwrap(), FileSize(500 << 20));
+        assert_eq!("2g".parse::<FileSize>().unwrap(), FileSize(2 << 30));
+        assert_eq!(" 12K ".parse::<FileSize>().unwrap(), FileSize(12 << 10));
+        assert_eq!("1000".parse::<FileSize>().unwrap(), FileSize(1000));
+        assert!("M".parse::<FileSize>().is_err());
+        assert!("500MB".parse::<FileSize>().is_err());
+        assert!("-1".parse::<FileSize>().is_err());
+        assert_eq!(FileSize(500 << 20).to_string(), "500M");
+        assert_eq!(FileSize(1000).to_string(), "1000");
+    }
+
+    #[test]
+    fn test_skip_large_files() {
+        let (mut library, tempdir) = setup_library();
+        let small = tempdir.path().join("small.flac");
+        fs::write(&small, [0; 10]).unwrap();
+        let large = tempdir.path().join("large.flac");
+        fs::write(&large, [0; 2048]).unwrap();
+        let analyzed = tempdir.path().join("analyzed.flac");
+        fs::write(&analyzed, [0; 2048]).unwrap();
+        let paths = [&small, &large, &analyzed]
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        insert_songs(&library, &[(1, paths[2].as_str(), None, 0.)]);
+
+        assert_eq!(library.skip_large_files(paths.to_owned()).unwrap(), paths);
+        assert!(library.skipped_songs().unwrap().is_empty());
+
+        library.library.config.max_file_size = Some(FileSize(1 << 10));
+        assert_eq!(
+            library.skip_large_files(paths.to_owned()).unwrap(),
+            vec![paths[0].to_owned(), paths[2].to_owned()],
+        );
+        assert_eq!(
+            library.skipped_songs().unwrap(),
+            vec![(paths[1].to_owned(), 2048)]
+        );
+
+        library.library.config.max_file_size = Some(FileSize(4 << 10));
+        assert_eq!(library.skip_large_files(paths.to_owned()).unwrap(), paths);
+        assert!(library.skipped_songs().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,570 @@
+//! The filters the candidates of a playlist go through, in order, see
+//! [Stage].
+//!
+//! Candidates come sorted by distance to the songs the playlist is made
+//! from, and each stage leaves some of them out. The stages only pull as many
+//! candidates as needed to fill the playlist, so `--explain-filtering` shows
+//! how many of the candidates looked at each stage removed, not how many
+//! songs of the whole library it would remove.
+//!
+//! The songs the playlist is made from are kept by every stage.
+use crate::describe::{loudness_db, tempo_bpm};
+use crate::genre::parse_genres;
+use crate::{dedup_playlist, history, seed_album, MPDLibrary, PlaylistOptions};
+use bliss_audio::library::LibrarySong;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Filters set in the `playlist` section of the configuration file, or of a
+/// profile.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub(crate) struct PlaylistFilters {
+    /// Only queue songs having one of these genres. Multi-value genre tags,
+    /// like "Electronic; Ambient", match each of their genres.
+    pub genres: Vec<String>,
+    /// Leave out the songs of an artist queued less than this many songs
+    /// before.
+    pub artist_spacing: Option<usize>,
+    /// Leave out the songs shorter than this many seconds.
+    pub min_duration: Option<u64>,
+    /// Leave out the songs longer than this many seconds.
+    pub max_duration: Option<u64>,
+    /// Leave out the songs slower than this many beats per minute, see
+    /// `--bpm`.
+    pub min_bpm: Option<f32>,
+    /// Leave out the songs faster than this many beats per minute.
+    pub max_bpm: Option<f32>,
+    /// Queue at most this many songs of each artist.
+    pub max_songs_per_artist: Option<usize>,
+    /// Leave out the songs whose mean loudness differs from the song kept
+    /// before them by more than this many decibels, see
+    /// `--consistent-loudness`.
+    pub max_loudness_jump: Option<f32>,
+}
+
+/// The loudness jump `--consistent-loudness` allows between two songs, in
+/// decibels, when `max_loudness_jump` isn't set.
+pub(crate) const DEFAULT_MAX_LOUDNESS_JUMP: f32 = 6.;
+
+/// A step of the pipeline, in the order they run.
+///
+/// Stages that only look at the song itself run first, and the ones
+/// depending on the songs kept before it last, so that songs left out by
+/// later stages don't count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    Genre,
+    Duration,
+    Tempo,
+    Dedup,
+    Queued,
+    Excluded,
+    Offset,
+    Familiar,
+    SeedAlbum,
+    ArtistSpacing,
+    Diversity,
+    Loudness,
+}
+
+impl Stage {
+    fn description(self) -> &'static str {
+        match self {
+            Stage::Genre => "genre filter",
+            Stage::Duration => "duration filter",
+            Stage::Tempo => "tempo filter",
+            Stage::Dedup => "deduplication",
+            Stage::Queued => "already queued",
+            Stage::Excluded => "excluded songs and titles",
+            Stage::Offset => "offset",
+            Stage::Familiar => "familiar artists",
+            Stage::SeedAlbum => "seed album",
+            Stage::ArtistSpacing => "artist spacing",
+            Stage::Diversity => "songs per artist",
+            Stage::Loudness => "loudness jumps",
+        }
+    }
+}
+
+/// Candidates, as they go through the pipeline.
+type Songs<'a> = Box<dyn Iterator<Item = LibrarySong<()>> + 'a>;
+
+/// An iterator counting the songs that go through it.
+struct Counted<'a> {
+    songs: Songs<'a>,
+    count: Rc<Cell<usize>>,
+}
+
+impl Iterator for Counted<'_> {
+    type Item = LibrarySong<()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let song = self.songs.next()?;
+        self.count.set(self.count.get() + 1);
+        Some(song)
+    }
+}
+
+/// The number of candidates that went into the pipeline, and out of each
+/// stage.
+#[derive(Debug, Default)]
+pub(crate) struct FilterCounts {
+    candidates: Rc<Cell<usize>>,
+    stages: Vec<(Stage, Rc<Cell<usize>>)>,
+}
+
+impl FilterCounts {
+    /// The number of candidates each stage removed so far.
+    pub fn removed(&self) -> Vec<(Stage, usize)> {
+        let mut before = self.candidates.get();
+        self.stages
+            .iter()
+            .map(|(stage, after)| {
+                let removed = before - after.get();
+                before = after.get();
+                (*stage, removed)
+            })
+            .collect()
+    }
+
+    /// Print how many candidates each stage removed, for
+    /// `--explain-filtering`. Printed on stderr, so that it can be used with
+    /// `--dry-run --format json`.
+    pub fn explain(&self) {
+        eprintln!("{} candidate(s) looked at:", self.candidates.get());
+        for (stage, removed) in self.removed() {
+            eprintln!("  {:<26} removed {}", stage.description(), removed);
+        }
+    }
+}
+
+/// The songs going through the pipeline, and the counts of its stages.
+struct Pipeline<'a> {
+    songs: Songs<'a>,
+    counts: FilterCounts,
+}
+
+impl<'a> Pipeline<'a> {
+    fn new(songs: impl Iterator<Item = LibrarySong<()>> + 'a) -> Self {
+        let counts = FilterCounts::default();
+        let songs = Box::new(Counted {
+            songs: Box::new(songs),
+            count: counts.candidates.clone(),
+        });
+        Self { songs, counts }
+    }
+
+    /// Add `stage`, making the songs that go out of it with `f`.
+    fn stage(mut self, stage: Stage, f: impl FnOnce(Songs<'a>) -> Songs<'a>) -> Self {
+        let count = Rc::new(Cell::new(0));
+        self.songs = Box::new(Counted {
+            songs: f(self.songs),
+            count: count.clone(),
+        });
+        self.counts.stages.push((stage, count));
+        self
+    }
+
+    /// Add `stage`, keeping the songs matching `predicate`.
+    fn filter(self, stage: Stage, predicate: impl FnMut(&LibrarySong<()>) -> bool + 'a) -> Self {
+        self.stage(stage, |songs| Box::new(songs.filter(predicate)))
+    }
+}
+
+/// The artist of `song`, ignoring case.
+fn artist(song: &LibrarySong<()>) -> Option<String> {
+    song.bliss_song.artist.as_ref().map(|a| a.to_lowercase())
+}
+
+/// Leave out the songs of an artist kept less than `spacing` songs before.
+fn artist_spacing(spacing: usize) -> impl FnMut(&LibrarySong<()>) -> bool {
+    let mut last_artists: VecDeque<Option<String>> = VecDeque::new();
+    move |song| {
+        let artist = artist(song);
+        if artist.is_some() && last_artists.contains(&artist) {
+            return false;
+        }
+        last_artists.push_back(artist);
+        if last_artists.len() > spacing {
+            last_artists.pop_front();
+        }
+        true
+    }
+}
+
+/// Keep at most `max_songs` songs of each artist.
+fn max_songs_per_artist(max_songs: usize) -> impl FnMut(&LibrarySong<()>) -> bool {
+    let mut songs_per_artist: HashMap<String, usize> = HashMap::new();
+    move |song| match artist(song) {
+        Some(artist) => {
+            let songs = songs_per_artist.entry(artist).or_default();
+            *songs += 1;
+            *songs <= max_songs
+        }
+        None => true,
+    }
+}
+
+/// Leave out the songs whose loudness is more than `max_jump` decibels away
+/// from the loudness of the song kept before them. The songs the playlist is
+/// made from are always kept, and set the loudness the next songs are
+/// compared to.
+fn loudness_jump(
+    max_jump: f32,
+    is_seed: impl Fn(&LibrarySong<()>) -> bool,
+) -> impl FnMut(&LibrarySong<()>) -> bool {
+    let mut last_loudness = None;
+    move |song| {
+        let loudness = loudness_db(&song.bliss_song);
+        if !is_seed(song) && last_loudness.is_some_and(|l: f32| (loudness - l).abs() > max_jump) {
+            return false;
+        }
+        last_loudness = Some(loudness);
+        true
+    }
+}
+
+/// What a playlist is made from, and what it goes into.
+pub(crate) struct FilterContext<'a> {
+    /// The paths of the songs the playlist is made from, which are never
+    /// left out.
+    pub seed_paths: &'a [PathBuf],
+    /// The songs the playlist is made from, if they were loaded, see
+    /// [avoid_seed_album](PlaylistOptions::avoid_seed_album).
+    pub seeds: &'a [LibrarySong<()>],
+    /// The MPD files of the songs that stay in the queue, see
+    /// `--skip-queued`.
+    pub queued_files: &'a HashSet<String>,
+    pub number_songs: usize,
+}
+
+impl MPDLibrary {
+    /// Run `songs`, the candidates of a playlist sorted by distance, through
+    /// the filters of `options`, see [Stage].
+    ///
+    /// Returns the songs left, and the counts of the candidates each stage
+    /// removed, filled as the songs are consumed.
+    pub(crate) fn filter_playlist<'a>(
+        &'a self,
+        songs: impl Iterator<Item = LibrarySong<()>> + 'a,
+        options: &'a PlaylistOptions,
+        context: FilterContext<'a>,
+    ) -> (impl Iterator<Item = LibrarySong<()>> + 'a, FilterCounts) {
+        let filters = &options.filters;
+        let seed_paths = context.seed_paths;
+        let is_seed = move |song: &LibrarySong<()>| seed_paths.contains(&song.bliss_song.path);
+        let genres = filters
+            .genres
+            .iter()
+            .flat_map(|g| parse_genres(g))
+            .collect::<HashSet<_>>();
+        let min_duration = filters.min_duration.map(Duration::from_secs);
+        let max_duration = filters.max_duration.map(Duration::from_secs);
+        let mut offset = options.offset;
+        let mut artist_spacing = filters.artist_spacing.map(artist_spacing);
+        let mut max_songs_per_artist = filters.max_songs_per_artist.map(max_songs_per_artist);
+        let mut loudness_jump = filters
+            .max_loudness_jump
+            .map(|max_jump| loudness_jump(max_jump, is_seed));
+
+        let pipeline = Pipeline::new(songs)
+            .filter(Stage::Genre, move |s| {
+                genres.is_empty()
+                    || is_seed(s)
+                    || s.bliss_song
+                        .genre
+                        .as_ref()
+                        .is_some_and(|g| parse_genres(g).iter().any(|g| genres.contains(g)))
+            })
+            .filter(Stage::Duration, move |s| {
+                let duration = s.bliss_song.duration;
+                is_seed(s)
+                    || (min_duration.is_none_or(|m| duration >= m)
+                        && max_duration.is_none_or(|m| duration <= m))
+            })
+            .filter(Stage::Tempo, move |s| {
+                let bpm = tempo_bpm(&s.bliss_song.analysis);
+                is_seed(s)
+                    || (filters.min_bpm.is_none_or(|m| bpm >= m)
+                        && filters.max_bpm.is_none_or(|m| bpm <= m))
+            })
+            .stage(Stage::Dedup, |songs| {
+                Box::new(dedup_playlist(
+                    songs,
+                    options.dedup,
+                    options.dedup_distance,
+                    move |s| self.recording_id(s),
+                ))
+            })
+            .filter(Stage::Queued, move |s| {
+                !self.is_queued(s, context.queued_files)
+            })
+            .filter(Stage::Excluded, move |s| is_seed(s) || !options.excludes(s))
+            .filter(Stage::Offset, move |s| {
+                if is_seed(s) || offset == 0 {
+                    return true;
+                }
+                offset -= 1;
+                false
+            })
+            .filter(
+                Stage::Familiar,
+                history::familiar_budget(options, context.number_songs, seed_paths),
+            )
+            .stage(Stage::SeedAlbum, |songs| {
+                Box::new(seed_album::avoid_seed_albums(
+                    songs,
+                    context.seeds,
+                    options.avoid_seed_album,
+                ))
+            })
+            .filter(Stage::ArtistSpacing, move |s| {
+                is_seed(s) || artist_spacing.as_mut().is_none_or(|f| f(s))
+            })
+            .filter(Stage::Diversity, move |s| {
+                is_seed(s) || max_songs_per_artist.as_mut().is_none_or(|f| f(s))
+            })
+            .filter(Stage::Loudness, move |s| {
+                loudness_jump.as_mut().is_none_or(|f| f(s))
+            });
+        (pipeline.songs, pipeline.counts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_filter_playlist() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first_song.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", None, 0.),
+                (2, "path/second_song.flac", None, 0.1),
+                (3, "path/third_song.flac", None, 0.2),
+                (4, "path/fourth_song.flac", None, 0.3),
+                (5, "path/fifth_song.flac", None, 0.4),
+                (6, "path/sixth_song.flac", None, 0.5),
+                (7, "path/seventh_song.flac", None, 0.6),
+            ],
+        );
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            for (id, artist, genre, duration) in [
+                (1, "Seed", "Jazz", 200),
+                (2, "A", "Rock", 200),
+                (3, "A", "Jazz; Soul", 200),
+                (4, "A", "jazz", 200),
+                (5, "B", "Jazz", 20),
+                (6, "C", "Jazz", 200),
+                (7, "A", "Jazz", 200),
+            ] {
+                sqlite_conn
+                    .execute(
+                        "update song set artist = ?1, genre = ?2, duration = ?3 where id = ?4",
+                        (artist, genre, duration, id),
+                    )
+                    .unwrap();
+            }
+        }
+        let options = PlaylistOptions {
+            dedup: None,
+            filters: PlaylistFilters {
+                genres: vec![String::from("JAZZ")],
+                artist_spacing: Some(1),
+                min_duration: Some(30),
+                max_duration: None,
+                min_bpm: None,
+                max_bpm: None,
+                max_songs_per_artist: Some(2),
+                max_loudness_jump: None,
+            },
+            ..Default::default()
+        };
+        let seed_paths = [PathBuf::from("path/first_song.flac")];
+        let songs = library.library.songs_from_library::<()>().unwrap();
+        let seed = library
+            .library
+            .song_from_path::<()>("path/first_song.flac")
+            .unwrap();
+        let queued_files = HashSet::new();
+        let (playlist, counts) = library.filter_playlist(
+            closest_to_songs(&[seed], &songs, &euclidean_distance),
+            &options,
+            FilterContext {
+                seed_paths: &seed_paths,
+                seeds: &[],
+                queued_files: &queued_files,
+                number_songs: 20,
+            },
+        );
+        let playlist = playlist
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            playlist,
+            vec![
+                "path/first_song.flac",
+                "path/third_song.flac",
+                "path/sixth_song.flac",
+                "path/seventh_song.flac",
+            ],
+        );
+        let removed = counts
+            .removed()
+            .into_iter()
+            .filter(|(_, removed)| *removed > 0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            removed,
+            vec![
+                (Stage::Genre, 1),
+                (Stage::Duration, 1),
+                (Stage::ArtistSpacing, 1),
+            ],
+        );
+
+        // Without artist spacing, the fourth song is kept, which makes the
+        // seventh song the third song of artist A.
+        let options = PlaylistOptions {
+            filters: PlaylistFilters {
+                artist_spacing: None,
+                ..options.filters
+            },
+            ..options
+        };
+        library
+            .queue_from_song(None, 20, &euclidean_distance, closest_to_songs, &options)
+            .unwrap();
+        let queue = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|x| x.file.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            queue,
+            vec![
+                "first_song.flac",
+                "third_song.flac",
+                "fourth_song.flac",
+                "sixth_song.flac",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_loudness_jump() {
+        let (library, _tempdir) = setup_library();
+        // A feature of 0.1 is 4.5 dB louder than a feature of 0.
+        insert_songs(
+            &library,
+            &[
+                (1, "path/seed.flac", None, 0.),
+                (2, "path/close.flac", None, 0.1),
+                (3, "path/loud.flac", None, 0.3),
+                (4, "path/louder.flac", None, 0.35),
+            ],
+        );
+        let options = PlaylistOptions {
+            dedup: None,
+            filters: PlaylistFilters {
+                max_loudness_jump: Some(DEFAULT_MAX_LOUDNESS_JUMP),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let seed_paths = [PathBuf::from("path/seed.flac")];
+        let songs = library.library.songs_from_library::<()>().unwrap();
+        let seed = library
+            .library
+            .song_from_path::<()>("path/seed.flac")
+            .unwrap();
+        let queued_files = HashSet::new();
+        let (playlist, counts) = library.filter_playlist(
+            closest_to_songs(&[seed], &songs, &euclidean_distance),
+            &options,
+            FilterContext {
+                seed_paths: &seed_paths,
+                seeds: &[],
+                queued_files: &queued_files,
+                number_songs: 20,
+            },
+        );
+        let playlist = playlist
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        // The loud songs are further than 6 dB from the last song kept, even
+        // though they are close to each other.
+        assert_eq!(playlist, vec!["path/seed.flac", "path/close.flac"]);
+        assert_eq!(counts.removed().last(), Some(&(Stage::Loudness, 2)));
+    }
+
+    #[test]
+    fn test_bpm_filter() {
+        let (library, _tempdir) = setup_library();
+        // A feature of 0 is about 155 BPM, and each 0.1 about 10 BPM more.
+        insert_songs(
+            &library,
+            &[
+                (1, "path/seed.flac", None, 0.3),
+                (2, "path/slow.flac", None, 0.),
+                (3, "path/in_range.flac", None, 0.1),
+                (4, "path/fast.flac", None, 0.6),
+            ],
+        );
+        let options = PlaylistOptions {
+            dedup: None,
+            filters: PlaylistFilters {
+                min_bpm: Some(160.),
+                max_bpm: Some(170.),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let seed_paths = [PathBuf::from("path/seed.flac")];
+        let songs = library.library.songs_from_library::<()>().unwrap();
+        let seed = library
+            .library
+            .song_from_path::<()>("path/seed.flac")
+            .unwrap();
+        let queued_files = HashSet::new();
+        let (playlist, counts) = library.filter_playlist(
+            closest_to_songs(&[seed], &songs, &euclidean_distance),
+            &options,
+            FilterContext {
+                seed_paths: &seed_paths,
+                seeds: &[],
+                queued_files: &queued_files,
+                number_songs: 20,
+            },
+        );
+        let playlist = playlist
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        // The seed is kept, even though it is faster.
+        assert_eq!(playlist, vec!["path/seed.flac", "path/in_range.flac"]);
+        assert!(counts.removed().contains(&(Stage::Tempo, 2)));
+    }
+}
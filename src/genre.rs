@@ -0,0 +1,129 @@
+//! Multi-value genre tags.
+//!
+//! bliss stores a song's genre tag as a single string, like
+//! "Electronic; Ambient; Idm", or with one genre per tag frame joined
+//! together. This splits them into a `song_genre` table, with one normalized
+//! genre per row, so songs can be looked up by genre.
+use crate::MPDLibrary;
+use anyhow::Result;
+
+/// The separators between genres in a genre tag. NUL separates genres
+/// coming from different tag frames.
+const GENRE_SEPARATORS: &[char] = &[';', '/', ',', '\0'];
+
+/// Split a genre tag into normalized genres: lowercased, without surrounding
+/// or repeated whitespace, and without duplicates.
+pub fn parse_genres(tag: &str) -> Vec<String> {
+    let mut genres: Vec<String> = vec![];
+    for genre in tag.split(GENRE_SEPARATORS) {
+        let genre = genre
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        if !genre.is_empty() && !genres.contains(&genre) {
+            genres.push(genre);
+        }
+    }
+    genres
+}
+
+impl MPDLibrary {
+    /// Create the table storing each song's genres, if it doesn't exist yet.
+    pub(crate) fn create_genre_table(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "create table if not exists song_genre (
+                song_id integer not null,
+                genre text not null,
+                primary key (song_id, genre)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Fill the `song_genre` table from the genre tags of the songs in the
+    /// database. Meant to be called after the library was updated.
+    pub(crate) fn sync_genres(&self) -> Result<()> {
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let tx = sqlite_conn.transaction()?;
+        tx.execute("delete from song_genre", [])?;
+        {
+            let mut query = tx.prepare("select id, genre from song where genre is not null")?;
+            let songs = query
+                .query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut insert =
+                tx.prepare("insert into song_genre (song_id, genre) values (?1, ?2)")?;
+            for (id, tag) in songs {
+                for genre in parse_genres(&tag) {
+                    insert.execute((id, genre))?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The paths of the songs having `genre` as one of their genres.
+    pub(crate) fn songs_with_genre(&self, genre: &str) -> Result<Vec<String>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select song.path from song
+            inner join song_genre on song_genre.song_id = song.id
+            where song_genre.genre = ?1 order by song.path",
+        )?;
+        let paths = query
+            .query_map([genre.trim().to_lowercase()], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_genres() {
+        assert_eq!(
+            parse_genres("Electronic; Ambient;Idm"),
+            vec!["electronic", "ambient", "idm"]
+        );
+        assert_eq!(
+            parse_genres("Rock/Pop, Hip  Hop\0rock"),
+            vec!["rock", "pop", "hip hop"]
+        );
+        assert!(parse_genres(" ; ").is_empty());
+    }
+
+    #[test]
+    fn test_sync_genres() {
+        let (library, _tempdir) = setup_library();
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            sqlite_conn
+                .execute_batch(
+                    "insert into song (id, path, genre, analyzed, duration, version) values
+                    (1, 'path/first.flac', 'Electronic; Ambient', true, 10, 1),
+                    (2, 'path/second.flac', 'ambient', true, 10, 1),
+                    (3, 'path/third.flac', null, true, 10, 1);",
+                )
+                .unwrap();
+        }
+        library.sync_genres().unwrap();
+        assert_eq!(
+            library.songs_with_genre("Ambient").unwrap(),
+            vec!["path/first.flac", "path/second.flac"],
+        );
+        assert_eq!(
+            library.songs_with_genre("electronic").unwrap(),
+            vec!["path/first.flac"],
+        );
+        assert!(library.songs_with_genre("rock").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! Exporting the similarity graph of the library, see `blissify export-graph`.
+//!
+//! Each analyzed song is written on its own line, as JSON, along with its
+//! `k` nearest songs and their distance to it, closest first, so that other
+//! tools (e.g. a Markov chain shuffler) can walk the library without
+//! analyzing it themselves:
+//!
+//! ```text
+//! {"path":"/music/a.flac","neighbors":[{"path":"/music/b.flac","distance":0.12},...]}
+//! ```
+use crate::MPDLibrary;
+use anyhow::Result;
+use bliss_audio::playlist::DistanceMetricBuilder;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// A song of the graph, and its nearest songs.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GraphNode {
+    pub path: String,
+    pub neighbors: Vec<Neighbor>,
+}
+
+/// One of the nearest songs of a [GraphNode].
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Neighbor {
+    pub path: String,
+    pub distance: f32,
+}
+
+impl MPDLibrary {
+    /// Write the `k` nearest songs of each analyzed song according to
+    /// `distance` to `output`, one [GraphNode] per line. Returns the number
+    /// of songs written.
+    pub(crate) fn export_graph(
+        &self,
+        k: usize,
+        distance: &dyn DistanceMetricBuilder,
+        output: &mut dyn Write,
+    ) -> Result<usize> {
+        let songs = self.library.songs_from_library::<()>()?;
+        let vectors = songs
+            .iter()
+            .map(|s| s.bliss_song.analysis.as_arr1())
+            .collect::<Vec<_>>();
+        for (i, vector) in vectors.iter().enumerate() {
+            let metric = distance.build(std::slice::from_ref(vector));
+            let mut neighbors = vectors
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(j, v)| (j, metric.distance(v)))
+                .collect::<Vec<_>>();
+            // Only the k nearest songs need sorting.
+            if neighbors.len() > k {
+                neighbors.select_nth_unstable_by(k, |a, b| a.1.total_cmp(&b.1));
+                neighbors.truncate(k);
+            }
+            neighbors.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let node = GraphNode {
+                path: songs[i].bliss_song.path.to_string_lossy().to_string(),
+                neighbors: neighbors
+                    .into_iter()
+                    .map(|(j, distance)| Neighbor {
+                        path: songs[j].bliss_song.path.to_string_lossy().to_string(),
+                        distance,
+                    })
+                    .collect(),
+            };
+            writeln!(output, "{}", serde_json::to_string(&node)?)?;
+        }
+        output.flush()?;
+        Ok(songs.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::playlist::euclidean_distance;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_export_graph() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/second.flac", None, 0.1),
+                (3, "path/third.flac", None, 0.3),
+                (4, "path/fourth.flac", None, 1.),
+            ],
+        );
+        let mut output = vec![];
+        assert_eq!(
+            library
+                .export_graph(2, &euclidean_distance, &mut output)
+                .unwrap(),
+            4
+        );
+        let nodes = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<GraphNode>(l).unwrap())
+            .map(|n| {
+                (
+                    n.path,
+                    n.neighbors.into_iter().map(|n| n.path).collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let node = |path: &str, neighbors: [&str; 2]| {
+            (
+                path.to_string(),
+                neighbors.iter().map(|n| n.to_string()).collect(),
+            )
+        };
+        assert_eq!(
+            nodes,
+            vec![
+                node("path/first.flac", ["path/second.flac", "path/third.flac"]),
+                node("path/second.flac", ["path/first.flac", "path/third.flac"]),
+                node("path/third.flac", ["path/second.flac", "path/first.flac"]),
+                node("path/fourth.flac", ["path/third.flac", "path/second.flac"]),
+            ],
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Mixing the songs of another library into playlists, see
+//! `playlist --guest-db`.
+//!
+//! A guest database is another bliss database, like a friend's exported
+//! blissify database, whose music is mounted somewhere else here, e.g. on a
+//! USB drive. Its analyzed songs are read once, with their paths translated
+//! as for `import-db`, and become candidates along with the songs of the
+//! library. The guest database is only read from, never written to. Dry runs
+//! show which library each song comes from.
+use crate::{MPDLibrary, PlaylistOptions};
+use anyhow::{bail, Result};
+use bliss_audio::library::LibrarySong;
+use bliss_audio::playlist::DistanceMetricBuilder;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Whether `song` comes from the guest database of `options`.
+pub fn is_guest(song: &LibrarySong<()>, options: &PlaylistOptions) -> bool {
+    options
+        .guest_songs
+        .iter()
+        .any(|g| g.bliss_song.path == song.bliss_song.path)
+}
+
+impl MPDLibrary {
+    /// The analyzed songs of the guest database at `path`, with their paths
+    /// translated according to `replace_prefix`, leaving out the songs that
+    /// are also in the library.
+    pub(crate) fn guest_songs(
+        &self,
+        path: &Path,
+        replace_prefix: Option<(&str, &str)>,
+    ) -> Result<Vec<LibrarySong<()>>> {
+        // Attaching a database that doesn't exist would create it.
+        if !path.is_file() {
+            bail!(
+                "The guest database {} does not exist. Please check its path, and try again.",
+                path.display()
+            );
+        }
+        let local_paths = self
+            .library
+            .songs_from_library::<()>()?
+            .into_iter()
+            .map(|s| s.bliss_song.path)
+            .collect::<HashSet<_>>();
+        Ok(self
+            .read_other_database(path, replace_prefix)?
+            .into_iter()
+            .flatten()
+            .filter(|song| !local_paths.contains(&song.path))
+            .map(|bliss_song| LibrarySong {
+                bliss_song,
+                extra_info: (),
+            })
+            .collect())
+    }
+
+    /// The candidates of playlists: the analyzed songs of the library, and
+    /// the songs of the guest database of `options`, if any.
+    pub(crate) fn candidate_songs(
+        &self,
+        options: &PlaylistOptions,
+    ) -> Result<Vec<LibrarySong<()>>> {
+        let mut songs = self.library.songs_from_library()?;
+        songs.extend(options.guest_songs.iter().cloned());
+        Ok(songs)
+    }
+
+    /// Same as bliss' `playlist_from_custom`, without deduplication, but
+    /// also making the playlist out of the songs of the guest database of
+    /// `options`, if any.
+    pub(crate) fn playlist_from_seeds<'a, F, I>(
+        &self,
+        paths: &[&str],
+        distance: &'a dyn DistanceMetricBuilder,
+        sort_by: F,
+        options: &PlaylistOptions,
+    ) -> Result<Box<dyn Iterator<Item = LibrarySong<()>> + 'a>>
+    where
+        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
+        I: Iterator<Item = LibrarySong<()>> + 'a,
+    {
+        if options.guest_songs.is_empty() {
+            return Ok(Box::new(
+                self.library
+                    .playlist_from_custom(paths, distance, sort_by, false)?,
+            ));
+        }
+        let seeds = paths
+            .iter()
+            .map(|p| self.library.song_from_path(p))
+            .collect::<Result<Vec<LibrarySong<()>>, _>>()?;
+        let mut songs = self.candidate_songs(options)?;
+        songs.retain(|s| !seeds.contains(s));
+        let playlist = sort_by(&seeds, &songs, distance);
+        Ok(Box::new(seeds.into_iter().chain(playlist)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use bliss_audio::FEATURES_VERSION;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_guest_songs() {
+        let (library, tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/local.flac", None, 0.),
+                (2, "path/far.flac", None, 2.),
+            ],
+        );
+        let (guest, _guest_tempdir) = setup_library();
+        insert_songs(
+            &guest,
+            &[
+                (1, "/friend/Music/close.flac", None, 0.1),
+                (2, "/friend/Music/local.flac", None, 0.2),
+            ],
+        );
+        guest
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute("update song set version = ?1", [FEATURES_VERSION])
+            .unwrap();
+        let guest_path = guest.library.config.base_config.database_path.to_owned();
+        assert!(library
+            .guest_songs(&tempdir.path().join("missing.db"), None)
+            .is_err());
+
+        let guest_songs = library
+            .guest_songs(&guest_path, Some(("/friend/Music/", "path/")))
+            .unwrap();
+        assert_eq!(
+            guest_songs
+                .iter()
+                .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+                .collect::<Vec<_>>(),
+            vec!["path/close.flac"],
+        );
+
+        let options = PlaylistOptions {
+            guest_songs,
+            ..Default::default()
+        };
+        let playlist = library
+            .playlist_from_seeds(
+                &["path/local.flac"],
+                &euclidean_distance,
+                closest_to_songs,
+                &options,
+            )
+            .unwrap()
+            .map(|s| {
+                (
+                    s.bliss_song.path.to_string_lossy().to_string(),
+                    is_guest(&s, &options),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            playlist,
+            vec![
+                (String::from("path/local.flac"), false),
+                (String::from("path/close.flac"), true),
+                (String::from("path/far.flac"), false),
+            ],
+        );
+    }
+}
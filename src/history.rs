@@ -0,0 +1,183 @@
+//! The songs played in MPD, recorded by the daemon, see [crate::daemon].
+//!
+//! Each time MPD moves on to another song, the previous one is stored in a
+//! `play` table, with whether it was skipped and whether blissify queued it.
+//! Unlike feedback, the history is not tied to a user: it is MPD's.
+//!
+//! It is used by `playlist --max-familiar`, which keeps playlists from being
+//! made only of artists played recently.
+use crate::feedback::skip_window_start;
+use crate::{MPDLibrary, PlaylistOptions};
+use anyhow::Result;
+use bliss_audio::library::LibrarySong;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How recently an artist must have been played to count as familiar, see
+/// `--max-familiar`.
+pub const FAMILIAR_WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+impl MPDLibrary {
+    /// Create the table storing the songs played, if it doesn't exist yet.
+    pub(crate) fn create_play_table(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "create table if not exists play (
+                id integer primary key,
+                path text not null,
+                played_at integer not null,
+                skipped boolean not null,
+                queued_by_blissify boolean not null
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Record that the song at `path` was just played, or `skipped`.
+    pub(crate) fn record_play(&self, path: &str, skipped: bool) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "insert into play (path, played_at, skipped, queued_by_blissify)
+            values (?1, ?2, ?3, exists (
+                select 1 from queued_song where path = ?1 and queued_at >= ?4
+            ))",
+            (path, now, skipped, skip_window_start()?),
+        )?;
+        Ok(())
+    }
+
+    /// The artists of the songs played, and not skipped, in the last
+    /// [FAMILIAR_WINDOW].
+    pub(crate) fn familiar_artists(&self) -> Result<HashSet<String>> {
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .saturating_sub(FAMILIAR_WINDOW)
+            .as_secs() as i64;
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select distinct song.artist from play
+            join song on song.path = play.path
+            where play.played_at >= ?1 and not play.skipped and song.artist is not null",
+        )?;
+        let artists = query
+            .query_map([since], |row| row.get::<_, String>(0))?
+            .collect::<Result<HashSet<_>, _>>()?;
+        Ok(artists)
+    }
+}
+
+/// A filter keeping at most `options.max_familiar` of a playlist of
+/// `number_songs` songs from familiar artists, leaving the following songs of
+/// these artists out once the budget is spent, so that songs of artists
+/// played less often take their place. `seeds` are always kept.
+pub fn familiar_budget<'a>(
+    options: &'a PlaylistOptions,
+    number_songs: usize,
+    seeds: &'a [PathBuf],
+) -> impl FnMut(&LibrarySong<()>) -> bool + 'a {
+    let budget = options
+        .max_familiar
+        .map(|m| (m * number_songs as f32).floor() as usize);
+    let mut familiar = 0;
+    move |song| {
+        let budget = match budget {
+            Some(budget) => budget,
+            None => return true,
+        };
+        if seeds.contains(&song.bliss_song.path) {
+            return true;
+        }
+        let is_familiar = song
+            .bliss_song
+            .artist
+            .as_ref()
+            .is_some_and(|a| options.familiar_artists.contains(a));
+        if !is_familiar {
+            return true;
+        }
+        familiar += 1;
+        familiar <= budget
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_max_familiar() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/familiar1.flac", None, 0.1),
+                (3, "path/familiar2.flac", None, 0.2),
+                (4, "path/familiar3.flac", None, 0.3),
+                (5, "path/new1.flac", None, 0.4),
+                (6, "path/new2.flac", None, 0.5),
+                (7, "path/skipped.flac", None, 0.6),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "update song set artist = 'Familiar' where path like 'path/familiar%';
+                update song set artist = 'Skipped' where path = 'path/skipped.flac';
+                update song set artist = 'Played long ago' where path like 'path/new%';
+                insert into play (path, played_at, skipped, queued_by_blissify) values
+                    ('path/new1.flac', 0, false, false);",
+            )
+            .unwrap();
+        library.record_play("path/familiar1.flac", false).unwrap();
+        library.record_play("path/skipped.flac", true).unwrap();
+        assert_eq!(
+            library.familiar_artists().unwrap(),
+            [String::from("Familiar")].into(),
+        );
+
+        let options = PlaylistOptions {
+            dedup: None,
+            max_familiar: Some(0.5),
+            familiar_artists: library.familiar_artists().unwrap(),
+            ..Default::default()
+        };
+        library
+            .queue_from_song(None, 4, &euclidean_distance, closest_to_songs, &options)
+            .unwrap();
+        let playlist = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|x| x.file.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            playlist,
+            vec![
+                "first.flac",
+                "familiar1.flac",
+                "familiar2.flac",
+                "new1.flac",
+                "new2.flac",
+            ],
+        );
+    }
+}
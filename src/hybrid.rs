@@ -0,0 +1,352 @@
+//! Distance metrics combining several others, see `--distance`.
+//!
+//! A distance is either the name of one of the [METRICS], or a weighted sum of
+//! them, like `0.8*euclidean + 0.2*genre_bonus`. Besides the metrics on the
+//! songs' analyses, metadata "bonuses" are 0 for songs sharing a genre (or an
+//! artist) with the songs the playlist is made from, and 1 otherwise, so that
+//! they move the songs sharing it closer.
+//!
+//! New metrics only need an entry in [METRICS]: `--distance` accepts them,
+//! and `blissify list-metrics` lists them, from there.
+use crate::genre::parse_genres;
+use crate::{forest_options, MPDLibrary};
+use anyhow::{anyhow, bail, Result};
+use bliss_audio::library::LibrarySong;
+use bliss_audio::playlist::{
+    cosine_distance, euclidean_distance, mahalanobis_distance_builder, DistanceMetric,
+    DistanceMetricBuilder,
+};
+use ndarray::Array1;
+use std::collections::{HashMap, HashSet};
+
+/// Makes a distance metric, given the library it is used on.
+type MetricConstructor = fn(&MPDLibrary) -> Result<Box<dyn DistanceMetricBuilder>>;
+
+/// The distance metrics `--distance` can use and combine, by name, with a
+/// description for `blissify list-metrics`.
+const METRICS: &[(&str, &str, MetricConstructor)] = &[
+    (
+        "euclidean",
+        "The euclidean distance between the songs' analyses. The default for playlists made from a single song.",
+        |_| Ok(Box::new(euclidean_distance)),
+    ),
+    (
+        "cosine",
+        "The cosine distance between the songs' analyses.",
+        |_| Ok(Box::new(cosine_distance)),
+    ),
+    (
+        "mahalanobis",
+        "The euclidean distance, transformed by the metric imported with \"blissify metric import\", if any.",
+        |library| {
+            Ok(Box::new(mahalanobis_distance_builder(
+                library.metric_matrix().to_owned(),
+            )))
+        },
+    ),
+    (
+        "extended_isolation_forest",
+        "How much songs stand out from the songs the playlist is made from. The default for playlists made from several songs.",
+        |_| Ok(Box::new(forest_options())),
+    ),
+    (
+        "genre_bonus",
+        "0 for songs sharing a genre with the songs the playlist is made from, 1 otherwise, to be combined with other metrics.",
+        |library| {
+            MetadataBonus::from_library(library, |song| {
+                song.bliss_song
+                    .genre
+                    .as_deref()
+                    .map(parse_genres)
+                    .unwrap_or_default()
+            })
+        },
+    ),
+    (
+        "artist_bonus",
+        "0 for songs sharing an artist with the songs the playlist is made from, 1 otherwise, to be combined with other metrics.",
+        |library| {
+            MetadataBonus::from_library(library, |song| {
+                song.bliss_song.artist.iter().cloned().collect()
+            })
+        },
+    ),
+];
+
+/// The names and descriptions of the metrics `--distance` can use, see
+/// `blissify list-metrics`.
+pub fn metrics() -> impl Iterator<Item = (&'static str, &'static str)> {
+    METRICS
+        .iter()
+        .map(|(name, description, _)| (*name, *description))
+}
+
+/// The names of the [METRICS], for error messages.
+fn metric_names() -> String {
+    METRICS
+        .iter()
+        .map(|(name, _, _)| format!("'{}'", name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parse a distance like `0.8*euclidean + 0.2*genre_bonus` into weights and
+/// metric names. A metric without a weight weighs 1.
+pub fn parse_distance(distance: &str) -> Result<Vec<(f32, &str)>> {
+    distance
+        .split('+')
+        .map(|term| {
+            let (weight, name) = match term.split_once('*') {
+                Some((weight, name)) => (
+                    weight
+                        .trim()
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|w| *w >= 0.)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Invalid weight '{}' in distance '{}', weights must be positive numbers.",
+                                weight.trim(),
+                                distance,
+                            )
+                        })?,
+                    name.trim(),
+                ),
+                None => (1., term.trim()),
+            };
+            if name.is_empty() {
+                bail!(
+                    "Missing distance name in '{}', please choose between {}, or a weighted sum of them like '0.8*euclidean + 0.2*genre_bonus'.",
+                    distance,
+                    metric_names(),
+                );
+            }
+            if !METRICS.iter().any(|(n, _, _)| *n == name) {
+                bail!(
+                    "Unknown distance '{}', please choose between {}, or a weighted sum of them like '0.8*euclidean + 0.2*genre_bonus'. Run \"blissify list-metrics\" to see what each of them does.",
+                    name,
+                    metric_names(),
+                );
+            }
+            Ok((weight, name))
+        })
+        .collect()
+}
+
+/// A weighted sum of distance metrics.
+pub struct HybridDistance {
+    terms: Vec<(f32, Box<dyn DistanceMetricBuilder>)>,
+}
+
+impl DistanceMetricBuilder for HybridDistance {
+    fn build<'a>(&'a self, vectors: &[Array1<f32>]) -> Box<dyn DistanceMetric + 'a> {
+        Box::new(HybridMetric {
+            terms: self
+                .terms
+                .iter()
+                .map(|(weight, builder)| (*weight, builder.build(vectors)))
+                .collect(),
+        })
+    }
+}
+
+struct HybridMetric<'a> {
+    terms: Vec<(f32, Box<dyn DistanceMetric + 'a>)>,
+}
+
+impl DistanceMetric for HybridMetric<'_> {
+    fn distance(&self, vector: &Array1<f32>) -> f32 {
+        self.terms
+            .iter()
+            .map(|(weight, metric)| weight * metric.distance(vector))
+            .sum()
+    }
+}
+
+/// The key songs are looked up by from their analysis, since distance
+/// metrics only get to see analyses.
+fn analysis_key(vector: &Array1<f32>) -> Vec<u32> {
+    vector.iter().map(|x| x.to_bits()).collect()
+}
+
+/// 0 for songs sharing some metadata with the songs the playlist is made
+/// from, 1 otherwise, see the [module](self) documentation.
+pub struct MetadataBonus {
+    /// The metadata values of each song of the library, by analysis.
+    values: HashMap<Vec<u32>, HashSet<String>>,
+}
+
+impl MetadataBonus {
+    fn from_library(
+        library: &MPDLibrary,
+        values: fn(&LibrarySong<()>) -> Vec<String>,
+    ) -> Result<Box<dyn DistanceMetricBuilder>> {
+        let mut bonus = MetadataBonus {
+            values: HashMap::new(),
+        };
+        for song in library.library.songs_from_library::<()>()? {
+            bonus
+                .values
+                .entry(analysis_key(&song.bliss_song.analysis.as_arr1()))
+                .or_default()
+                .extend(values(&song));
+        }
+        Ok(Box::new(bonus))
+    }
+}
+
+impl DistanceMetricBuilder for MetadataBonus {
+    fn build<'a>(&'a self, vectors: &[Array1<f32>]) -> Box<dyn DistanceMetric + 'a> {
+        let seeds = vectors
+            .iter()
+            .filter_map(|v| self.values.get(&analysis_key(v)))
+            .flatten()
+            .collect::<HashSet<_>>();
+        Box::new(MetadataBonusMetric {
+            values: &self.values,
+            seeds,
+        })
+    }
+}
+
+struct MetadataBonusMetric<'a> {
+    values: &'a HashMap<Vec<u32>, HashSet<String>>,
+    seeds: HashSet<&'a String>,
+}
+
+impl DistanceMetric for MetadataBonusMetric<'_> {
+    fn distance(&self, vector: &Array1<f32>) -> f32 {
+        let shares = self
+            .values
+            .get(&analysis_key(vector))
+            .is_some_and(|values| values.iter().any(|v| self.seeds.contains(v)));
+        if shares {
+            0.
+        } else {
+            1.
+        }
+    }
+}
+
+impl MPDLibrary {
+    /// The distance metric `distance` stands for, see [parse_distance].
+    pub(crate) fn distance_metric(&self, distance: &str) -> Result<Box<dyn DistanceMetricBuilder>> {
+        let terms = parse_distance(distance)?;
+        let constructor = |name: &str| {
+            let (_, _, constructor) = METRICS.iter().find(|(n, _, _)| *n == name).unwrap();
+            constructor(self)
+        };
+        if let [(weight, name)] = terms[..] {
+            if weight == 1. {
+                return constructor(name);
+            }
+        }
+        Ok(Box::new(HybridDistance {
+            terms: terms
+                .into_iter()
+                .map(|(weight, name)| Ok((weight, constructor(name)?)))
+                .collect::<Result<_>>()?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use crate::PlaylistOptions;
+    use bliss_audio::playlist::closest_to_songs;
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_distance() {
+        assert_eq!(
+            parse_distance("euclidean").unwrap(),
+            vec![(1., "euclidean")]
+        );
+        assert_eq!(
+            parse_distance("0.8*euclidean + 0.2 * genre_bonus").unwrap(),
+            vec![(0.8, "euclidean"), (0.2, "genre_bonus")],
+        );
+        assert!(parse_distance("manhattan")
+            .unwrap_err()
+            .to_string()
+            .contains("'euclidean', 'cosine'"));
+        assert!(parse_distance("manhattan")
+            .unwrap_err()
+            .to_string()
+            .contains("blissify list-metrics"));
+        assert_eq!(
+            parse_distance("euclidean +").unwrap_err().to_string(),
+            "Missing distance name in 'euclidean +', please choose between 'euclidean', 'cosine', 'mahalanobis', 'extended_isolation_forest', 'genre_bonus', 'artist_bonus', or a weighted sum of them like '0.8*euclidean + 0.2*genre_bonus'.",
+        );
+        // Every metric listed can be used.
+        for (name, _) in metrics() {
+            assert_eq!(parse_distance(name).unwrap(), vec![(1., name)]);
+        }
+        assert!(parse_distance("-1*euclidean").is_err());
+        assert!(parse_distance("a lot*euclidean").is_err());
+        assert!(parse_distance("euclidean +").is_err());
+    }
+
+    #[test]
+    fn test_hybrid_distance() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/close.flac", None, 0.01),
+                (3, "path/same_genre.flac", None, 0.02),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "update song set genre = 'Ambient; Drone' where id = 1;
+                update song set genre = 'Techno' where id = 2;
+                update song set genre = 'drone' where id = 3;",
+            )
+            .unwrap();
+        let queue = |distance: &str| {
+            let distance = library.distance_metric(distance).unwrap();
+            library.mpd_conn().unwrap().mpd_queue.truncate(1);
+            let options = PlaylistOptions {
+                dedup: None,
+                ..Default::default()
+            };
+            library
+                .queue_from_song(None, 2, &*distance, closest_to_songs, &options)
+                .unwrap();
+            library
+                .mpd_conn()
+                .unwrap()
+                .mpd_queue
+                .iter()
+                .map(|s| s.file.to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            queue("euclidean"),
+            vec!["first.flac", "close.flac", "same_genre.flac"],
+        );
+        assert_eq!(
+            queue("0.8*euclidean + 0.2*genre_bonus"),
+            vec!["first.flac", "same_genre.flac", "close.flac"],
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Importing analyses from the databases of other bliss-based tools, see
+//! `blissify import-db`.
+//!
+//! Other bliss frontends, or scripts using bliss-rs directly, store analyses
+//! in the same `song` and `feature` tables as blissify, so songs analyzed
+//! there don't need to be analyzed again. The songs of the other database are
+//! matched with the songs of MPD's library by path, once the beginning of
+//! their paths is replaced if the music was in another directory there.
+//! They are not matched by MusicBrainz recording id: bliss' tables don't
+//! store tags beyond the basic ones, so songs whose paths can't be made to
+//! match are analyzed again.
+//! Only complete analyses made with blissify's version of the features are
+//! imported, and songs blissify already analyzed are left as they are.
+use crate::MPDLibrary;
+use anyhow::{Context, Result};
+use bliss_audio::cue::CueInfo;
+use bliss_audio::{Analysis, Song, FEATURES_VERSION, NUMBER_FEATURES};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What [import_database](MPDLibrary::import_database) did with the songs of
+/// the other database.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Songs imported.
+    pub imported: usize,
+    /// Songs blissify had already analyzed.
+    pub already_analyzed: usize,
+    /// Songs that are not in MPD's library.
+    pub not_in_library: usize,
+    /// Songs that were not analyzed, only partially, or with another version
+    /// of the features.
+    pub unusable: usize,
+}
+
+/// `path`, with its beginning replaced according to `replace_prefix`.
+fn translate_path(path: &str, replace_prefix: Option<(&str, &str)>) -> PathBuf {
+    match replace_prefix.and_then(|(old, new)| Some((path.strip_prefix(old)?, new))) {
+        Some((rest, new)) => PathBuf::from(format!("{}{}", new, rest)),
+        None => PathBuf::from(path),
+    }
+}
+
+impl MPDLibrary {
+    /// Read the songs of the other bliss database at `path`, with their
+    /// paths translated according to `replace_prefix`. Songs that can't be
+    /// used, like songs that failed to be analyzed, are `None`.
+    pub(crate) fn read_other_database(
+        &self,
+        path: &Path,
+        replace_prefix: Option<(&str, &str)>,
+    ) -> Result<Vec<Option<Song>>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        sqlite_conn.execute("attach database ?1 as other", [path.to_string_lossy()])?;
+        let read = || -> Result<Vec<Option<Song>>> {
+            let mut features: HashMap<i64, Vec<f32>> = HashMap::new();
+            let mut query = sqlite_conn.prepare(
+                "select song_id, feature from other.feature order by song_id, feature_index",
+            )?;
+            for row in query.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+                let (id, feature) = row?;
+                features.entry(id).or_default().push(feature);
+            }
+            // Other tools may have an older version of bliss' schema, with
+            // fewer columns, so columns are read by name when they exist.
+            let mut query = sqlite_conn.prepare("select * from other.song")?;
+            let songs = query
+                .query_map([], |row| {
+                    let text = |name: &str| row.get::<_, Option<String>>(name).ok().flatten();
+                    let number = |name: &str| row.get::<_, Option<i64>>(name).ok().flatten();
+                    let analysis = features
+                        .get(&row.get::<_, i64>("id")?)
+                        .and_then(|f| <[f32; NUMBER_FEATURES]>::try_from(f.as_slice()).ok());
+                    let analysis = match analysis {
+                        Some(analysis)
+                            if number("analyzed").unwrap_or(1) != 0
+                                && number("version") == Some(FEATURES_VERSION.into()) =>
+                        {
+                            analysis
+                        }
+                        _ => return Ok(None),
+                    };
+                    let path = |name: &str| text(name).map(|p| translate_path(&p, replace_prefix));
+                    Ok(Some(Song {
+                        path: translate_path(&row.get::<_, String>("path")?, replace_prefix),
+                        artist: text("artist"),
+                        title: text("title"),
+                        album: text("album"),
+                        album_artist: text("album_artist"),
+                        track_number: number("track_number").map(|n| n as i32),
+                        disc_number: number("disc_number").map(|n| n as i32),
+                        genre: text("genre"),
+                        duration: Duration::from_secs_f64(
+                            row.get::<_, Option<f64>>("duration")
+                                .ok()
+                                .flatten()
+                                .unwrap_or(0.),
+                        ),
+                        analysis: Analysis::new(analysis),
+                        features_version: FEATURES_VERSION,
+                        cue_info: match (path("cue_path"), path("audio_file_path")) {
+                            (Some(cue_path), Some(audio_file_path)) => Some(CueInfo {
+                                cue_path,
+                                audio_file_path,
+                            }),
+                            _ => None,
+                        },
+                    }))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(songs)
+        };
+        let songs = read();
+        sqlite_conn.execute("detach database other", [])?;
+        songs
+    }
+
+    /// Import the analyses of the other bliss database at `path` for the
+    /// songs of MPD's library that are not analyzed yet.
+    ///
+    /// If the music was in another directory for the other tool, the paths
+    /// of the other database starting with `replace_prefix.0` are made to
+    /// start with `replace_prefix.1` instead.
+    pub(crate) fn import_database(
+        &mut self,
+        path: &Path,
+        replace_prefix: Option<(&str, &str)>,
+    ) -> Result<ImportSummary> {
+        let songs = self
+            .read_other_database(path, replace_prefix)
+            .with_context(|| format!("while reading the bliss database {}", path.display()))?;
+        let library_paths = self.get_songs_paths()?.into_iter().collect::<HashSet<_>>();
+        let analyzed = self.analyzed_paths()?;
+        let mut summary = ImportSummary::default();
+        for song in songs {
+            let song = match song {
+                Some(song) => song,
+                None => {
+                    summary.unusable += 1;
+                    continue;
+                }
+            };
+            // CUE tracks are listed by their CUE sheet.
+            let listed_path = match &song.cue_info {
+                Some(cue_info) => cue_info.cue_path.to_owned(),
+                None => song.path.to_owned(),
+            };
+            let listed_path = listed_path.to_string_lossy().to_string();
+            if !library_paths.contains(&listed_path) {
+                summary.not_in_library += 1;
+            } else if analyzed.contains(&listed_path) {
+                summary.already_analyzed += 1;
+            } else {
+                self.store_analyzed_song(song)?;
+                summary.imported += 1;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library, test_analysis};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_import_database() {
+        let (mut library, _tempdir) = setup_library();
+        // The songs MPD lists, see MockMPDClient::search.
+        let base_path = library.library.config.mpd_base_path.to_owned();
+        let in_library = |file: &str| base_path.join(file).to_string_lossy().to_string();
+        insert_songs(
+            &library,
+            &[(1, &in_library("s16_stereo_22_5kHz.flac"), None, 0.)],
+        );
+
+        let (other, other_tempdir) = setup_library();
+        insert_songs(
+            &other,
+            &[
+                (1, "/old/music/s16_mono_22_5kHz.flac", None, 0.5),
+                (2, "/old/music/s16_stereo_22_5kHz.flac", None, 0.7),
+                (3, "/old/music/not_in_library.flac", None, 0.),
+                (4, "/old/music/foo", None, 0.),
+            ],
+        );
+        other
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch(&format!(
+                "update song set version = {};
+                delete from feature where song_id = 4 and feature_index > 3;",
+                FEATURES_VERSION
+            ))
+            .unwrap();
+        let other_path = other.library.config.base_config.database_path.to_owned();
+        drop(other);
+
+        let new_prefix = format!("{}/", base_path.display());
+        let summary = library
+            .import_database(&other_path, Some(("/old/music/", &new_prefix)))
+            .unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                imported: 1,
+                already_analyzed: 1,
+                not_in_library: 1,
+                unusable: 1,
+            }
+        );
+        let song = library
+            .library
+            .song_from_path::<()>(&in_library("s16_mono_22_5kHz.flac"))
+            .unwrap();
+        assert_eq!(song.bliss_song.analysis, test_analysis(0.5));
+        // Already analyzed songs are left alone.
+        let song = library
+            .library
+            .song_from_path::<()>(&in_library("s16_stereo_22_5kHz.flac"))
+            .unwrap();
+        assert_eq!(song.bliss_song.analysis, test_analysis(0.));
+        drop(other_tempdir);
+    }
+}
@@ -0,0 +1,294 @@
+//! Analyzing songs in worker processes, see `--isolate`.
+//!
+//! Decoders can crash on corrupt files, e.g. ffmpeg segfaulting, which takes
+//! the whole analysis down with it, days into the scan of a big library. With
+//! [Config::isolate_analysis](crate::Config::isolate_analysis), files are
+//! decoded by `blissify analyze-worker` processes, one file at a time, so that
+//! a worker dying only fails the file it was decoding: it is stored as an
+//! error, and a new worker takes over the rest of the files.
+use crate::{Decoder, MPDLibrary};
+use anyhow::{bail, Result};
+use bliss_audio::decoder::Decoder as _;
+use bliss_audio::{BlissError, Song};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+
+/// What a worker writes back, one JSON message per line, for each file it
+/// is sent.
+#[derive(Serialize, Deserialize)]
+enum WorkerMessage {
+    /// A song analyzed from the file, or the error analyzing it. CUE sheets
+    /// give one song per track.
+    Analyzed(PathBuf, Result<Box<Song>, String>),
+    /// The file is done.
+    Done,
+}
+
+/// The results of analyzing a file.
+type Analyses = Vec<(PathBuf, Result<Song, String>)>;
+
+/// Run as `blissify analyze-worker`: analyze the files whose paths are read
+/// from stdin, one JSON string per line, and write the results to stdout.
+pub fn run_worker() -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        let path: PathBuf = serde_json::from_str(&line?)?;
+        for (path, result) in Decoder::analyze_paths_with_cores([path], NonZeroUsize::MIN) {
+            let message =
+                WorkerMessage::Analyzed(path, result.map(Box::new).map_err(|e| e.to_string()));
+            writeln!(stdout, "{}", serde_json::to_string(&message)?)?;
+        }
+        writeln!(stdout, "{}", serde_json::to_string(&WorkerMessage::Done)?)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// A running worker process.
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Worker {
+    fn spawn(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Worker {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// Analyze the file at `path`, failing if the worker died doing so.
+    fn analyze(&mut self, path: &Path) -> Result<Analyses> {
+        writeln!(self.stdin, "{}", serde_json::to_string(path)?)?;
+        self.stdin.flush()?;
+        let mut analyses = vec![];
+        loop {
+            let mut line = String::new();
+            if self.stdout.read_line(&mut line)? == 0 {
+                bail!("the analysis worker died ({})", self.child.wait()?);
+            }
+            match serde_json::from_str(&line)? {
+                WorkerMessage::Analyzed(path, result) => analyses.push((path, result.map(|s| *s))),
+                WorkerMessage::Done => return Ok(analyses),
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Analyze the files of `queue` with workers started with `command`, one
+/// after the other, sending the results to `results`, until `queue` is
+/// empty. A worker is started again after each crash.
+fn supervise(
+    command: &dyn Fn() -> Command,
+    queue: &Mutex<VecDeque<PathBuf>>,
+    results: Sender<Analyses>,
+) {
+    let mut worker = None;
+    while let Some(path) = queue.lock().unwrap().pop_front() {
+        let analyses = match worker.take().map_or_else(|| Worker::spawn(command()), Ok) {
+            Ok(mut running) => match running.analyze(&path) {
+                Ok(analyses) => {
+                    worker = Some(running);
+                    analyses
+                }
+                Err(e) => vec![(path, Err(format!("Decoding crashed: {}", e)))],
+            },
+            Err(e) => vec![(
+                path,
+                Err(format!("Could not start an analysis worker: {}", e)),
+            )],
+        };
+        if results.send(analyses).is_err() {
+            return;
+        }
+    }
+}
+
+impl MPDLibrary {
+    /// Analyze the songs at `paths` that are not in the database yet, in
+    /// worker processes if [isolate_analysis](crate::Config::isolate_analysis)
+    /// is set, or with bliss otherwise. The songs that are not in `paths`
+    /// are removed from the database if `delete_everything_else` is set.
+    /// Files larger than [max_file_size](crate::Config::max_file_size) are
+    /// skipped.
+    ///
+    /// `paths` are listed as in [get_songs_paths](Self::get_songs_paths).
+    pub(crate) fn update_library_paths(
+        &mut self,
+        paths: Vec<String>,
+        delete_everything_else: bool,
+        show_progress_bar: bool,
+    ) -> Result<()> {
+        let paths = self.skip_large_files(paths)?;
+        if !self.library.config.isolate_analysis {
+            self.library
+                .update_library(paths, delete_everything_else, show_progress_bar)?;
+            return Ok(());
+        }
+        if delete_everything_else {
+            let base_paths = self
+                .library
+                .config
+                .base_paths()
+                .cloned()
+                .collect::<Vec<_>>();
+            for base_path in base_paths {
+                self.delete_stale_songs(&base_path, &paths)?;
+            }
+        }
+        let stored = {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut stmt = sqlite_conn.prepare("select coalesce(cue_path, path) from song")?;
+            let paths = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<HashSet<String>, _>>()?;
+            paths
+        };
+        let new_paths = paths
+            .into_iter()
+            .filter(|p| !stored.contains(p))
+            .map(PathBuf::from)
+            .collect();
+        self.analyze_isolated(new_paths)?;
+        Ok(())
+    }
+
+    /// Analyze the files at `paths` in `blissify analyze-worker` processes,
+    /// storing the songs analyzed, or the errors, as they come. Returns the
+    /// number of songs that failed to be analyzed.
+    pub(crate) fn analyze_isolated(&mut self, paths: Vec<PathBuf>) -> Result<usize> {
+        let exe = std::env::current_exe()?;
+        self.analyze_in_workers(paths, &|| {
+            let mut command = Command::new(&exe);
+            command.arg("analyze-worker");
+            command
+        })
+    }
+
+    fn analyze_in_workers(
+        &mut self,
+        paths: Vec<PathBuf>,
+        command: &(dyn Fn() -> Command + Sync),
+    ) -> Result<usize> {
+        if paths.is_empty() {
+            return Ok(0);
+        }
+        info!("Analyzing {} file(s) in worker processes.", paths.len());
+        let number_workers = self
+            .library
+            .config
+            .base_config
+            .number_cores
+            .get()
+            .min(paths.len());
+        let queue = Mutex::new(VecDeque::from(paths));
+        let (sender, receiver) = mpsc::channel();
+        std::thread::scope(|scope| {
+            for _ in 0..number_workers {
+                let sender = sender.clone();
+                let queue = &queue;
+                scope.spawn(move || supervise(command, queue, sender));
+            }
+            drop(sender);
+            let mut failed = 0;
+            for (path, result) in receiver.into_iter().flatten() {
+                match result {
+                    Ok(song) => self.store_analyzed_song(song)?,
+                    Err(e) => {
+                        warn!("Analysis of {} failed: {}", path.display(), e);
+                        self.store_failed_analysis(&path, BlissError::DecodingError(e))?;
+                        failed += 1;
+                    }
+                }
+            }
+            Ok(failed)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_analyze_in_workers() {
+        let (mut library, _tempdir) = setup_library();
+        // Fails every file with "unsupported", crashing on the ones named
+        // crash.flac.
+        let worker = || {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(
+                r#"while read path; do
+                    case "$path" in *crash.flac*) kill -SEGV $$;; esac
+                    echo "{\"Analyzed\":[$path,{\"Err\":\"unsupported\"}]}"
+                    echo '"Done"'
+                done"#,
+            );
+            command
+        };
+        let failed = library
+            .analyze_in_workers(
+                vec![
+                    PathBuf::from("path/first.flac"),
+                    PathBuf::from("path/crash.flac"),
+                    PathBuf::from("path/second.flac"),
+                ],
+                &worker,
+            )
+            .unwrap();
+        assert_eq!(failed, 3);
+
+        let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+        let mut stmt = sqlite_conn
+            .prepare("select path, analyzed, error from song order by path")
+            .unwrap();
+        let songs = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<(String, bool, String)>, _>>()
+            .unwrap();
+        assert_eq!(songs.len(), 3);
+        assert_eq!(songs[0].0, "path/crash.flac");
+        assert!(songs[0].2.contains("Decoding crashed"), "{}", songs[0].2);
+        assert_eq!(
+            songs[1..],
+            [
+                (
+                    String::from("path/first.flac"),
+                    false,
+                    String::from("error happened while decoding file – unsupported"),
+                ),
+                (
+                    String::from("path/second.flac"),
+                    false,
+                    String::from("error happened while decoding file – unsupported"),
+                ),
+            ],
+        );
+    }
+}
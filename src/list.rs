@@ -0,0 +1,318 @@
+//! Filtering and sorting the songs printed by `list-db`.
+//!
+//! Filters are applied by SQLite directly, so listing a single artist doesn't
+//! load the analysis of the whole library.
+use crate::MPDLibrary;
+use anyhow::Result;
+use bliss_audio::{Analysis, BlissError, NUMBER_FEATURES};
+use std::path::Path;
+
+/// The order `list-db` prints songs in, see `--sort`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ListSort {
+    /// By path.
+    #[default]
+    Path,
+    /// By artist, then by album and track.
+    Artist,
+    /// By album, then by track.
+    Album,
+    /// From the shortest song to the longest one.
+    Duration,
+}
+
+impl std::str::FromStr for ListSort {
+    type Err = BlissError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(ListSort::Path),
+            "artist" => Ok(ListSort::Artist),
+            "album" => Ok(ListSort::Album),
+            "duration" => Ok(ListSort::Duration),
+            _ => Err(BlissError::ProviderError(format!(
+                "Unknown sort order '{}', please choose between 'path', 'artist', 'album' and 'duration'.",
+                s
+            ))),
+        }
+    }
+}
+
+impl ListSort {
+    fn order_by(self) -> &'static str {
+        match self {
+            ListSort::Path => "song.path",
+            ListSort::Artist => {
+                "song.artist collate nocase, song.album collate nocase, \
+                song.disc_number, song.track_number, song.path"
+            }
+            ListSort::Album => {
+                "song.album collate nocase, song.disc_number, song.track_number, song.path"
+            }
+            ListSort::Duration => "song.duration, song.path",
+        }
+    }
+}
+
+/// Which songs `list-db` prints, and in which order.
+#[derive(Clone, Debug, Default)]
+pub struct ListFilter {
+    /// Only list songs whose artist or album artist is this one, ignoring case.
+    pub artist: Option<String>,
+    /// Only list songs of this album, ignoring case.
+    pub album: Option<String>,
+    /// Only list songs having this genre, see [parse_genres](crate::genre::parse_genres).
+    pub genre: Option<String>,
+    /// Only list songs whose path starts with this, as stored in the database.
+    pub path_prefix: Option<String>,
+    /// List the songs that are not analyzed, e.g. because their analysis
+    /// failed, instead of the analyzed ones.
+    pub pending: bool,
+    /// Only list the songs whose file doesn't exist anymore, analyzed or
+    /// not. For CUE tracks, the file is the CUE sheet.
+    pub missing_files: bool,
+    pub sort: ListSort,
+}
+
+/// A song printed by `list-db`.
+#[derive(Debug, PartialEq)]
+pub struct ListedSong {
+    pub path: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    /// The song's analysis, if it was asked for and the song is analyzed.
+    pub analysis: Option<Analysis>,
+    /// Why the song could not be analyzed, for pending songs.
+    pub error: Option<String>,
+    /// The kind of `error`, see [ErrorKind](crate::errors::ErrorKind).
+    pub error_kind: Option<String>,
+    /// When the song was analyzed, as a local date and time, if it is known.
+    pub analyzed_at: Option<String>,
+}
+
+impl MPDLibrary {
+    /// The songs of the database matching `filter`, in the order it asks for,
+    /// with their analysis if `with_analysis` is set.
+    pub(crate) fn list_songs(
+        &self,
+        filter: &ListFilter,
+        with_analysis: bool,
+    ) -> Result<Vec<ListedSong>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(&format!(
+            "select song.id, song.path, song.artist, song.title, song.album, song.error,
+            song.error_kind, song.analyzed, coalesce(song.cue_path, song.path),
+            datetime(song.analyzed_at, 'unixepoch', 'localtime')
+            from song
+            where (?6 or song.analyzed = ?1)
+            and (?2 is null or exists (
+                select 1 from song_genre
+                where song_genre.song_id = song.id and song_genre.genre = ?2
+            ))
+            and (?3 is null
+                or song.artist = ?3 collate nocase
+                or song.album_artist = ?3 collate nocase)
+            and (?4 is null or song.album = ?4 collate nocase)
+            and (?5 is null or substr(song.path, 1, length(?5)) = ?5)
+            order by {}",
+            filter.sort.order_by(),
+        ))?;
+        let genre = filter.genre.as_ref().map(|g| g.trim().to_lowercase());
+        let rows = query
+            .query_map(
+                (
+                    !filter.pending,
+                    genre,
+                    &filter.artist,
+                    &filter.album,
+                    &filter.path_prefix,
+                    filter.missing_files,
+                ),
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, bool>(7)?,
+                        row.get::<_, String>(8)?,
+                        ListedSong {
+                            path: row.get(1)?,
+                            artist: row.get(2)?,
+                            title: row.get(3)?,
+                            album: row.get(4)?,
+                            analysis: None,
+                            error: row.get(5)?,
+                            error_kind: row.get(6)?,
+                            analyzed_at: row.get(9)?,
+                        },
+                    ))
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        // Files are checked here rather than in SQLite, which can't.
+        let rows = rows
+            .into_iter()
+            .filter(|(_, _, file, _)| !filter.missing_files || !Path::new(file).exists())
+            .map(|(id, analyzed, _, song)| (id, analyzed, song));
+        if !with_analysis || filter.pending {
+            return Ok(rows.map(|(_, _, song)| song).collect());
+        }
+
+        let mut features = sqlite_conn
+            .prepare("select feature from feature where song_id = ?1 order by feature_index")?;
+        let mut songs = vec![];
+        for (id, analyzed, mut song) in rows {
+            if !analyzed {
+                songs.push(song);
+                continue;
+            }
+            let analysis = features
+                .query_map([id], |row| row.get::<_, f32>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let analysis: [f32; NUMBER_FEATURES] = analysis.try_into().map_err(|_| {
+                BlissError::ProviderError(format!(
+                    "The analysis of {} has an unexpected number of features, try running `blissify rescan`.",
+                    song.path
+                ))
+            })?;
+            song.analysis = Some(Analysis::new(analysis));
+            songs.push(song);
+        }
+        Ok(songs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use pretty_assertions::assert_eq;
+
+    fn paths(songs: Vec<ListedSong>) -> Vec<String> {
+        songs.into_iter().map(|s| s.path).collect()
+    }
+
+    #[test]
+    fn test_list_songs() {
+        let (library, _tempdir) = setup_library();
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            sqlite_conn
+                .execute_batch(
+                    "insert into song
+                    (id, path, artist, album_artist, album, track_number, genre, analyzed, duration, version, error) values
+                    (1, 'path/Jazz/b.flac', 'Miles', null, 'Blue', 2, 'Jazz', true, 30, 1, null),
+                    (2, 'path/Jazz/a.flac', 'miles', null, 'Blue', 1, 'Jazz; Modal', true, 20, 1, null),
+                    (3, 'path/Rock/c.flac', 'Guest', 'Miles', 'Another', 1, 'Rock', true, 10, 1, null),
+                    (4, 'path/Rock/d.flac', 'Band', null, 'Another', 2, 'Rock', false, 10, 1, 'Decoding failed');",
+                )
+                .unwrap();
+            let features = (0..NUMBER_FEATURES)
+                .map(|i| format!("(1, 0.5, {})", i))
+                .collect::<Vec<_>>()
+                .join(", ");
+            sqlite_conn
+                .execute(
+                    &format!(
+                        "insert into feature (song_id, feature, feature_index) values {}",
+                        features
+                    ),
+                    [],
+                )
+                .unwrap();
+        }
+        library.sync_genres().unwrap();
+
+        let list = |filter: ListFilter| paths(library.list_songs(&filter, false).unwrap());
+        assert_eq!(
+            list(ListFilter::default()),
+            vec!["path/Jazz/a.flac", "path/Jazz/b.flac", "path/Rock/c.flac"],
+        );
+        assert_eq!(
+            list(ListFilter {
+                artist: Some(String::from("MILES")),
+                sort: ListSort::Album,
+                ..Default::default()
+            }),
+            vec!["path/Rock/c.flac", "path/Jazz/a.flac", "path/Jazz/b.flac"],
+        );
+        assert_eq!(
+            list(ListFilter {
+                genre: Some(String::from("modal")),
+                ..Default::default()
+            }),
+            vec!["path/Jazz/a.flac"],
+        );
+        assert_eq!(
+            list(ListFilter {
+                path_prefix: Some(String::from("path/Jazz/")),
+                album: Some(String::from("blue")),
+                sort: ListSort::Duration,
+                ..Default::default()
+            }),
+            vec!["path/Jazz/a.flac", "path/Jazz/b.flac"],
+        );
+
+        let pending = library
+            .list_songs(
+                &ListFilter {
+                    pending: true,
+                    ..Default::default()
+                },
+                true,
+            )
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].path, "path/Rock/d.flac");
+        assert_eq!(pending[0].error.as_deref(), Some("Decoding failed"));
+
+        let detailed = library
+            .list_songs(
+                &ListFilter {
+                    path_prefix: Some(String::from("path/Jazz/b")),
+                    ..Default::default()
+                },
+                true,
+            )
+            .unwrap();
+        assert_eq!(
+            detailed[0].analysis,
+            Some(Analysis::new([0.5; NUMBER_FEATURES]))
+        );
+    }
+
+    #[test]
+    fn test_list_missing_files() {
+        let (library, tempdir) = setup_library();
+        let existing = tempdir.path().join("existing.flac");
+        let cue = tempdir.path().join("album.cue");
+        std::fs::write(&existing, b"").unwrap();
+        std::fs::write(&cue, b"").unwrap();
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            sqlite_conn
+                .execute(
+                    "insert into song (id, path, cue_path, analyzed, duration, version) values
+                    (1, ?1, null, true, 10, 1),
+                    (2, ?2, ?3, true, 10, 1),
+                    (3, 'path/gone.flac', null, true, 10, 1),
+                    (4, 'path/gone_too.flac', null, false, 10, 1)",
+                    [
+                        existing.to_string_lossy(),
+                        cue.join("CUE_TRACK001").to_string_lossy(),
+                        cue.to_string_lossy(),
+                    ],
+                )
+                .unwrap();
+        }
+        let missing = library
+            .list_songs(
+                &ListFilter {
+                    missing_files: true,
+                    ..Default::default()
+                },
+                false,
+            )
+            .unwrap();
+        assert_eq!(paths(missing), vec!["path/gone.flac", "path/gone_too.flac"]);
+    }
+}
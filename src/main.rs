@@ -6,34 +6,43 @@
 //!
 //! Playlists can then subsequently be made from the current song using
 //! --playlist.
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bliss_audio::library::{AppConfigTrait, BaseConfig, Library, LibrarySong};
 use bliss_audio::playlist::{
     closest_to_songs, cosine_distance, euclidean_distance, mahalanobis_distance_builder,
-    song_to_song, DistanceMetricBuilder,
+    DistanceMetricBuilder,
 };
-use bliss_audio::{BlissError, BlissResult};
-use clap::{App, Arg, ArgMatches, SubCommand};
-use log::warn;
+use bliss_audio::{AnalysisIndex, BlissError, BlissResult, Song};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{info, warn};
 use mpd::search::{Query, Term, Window};
 use mpd::song::Song as MPDSong;
 #[cfg(not(test))]
 use mpd::Client;
+use ndarray::{Array1, Array2};
 use noisy_float::prelude::*;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
 use std::char;
-#[cfg(not(test))]
+use std::collections::{BTreeMap, HashSet};
 use std::env;
+use std::net::TcpListener;
 #[cfg(not(test))]
 use std::net::TcpStream;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use content_hash::ContentHash;
 use extended_isolation_forest::ForestOptions;
+use file_size::FileSize;
 
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, Write};
 #[cfg(not(test))]
 use std::{io::Read, os::unix::net::UnixStream};
 
@@ -41,6 +50,63 @@ use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 
 use bliss_audio::decoder::ffmpeg::FFmpeg as Decoder;
+use bliss_audio::decoder::Decoder as _;
+
+mod aliases;
+mod analyzed_at;
+mod announce;
+mod batch;
+mod check;
+mod content_hash;
+mod control;
+mod cue;
+mod daemon;
+mod degenerate;
+mod describe;
+mod errors;
+mod exclude;
+mod fallback;
+mod feedback;
+mod file_size;
+mod filters;
+mod genre;
+mod graph;
+mod guest;
+mod history;
+mod hybrid;
+mod import;
+mod isolation;
+mod list;
+mod metric;
+mod metrics;
+mod missing_seed;
+mod mpd_log;
+mod pin;
+mod playcount;
+mod playlist_history;
+mod priority;
+mod queue_song;
+mod release;
+mod remote;
+mod rescan;
+mod schedule;
+mod schema;
+mod seed;
+mod seed_album;
+mod serve;
+mod servers;
+mod settings;
+mod snapshot;
+mod station;
+mod stats;
+mod store;
+mod timing;
+mod top;
+mod wizard;
+
+use feedback::Rating;
+use filters::FilterContext;
+use rescan::RescanSelection;
 
 /// The main struct that stores both the Library object, and some other
 /// helper functions to make everything work properly.
@@ -49,11 +115,19 @@ struct MPDLibrary {
     pub library: Library<Config, Decoder>,
     /// A connection to the MPD server, used for retrieving song's paths,
     /// currently played songs, and queue tracks.
-    #[cfg(not(test))]
-    pub mpd_conn: Arc<Mutex<Client<MPDStream>>>,
-    /// A mock MPDClient, used for testing purposes only.
-    #[cfg(test)]
-    pub mpd_conn: Arc<Mutex<MockMPDClient>>,
+    ///
+    /// In tests, this is a mock MPDClient. `None` when running offline, see
+    /// [mpd_conn](Self::mpd_conn).
+    pub mpd_conn: Option<Arc<Mutex<MPDClient>>>,
+    /// The user selected with `--user`, whose likes, dislikes and
+    /// [Profile] are used. `None` for the default user.
+    pub user: Option<String>,
+    /// The server selected with `--target-server`, whose paths MPD's paths
+    /// are translated to. `None` for the server given by MPD_HOST.
+    pub server: Option<servers::MPDServer>,
+    /// Where [server](Self::server) is, with its password read once, so that
+    /// all the connections to MPD go there. `None` for MPD_HOST and MPD_PORT.
+    pub server_address: Option<MPDAddress>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -63,6 +137,147 @@ struct Config {
     /// The MPD base path, as specified by the user and written in the MPD
     /// config file. Example: "/home/user/Music".
     pub mpd_base_path: PathBuf,
+    /// Other directories the paths MPD gives are looked for in when they are
+    /// not in [mpd_base_path](Self::mpd_base_path), e.g. the local
+    /// directories of MPD mounts, or of several drives.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_base_paths: Vec<PathBuf>,
+    /// MPD mounts, added with MPD's `mount` command, by the directory they
+    /// are mounted on in MPD's library, mapped to the local directories their
+    /// songs are in, e.g. `{"nas": "/mnt/nas"}` after
+    /// `mpc mount nas nfs://server/music`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub mounts: BTreeMap<String, PathBuf>,
+    /// Turn MPD's random mode off before queueing playlists, instead of
+    /// just warning about it.
+    #[serde(default)]
+    pub disable_random: bool,
+    /// Default options for the `playlist` subcommand.
+    #[serde(default)]
+    pub playlist: PlaylistDefaults,
+    /// The number of songs asked to MPD at once when listing the library.
+    /// Lower it if listing songs times out on slow servers.
+    #[serde(default = "default_search_chunk_size")]
+    pub mpd_search_chunk_size: u32,
+    /// The number of connections to MPD listing the library at the same
+    /// time, each asking for [mpd_search_chunk_size](Self::mpd_search_chunk_size)
+    /// songs at once. Raise it to list large libraries on remote servers
+    /// faster.
+    #[serde(default = "default_search_connections")]
+    pub mpd_search_connections: NonZeroUsize,
+    /// How much songs given feedback with `blissify like` / `blissify dislike`
+    /// bias playlists. 0 disables it.
+    #[serde(default = "default_feedback_weight")]
+    pub feedback_weight: f32,
+    /// Per-user settings, selected with `--user NAME`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    /// MPD's log file, the `log_file` of mpd.conf, read by
+    /// `playlist --from-log`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mpd_log_path: Option<PathBuf>,
+    /// Decode songs in worker processes when scanning the library, so that
+    /// a decoder crashing on a file only fails that file, as with `--isolate`.
+    #[serde(default)]
+    pub isolate_analysis: bool,
+    /// Hash the songs' files on updates, and analyze the ones whose contents
+    /// changed again, see [content_hash].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<ContentHash>,
+    /// Leave the files larger than this out of scans, like "500M", as with
+    /// `--max-file-size`, see [file_size].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<FileSize>,
+    /// Other MPD servers playlists can be queued on, by name, see
+    /// `--target-server`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub servers: BTreeMap<String, servers::MPDServer>,
+}
+
+/// Settings of a user selected with `--user`, overriding the ones of the
+/// configuration file when set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+struct Profile {
+    /// Overrides [Config::playlist].
+    pub playlist: Option<PlaylistDefaults>,
+    /// Overrides [Config::feedback_weight].
+    pub feedback_weight: Option<f32>,
+    /// Overrides the metric learned for the mahalanobis distance, see
+    /// [BaseConfig::m].
+    pub m: Option<Array2<f32>>,
+    /// When `blissify daemon` queues a playlist with this profile, like
+    /// "Fri 19:00", see [schedule].
+    pub schedule: Option<schedule::Schedule>,
+}
+
+fn default_search_chunk_size() -> u32 {
+    10_000
+}
+
+fn default_search_connections() -> NonZeroUsize {
+    NonZeroUsize::new(4).unwrap()
+}
+
+fn default_feedback_weight() -> f32 {
+    1.
+}
+
+/// Default values for the `playlist` subcommand, used when the corresponding
+/// arguments are not given on the command-line.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+struct PlaylistDefaults {
+    /// The number of songs to queue, if NUMBER_SONGS is not given.
+    pub number_songs: Option<usize>,
+    /// The distance metric's name, as passed to `--distance`.
+    pub distance: Option<String>,
+    /// Make "seeded" playlists, as with `--seed-song`.
+    pub seed_song: bool,
+    /// The algorithm ordering the songs, as passed to `--algorithm`.
+    /// Overrides `seed_song`.
+    pub algorithm: Option<String>,
+    /// Deduplicate songs; setting it to false is the same as
+    /// passing `--no-deduplication`.
+    pub deduplication: bool,
+    /// Keep the current queue, as with `--keep-current-queue`.
+    pub keep_current_queue: bool,
+    /// Warn about playlists whose songs are further than this from the songs
+    /// they were made from, on average, as with `--max-average-distance`.
+    pub max_average_distance: Option<f32>,
+    /// Refuse to queue the playlists above `max_average_distance`, unless
+    /// `--force` is given.
+    pub refuse_distant_playlists: bool,
+    /// Analyze new songs before making playlists, as with `--update-first`.
+    pub update_first: bool,
+    /// Never grow MPD's queue beyond this many songs, as with
+    /// `--max-queue-length`.
+    pub max_queue_length: Option<usize>,
+    /// Regular expressions matched against song titles, ignoring case, to
+    /// leave live versions, remixes, etc. out of playlists, unless
+    /// `--include-versions` is given.
+    pub exclude_titles: Vec<String>,
+    /// The genre, duration and artist filters of playlists, see [filters].
+    pub filters: filters::PlaylistFilters,
+}
+
+impl Default for PlaylistDefaults {
+    fn default() -> Self {
+        Self {
+            number_songs: None,
+            distance: None,
+            seed_song: false,
+            algorithm: None,
+            deduplication: true,
+            keep_current_queue: false,
+            max_average_distance: None,
+            refuse_distant_playlists: false,
+            update_first: false,
+            max_queue_length: None,
+            exclude_titles: vec![],
+            filters: filters::PlaylistFilters::default(),
+        }
+    }
 }
 
 impl Config {
@@ -76,8 +291,76 @@ impl Config {
         Ok(Self {
             base_config,
             mpd_base_path,
+            extra_base_paths: vec![],
+            mounts: BTreeMap::new(),
+            disable_random: false,
+            playlist: PlaylistDefaults::default(),
+            mpd_search_chunk_size: default_search_chunk_size(),
+            mpd_search_connections: default_search_connections(),
+            feedback_weight: default_feedback_weight(),
+            profiles: BTreeMap::new(),
+            mpd_log_path: None,
+            isolate_analysis: false,
+            content_hash: None,
+            max_file_size: None,
+            servers: BTreeMap::new(),
         })
     }
+
+    /// [mpd_base_path](Self::mpd_base_path), then the
+    /// [extra_base_paths](Self::extra_base_paths).
+    fn base_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.mpd_base_path).chain(&self.extra_base_paths)
+    }
+
+    /// The absolute path of `path`, relative to MPD's music directory: in the
+    /// local directory of the [mount](Self::mounts) it is in, if any, else in
+    /// the first base path it exists in, or in `mpd_base_path` if it exists
+    /// in none. CUE tracks are looked for through their CUE sheet.
+    fn resolve_mpd_path(&self, path: &Path) -> PathBuf {
+        let mount = self
+            .mounts
+            .iter()
+            .filter_map(|(mount_point, local)| {
+                Some((mount_point, local, path.strip_prefix(mount_point).ok()?))
+            })
+            .max_by_key(|(mount_point, _, _)| mount_point.len());
+        if let Some((_, local, path)) = mount {
+            return local.join(path);
+        }
+        if !self.extra_base_paths.is_empty() {
+            let file = cue_sheet_path(path).unwrap_or(path);
+            if let Some(base_path) = self.base_paths().find(|b| b.join(file).exists()) {
+                return base_path.join(path);
+            }
+        }
+        self.mpd_base_path.join(path)
+    }
+
+    /// `path`, relative to the base path it is in, if any, or to MPD's
+    /// music directory for the songs of [mounts](Self::mounts).
+    fn strip_base_path(&self, path: &Path) -> Option<PathBuf> {
+        let mount = self
+            .mounts
+            .iter()
+            .filter_map(|(mount_point, local)| {
+                Some((mount_point, local, path.strip_prefix(local).ok()?))
+            })
+            .max_by_key(|(_, local, _)| local.as_os_str().len());
+        if let Some((mount_point, _, path)) = mount {
+            return Some(Path::new(mount_point).join(path));
+        }
+        self.base_paths()
+            .find_map(|b| path.strip_prefix(b).ok())
+            .map(Path::to_path_buf)
+    }
+}
+
+/// Whether the configuration file at `path` should be read and written as
+/// TOML instead of JSON, based on its extension.
+fn is_toml_config(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("toml"))
 }
 
 impl AppConfigTrait for Config {
@@ -88,1229 +371,6870 @@ impl AppConfigTrait for Config {
     fn base_config_mut(&mut self) -> &mut BaseConfig {
         &mut self.base_config
     }
-}
 
-#[cfg(test)]
-#[derive(Default)]
-/// Convenience Mock for testing.
-pub struct MockMPDClient {
-    mpd_queue: Vec<MPDSong>,
-    // FIXME only useful because the Window struct
-    // https://docs.rs/mpd/latest/mpd/search/struct.Window.html
-    // is still work in progress, remove when the corresponding
-    // fields can be accessed.
-    search_window: u32,
+    fn serialize_config(&self) -> Result<String> {
+        if is_toml_config(&self.base_config.config_path) {
+            // Going through a `toml::Value` puts tables after plain values,
+            // as TOML requires. Not using the "pretty" output, which would
+            // print the mahalanobis matrix on hundreds of lines.
+            Ok(toml::to_string(&toml::Value::try_from(self)?)?)
+        } else {
+            Ok(serde_json::to_string_pretty(self)?)
+        }
+    }
+
+    fn from_path(path: &str) -> Result<Self> {
+        let data = std::fs::read_to_string(path)?;
+        if is_toml_config(Path::new(path)) {
+            Ok(toml::from_str(&data)?)
+        } else {
+            Ok(serde_json::from_str(&data)?)
+        }
+    }
 }
 
-#[cfg(not(test))]
-enum MPDStream {
-    Tcp(TcpStream),
-    Unix(UnixStream),
+/// How songs are considered duplicates of each other when deduplicating
+/// a playlist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DedupStrategy {
+    /// Songs sharing the same title and artist are duplicates.
+    Title,
+    /// Songs sharing the same title and artist, or whose analysis are
+    /// closer than a given distance, are duplicates.
+    Features,
+    /// Songs coming from the same album are duplicates, effectively
+    /// keeping only one song per album.
+    Album,
+    /// Same as [Features](Self::Features), but releases of the same
+    /// recording, like remasters, are duplicates as well, see [release].
+    Release,
 }
 
-#[cfg(not(test))]
-impl Read for MPDStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self {
-            MPDStream::Tcp(v) => v.read(buf),
-            MPDStream::Unix(v) => v.read(buf),
+impl std::str::FromStr for DedupStrategy {
+    type Err = BlissError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "title" => Ok(DedupStrategy::Title),
+            "features" => Ok(DedupStrategy::Features),
+            "album" => Ok(DedupStrategy::Album),
+            "release" => Ok(DedupStrategy::Release),
+            _ => Err(BlissError::ProviderError(format!(
+                "Unknown deduplication strategy '{}', please choose between 'title', 'features', 'album' and 'release'.",
+                s
+            ))),
         }
     }
 }
-#[cfg(not(test))]
-impl Write for MPDStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self {
-            MPDStream::Tcp(v) => v.write(buf),
-            MPDStream::Unix(v) => v.write(buf),
+
+/// How blissify prints its results on stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Free-form text, meant to be read by humans.
+    Plain,
+    /// JSON, meant to be parsed by other programs.
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = BlissError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(OutputFormat::Plain),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(BlissError::ProviderError(format!(
+                "Unknown output format '{}', please choose between 'plain' and 'json'.",
+                s
+            ))),
         }
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        match self {
-            MPDStream::Tcp(v) => v.flush(),
-            MPDStream::Unix(v) => v.flush(),
+/// A song, as printed with `--format json`.
+#[derive(Serialize, Debug, PartialEq)]
+struct SongOutput {
+    path: String,
+    artist: Option<String>,
+    title: Option<String>,
+    album: Option<String>,
+    /// The tempo, in beats per minute, see [describe::tempo_bpm].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bpm: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    analysis: Option<Vec<f32>>,
+    /// Why the song could not be analyzed, for songs listed with
+    /// `list-db --pending-only`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The kind of `error`, see [errors::ErrorKind].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<String>,
+    /// When the song was analyzed, for songs listed with
+    /// `list-db --detailed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    analyzed_at: Option<String>,
+    /// Which library the song comes from, "local" or "guest", for dry runs
+    /// with a guest database, see [guest].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+}
+
+impl SongOutput {
+    fn new(song: &LibrarySong<()>) -> Self {
+        let song = &song.bliss_song;
+        Self {
+            path: song.path.to_string_lossy().to_string(),
+            artist: song.artist.to_owned(),
+            title: song.title.to_owned(),
+            album: song.album.to_owned(),
+            bpm: Some(describe::tempo_bpm(&song.analysis)),
+            distance: None,
+            analysis: None,
+            error: None,
+            error_kind: None,
+            analyzed_at: None,
+            source: None,
         }
     }
 }
 
-impl MPDLibrary {
-    /// Get a connection to the MPD database given some environment
-    /// variables.
-    #[cfg(not(test))]
-    fn get_mpd_conn() -> Result<Client<MPDStream>> {
-        #[cfg(target_os = "linux")]
-        use std::os::linux::net::SocketAddrExt;
-        use std::os::unix::net::SocketAddr;
-
-        let (password, mpd_host) = match env::var("MPD_HOST") {
-            Ok(h) => match h.split_once('@') {
-                None => (None, h),
-                // If it's a unix abstract socket, there will be nothing before the '@'
-                Some(("", _)) => (None, h),
-                Some((password, host)) => (Some(password.to_owned()), host.to_owned()),
-            },
-            Err(_) => {
-                warn!("Could not find any MPD_HOST environment variable set. Defaulting to 127.0.0.1.");
-                (None, String::from("127.0.0.1"))
-            }
-        };
-        let mpd_port = match env::var("MPD_PORT") {
-            Ok(p) => p
-                .parse::<u16>()
-                .with_context(|| "while trying to coerce MPD_PORT to an integer")?,
-            Err(_) => {
-                warn!("Could not find any MPD_PORT environment variable set. Defaulting to 6600.");
-                6600
+/// Analyze `file` with `decoder`, without storing it anywhere, for
+/// `blissify analyze-file`. Errors are part of the output, only unknown
+/// decoders fail.
+fn analyze_file(file: &Path, decoder: &str) -> Result<SongOutput> {
+    let song = match decoder {
+        "ffmpeg" => Decoder::song_from_path(file),
+        #[cfg(feature = "symphonia")]
+        "symphonia" => fallback::SymphoniaDecoder::song_from_path(file),
+        _ => bail!(
+            "blissify was built without the {} decoder, build it with `--features {}` to use it.",
+            decoder,
+            decoder
+        ),
+    };
+    Ok(match song {
+        Ok(song) => SongOutput {
+            analysis: Some(song.analysis.as_vec()),
+            ..SongOutput::new(&LibrarySong {
+                bliss_song: song,
+                extra_info: (),
+            })
+        },
+        Err(e) => {
+            let error = e.to_string();
+            SongOutput {
+                path: file.to_string_lossy().to_string(),
+                artist: None,
+                title: None,
+                album: None,
+                bpm: None,
+                distance: None,
+                analysis: None,
+                error_kind: Some(errors::ErrorKind::classify(&error).to_string()),
+                error: Some(error),
+                analyzed_at: None,
+                source: None,
             }
-        };
+        }
+    })
+}
 
-        let mut client = {
-            // TODO It is most likely a socket if it starts by "/", but maybe not necessarily?
-            // find a solution that doesn't depend on a url crate that pulls the entire internet
-            // with it
-            if mpd_host.starts_with('/') || mpd_host.starts_with('~') {
-                return Ok(Client::new(MPDStream::Unix(UnixStream::connect(
-                    mpd_host,
-                )?))?);
-            }
-            #[cfg(target_os = "linux")]
-            if mpd_host.starts_with('@') {
-                let addr = SocketAddr::from_abstract_name(mpd_host.split_once('@').unwrap().1)?;
-                return Ok(Client::new(MPDStream::Unix(UnixStream::connect_addr(
-                    &addr,
-                )?))?);
-            }
-            // It is a hostname or an IP address
-            Client::new(MPDStream::Tcp(TcpStream::connect(format!(
-                "{}:{}",
-                mpd_host, mpd_port
-            ))?))?
-        };
-        if let Some(pw) = password {
-            client.login(&pw)?;
+/// A playlist, as printed by dry runs with `--format json`.
+#[derive(Serialize)]
+struct PlaylistOutput {
+    /// The songs the playlist was made from, only filled with `--show-distance`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    seeds: Vec<SongOutput>,
+    songs: Vec<SongOutput>,
+    /// The transitions between each song and the next one, see
+    /// [playlist_transitions].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    transitions: Vec<TransitionOutput>,
+}
+
+/// How much the tempo and loudness change from a song of a playlist to the
+/// next one, so DJ tools can pick crossfade lengths.
+///
+/// Deltas are differences of bliss' normalized features, between -2 and 2.
+#[derive(Serialize, Debug, PartialEq)]
+struct TransitionOutput {
+    tempo_delta: f32,
+    loudness_delta: f32,
+}
+
+/// An album playlist, as printed by dry runs of `--album-playlist` with
+/// `--format json`.
+#[derive(Serialize)]
+struct AlbumPlaylistOutput {
+    albums: Vec<AlbumGroupOutput>,
+}
+
+/// One of the albums of an album playlist, see [album_groups].
+#[derive(Serialize, Debug, PartialEq)]
+struct AlbumGroupOutput {
+    album: Option<String>,
+    artist: Option<String>,
+    /// The distance to the album the playlist was made from.
+    distance: f32,
+    songs: Vec<SongOutput>,
+}
+
+/// An album printed by `similar-album`.
+#[derive(Serialize, Debug, PartialEq)]
+struct AlbumOutput {
+    album: String,
+    artist: Option<String>,
+    number_songs: usize,
+}
+
+/// The result of `compare --album`.
+#[derive(Serialize, Debug)]
+struct AlbumComparison {
+    /// The distance between the two albums.
+    distance: f32,
+    /// The tracks of both albums, each with its distance to the other album,
+    /// from the furthest to the closest.
+    tracks: Vec<SongOutput>,
+}
+
+/// The metadata used to break ties between songs that are at about the same
+/// distance from the songs a playlist was made from, see `--tie-break`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TieBreak {
+    /// Prefer songs sharing a genre with the seed(s).
+    Genre,
+    /// Prefer songs sharing an artist with the seed(s).
+    Artist,
+}
+
+impl std::str::FromStr for TieBreak {
+    type Err = BlissError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "genre" => Ok(TieBreak::Genre),
+            "artist" => Ok(TieBreak::Artist),
+            _ => Err(BlissError::ProviderError(format!(
+                "Unknown tie-breaking strategy '{}', please choose between 'genre' and 'artist'.",
+                s
+            ))),
         }
-        Ok(client)
     }
+}
 
-    fn mpd_to_bliss_path(&self, mpd_song: &MPDSong) -> Result<PathBuf> {
-        let file = &mpd_song.file;
-        let path = if file.to_lowercase().contains(".cue/track")
-            || file.to_lowercase().contains(".flac/track")
-        {
-            let lowercase_string = file.to_lowercase();
-            let idx: Vec<_> = lowercase_string.match_indices("/track").collect();
-            let beginning_file = file.split_at(idx[0].0).0.to_owned();
-            let track_number = file
-                .split_at(idx[0].0)
-                .1
-                .to_owned()
-                .strip_prefix("/track")
-                .ok_or_else(|| {
-                    BlissError::ProviderError(format!(
-                        "CUE track {} has an invalid track number",
-                        file
-                    ))
-                })?
-                .parse::<usize>()?;
-            format!("{}/CUE_TRACK{:03}", beginning_file, track_number)
-        } else {
-            file.to_string()
-        };
-        let path = &self.library.config.mpd_base_path.join(PathBuf::from(&path));
-        Ok(path.to_path_buf())
-    }
+/// How the songs of a playlist are ordered, see `--algorithm`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Algorithm {
+    /// The songs closest to the song(s) the playlist is made from first.
+    Closest,
+    /// Each song is the closest to the previous one, see `--seed-song`.
+    Chained,
+}
 
-    /// Convert a `MPDSong` to a previously analyzed `LibrarySong`, if it exists
-    /// in blissify's database.
-    fn mpd_to_bliss_song(&self, mpd_song: &MPDSong) -> Result<Option<LibrarySong<()>>> {
-        let path = self.mpd_to_bliss_path(mpd_song)?;
-        let song = self.library.song_from_path(&path.to_string_lossy()).ok();
-        Ok(song)
-    }
+impl std::str::FromStr for Algorithm {
+    type Err = BlissError;
 
-    /// Convert a bliss song to an MPDSong, regardless whether the song
-    /// exists in the MPD database or not.
-    ///
-    /// Useful to convert CUE tracks to the right format, but does not
-    /// include metadata in the MPDSong.
-    fn bliss_song_to_mpd(&self, song: &LibrarySong<()>) -> Result<MPDSong> {
-        let path = match song.bliss_song.cue_info.to_owned() {
-            Some(cue_info) => {
-                let track_number = song.bliss_song.track_number.ok_or_else(|| {
-                    BlissError::ProviderError(format!(
-                        "CUE track {} has an invalid track number",
-                        song.bliss_song.path.display()
-                    ))
-                })?;
-                cue_info.cue_path.join(format!("track{:04}", track_number))
-            }
-            _ => song.bliss_song.path.to_owned(),
-        };
-        let path = path.strip_prefix(&*self.library.config.mpd_base_path.to_string_lossy())?;
-        Ok(MPDSong {
-            file: path.to_string_lossy().to_string(),
-            ..Default::default()
-        })
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "closest" => Ok(Algorithm::Closest),
+            "chained" => Ok(Algorithm::Chained),
+            _ => Err(BlissError::ProviderError(format!(
+                "Unknown playlist algorithm '{}', please choose between 'closest' and 'chained'.",
+                s
+            ))),
+        }
     }
+}
 
-    /// Create a new MPDLibrary object.
-    ///
-    /// This means creating the necessary folders and the database file
-    /// if it doesn't exist, as well as getting a connection to MPD ready.
-    fn new(
-        mpd_base_path: PathBuf,
-        config_path: Option<PathBuf>,
-        database_path: Option<PathBuf>,
-        number_cores: Option<NonZeroUsize>,
+impl Algorithm {
+    /// The algorithm to use, from `--algorithm`, or `--seed-song`, its alias
+    /// for "chained", or else from `defaults`.
+    fn from_options(
+        algorithm: Option<&str>,
+        seed_song: bool,
+        defaults: &PlaylistDefaults,
     ) -> Result<Self> {
-        let config = Config::new(mpd_base_path, config_path, database_path, number_cores)?;
-        let library = Library::new(config)?;
-        let mpd_library = MPDLibrary {
-            library,
-            mpd_conn: Arc::new(Mutex::new(Self::get_mpd_conn()?)),
-        };
-        Ok(mpd_library)
+        if let Some(algorithm) = algorithm {
+            return Ok(algorithm.parse()?);
+        }
+        if seed_song {
+            return Ok(Algorithm::Chained);
+        }
+        match &defaults.algorithm {
+            Some(algorithm) => Ok(algorithm.parse()?),
+            None if defaults.seed_song => Ok(Algorithm::Chained),
+            None => Ok(Algorithm::Closest),
+        }
     }
+}
 
-    /// Get new MPDLibrary object from an existing configuration.
-    ///
-    /// This means creating the necessary folders and the database file
-    /// if it doesn't exist, as well as getting a connection to MPD ready.
-    fn from_config_path(config_path: Option<PathBuf>) -> Result<Self> {
-        let library = Library::from_config_path(config_path)?;
-        let mpd_library = MPDLibrary {
-            library,
-            mpd_conn: Arc::new(Mutex::new(Self::get_mpd_conn()?)),
-        };
-        Ok(mpd_library)
+/// What album playlists play from the current album before the next albums,
+/// see `--after-current`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum AfterCurrent {
+    /// The rest of the current album.
+    #[default]
+    Album,
+    /// The rest of the current disc of the current album.
+    Disc,
+    /// Nothing, the next albums start right after the current song.
+    Track,
+}
+
+impl std::str::FromStr for AfterCurrent {
+    type Err = BlissError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "album" => Ok(AfterCurrent::Album),
+            "disc" => Ok(AfterCurrent::Disc),
+            "track" => Ok(AfterCurrent::Track),
+            _ => Err(BlissError::ProviderError(format!(
+                "Unknown value '{}' for --after-current, please choose between 'album', 'disc' and 'track'.",
+                s
+            ))),
+        }
     }
+}
 
-    /// Remove the contents of the current database, and analyze all
-    /// MPD's songs again.
-    ///
-    /// Useful in case the database got corrupted somehow.
-    fn full_rescan(&mut self) -> Result<()> {
-        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
-        sqlite_conn.execute("delete from feature", [])?;
-        sqlite_conn.execute("delete from song", [])?;
+/// Options shared by the different ways of making a playlist.
+#[derive(Clone, Debug)]
+struct PlaylistOptions {
+    /// How to deduplicate songs from the resulting playlist, or `None` to
+    /// keep duplicates.
+    pub dedup: Option<DedupStrategy>,
+    /// The distance under which two songs are considered duplicates when
+    /// using [DedupStrategy::Features].
+    pub dedup_distance: f32,
+    /// Do not modify the queue, instead print the files that would
+    /// be added to the playlist.
+    pub dry_run: bool,
+    /// If false, will remove the content of the entire queue save for the
+    /// currently playing song and the songs pinned with `blissify pin`, and will
+    /// queue the playlist after it. If true, will queue
+    /// the playlist after the current song, but will keep the queue intact.
+    pub keep_queue: bool,
+    /// Do not queue songs that are already present anywhere in the queue.
+    pub skip_queued: bool,
+    /// Turn MPD's random mode off before queueing the playlist.
+    pub disable_random: bool,
+    /// Turn MPD's random mode back on after queueing the playlist if it was
+    /// disabled because of `disable_random`.
+    pub restore_random: bool,
+    /// Queue the playlist from the least similar song to the most similar one,
+    /// ending on the song the playlist was made from.
+    pub reverse: bool,
+    /// The number of closest songs to skip before queueing songs, e.g. to
+    /// get the "second ring" of similar songs.
+    pub offset: usize,
+    /// When doing a dry run, also print the songs the playlist was made from,
+    /// and each song's distance to them.
+    pub show_distance: bool,
+    /// Write the playlist to this M3U file instead of queueing it.
+    pub output_m3u: Option<PathBuf>,
+    /// The format dry runs print the playlist in.
+    pub format: OutputFormat,
+    /// Store each queued song's distance to the songs the playlist was made
+    /// from in MPD stickers.
+    pub publish_stickers: bool,
+    /// Prefer songs sharing this metadata with the songs the playlist was made
+    /// from, among songs at about the same distance from them.
+    pub tie_break: Option<TieBreak>,
+    /// How close the distances of two songs must be for [TieBreak] to
+    /// reorder them.
+    pub tie_break_epsilon: f32,
+    /// Warn if the songs of the playlist are further than this from the songs
+    /// it was made from, on average.
+    pub max_average_distance: Option<f32>,
+    /// Fail instead of warning when the playlist is above
+    /// `max_average_distance`.
+    pub refuse_distant: bool,
+    /// What album playlists play from the current album before the next
+    /// albums.
+    pub after_current: AfterCurrent,
+    /// Make album playlists play the current album (or disc, see
+    /// [after_current](Self::after_current)) again from its first track,
+    /// instead of from the track after the current one, when not keeping the
+    /// queue.
+    pub restart_current_album: bool,
+    /// Trim MPD's queue down to this many songs after queueing the playlist,
+    /// see [trim_queue](MPDLibrary::trim_queue).
+    pub max_queue_length: Option<usize>,
+    /// Leave the songs whose title matches one of these out of the playlist,
+    /// save for the songs it is made from.
+    pub exclude_titles: Vec<Regex>,
+    /// The paths of the songs excluded with `blissify exclude`, left out of
+    /// the playlist save for the songs it is made from.
+    pub excluded_songs: HashSet<PathBuf>,
+    /// The share of the playlist, between 0 and 1, that may come from
+    /// [familiar_artists](Self::familiar_artists), see `--max-familiar`.
+    pub max_familiar: Option<f32>,
+    /// The artists played recently, see
+    /// [familiar_artists](MPDLibrary::familiar_artists).
+    pub familiar_artists: HashSet<String>,
+    /// Hold the other songs of the albums of the songs the playlist is made
+    /// from back until this many songs were queued, or leave them out if it
+    /// is `usize::MAX`, see `--avoid-seed-album`.
+    pub avoid_seed_album: Option<usize>,
+    /// The genre, duration and artist filters, see [filters].
+    pub filters: filters::PlaylistFilters,
+    /// Print how many candidates each filter removed, see
+    /// [FilterCounts::explain](filters::FilterCounts::explain).
+    pub explain_filtering: bool,
+    /// The songs of the guest database, which playlists are also made of,
+    /// see [guest].
+    pub guest_songs: Vec<LibrarySong<()>>,
+    /// What the playlist is made with, to store it in the history along with
+    /// it, see [playlist_history]. Not stored if `None`.
+    pub history: Option<playlist_history::PlaylistParameters>,
+}
 
-        drop(sqlite_conn);
-        let paths = self.get_songs_paths()?;
-        self.library.analyze_paths(paths, true)?;
-        Ok(())
+impl PlaylistOptions {
+    /// Whether the playlist is going to be queued in MPD, as opposed to
+    /// only being printed or written to a file.
+    fn modifies_queue(&self) -> bool {
+        !self.dry_run && self.output_m3u.is_none()
     }
 
-    /// Make a playlist composed of albums similar to the album that's currently playing,
-    /// and queue them.
-    ///
-    /// # Parameters
-    ///
-    /// - `number_albums`: The number of albums to queue
-    /// - `dry_run`: Do not modify the queue, instead print the files that would
-    ///   be added to the playlist
-    /// - `keep_queue`: if false, will remove the content of the current queue save for the
-    ///   currently playing album, and will queue the playlist after the last song of the
-    ///   current album. If true, will queue the playlist after the last song of the current album,
-    ///   but will keep the queue intact
-    // TODO write tests for keep_queue also
-    fn queue_from_current_album(
-        &self,
-        number_albums: usize,
-        dry_run: bool,
-        keep_queue: bool,
-    ) -> Result<()> {
-        let mut mpd_conn = self.mpd_conn.lock().unwrap();
-        if mpd_conn.status()?.random {
-            warn!("Random mode is enabled for MPD, you might want to turn it off to get the most out of your playlist.");
+    /// Whether `song` is left out of playlists, because of its title, see
+    /// `exclude_titles`, because it was excluded, or because its analysis is
+    /// degenerate.
+    fn excludes(&self, song: &LibrarySong<()>) -> bool {
+        self.excluded_songs.contains(&song.bliss_song.path)
+            || degenerate::is_degenerate(&song.bliss_song.analysis)
+            || song
+                .bliss_song
+                .title
+                .as_ref()
+                .is_some_and(|t| self.exclude_titles.iter().any(|r| r.is_match(t)))
+    }
+}
+
+impl Default for PlaylistOptions {
+    fn default() -> Self {
+        Self {
+            dedup: Some(DedupStrategy::Features),
+            dedup_distance: DEFAULT_DEDUP_DISTANCE,
+            dry_run: false,
+            keep_queue: false,
+            skip_queued: false,
+            disable_random: false,
+            restore_random: false,
+            reverse: false,
+            offset: 0,
+            show_distance: false,
+            output_m3u: None,
+            format: OutputFormat::Plain,
+            publish_stickers: false,
+            tie_break: None,
+            tie_break_epsilon: DEFAULT_TIE_BREAK_EPSILON,
+            max_average_distance: None,
+            refuse_distant: false,
+            after_current: AfterCurrent::Album,
+            restart_current_album: false,
+            max_queue_length: None,
+            exclude_titles: vec![],
+            excluded_songs: HashSet::new(),
+            max_familiar: None,
+            familiar_artists: HashSet::new(),
+            avoid_seed_album: None,
+            filters: filters::PlaylistFilters::default(),
+            explain_filtering: false,
+            guest_songs: vec![],
+            history: None,
         }
-        let mpd_song = match mpd_conn.currentsong()? {
-            Some(s) => s,
-            None => bail!("No song is currently playing. Add a song to start the playlist from, and try again."),
-        };
+    }
+}
 
-        let current_song = self.mpd_to_bliss_song(&mpd_song)?.with_context(|| {
-            "The song currently playing could not be found in blissify's library. Please analyze it, and try again."
-        })?;
-        let current_album = current_song.bliss_song.album.ok_or_else(|| {
-            BlissError::ProviderError(String::from(
-                "The current song does not have any album information.",
-            ))
-        })?;
-        let playlist = self
-            .library
-            .album_playlist_from::<()>(current_album.clone(), number_albums)?;
+/// Same default threshold as the one bliss uses for its own deduplication.
+const DEFAULT_DEDUP_DISTANCE: f32 = 0.05;
+const DEFAULT_TIE_BREAK_EPSILON: f32 = 0.1;
 
-        let current_track_number = if let Some(track_number) = &current_song.bliss_song.track_number
-        {
-            *track_number as usize
-        } else {
-            1
-        };
-        // If we don't want to keep the queue, we start the playlist where the
-        // currently playing track is playing, and we won't have any album leftovers to
-        // shift, since we're erasing the current queue and replacing it with our fresh one.
-        let (index, album_leftovers): (usize, usize) = if !keep_queue {
-            (current_track_number, 1)
-        }
-        // If we want to keep the queue, we should iterate on the current playlist
-        // until we find the end of the current album, and set the beginning of it there,
-        // since we want to preserve the queue as much as possible.
-        else {
-            let queue_from_current_song = mpd_conn.songs(mpd_song.place.unwrap().pos..)?;
-            let album_leftovers = queue_from_current_song
-                .iter()
-                .take_while(|s| {
-                    for (tagname, value) in s.tags.iter() {
-                        if tagname.to_ascii_lowercase() == *"album" && *value == current_album {
-                            return true;
+/// The CUE sheet a CUE track's path, like `album.cue/CUE_TRACK003`, refers to,
+/// or `None` if `path` is not a CUE track.
+fn cue_sheet_path(path: &Path) -> Option<&Path> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("CUE_TRACK")?
+        .parse::<usize>()
+        .ok()?;
+    path.parent()
+}
+
+/// Search `query` in MPD's database a window of `chunk_size` songs at a time,
+/// through all of `clients` at the same time, until the windows are past the
+/// last song. The songs are returned in no particular order.
+fn search_windows(
+    clients: Vec<&mut MPDClient>,
+    query: &Query,
+    chunk_size: u32,
+) -> Result<Vec<MPDSong>, mpd::error::Error> {
+    let next_index = AtomicU32::new(0);
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let workers = clients
+            .into_iter()
+            .map(|client| {
+                let (next_index, done) = (&next_index, &done);
+                scope.spawn(move || {
+                    let mut songs = vec![];
+                    while !done.load(Ordering::SeqCst) {
+                        let index = next_index.fetch_add(chunk_size, Ordering::SeqCst);
+                        let search =
+                            client.search(query, Window::from((index, index + chunk_size)));
+                        match search {
+                            Ok(search) if search.is_empty() => done.store(true, Ordering::SeqCst),
+                            Ok(search) => songs.extend(search),
+                            Err(e) => {
+                                done.store(true, Ordering::SeqCst);
+                                return Err(e);
+                            }
                         }
                     }
-                    false
+                    Ok(songs)
                 })
-                .count();
-            let index = playlist
-                .iter()
-                .position(|s| s.bliss_song.album.as_ref() != Some(&current_album))
-                .ok_or(BlissError::ProviderError(String::from(
-                    "Could not find current album in playlist",
-                )))?;
-            (index, album_leftovers)
-        };
+            })
+            .collect::<Vec<_>>();
+        let mut songs = vec![];
+        for worker in workers {
+            songs.extend(worker.join().unwrap()?);
+        }
+        Ok(songs)
+    })
+}
+
+/// The number of songs [random_dense_song] estimates the density of the
+/// library around, and the number of neighbours it looks at for each.
+const RANDOM_START_CANDIDATES: usize = 200;
+const RANDOM_START_NEIGHBOURS: usize = 10;
+
+/// Pick a random song among the ones in the densest regions of `songs`, i.e.
+/// the ones whose `neighbours`-th closest song is the closest.
+///
+/// The density is only estimated for a random sample of the songs, so this
+/// stays fast on large libraries.
+fn random_dense_song<'a, R: Rng>(
+    songs: &'a [LibrarySong<()>],
+    neighbours: usize,
+    rng: &mut R,
+) -> Option<&'a LibrarySong<()>> {
+    let mut candidates = songs
+        .choose_multiple(rng, RANDOM_START_CANDIDATES)
+        .map(|candidate| {
+            let analysis = candidate.bliss_song.analysis.as_arr1();
+            let mut distances = songs
+                .iter()
+                .filter(|s| s.bliss_song.path != candidate.bliss_song.path)
+                .map(|s| {
+                    n32(euclidean_distance(
+                        &analysis,
+                        &s.bliss_song.analysis.as_arr1(),
+                    ))
+                })
+                .collect::<Vec<_>>();
+            distances.sort();
+            let density = distances
+                .get(neighbours.min(distances.len()).saturating_sub(1))
+                .copied()
+                .unwrap_or(n32(0.));
+            (density, candidate)
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(density, _)| *density);
+    // Keep the densest quarter.
+    candidates.truncate(candidates.len().div_ceil(4));
+    candidates.choose(rng).map(|(_, song)| *song)
+}
+
+/// The name of the MPD sticker storing each song's distance to the songs its
+/// playlist was made from, see `--publish-stickers`.
+const DISTANCE_STICKER: &str = "bliss_distance";
+
+/// Compute the distance of each song of `playlist` to the songs in `seeds`.
+fn playlist_distances(
+    playlist: &[LibrarySong<()>],
+    seeds: &[LibrarySong<()>],
+    distance: &dyn DistanceMetricBuilder,
+) -> Vec<f32> {
+    let vectors = seeds
+        .iter()
+        .map(|s| s.bliss_song.analysis.as_arr1())
+        .collect::<Vec<_>>();
+    let metric = distance.build(&vectors);
+    playlist
+        .iter()
+        .map(|s| metric.distance(&s.bliss_song.analysis.as_arr1()))
+        .collect()
+}
+
+/// Warn if the songs of `playlist` are, on average, further from `seeds` than
+/// `options.max_average_distance`, which happens with seeds unlike anything in
+/// the library, or with small libraries. Fails instead if
+/// `options.refuse_distant` is set.
+fn check_playlist_distance(
+    playlist: &[LibrarySong<()>],
+    seeds: &[LibrarySong<()>],
+    distance: &dyn DistanceMetricBuilder,
+    options: &PlaylistOptions,
+) -> Result<()> {
+    let threshold = match options.max_average_distance {
+        Some(threshold) => threshold,
+        None => return Ok(()),
+    };
+    // The seeds themselves can be part of the playlist.
+    let songs = playlist
+        .iter()
+        .filter(|s| !seeds.contains(s))
+        .cloned()
+        .collect::<Vec<_>>();
+    if songs.is_empty() || seeds.is_empty() {
+        return Ok(());
+    }
+    let distances = playlist_distances(&songs, seeds, distance);
+    let average = distances.iter().sum::<f32>() / distances.len() as f32;
+    if average <= threshold {
+        return Ok(());
+    }
+    let message = format!(
+        "The songs of this playlist are far from the song(s) it was made from \
+        (average distance of {:.3}, above {:.3}), there might not be anything \
+        similar in the library.",
+        average, threshold
+    );
+    if options.refuse_distant {
+        bail!("{} Use --force to queue it anyway.", message);
+    }
+    warn!("{}", message);
+    Ok(())
+}
 
-        if dry_run {
-            for song in &playlist[index..] {
-                println!("{}", song.bliss_song.path.to_string_lossy());
+/// The album of `songs` whose mean analysis is the closest to `song`, other
+/// than `song`'s own album.
+fn closest_album(song: &LibrarySong<()>, songs: &[LibrarySong<()>]) -> Option<String> {
+    let mut albums: BTreeMap<&str, Vec<&LibrarySong<()>>> = BTreeMap::new();
+    for other in songs {
+        match &other.bliss_song.album {
+            Some(album) if Some(album) != song.bliss_song.album.as_ref() => {
+                albums.entry(album).or_default().push(other)
             }
-            return Ok(());
+            _ => (),
         }
+    }
+    let analysis = song.bliss_song.analysis.as_arr1();
+    albums
+        .into_iter()
+        .map(|(album, songs)| {
+            let mean = album_mean(songs.into_iter());
+            (n32(euclidean_distance(&analysis, &mean)), album)
+        })
+        .min()
+        .map(|(_, album)| album.to_owned())
+}
 
-        let mut current_pos = mpd_song.place.unwrap().pos;
+/// The mean analysis of the songs of an album, as used for album distances.
+/// `songs` must not be empty.
+fn album_mean<'a>(songs: impl ExactSizeIterator<Item = &'a LibrarySong<()>>) -> Array1<f32> {
+    let number_songs = songs.len() as f32;
+    songs
+        .map(|s| s.bliss_song.analysis.as_arr1())
+        .reduce(|a, b| a + b)
+        .unwrap()
+        / number_songs
+}
 
-        // Delete everything except the current song if we don't
-        // want to keep the queue.
-        if !keep_queue {
-            mpd_conn.delete(0..current_pos)?;
-            if mpd_conn.queue()?.len() > 1 {
-                mpd_conn.delete(1..)?;
+/// Print the songs of a playlist on stdout, one path per line, or as JSON
+/// depending on `options.format`.
+///
+/// If `options.show_distance` is set, also print the songs the playlist was
+/// made from, and each song's distance to them.
+fn print_playlist(
+    playlist: &[LibrarySong<()>],
+    seeds: &[LibrarySong<()>],
+    distance: &dyn DistanceMetricBuilder,
+    options: &PlaylistOptions,
+) -> Result<()> {
+    let distances = if options.show_distance {
+        playlist_distances(playlist, seeds, distance)
+            .into_iter()
+            .map(Some)
+            .collect()
+    } else {
+        vec![None; playlist.len()]
+    };
+    match options.format {
+        OutputFormat::Plain => {
+            if options.show_distance {
+                for seed in seeds {
+                    println!("Seed: {}", seed.bliss_song.path.to_string_lossy());
+                }
+            }
+            for (song, distance) in playlist.iter().zip(distances) {
+                let mut line = String::new();
+                if let Some(source) = song_source(song, options) {
+                    line.push_str(&format!("{}\t", source));
+                }
+                if let Some(d) = distance {
+                    line.push_str(&format!("{:.6}\t", d));
+                }
+                println!("{}{}", line, song.bliss_song.path.to_string_lossy());
             }
-            current_pos = 0;
         }
-        // Add songs to the queue from the built playlist, starting either
-        // from the current song or from the beginning of the next album
-        for (i, song) in playlist[index..].iter().enumerate() {
-            let mpd_song = self.bliss_song_to_mpd(song)?;
-            mpd_conn.insert(mpd_song, (current_pos + i as u32).try_into()?)?;
+        OutputFormat::Json => {
+            let output = playlist_output(playlist, seeds, distances, options);
+            println!("{}", serde_json::to_string(&output)?);
         }
-        let new_pos = current_pos + playlist[index..].len() as u32;
-        // Put back the songs from the current album that were shifted around
-        mpd_conn.shift(
-            new_pos..new_pos + album_leftovers as u32,
-            current_pos.try_into()?,
-        )?;
-
-        Ok(())
     }
+    Ok(())
+}
 
-    /// Make a playlist made of songs that are similar to the songs currently
-    /// in MPD playlist, and queue these songs after the last one.
-    /// Works better with extended_isolation_forest as the distance metric.
-    ///
-    /// # Parameters
-    ///
-    /// - `number_songs`: The number of songs to queue.
-    /// - `distance`: The distance metric used to compute distances between the current
-    ///   playlist and the songs, see [bliss_audio::playlist] for details on distance metrics.
-    ///   [extended_isolation_forest] works better than the other for this
-    ///   kind of comparison.
-    /// - `sort_by`: A closure that does the actual sorting of the playlist in place, based on
-    ///   the `distance` metric chosen, see [bliss_audio::playlist::closest_to_songs] for instance
-    ///   for details on sorting algorithms.
-    /// - `dedup`: Whether or not to deduplicate same songs from the resulting playlist.
-    /// - `dry_run`: Do not modify the queue, instead print the files that would
-    ///   be added to the playlist.
-    fn queue_from_current_playlist<'a, F, I>(
-        &self,
-        number_songs: usize,
-        distance: &'a dyn DistanceMetricBuilder,
-        sort_by: F,
-        dedup: bool,
-        dry_run: bool,
-    ) -> Result<()>
-    where
-        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
-        I: Iterator<Item = LibrarySong<()>> + 'a,
-    {
-        let mut mpd_conn = self.mpd_conn.lock().unwrap();
-        if mpd_conn.status()?.random {
-            warn!("Random mode is enabled for MPD, you might want to turn it off to get the most out of your playlist.");
-        }
-        let mpd_songs = mpd_conn.queue()?;
-
-        if mpd_songs.is_empty() {
-            bail!("No song is currently playing. Add a song to start the playlist from, and try again.");
-        }
-        let paths = mpd_songs
+/// A playlist as printed by dry runs with `--format json`, `distances`
+/// being the distance of each song to `seeds`, if computed.
+fn playlist_output(
+    playlist: &[LibrarySong<()>],
+    seeds: &[LibrarySong<()>],
+    distances: Vec<Option<f32>>,
+    options: &PlaylistOptions,
+) -> PlaylistOutput {
+    PlaylistOutput {
+        seeds: if options.show_distance {
+            seeds.iter().map(SongOutput::new).collect()
+        } else {
+            vec![]
+        },
+        songs: playlist
             .iter()
-            .map(|s| {
-                self.mpd_to_bliss_path(s)
-                    .map(|s| s.to_string_lossy().to_string())
+            .zip(distances)
+            .map(|(song, distance)| SongOutput {
+                distance,
+                source: song_source(song, options).map(String::from),
+                ..SongOutput::new(song)
             })
-            .collect::<Result<Vec<String>, _>>()?;
-        let paths = paths.iter().map(|s| &**s).collect::<Vec<&str>>();
+            .collect(),
+        transitions: playlist_transitions(playlist),
+    }
+}
 
-        let playlist = self
-            .library
-            .playlist_from_custom(&paths, distance, sort_by, dedup)?
-            .take(number_songs);
+/// Which library `song` comes from, "local" or "guest", if `options` has a
+/// guest database, see [guest].
+fn song_source(song: &LibrarySong<()>, options: &PlaylistOptions) -> Option<&'static str> {
+    if options.guest_songs.is_empty() {
+        None
+    } else if guest::is_guest(song, options) {
+        Some("guest")
+    } else {
+        Some("local")
+    }
+}
+
+/// Split an album playlist into its albums, in order, each with the
+/// distance between its mean analysis and `reference`, the mean analysis of
+/// the album the playlist was made from.
+fn album_groups(playlist: &[LibrarySong<()>], reference: &Array1<f32>) -> Vec<AlbumGroupOutput> {
+    let mut groups = vec![];
+    let mut start = 0;
+    while start < playlist.len() {
+        let album = &playlist[start].bliss_song.album;
+        let length = playlist[start..]
+            .iter()
+            .take_while(|s| &s.bliss_song.album == album)
+            .count();
+        let songs = &playlist[start..start + length];
+        let first = &songs[0].bliss_song;
+        groups.push(AlbumGroupOutput {
+            album: album.to_owned(),
+            artist: first
+                .album_artist
+                .to_owned()
+                .or_else(|| first.artist.to_owned()),
+            distance: euclidean_distance(&album_mean(songs.iter()), reference),
+            songs: songs.iter().map(SongOutput::new).collect(),
+        });
+        start += length;
+    }
+    groups
+}
 
-        if dry_run {
-            for song in playlist {
-                println!("{}", song.bliss_song.path.to_string_lossy());
+/// Print an album playlist made from `current_album`, album by album, with
+/// a header giving each album's distance to `current_album`, or as nested
+/// JSON depending on `options.format`.
+fn print_album_playlist(
+    playlist: &[LibrarySong<()>],
+    current_album: &[LibrarySong<()>],
+    options: &PlaylistOptions,
+) -> Result<()> {
+    let groups = album_groups(playlist, &album_mean(current_album.iter()));
+    match options.format {
+        OutputFormat::Plain => {
+            for (i, group) in groups.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                println!(
+                    "Album: {} - {} ({:.6})",
+                    group.artist.as_deref().unwrap_or("<No artist>"),
+                    group.album.as_deref().unwrap_or("<No album>"),
+                    group.distance,
+                );
+                for song in &group.songs {
+                    println!("{}", song.path);
+                }
             }
-            return Ok(());
         }
-
-        for song in playlist {
-            let mpd_song = self.bliss_song_to_mpd(&song)?;
-            mpd_conn.push(mpd_song)?;
+        OutputFormat::Json => {
+            let output = AlbumPlaylistOutput { albums: groups };
+            println!("{}", serde_json::to_string(&output)?);
         }
-        Ok(())
     }
+    Ok(())
+}
 
-    /// Make a playlist composed of songs similar to the song that's currently playing,
-    /// and queue them.
-    ///
-    /// # Parameters
-    ///
-    /// - `song_path`: The path to the song to make a playlist from. Can be either an absolute
-    ///   path, i.e. `/home/user/Music/album/song.flac`, or a path relative to
-    ///   (mpd_base_path)[Config::mpd_base_path], like `album/song.flac`. If not specified,
-    ///   defaults to the currently playing song.
-    /// - `number_songs`: The number of songs to queue.
-    /// - `distance`: The distance metric used to compute distances between songs, see the
-    ///   [bliss_audio::playlist] for details on distance metrics.
-    /// - `sort_by`: A closure that does the actual sorting of the playlist in place, based on
-    ///   the `distance` metric chosen, see [bliss_audio::playlist::closest_to_songs] for instance
-    ///   for details on sorting algorithms.
-    /// - `dedup`: Whether or not to deduplicate same songs from the resulting playlist.
-    /// - `dry_run`: Do not modify the queue, instead print the files that would
-    ///   be added to the playlist.
-    /// - `keep_queue`: if false, will remove the content of the entire queue save for the
-    ///   currently playing song, and will queue the playlist after it. If true, will queue
-    ///   the playlist after the current song, but will keep the queue intact.
-    // TODO do we want a flag to toggle "random" off automatically here? And a flag to keep /
-    // exclude the current song from the playlist?
-    // TODO maybe we don't have to collect? But the magic at the end makes it very convenient
-    fn queue_from_song<'a, F, I>(
-        &self,
-        song_path: Option<&str>,
-        number_songs: usize,
-        distance: &'a dyn DistanceMetricBuilder,
-        sort_by: F,
-        dedup: bool,
-        dry_run: bool,
-        keep_queue: bool,
-    ) -> Result<()>
-    where
-        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
-        I: Iterator<Item = LibrarySong<()>> + 'a,
-    {
-        let mut mpd_conn = self.mpd_conn.lock().unwrap();
-        if mpd_conn.status()?.random {
-            warn!("Random mode is enabled for MPD, you might want to turn it off to get the most out of your playlist.");
+/// The transitions between consecutive songs of `playlist`, the first one
+/// being from the first song to the second.
+fn playlist_transitions(playlist: &[LibrarySong<()>]) -> Vec<TransitionOutput> {
+    playlist
+        .windows(2)
+        .map(|songs| {
+            let (from, to) = (&songs[0].bliss_song.analysis, &songs[1].bliss_song.analysis);
+            TransitionOutput {
+                tempo_delta: to[AnalysisIndex::Tempo] - from[AnalysisIndex::Tempo],
+                loudness_delta: to[AnalysisIndex::MeanLoudness] - from[AnalysisIndex::MeanLoudness],
+            }
+        })
+        .collect()
+}
+
+/// Write the songs of a playlist to an extended M3U file at `path`,
+/// overwriting it if it exists.
+fn write_m3u(playlist: &[LibrarySong<()>], path: &Path) -> Result<()> {
+    let mut file = std::fs::File::create(path)
+        .with_context(|| format!("while creating the M3U file {}", path.display()))?;
+    writeln!(file, "#EXTM3U")?;
+    for song in playlist {
+        let song = &song.bliss_song;
+        writeln!(
+            file,
+            "#EXTINF:{},{} - {}",
+            song.duration.as_secs(),
+            song.artist.as_deref().unwrap_or("<No artist>"),
+            song.title.as_deref().unwrap_or("<No title>"),
+        )?;
+        writeln!(file, "{}", song.path.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+/// Whether `song1` and `song2` are duplicates according to `strategy`.
+///
+/// `recording_ids` are the MusicBrainz recording ids of the songs, only
+/// looked at by [DedupStrategy::Release].
+fn are_duplicates(
+    song1: &LibrarySong<()>,
+    song2: &LibrarySong<()>,
+    strategy: DedupStrategy,
+    distance_threshold: f32,
+    recording_ids: [Option<&str>; 2],
+) -> bool {
+    let same_title_artist = |s1: &Song, s2: &Song| match (&s1.title, &s2.title) {
+        (Some(t1), Some(t2)) => {
+            t1.trim().to_lowercase() == t2.trim().to_lowercase()
+                && s1.artist.as_ref().map(|a| a.trim().to_lowercase())
+                    == s2.artist.as_ref().map(|a| a.trim().to_lowercase())
         }
-        let mpd_song = match mpd_conn.currentsong()? {
+        _ => false,
+    };
+    let (s1, s2) = (&song1.bliss_song, &song2.bliss_song);
+    match strategy {
+        DedupStrategy::Title => same_title_artist(s1, s2),
+        DedupStrategy::Features => {
+            same_title_artist(s1, s2)
+                || euclidean_distance(&s1.analysis.as_arr1(), &s2.analysis.as_arr1())
+                    < distance_threshold
+        }
+        DedupStrategy::Album => s1.album.is_some() && s1.album == s2.album,
+        DedupStrategy::Release => {
+            let [id1, id2] = recording_ids;
+            are_duplicates(
+                song1,
+                song2,
+                DedupStrategy::Features,
+                distance_threshold,
+                [None; 2],
+            ) || release::same_recording(s1, id1, s2, id2)
+        }
+    }
+}
+
+/// Whether `song` shares the metadata `tie_break` looks at with one of `seeds`.
+fn shares_metadata(song: &LibrarySong<()>, seeds: &[LibrarySong<()>], tie_break: TieBreak) -> bool {
+    // Songs with several genres share a genre if any of them match.
+    let values = |s: &LibrarySong<()>| match tie_break {
+        TieBreak::Genre => s
+            .bliss_song
+            .genre
+            .as_deref()
+            .map(genre::parse_genres)
+            .unwrap_or_default(),
+        TieBreak::Artist => s
+            .bliss_song
+            .artist
+            .iter()
+            .map(|a| a.trim().to_lowercase())
+            .collect(),
+    };
+    let song_values = values(song);
+    seeds
+        .iter()
+        .any(|s| values(s).iter().any(|v| song_values.contains(v)))
+}
+
+/// Among consecutive songs whose distances to `seeds` are within `epsilon` of
+/// each other, put the ones sharing `tie_break`'s metadata with `seeds` first,
+/// keeping the order given by `distance` otherwise.
+fn tie_break_playlist<'a, I>(
+    songs: I,
+    seeds: &[LibrarySong<()>],
+    distance: &dyn DistanceMetricBuilder,
+    tie_break: Option<TieBreak>,
+    epsilon: f32,
+) -> Box<dyn Iterator<Item = LibrarySong<()>> + 'a>
+where
+    I: Iterator<Item = LibrarySong<()>> + 'a,
+{
+    let tie_break = match tie_break {
+        Some(t) if !seeds.is_empty() => t,
+        _ => return Box::new(songs),
+    };
+    let mut songs = songs.collect::<Vec<_>>();
+    let distances = playlist_distances(&songs, seeds, distance);
+    let mut start = 0;
+    while start < songs.len() {
+        let end = (start..songs.len())
+            .find(|&i| (distances[i] - distances[start]).abs() > epsilon)
+            .unwrap_or(songs.len());
+        // The sort is stable, so songs keep their order within each half.
+        songs[start..end].sort_by_key(|s| !shares_metadata(s, seeds, tie_break));
+        start = end;
+    }
+    Box::new(songs.into_iter())
+}
+
+/// Lazily remove duplicates from `songs`, comparing each song to all the
+/// songs that were kept before it, not only the adjacent ones.
+///
+/// The first song is always kept.
+fn dedup_playlist<I, R>(
+    songs: I,
+    strategy: Option<DedupStrategy>,
+    distance_threshold: f32,
+    recording_id: R,
+) -> impl Iterator<Item = LibrarySong<()>>
+where
+    I: Iterator<Item = LibrarySong<()>>,
+    R: Fn(&LibrarySong<()>) -> Option<String>,
+{
+    // The songs kept, along with their recording id for DedupStrategy::Release.
+    let mut kept: Vec<(LibrarySong<()>, Option<String>)> = vec![];
+    songs.filter(move |song| {
+        let strategy = match strategy {
             Some(s) => s,
-            None => bail!("No song is currently playing. Add a song to start the playlist from, and try again."),
+            None => return true,
         };
-        let path = if let Some(path) = song_path {
-            if path.contains(self.library.config.mpd_base_path.to_string_lossy().as_ref()) {
-                PathBuf::from(path)
-            } else {
-                self.library.config.mpd_base_path.join(path)
-            }
+        let id = if strategy == DedupStrategy::Release {
+            recording_id(song)
         } else {
-            self.mpd_to_bliss_path(&mpd_song)?
+            None
         };
+        if kept.iter().any(|(k, k_id)| {
+            are_duplicates(
+                k,
+                song,
+                strategy,
+                distance_threshold,
+                [k_id.as_deref(), id.as_deref()],
+            )
+        }) {
+            return false;
+        }
+        kept.push((song.to_owned(), id));
+        true
+    })
+}
 
-        // If we specified a song path on the CLI, chances are the song is not already
-        // in the queue (nor anywhere else).
-        // If we didn't, we're using the current_song, and chances are that the song is
-        // already in the queue, so we want to get an extra song there, since the current
-        // song doesn't count.
-        let number_songs = if song_path.is_some() {
-            number_songs
-        } else {
-            number_songs + 1
-        };
-        let playlist: Vec<LibrarySong<_>> = self
-            .library
-            .playlist_from_custom(&[&path.to_string_lossy().clone()], distance, sort_by, dedup)?
-            .take(number_songs)
-            .collect();
+#[cfg(test)]
+#[derive(Default)]
+/// Convenience Mock for testing.
+pub struct MockMPDClient {
+    mpd_queue: Vec<MPDSong>,
+    // FIXME only useful because the Window struct
+    // https://docs.rs/mpd/latest/mpd/search/struct.Window.html
+    // is still work in progress, remove when the corresponding
+    // fields can be accessed.
+    search_window: u32,
+    random: bool,
+    // Stickers, indexed by (uri, name).
+    stickers: std::collections::HashMap<(String, String), String>,
+    // Make `search` fail, like it can on older servers.
+    fail_search: bool,
+    consume: bool,
+    // Client-to-client messages sent.
+    messages: Vec<(mpd::Channel, String)>,
+}
 
-        if dry_run {
-            for song in &playlist {
-                println!("{}", song.bliss_song.path.to_string_lossy());
-            }
-            return Ok(());
+/// The MPD client used throughout blissify.
+#[cfg(not(test))]
+type MPDClient = Client<MPDStream>;
+#[cfg(test)]
+type MPDClient = MockMPDClient;
+
+/// Where to connect to MPD, and with which password.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct MPDAddress {
+    /// A hostname, an IP address, or a socket path, like MPD_HOST but
+    /// without the password.
+    pub host: String,
+    pub port: u16,
+    pub password: Option<String>,
+}
+
+impl Default for MPDAddress {
+    fn default() -> Self {
+        MPDAddress {
+            host: String::from("127.0.0.1"),
+            port: 6600,
+            password: None,
         }
+    }
+}
 
-        let mut current_pos = mpd_song.place.unwrap().pos;
-        // Delete everything except the current song if we don't
-        // want to keep the queue.
-        if !keep_queue {
-            mpd_conn.delete(0..current_pos)?;
-            if mpd_conn.queue()?.len() > 1 {
-                mpd_conn.delete(1..)?;
-            }
-            current_pos = 0;
+impl MPDAddress {
+    /// The address of `host`, written as MPD_HOST: a hostname, an IP
+    /// address or a socket path, optionally preceded by "password@".
+    fn new(host: &str, port: u16) -> Self {
+        let (password, host) = match host.split_once('@') {
+            None => (None, host),
+            // If it's a unix abstract socket, there will be nothing before the '@'
+            Some(("", _)) => (None, host),
+            Some((password, host)) => (Some(password.to_owned()), host),
+        };
+        MPDAddress {
+            host: host.to_owned(),
+            port,
+            password,
         }
+    }
 
-        // If we're starting from a song specified in song_path,
-        // push the playlist straight at the end.
-        if song_path.is_some() {
-            for song in &playlist {
-                let mpd_song = self.bliss_song_to_mpd(song)?;
-                mpd_conn.push(mpd_song)?;
+    /// The address given by the MPD_HOST and MPD_PORT environment variables.
+    fn from_env() -> Result<Self> {
+        let mpd_port = match env::var("MPD_PORT") {
+            Ok(p) => p
+                .parse::<u16>()
+                .with_context(|| "while trying to coerce MPD_PORT to an integer")?,
+            Err(_) => {
+                warn!("Could not find any MPD_PORT environment variable set. Defaulting to 6600.");
+                6600
+            }
+        };
+        match env::var("MPD_HOST") {
+            Ok(h) => Ok(Self::new(&h, mpd_port)),
+            Err(_) => {
+                warn!("Could not find any MPD_HOST environment variable set. Defaulting to 127.0.0.1.");
+                Ok(MPDAddress {
+                    port: mpd_port,
+                    ..Default::default()
+                })
             }
-            return Ok(());
-        }
-        // Else, do some magic to preserve the queue depending on the
-        // --keep-current-queue argument.
-        for (index, song) in playlist[1..].iter().enumerate() {
-            let mpd_song = self.bliss_song_to_mpd(song)?;
-            mpd_conn.insert(mpd_song, (current_pos + index as u32).try_into()?)?;
         }
-        let new_pos = current_pos + playlist.len() as u32 - 1;
-        mpd_conn.shift(new_pos..new_pos + 1, current_pos.try_into()?)?;
-
-        Ok(())
     }
+}
 
-    /// Get the song's paths from the MPD database.
-    ///
-    /// Instead of returning one filename per CUE track (file.cue/track0001,
-    /// file2.cue/track0002, etc), returns the CUE sheet itself (file.cue)
-    ///
-    /// Note: this uses [mpd_base_path](Config::mpd_base_path) because MPD
-    /// returns paths without including MPD_BASE_PATH.
-    fn get_songs_paths(&self) -> BlissResult<Vec<String>> {
-        let mut mpd_conn = self.mpd_conn.lock().unwrap();
+#[cfg(not(test))]
+enum MPDStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
 
-        let mut query = Query::new();
-        let query = query.and(Term::File, "");
-        let (mut index, chunk_size) = (0, 10_000);
-        let mut files = vec![];
-        loop {
-            let search = mpd_conn
-                .search(query, Window::from((index, index + chunk_size)))
-                .map_err(|e| BlissError::ProviderError(e.to_string()))?;
-            if search.is_empty() {
-                break;
-            }
-            files.extend(
-                search
-                    .into_iter()
-                    .map(|s| s.file.to_owned())
-                    .map(|s| {
-                        if s.to_lowercase().contains(".cue/track") {
-                            let lowercase_string = s.to_lowercase();
-                            let idx: Vec<_> = lowercase_string.match_indices("/track").collect();
-                            s.split_at(idx[0].0).0.to_owned()
-                        } else {
-                            s
-                        }
-                    })
-                    .map(|s| {
-                        String::from(
-                            Path::new(&self.library.config.mpd_base_path)
-                                .join(Path::new(&s))
-                                .to_str()
-                                .unwrap(),
-                        )
-                    })
-                    .collect::<Vec<String>>(),
-            );
-            index += chunk_size;
+#[cfg(not(test))]
+impl Read for MPDStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MPDStream::Tcp(v) => v.read(buf),
+            MPDStream::Unix(v) => v.read(buf),
+        }
+    }
+}
+#[cfg(not(test))]
+impl Write for MPDStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MPDStream::Tcp(v) => v.write(buf),
+            MPDStream::Unix(v) => v.write(buf),
         }
-        files.sort();
-        files.dedup();
-
-        Ok(files)
     }
 
-    pub fn make_interactive_playlist(
-        &mut self,
-        continue_playlist: bool,
-        number_choices: usize,
-    ) -> Result<()> {
-        let mut mpd_conn = self.mpd_conn.lock().unwrap();
-        mpd_conn.random(false)?;
-        let mpd_song = if !continue_playlist {
-            match mpd_conn.currentsong()? {
-                Some(s) => s,
-                None => bail!(
-                    "No song is currently playing. Add a song to start \
-                    the playlist from, and try again.",
-                ),
-            }
-        } else {
-            match mpd_conn.queue()?.last() {
-                Some(s) => s.to_owned(),
-                None => bail!(
-                    "The current playlist is empty. Add at least a song \
-                    to start the playlist from, and try again.",
-                ),
-            }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MPDStream::Tcp(v) => v.flush(),
+            MPDStream::Unix(v) => v.flush(),
+        }
+    }
+}
+
+#[cfg(not(test))]
+impl MPDStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            MPDStream::Tcp(v) => MPDStream::Tcp(v.try_clone()?),
+            MPDStream::Unix(v) => MPDStream::Unix(v.try_clone()?),
+        })
+    }
+}
+
+impl MPDLibrary {
+    /// Get a connection to the MPD server at `address`.
+    #[cfg(not(test))]
+    fn get_mpd_conn(address: &MPDAddress) -> Result<Client<MPDStream>> {
+        let stream = Self::mpd_stream(address)?;
+        let mut client = Client::new(stream)?;
+        if let Some(pw) = &address.password {
+            client.login(pw)?;
+        }
+        Ok(client)
+    }
+
+    /// Open a raw connection to the MPD server at `address`, see
+    /// [get_mpd_conn](Self::get_mpd_conn). Logging in with the address'
+    /// password is left to the caller.
+    #[cfg(not(test))]
+    fn mpd_stream(address: &MPDAddress) -> Result<MPDStream> {
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let mpd_host = &address.host;
+        // TODO It is most likely a socket if it starts by "/", but maybe not necessarily?
+        // find a solution that doesn't depend on a url crate that pulls the entire internet
+        // with it
+        if mpd_host.starts_with('/') || mpd_host.starts_with('~') {
+            return Ok(MPDStream::Unix(UnixStream::connect(mpd_host)?));
+        }
+        #[cfg(target_os = "linux")]
+        if mpd_host.starts_with('@') {
+            let addr = SocketAddr::from_abstract_name(mpd_host.split_once('@').unwrap().1)?;
+            return Ok(MPDStream::Unix(UnixStream::connect_addr(&addr)?));
+        }
+        // It is a hostname or an IP address
+        let stream = TcpStream::connect(format!("{}:{}", mpd_host, address.port))?;
+        Ok(MPDStream::Tcp(stream))
+    }
+
+    /// Where MPD is: the server selected with `--target-server`, or the one
+    /// given by MPD_HOST and MPD_PORT.
+    fn mpd_address(&self) -> Result<MPDAddress> {
+        match &self.server_address {
+            Some(address) => Ok(address.to_owned()),
+            None => MPDAddress::from_env(),
+        }
+    }
+
+    fn mpd_to_bliss_path(&self, mpd_song: &MPDSong) -> Result<PathBuf> {
+        let file = match &self.server {
+            Some(server) => server.local_path(&mpd_song.file),
+            None => mpd_song.file.to_owned(),
+        };
+        let path = match cue::split_cue_track(&file) {
+            Some((file, track_number)) => format!("{}/CUE_TRACK{:03}", file, track_number),
+            None => file.to_string(),
+        };
+        let path = self.library.config.resolve_mpd_path(Path::new(&path));
+        // Tracks of sidecar CUE sheets are queued as their whole file, played
+        // from the track's start.
+        let path = match mpd_song
+            .range
+            .and_then(|range| cue::sidecar_track_at(&path, range.0))
+        {
+            Some(track_number) => path.join(format!("CUE_TRACK{:03}", track_number)),
+            None => path,
         };
+        // Files MPD lists through links are stored under their direct path.
+        Ok(self.library.config.direct_path(&path))
+    }
 
-        let mut current_song = self.mpd_to_bliss_song(&mpd_song)?.with_context(|| {
-            "The current song is not in bliss' database. Run `blissify \
-            update /path/to/mpd` and try again."
-        })?;
-        println!(
-            "The playlist will start from: '{} - {}'.",
-            current_song
-                .bliss_song
-                .artist
-                .as_deref()
-                .unwrap_or("<No artist>"),
-            current_song
-                .bliss_song
-                .title
-                .as_deref()
-                .unwrap_or("<No title>"),
-        );
+    /// Get the path of a song as stored in blissify's database from a
+    /// user-provided path, which can be either an absolute path, i.e.
+    /// `/home/user/Music/album/song.flac`, or a path relative to
+    /// [mpd_base_path](Config::mpd_base_path), like `album/song.flac`
+    /// or `album/file.cue/track0001`.
+    fn resolve_song_path(&self, path: &str) -> Result<PathBuf> {
+        if self
+            .library
+            .config
+            .base_paths()
+            .any(|b| path.contains(b.to_string_lossy().as_ref()))
+        {
+            return Ok(PathBuf::from(path));
+        }
+        self.mpd_to_bliss_path(&MPDSong {
+            file: path.to_owned(),
+            ..Default::default()
+        })
+    }
 
-        // Remove all songs from the playlist except the first one.
-        if !continue_playlist {
-            let current_pos = mpd_song.place.unwrap().pos;
-            mpd_conn.delete(0..current_pos)?;
-            if mpd_conn.queue()?.len() > 1 {
-                mpd_conn.delete(1..)?;
+    /// Convert a `MPDSong` to a previously analyzed `LibrarySong`, if it exists
+    /// in blissify's database.
+    fn mpd_to_bliss_song(&self, mpd_song: &MPDSong) -> Result<Option<LibrarySong<()>>> {
+        let path = self.mpd_to_bliss_path(mpd_song)?;
+        let song = self.library.song_from_path(&path.to_string_lossy()).ok();
+        Ok(song)
+    }
+
+    /// Convert a bliss song to an MPDSong, regardless whether the song
+    /// exists in the MPD database or not.
+    ///
+    /// Useful to convert CUE tracks to the right format, but does not
+    /// include metadata in the MPDSong.
+    fn bliss_song_to_mpd(&self, song: &LibrarySong<()>) -> Result<MPDSong> {
+        let (path, range) = match song.bliss_song.cue_info.to_owned() {
+            Some(cue_info) => {
+                let track_number = song.bliss_song.track_number.ok_or_else(|| {
+                    BlissError::ProviderError(format!(
+                        "CUE track {} has an invalid track number",
+                        song.bliss_song.path.display()
+                    ))
+                })?;
+                // MPD doesn't know about the tracks of sidecar CUE sheets.
+                match cue::sidecar_track_range(&cue_info.cue_path, track_number as usize)? {
+                    Some(range) => (cue_info.cue_path, Some(range)),
+                    None => (
+                        cue_info.cue_path.join(format!("track{:04}", track_number)),
+                        None,
+                    ),
+                }
             }
+            _ => (song.bliss_song.path.to_owned(), None),
+        };
+        let path = match self.library.config.strip_base_path(&path) {
+            Some(path) => path,
+            None => bail!(
+                "{} is not in MPD's music directory, see mpd_base_path.",
+                path.display()
+            ),
+        };
+        let file = path.to_string_lossy();
+        Ok(MPDSong {
+            file: match &self.server {
+                Some(server) => server.server_path(&file),
+                None => file.to_string(),
+            },
+            range,
+            ..Default::default()
+        })
+    }
+
+    /// Get the paths of the songs that were successfully analyzed, with CUE
+    /// sheets' paths instead of their individual tracks, as returned by
+    /// [get_songs_paths](Self::get_songs_paths).
+    fn analyzed_paths(&self) -> Result<HashSet<String>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut stmt = sqlite_conn
+            .prepare("select coalesce(cue_path, path) from song where analyzed = true")?;
+        let paths = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        Ok(paths)
+    }
+
+    /// Create the table storing the time of the MPD database update that
+    /// [update_new_songs](Self::update_new_songs) last caught up with.
+    fn create_mpd_update_table(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "create table if not exists mpd_update (
+                id integer primary key check (id = 0),
+                db_update integer not null
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// The time of the MPD database update that
+    /// [update_new_songs](Self::update_new_songs) last caught up with, if any.
+    fn last_mpd_update(&self) -> Result<Option<Duration>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare("select db_update from mpd_update where id = 0")?;
+        let db_update = query
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .next()
+            .transpose()?;
+        Ok(db_update.map(|d| Duration::from_secs(d as u64)))
+    }
+
+    fn set_last_mpd_update(&self, db_update: Duration) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "insert or replace into mpd_update (id, db_update) values (0, ?1)",
+            [db_update.as_secs() as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Analyze the songs that were added to MPD since its database was last
+    /// updated, leaving the rest of the library untouched.
+    ///
+    /// Does nothing if MPD's database did not change since the last time,
+    /// so it is cheap enough to run before every playlist. Returns the number
+    /// of songs that were analyzed.
+    fn update_new_songs(&mut self) -> Result<usize> {
+        let db_update = self.mpd_conn()?.stats()?.db_update;
+        if self.last_mpd_update()? == Some(db_update) {
+            return Ok(0);
         }
-        let mut songs = self.library.songs_from_library()?;
+        let analyzed = self.analyzed_paths()?;
+        let new_paths = self
+            .get_songs_paths()?
+            .into_iter()
+            .filter(|p| !analyzed.contains(p))
+            .collect::<Vec<_>>();
+        let number_songs = new_paths.len();
+        if number_songs > 0 {
+            info!("Analyzing {} new song(s) first.", number_songs);
+            self.analyze_embedded_cues(&new_paths)?;
+            self.update_library_paths(new_paths, false, true)?;
+            self.retry_failed_songs()?;
+            self.mark_degenerate_songs()?;
+            self.sync_genres()?;
+        }
+        self.set_last_mpd_update(db_update)?;
+        Ok(number_songs)
+    }
 
-        let mut playlist = mpd_conn
-            .queue()?
-            .iter()
-            .map(|s| self.mpd_to_bliss_song(s))
-            .collect::<Result<Option<Vec<LibrarySong<()>>>>>()?
-            .with_context(|| {
-                "No song is currently playing. Add a song to start the \
-                playlist from, and try again."
-            })?;
-        songs.retain(|s| !playlist.contains(s));
-        println!(
-            "The three closest songs will be displayed. Input '1' or 'Enter' \
-            to queue the first one, '2' to queue the second one, and '3' \
-            for the third one. 'q' or ctrl + c quits the session when you're \
-            done.",
-        );
-        while songs.len() > number_choices {
-            if !playlist.is_empty() {
-                println!(
-                    "Current playlist:\n{}\n",
-                    playlist
-                        .iter()
-                        .map(|s| format!(
-                            "\t{} - {}'",
-                            s.to_owned()
-                                .bliss_song
-                                .artist
-                                .unwrap_or_else(|| String::from("No artist")),
-                            s.to_owned()
-                                .bliss_song
-                                .title
-                                .unwrap_or_else(|| String::from("No title"))
-                        ))
-                        .collect::<Vec<String>>()
-                        .join("\n")
-                );
-            }
-            songs.sort_by_cached_key(|song| {
-                n32(euclidean_distance(
-                    &current_song.bliss_song.analysis.as_arr1(),
-                    &song.bliss_song.analysis.as_arr1(),
-                ))
-            });
-            // TODO put a proper dedup here
-            //dedup_playlist(&mut songs, None);
-            for (i, song) in songs[1..number_choices + 1].iter().enumerate() {
-                println!(
-                    "{}: '{} - {}'",
-                    i + 1,
-                    song.bliss_song
-                        .artist
-                        .as_ref()
-                        .unwrap_or(&String::from("<No artist>")),
-                    song.bliss_song
-                        .title
-                        .as_ref()
-                        .unwrap_or(&String::from("<No title>")),
-                );
-            }
+    /// `directory` relative to MPD's base path, without leading or trailing
+    /// slashes, whether it is given relative to it already or not.
+    fn mpd_directory(&self, directory: &str) -> String {
+        self.library
+            .config
+            .strip_base_path(Path::new(directory))
+            .unwrap_or_else(|| PathBuf::from(directory))
+            .to_string_lossy()
+            .trim_matches('/')
+            .to_owned()
+    }
 
-            use std::io::stdin;
-            let mut stdout = io::stdout().into_raw_mode().unwrap();
-            let stdin = stdin();
-            let mut next_song = None;
-            let number_choices_digit = char::from_digit(number_choices as u32, 10).unwrap();
-            for key in stdin.keys() {
-                next_song = if let Ok(key) = key {
-                    match key {
-                        termion::event::Key::Char('1') | termion::event::Key::Char('\n') => {
-                            let mpd_song = self.bliss_song_to_mpd(&songs[1])?;
-                            mpd_conn.push(mpd_song)?;
-                            let song = songs.remove(1);
-                            playlist.push(song.to_owned());
-                            Some(song)
-                        }
-                        termion::event::Key::Char(c @ '2'..='9') if c <= number_choices_digit => {
-                            let song = &songs[char::to_digit(c, 10).unwrap() as usize];
-                            let mpd_song = self.bliss_song_to_mpd(song)?;
-                            mpd_conn.push(mpd_song)?;
-                            let song = songs.remove(char::to_digit(c, 10).unwrap() as usize);
-                            playlist.push(song.to_owned());
-                            Some(song)
-                        }
-                        termion::event::Key::Char('q') | termion::event::Key::Ctrl('c') => None,
-                        _ => continue,
-                    }
-                } else {
-                    continue;
-                };
-                break;
+    /// Analyze the new songs in `directory`, relative to MPD's base path, and
+    /// remove the songs that were deleted from it, leaving the rest of the
+    /// library untouched.
+    fn update_directory(&mut self, directory: &str) -> Result<()> {
+        let directory = self.mpd_directory(directory);
+        let paths = self.get_songs_paths_in(Some(&directory))?;
+        if paths.is_empty() {
+            bail!(
+                "MPD doesn't know about any song in '{}'. Make sure it is relative to MPD's base path, and that MPD's database is up to date.",
+                directory
+            );
+        }
+        let directory_path = self.library.config.resolve_mpd_path(Path::new(&directory));
+        self.delete_stale_songs(&directory_path, &paths)?;
+        self.remove_changed_songs(&paths)?;
+        self.analyze_embedded_cues(&paths)?;
+        self.update_library_paths(paths, false, true)?;
+        self.retry_failed_songs()?;
+        self.mark_degenerate_songs()?;
+        self.sync_genres()?;
+        Ok(())
+    }
+
+    /// Analyze the songs at `paths` again, even if they are already in the
+    /// database, and update their rows only.
+    ///
+    /// `paths` can be CUE tracks, like `album.cue/CUE_TRACK003` or
+    /// `album.flac/CUE_TRACK003`: their CUE sheet is decoded, since tracks
+    /// can't be decoded on their own, but the other tracks of the sheet are
+    /// left untouched. Returns the number of
+    /// songs that failed to be analyzed.
+    fn reanalyze(&mut self, paths: &[PathBuf]) -> Result<usize> {
+        let mut files: Vec<PathBuf> = vec![];
+        for path in paths {
+            let file = cue_sheet_path(path).unwrap_or(path).to_owned();
+            if !files.contains(&file) {
+                files.push(file);
             }
-            if next_song.is_none() {
-                break;
+        }
+        // Tracks of FLAC files with an embedded CUE sheet or of files with a
+        // sidecar CUE sheet, as opposed to the tracks of a CUE sheet.
+        let (embedded, files): (Vec<_>, Vec<_>) = files.into_iter().partition(|f| {
+            paths.iter().any(|p| cue_sheet_path(p) == Some(f))
+                && !f.extension().is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+        });
+        let number_cores = self.library.config.base_config.number_cores;
+        let mut results =
+            Decoder::analyze_paths_with_cores(files, number_cores).collect::<Vec<_>>();
+        for file in embedded {
+            results.extend(self.analyze_file_tracks(&file)?);
+        }
+        let mut analyzed = HashSet::new();
+        let mut failed = 0;
+        for (file, result) in results {
+            match result {
+                Ok(song) => {
+                    if !paths.contains(&song.path) {
+                        continue;
+                    }
+                    analyzed.insert(song.path.to_owned());
+                    self.store_analyzed_song(song)?;
+                }
+                Err(e) => {
+                    for path in paths
+                        .iter()
+                        .filter(|p| **p == file || cue_sheet_path(p) == Some(&file))
+                    {
+                        warn!("Analysis of {} failed: {}", path.display(), e);
+                        self.store_failed_analysis(path, BlissError::DecodingError(e.to_string()))?;
+                        analyzed.insert(path.to_owned());
+                        failed += 1;
+                    }
+                }
             }
-            current_song = next_song.unwrap();
-            write!(stdout, "{}", termion::clear::All).unwrap();
         }
-        Ok(())
+        if let Some(missing) = paths.iter().find(|p| !analyzed.contains(*p)) {
+            bail!(
+                "Could not find {} while analyzing it. If it is a CUE track, make sure the CUE sheet has that track.",
+                missing.display()
+            );
+        }
+        failed += self.mark_degenerate_songs()?;
+        self.sync_genres()?;
+        Ok(failed)
     }
-}
 
-fn parse_number_cores(matches: &ArgMatches) -> Result<Option<NonZeroUsize>, BlissError> {
-    matches
-        .value_of("number-cores")
-        .map(|x| x.parse::<NonZeroUsize>())
-        .map_or(Ok(None), |r| r.map(Some))
-        .map_err(|_| BlissError::ProviderError(String::from("Number of cores must be positive")))
-}
+    /// Remove the songs of `directory` that are not in `paths` from the
+    /// database, returning the number of songs removed.
+    ///
+    /// `directory` and `paths` include MPD's base path, and CUE sheets are
+    /// listed instead of their tracks, as in [get_songs_paths](Self::get_songs_paths).
+    fn delete_stale_songs(&mut self, directory: &Path, paths: &[String]) -> Result<usize> {
+        let mut directory = directory.to_string_lossy().to_string();
+        if !directory.ends_with('/') {
+            directory.push('/');
+        }
+        let paths = paths.iter().collect::<HashSet<_>>();
+        let stale_paths = {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut stmt = sqlite_conn.prepare(
+                "select path, coalesce(cue_path, path) from song
+                where substr(path, 1, length(?1)) = ?1",
+            )?;
+            let rows = stmt
+                .query_map([&directory], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            rows.into_iter()
+                .filter(|(_, file)| !paths.contains(file))
+                .map(|(path, _)| path)
+                .collect::<Vec<String>>()
+        };
+        self.library.delete_paths(stale_paths)
+    }
 
-fn main() -> Result<()> {
-    env_logger::init_from_env(env_logger::Env::default().filter_or("RUST_LOG", "warn"));
-    let config_argument = Arg::with_name("config-path")
-            .short("c")
-            .long("config-path")
-            .help(
-                "Optional argument specifying the configuration path, for both loading and initializing blissify. Example: \"/path/to/config.json\". If not specified, defaults to \"XDG_CONFIG_HOME/bliss-rs/config.json\", e.g. \"/home/user/.config/bliss-rs/config.json\".",
+    /// Count the songs in the database, returning the number of songs that
+    /// were analyzed successfully, and the number of songs that failed to be.
+    fn count_songs(&self) -> Result<(u64, u64)> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let count = |analyzed: bool| {
+            sqlite_conn.query_row(
+                "select count(*) from song where analyzed = ?1",
+                [analyzed],
+                |row| row.get::<_, u64>(0),
             )
-            .required(false)
-            .takes_value(true);
+        };
+        Ok((count(true)?, count(false)?))
+    }
 
-    let matches = App::new("blissify")
-        .version(env!("CARGO_PKG_VERSION"))
-        .author("Polochon_street")
-        .about("Analyze and make smart playlists from an MPD music database.")
-        .arg(config_argument.clone().hidden(true))
-        .subcommand(
-            SubCommand::with_name("list-db")
-            .about("Print songs that have been analyzed and are in blissify's database.")
-            .arg(Arg::with_name("detailed").long("detailed")
-                .takes_value(false)
-                .help("Display analyzed song paths, as well as the corresponding analysis.")
-            )
-            .arg(config_argument.clone())
-        )
-        .subcommand(
-            SubCommand::with_name("init")
-            .about(
-                "Initialize blissify on an MPD library.\n\
-                By default, it creates the configuration folder \"bliss-rs\" \
-                in XDG_CONFIG_HOME, most likely `/home/user/.config/bliss-rs`, \
-                and analyzes the songs in the given MPD library.\n\
-                It can take some time."
-            )
-            .arg(Arg::with_name("MPD_BASE_PATH")
-                .help("MPD base path. The value of `music_directory` in your mpd.conf.")
-                .required(true)
-            )
-            .arg(config_argument.clone())
-            .arg(Arg::with_name("database-path")
-                .short("d")
-                .long("database-path")
-                .help(
-                    "Optional argument specifying where to store the database containing analyzed songs. Example: \"/path/to/bliss.db\". If not specified, defaults to \"XDG_CONFIG_HOME/bliss-rs/songs.db\", e.g. \"/home/user/.config/bliss-rs/songs.db\"."
+    /// Whether `song` is one of the MPD files in `queued_files`.
+    fn is_queued(&self, song: &LibrarySong<()>, queued_files: &HashSet<String>) -> bool {
+        !queued_files.is_empty()
+            && self
+                .bliss_song_to_mpd(song)
+                .map(|s| queued_files.contains(&s.file))
+                .unwrap_or(false)
+    }
+
+    /// Create a new MPDLibrary object.
+    ///
+    /// This means creating the necessary folders and the database file
+    /// if it doesn't exist, as well as getting a connection to MPD ready.
+    fn new(
+        mpd_base_path: PathBuf,
+        config_path: Option<PathBuf>,
+        database_path: Option<PathBuf>,
+        number_cores: Option<NonZeroUsize>,
+    ) -> Result<Self> {
+        let config = Config::new(mpd_base_path, config_path, database_path, number_cores)?;
+        let library = Library::new(config)?;
+        let mut mpd_library = MPDLibrary {
+            library,
+            mpd_conn: None,
+            user: None,
+            server: None,
+            server_address: None,
+        };
+        mpd_library.migrate()?;
+        mpd_library.connect()?;
+        Ok(mpd_library)
+    }
+
+    /// Get new MPDLibrary object from an existing configuration.
+    ///
+    /// This means creating the necessary folders and the database file
+    /// if it doesn't exist, as well as getting a connection to MPD ready.
+    fn from_config_path(config_path: Option<PathBuf>) -> Result<Self> {
+        let mut mpd_library = Self::from_config_path_offline(config_path)?;
+        mpd_library.connect()?;
+        Ok(mpd_library)
+    }
+
+    /// Get new MPDLibrary object from an existing configuration, without
+    /// connecting to MPD.
+    ///
+    /// Only the commands that work purely from blissify's database, like
+    /// dry runs, can be used on the resulting object.
+    fn from_config_path_offline(config_path: Option<PathBuf>) -> Result<Self> {
+        let library = Library::from_config_path(config_path)?;
+        let mpd_library = MPDLibrary {
+            library,
+            mpd_conn: None,
+            user: None,
+            server: None,
+            server_address: None,
+        };
+        mpd_library.migrate()?;
+        let removed = mpd_library.remove_incomplete_songs()?;
+        if removed > 0 {
+            warn!(
+                "Removed {} song(s) whose analysis was interrupted, they will be analyzed again on the next update.",
+                removed
+            );
+        }
+        Ok(mpd_library)
+    }
+
+    /// The [Profile] of the user selected with `--user`, if they have one.
+    fn profile(&self) -> Option<&Profile> {
+        self.user
+            .as_ref()
+            .and_then(|u| self.library.config.profiles.get(u))
+    }
+
+    /// The default `playlist` options of the current user.
+    fn playlist_defaults(&self) -> &PlaylistDefaults {
+        self.profile()
+            .and_then(|p| p.playlist.as_ref())
+            .unwrap_or(&self.library.config.playlist)
+    }
+
+    /// How much the current user's feedback biases playlists.
+    fn feedback_weight(&self) -> f32 {
+        self.profile()
+            .and_then(|p| p.feedback_weight)
+            .unwrap_or(self.library.config.feedback_weight)
+    }
+
+    /// The current user's learned metric, used by the mahalanobis distance.
+    fn metric_matrix(&self) -> &Array2<f32> {
+        self.profile()
+            .and_then(|p| p.m.as_ref())
+            .unwrap_or(&self.library.config.base_config.m)
+    }
+
+    /// Connect to MPD, replacing the existing connection if there is one.
+    fn connect(&mut self) -> Result<()> {
+        self.mpd_conn = Some(Arc::new(Mutex::new(Self::get_mpd_conn(
+            &self.mpd_address()?,
+        )?)));
+        Ok(())
+    }
+
+    /// Get the connection to MPD, or an error if blissify is running offline.
+    fn mpd_conn(&self) -> Result<MutexGuard<'_, MPDClient>> {
+        match &self.mpd_conn {
+            Some(mpd_conn) => Ok(mpd_conn.lock().unwrap()),
+            None => {
+                bail!("This needs a connection to MPD, which is not available in offline mode.")
+            }
+        }
+    }
+
+    /// Remove the contents of the current database, and analyze all
+    /// MPD's songs again.
+    ///
+    /// Useful in case the database got corrupted somehow.
+    fn full_rescan(&mut self) -> Result<()> {
+        self.library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch("begin; delete from feature; delete from song; commit;")?;
+        let paths = self.get_songs_paths()?;
+        self.analyze_rescanned_paths(paths)
+    }
+
+    /// Analyze the MPD songs at `paths`, whose rows were just removed by a
+    /// rescan.
+    fn analyze_rescanned_paths(&mut self, paths: Vec<String>) -> Result<()> {
+        self.analyze_embedded_cues(&paths)?;
+        // bliss would analyze files with an embedded CUE sheet again.
+        let analyzed = self.analyzed_paths()?;
+        let paths = paths
+            .into_iter()
+            .filter(|p| !analyzed.contains(p))
+            .collect::<Vec<_>>();
+        let paths = self.skip_large_files(paths)?;
+        if self.library.config.isolate_analysis {
+            self.analyze_isolated(paths.into_iter().map(PathBuf::from).collect())?;
+        } else {
+            self.library.analyze_paths(paths, true)?;
+        }
+        self.retry_failed_songs()?;
+        self.mark_degenerate_songs()?;
+        self.sync_genres()?;
+        Ok(())
+    }
+
+    /// Turn MPD's random mode off if `options.disable_random` is set, or
+    /// warn the user that it is enabled otherwise. Also warns about single
+    /// mode, which stops MPD before the playlist plays.
+    ///
+    /// Returns whether random mode was enabled in the first place, so it can
+    /// be restored with [restore_random_mode](Self::restore_random_mode).
+    fn handle_random_mode(mpd_conn: &mut MPDClient, options: &PlaylistOptions) -> Result<bool> {
+        let status = mpd_conn.status()?;
+        if status.single {
+            warn!("Single mode is enabled for MPD, so it will stop after the current song instead of playing the playlist.");
+        }
+        let random = status.random;
+        if random {
+            if !options.disable_random {
+                warn!("Random mode is enabled for MPD, you might want to turn it off to get the most out of your playlist (see --disable-random).");
+            } else if !options.dry_run {
+                mpd_conn.random(false)?;
+            }
+        }
+        Ok(random)
+    }
+
+    /// Turn MPD's random mode back on if it was turned off by
+    /// [handle_random_mode](Self::handle_random_mode) and the user asked for
+    /// it to be restored.
+    fn restore_random_mode(
+        mpd_conn: &mut MPDClient,
+        was_random: bool,
+        options: &PlaylistOptions,
+    ) -> Result<()> {
+        if was_random && options.disable_random && options.restore_random && !options.dry_run {
+            mpd_conn.random(true)?;
+        }
+        Ok(())
+    }
+
+    /// Run `change` between [handle_random_mode](Self::handle_random_mode)
+    /// and [restore_random_mode](Self::restore_random_mode), so that random
+    /// mode is restored even if `change` fails.
+    fn with_random_mode(
+        mpd_conn: &mut MPDClient,
+        options: &PlaylistOptions,
+        change: impl FnOnce(&mut MPDClient) -> Result<()>,
+    ) -> Result<()> {
+        let was_random = Self::handle_random_mode(mpd_conn, options)?;
+        let result = change(mpd_conn);
+        let restored = Self::restore_random_mode(mpd_conn, was_random, options);
+        // The error of `change` matters more than the one of restoring.
+        result?;
+        restored
+    }
+
+    /// The position of `mpd_song` in the queue now, which can differ from
+    /// its position when it was fetched, e.g. when MPD's consume mode removed
+    /// the songs before it in the meantime.
+    fn queue_position(mpd_conn: &mut MPDClient, mpd_song: &MPDSong) -> Result<u32> {
+        let place = mpd_song
+            .place
+            .with_context(|| format!("{} is not in the queue.", mpd_song.file))?;
+        match mpd_conn.playlistid(place.id)?.and_then(|s| s.place) {
+            Some(place) => Ok(place.pos),
+            None => bail!(
+                "{} was removed from the queue while making the playlist, please try again.",
+                mpd_song.file
+            ),
+        }
+    }
+
+    /// Remove songs from MPD's queue until it has at most `max_queue_length`
+    /// songs: first the songs that were already played, from the top, then
+    /// the songs at the end of the queue if that's not enough.
+    ///
+    /// Returns the number of songs removed.
+    fn trim_queue(mpd_conn: &mut MPDClient, max_queue_length: usize) -> Result<usize> {
+        let status = mpd_conn.status()?;
+        let excess = (status.queue_len as usize).saturating_sub(max_queue_length);
+        if excess == 0 {
+            return Ok(0);
+        }
+        let played = status.song.map_or(0, |s| s.pos as usize);
+        let from_top = excess.min(played);
+        if from_top > 0 {
+            mpd_conn.delete(0..from_top as u32)?;
+        }
+        if excess > from_top {
+            warn!(
+                "Removing {} song(s) from the end of the queue to keep it under {} songs.",
+                excess - from_top,
+                max_queue_length,
+            );
+            mpd_conn.delete(max_queue_length as u32..)?;
+        }
+        Ok(excess)
+    }
+
+    /// Store the distance of each song of `playlist` to `seeds` in the
+    /// [DISTANCE_STICKER] MPD sticker, so MPD clients can display why a song
+    /// was chosen.
+    fn publish_stickers(
+        &self,
+        mpd_conn: &mut MPDClient,
+        playlist: &[LibrarySong<()>],
+        seeds: &[LibrarySong<()>],
+        distance: &dyn DistanceMetricBuilder,
+    ) -> Result<()> {
+        for (song, distance) in playlist
+            .iter()
+            .zip(playlist_distances(playlist, seeds, distance))
+        {
+            let mpd_song = self.bliss_song_to_mpd(song)?;
+            mpd_conn.set_sticker(
+                "song",
+                &mpd_song.file,
+                DISTANCE_STICKER,
+                &format!("{:.6}", distance),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Remove the [DISTANCE_STICKER] sticker from all the songs of the MPD
+    /// database, returning the number of songs it was removed from.
+    fn clear_stickers(&self) -> Result<usize> {
+        let mut mpd_conn = self.mpd_conn()?;
+        let stickers = mpd_conn.find_sticker("song", "", DISTANCE_STICKER)?;
+        for (uri, _) in &stickers {
+            mpd_conn.delete_sticker("song", uri, DISTANCE_STICKER)?;
+        }
+        Ok(stickers.len())
+    }
+
+    /// Get the `number_albums` albums closest to `album`, from the closest to
+    /// the furthest, using the same album distance as
+    /// [queue_from_current_album](Self::queue_from_current_album).
+    fn similar_albums(&self, album: &str, number_albums: usize) -> Result<Vec<AlbumOutput>> {
+        if self.library.songs_from_album::<()>(album)?.is_empty() {
+            bail!(
+                "Album '{}' is not in blissify's database. Please check its name, and make sure it is analyzed.",
+                album
+            );
+        }
+        let playlist = self
+            .library
+            .album_playlist_from::<()>(album.to_owned(), number_albums + 1)?;
+        let mut albums: Vec<AlbumOutput> = vec![];
+        for song in playlist {
+            let song_album = match &song.bliss_song.album {
+                Some(a) if a != album => a,
+                _ => continue,
+            };
+            match albums.last_mut() {
+                Some(last) if &last.album == song_album => last.number_songs += 1,
+                _ => albums.push(AlbumOutput {
+                    album: song_album.to_owned(),
+                    artist: song
+                        .bliss_song
+                        .album_artist
+                        .to_owned()
+                        .or_else(|| song.bliss_song.artist.to_owned()),
+                    number_songs: 1,
+                }),
+            }
+        }
+        albums.truncate(number_albums);
+        Ok(albums)
+    }
+
+    /// Compute the distance between two albums, as used to make album
+    /// playlists: the distance between the mean analyses of their tracks.
+    ///
+    /// Also returns each track's distance to the other album, to show which
+    /// tracks make the albums sound different.
+    fn compare_albums(&self, first: &str, second: &str) -> Result<AlbumComparison> {
+        let songs = |album: &str| -> Result<Vec<LibrarySong<()>>> {
+            let songs = self.library.songs_from_album(album)?;
+            if songs.is_empty() {
+                bail!(
+                    "Album '{}' is not in blissify's database. Please check its name, and make sure it is analyzed.",
+                    album
+                );
+            }
+            Ok(songs)
+        };
+        let (first_songs, second_songs) = (songs(first)?, songs(second)?);
+        let (first_mean, second_mean) = (
+            album_mean(first_songs.iter()),
+            album_mean(second_songs.iter()),
+        );
+
+        let mut tracks = first_songs
+            .iter()
+            .map(|s| (s, &second_mean))
+            .chain(second_songs.iter().map(|s| (s, &first_mean)))
+            .map(|(song, other_mean)| SongOutput {
+                distance: Some(euclidean_distance(
+                    &song.bliss_song.analysis.as_arr1(),
+                    other_mean,
+                )),
+                ..SongOutput::new(song)
+            })
+            .collect::<Vec<_>>();
+        tracks.sort_by(|a, b| b.distance.partial_cmp(&a.distance).unwrap());
+        Ok(AlbumComparison {
+            distance: euclidean_distance(&first_mean, &second_mean),
+            tracks,
+        })
+    }
+
+    /// Make a playlist composed of albums similar to the album that's currently playing,
+    /// and queue them.
+    ///
+    /// # Parameters
+    ///
+    /// - `number_albums`: The number of albums to queue
+    /// - `options`: The dry-run, queue-keeping and random mode options, see
+    ///   [PlaylistOptions]. If `keep_queue` is false, will remove the content of the
+    ///   current queue save for the currently playing album, and will queue the playlist
+    ///   after the last song of the current album. If true, will queue the playlist after
+    ///   the last song of the current album, but will keep the queue intact.
+    ///   `after_current` decides how much of the current album is played before
+    ///   the next albums, and `restart_current_album` whether it is played
+    ///   from its first track again.
+    fn queue_from_current_album(
+        &self,
+        number_albums: usize,
+        options: &PlaylistOptions,
+    ) -> Result<()> {
+        let mut mpd_conn = self.mpd_conn()?;
+        let mpd_song = match mpd_conn.currentsong()? {
+            Some(s) => s,
+            None => bail!("No song is currently playing. Add a song to start the playlist from, and try again."),
+        };
+
+        let current_song = self.mpd_to_bliss_song(&mpd_song)?.with_context(|| {
+            "The song currently playing could not be found in blissify's library. Please analyze it, and try again."
+        })?;
+        let current_album = current_song.bliss_song.album.ok_or_else(|| {
+            BlissError::ProviderError(String::from(
+                "The current song does not have any album information.",
+            ))
+        })?;
+        let playlist = self
+            .library
+            .album_playlist_from::<()>(current_album.clone(), number_albums)?;
+
+        // The playlist starts with the current album, in order.
+        let album_length = playlist
+            .iter()
+            .take_while(|s| s.bliss_song.album.as_ref() == Some(&current_album))
+            .count();
+        let (album_songs, next_albums) = playlist.split_at(album_length);
+        let current_disc = current_song.bliss_song.disc_number;
+        let same_disc = |disc: Option<i32>| match options.after_current {
+            AfterCurrent::Album => true,
+            AfterCurrent::Disc => disc == current_disc,
+            AfterCurrent::Track => false,
+        };
+        // If we don't want to keep the queue, we start the playlist where the
+        // currently playing track is playing, queueing what should be left of the
+        // current album before the next albums. We won't have any album leftovers to
+        // shift, since we're erasing the current queue and replacing it with our fresh one.
+        let (playlist, album_leftovers): (Vec<LibrarySong<()>>, usize) = if !options.keep_queue {
+            let current_index = album_songs
+                .iter()
+                .position(|s| s.bliss_song.path == current_song.bliss_song.path)
+                .ok_or(BlissError::ProviderError(String::from(
+                    "Could not find current song in playlist",
+                )))?;
+            let start = if options.restart_current_album {
+                0
+            } else {
+                current_index + 1
+            };
+            let playlist = album_songs[start..]
+                .iter()
+                .filter(|s| same_disc(s.bliss_song.disc_number))
+                .chain(next_albums)
+                .cloned()
+                .collect();
+            (playlist, 1)
+        }
+        // If we want to keep the queue, we should iterate on the current playlist
+        // until we find the end of what should be left of the current album, and
+        // set the beginning of it there, since we want to preserve the queue as
+        // much as possible.
+        else {
+            let queue_from_current_song = mpd_conn.songs(mpd_song.place.unwrap().pos..)?;
+            let album_leftovers = queue_from_current_song
+                .iter()
+                .enumerate()
+                .take_while(|(i, s)| {
+                    let tag = |name: &str| {
+                        s.tags
+                            .iter()
+                            .find(|(tagname, _)| tagname.eq_ignore_ascii_case(name))
+                            .map(|(_, value)| value.as_str())
+                    };
+                    // "Disc" tags can also hold the number of discs, like "1/2".
+                    let disc = tag("disc")
+                        .and_then(|d| d.split('/').next())
+                        .and_then(|d| d.trim().parse().ok());
+                    *i == 0 || (tag("album") == Some(&current_album) && same_disc(disc))
+                })
+                .count();
+            (next_albums.to_vec(), album_leftovers)
+        };
+
+        self.record_playlist(
+            &[current_song.bliss_song.path.to_owned()],
+            &playlist,
+            options,
+        )?;
+        if let Some(m3u_path) = &options.output_m3u {
+            write_m3u(&playlist, m3u_path)?;
+        }
+        if options.dry_run {
+            print_album_playlist(&playlist, album_songs, options)?;
+        }
+        if !options.modifies_queue() {
+            return Ok(());
+        }
+
+        let mpd_songs = playlist
+            .iter()
+            .map(|s| self.bliss_song_to_mpd(s))
+            .collect::<Result<Vec<_>>>()?;
+        Self::with_random_mode(&mut mpd_conn, options, |mpd_conn| {
+            self.change_queue(mpd_conn, |mpd_conn| {
+                let mut current_pos = Self::queue_position(mpd_conn, &mpd_song)?;
+
+                // Delete everything except the current song and the pinned songs
+                // if we don't want to keep the queue.
+                if !options.keep_queue {
+                    current_pos = self.clear_queue_except(mpd_conn, current_pos)?;
+                }
+                // Add songs to the queue from the built playlist, starting either
+                // from the current song or from the beginning of the next album
+                self.add_to_queue(mpd_conn, &mpd_songs, Some(current_pos))?;
+                let new_pos = current_pos + playlist.len() as u32;
+                // Put back the songs from the current album that were shifted around
+                mpd_conn.shift(
+                    new_pos..new_pos + album_leftovers as u32,
+                    current_pos.try_into()?,
+                )?;
+                if let Some(max_queue_length) = options.max_queue_length {
+                    Self::trim_queue(mpd_conn, max_queue_length)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Queue the album that fits a song best, i.e. whose mean analysis is the
+    /// closest to it, in the album's order, after the song.
+    ///
+    /// # Parameters
+    ///
+    /// - `song_path`: The path to the song to find an album for, as for
+    ///   [queue_from_song](Self::queue_from_song). Defaults to the currently
+    ///   playing song.
+    /// - `options`: The dry-run, queue-keeping and random mode options, see
+    ///   [PlaylistOptions]. If `keep_queue` is false, will remove the content
+    ///   of the queue save for the current song before queueing the album.
+    fn queue_one_album(&self, song_path: Option<&str>, options: &PlaylistOptions) -> Result<()> {
+        let mpd_song = if song_path.is_none() || options.modifies_queue() {
+            match self.mpd_conn()?.currentsong()? {
+                Some(s) => Some(s),
+                None => bail!("No song is currently playing. Add a song to start the playlist from, and try again."),
+            }
+        } else {
+            None
+        };
+        let path = match (song_path, &mpd_song) {
+            (Some(path), _) => self.resolve_song_path(path)?,
+            (None, Some(mpd_song)) => self.mpd_to_bliss_path(mpd_song)?,
+            (None, None) => unreachable!(),
+        };
+        let song = self
+            .library
+            .song_from_path::<()>(&path.to_string_lossy())
+            .with_context(|| {
+                format!(
+                    "{} could not be found in blissify's library. Please analyze it, and try again.",
+                    path.display()
                 )
-                .required(false)
-                .takes_value(true)
-            )
-            .arg(Arg::with_name("number-cores")
-                .long("number-cores")
-                .help(
-                    "Number of CPU cores the analysis should use (defaults to the number of cores the CPU has).
-Useful to avoid a too heavy load on a machine.")
-                .required(false)
-                .takes_value(true)
-            )
-        )
-        .subcommand(
-            SubCommand::with_name("rescan")
-            .arg(config_argument.clone())
-            .arg(Arg::with_name("number-cores")
-                .long("number-cores")
-                .help(
-                    "Number of CPU cores the analysis should use (defaults to the number of cores the CPU has).
-Useful to avoid a too heavy load on a machine.")
-                .required(false)
-                .takes_value(true)
-            )
-            .about("(Re)scan completely an MPD library")
-        )
-        .subcommand(
-            SubCommand::with_name("update")
-            .arg(config_argument.clone())
-            .arg(Arg::with_name("number-cores")
-                .long("number-cores")
-                .help(
-                    "Number of CPU cores the analysis should use (defaults to the number of cores the CPU has).
-Useful to avoid a too heavy load on a machine.")
-                .required(false)
-                .takes_value(true)
-            )
-            .about("Scan new songs that were added to the MPD library since last scan.")
+            })?;
+        let album = match closest_album(&song, &self.library.songs_from_library()?) {
+            Some(album) => album,
+            None => bail!("There is no other album in blissify's database to queue."),
+        };
+        let playlist = self.library.songs_from_album::<()>(&album)?;
+
+        self.record_playlist(&[path.to_owned()], &playlist, options)?;
+        if let Some(m3u_path) = &options.output_m3u {
+            write_m3u(&playlist, m3u_path)?;
+        }
+        if options.dry_run {
+            print_playlist(&playlist, &[], &euclidean_distance, options)?;
+        }
+        let mpd_song = match mpd_song {
+            Some(mpd_song) if options.modifies_queue() => mpd_song,
+            _ => return Ok(()),
+        };
+
+        let mpd_songs = playlist
+            .iter()
+            .map(|s| self.bliss_song_to_mpd(s))
+            .collect::<Result<Vec<_>>>()?;
+        let mut mpd_conn = self.mpd_conn()?;
+        Self::with_random_mode(&mut mpd_conn, options, |mpd_conn| {
+            self.change_queue(mpd_conn, |mpd_conn| {
+                let mut current_pos = Self::queue_position(mpd_conn, &mpd_song)?;
+                if !options.keep_queue {
+                    current_pos = self.clear_queue_except(mpd_conn, current_pos)?;
+                }
+                self.add_to_queue(mpd_conn, &mpd_songs, Some(current_pos + 1))?;
+                if let Some(max_queue_length) = options.max_queue_length {
+                    Self::trim_queue(mpd_conn, max_queue_length)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Make a playlist made of songs that are similar to the songs currently
+    /// in MPD playlist, and queue these songs after the last one.
+    /// Works better with extended_isolation_forest as the distance metric.
+    ///
+    /// # Parameters
+    ///
+    /// - `number_songs`: The number of songs to queue.
+    /// - `distance`: The distance metric used to compute distances between the current
+    ///   playlist and the songs, see [bliss_audio::playlist] for details on distance metrics.
+    ///   [extended_isolation_forest] works better than the other for this
+    ///   kind of comparison.
+    /// - `sort_by`: A closure that does the actual sorting of the playlist in place, based on
+    ///   the `distance` metric chosen, see [bliss_audio::playlist::closest_to_songs] for instance
+    ///   for details on sorting algorithms.
+    /// - `options`: The deduplication and dry-run options, see [PlaylistOptions].
+    fn queue_from_current_playlist<'a, F, I>(
+        &self,
+        number_songs: usize,
+        distance: &'a dyn DistanceMetricBuilder,
+        sort_by: F,
+        options: &PlaylistOptions,
+    ) -> Result<()>
+    where
+        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
+        I: Iterator<Item = LibrarySong<()>> + 'a,
+    {
+        let mpd_songs = self.mpd_conn()?.queue()?;
+
+        if mpd_songs.is_empty() {
+            bail!("No song is currently playing. Add a song to start the playlist from, and try again.");
+        }
+        let paths = mpd_songs
+            .iter()
+            .map(|s| {
+                self.mpd_to_bliss_path(s)
+                    .map(|s| s.to_string_lossy().to_string())
+            })
+            .collect::<Result<Vec<String>, _>>()?;
+        self.queue_from_paths(&paths, number_songs, distance, sort_by, options)
+    }
+
+    /// Make a playlist made of songs that are similar to the songs in `paths`,
+    /// and queue these songs after the last song of the queue.
+    ///
+    /// # Parameters
+    ///
+    /// - `paths`: The paths of the songs to make the playlist from, as stored in
+    ///   blissify's database. See [resolve_song_path](Self::resolve_song_path) to
+    ///   get them from user-provided paths.
+    /// - `number_songs`, `distance`, `sort_by` and `options`: see
+    ///   [queue_from_current_playlist](Self::queue_from_current_playlist).
+    fn queue_from_paths<'a, F, I>(
+        &self,
+        paths: &[String],
+        number_songs: usize,
+        distance: &'a dyn DistanceMetricBuilder,
+        sort_by: F,
+        options: &PlaylistOptions,
+    ) -> Result<()>
+    where
+        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
+        I: Iterator<Item = LibrarySong<()>> + 'a,
+    {
+        if paths.is_empty() {
+            bail!("No song to start the playlist from was given, please give at least one, and try again.");
+        }
+        let paths = paths.iter().map(|s| &**s).collect::<Vec<&str>>();
+        let queued_files: HashSet<String> = if options.skip_queued {
+            self.mpd_conn()?
+                .queue()?
+                .into_iter()
+                .map(|s| s.file)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        let seeds = if options.show_distance
+            || options.publish_stickers
+            || options.tie_break.is_some()
+            || options.max_average_distance.is_some()
+            || options.avoid_seed_album.is_some()
+        {
+            paths
+                .iter()
+                .map(|p| self.library.song_from_path(p))
+                .collect::<Result<Vec<LibrarySong<()>>>>()?
+        } else {
+            vec![]
+        };
+        let seed_paths = paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        let (songs, filter_counts) = self.filter_playlist(
+            tie_break_playlist(
+                self.playlist_from_seeds(&paths, distance, sort_by, options)?,
+                &seeds,
+                distance,
+                options.tie_break,
+                options.tie_break_epsilon,
+            ),
+            options,
+            FilterContext {
+                seed_paths: &seed_paths,
+                seeds: &seeds,
+                queued_files: &queued_files,
+                number_songs,
+            },
+        );
+        let mut playlist: Vec<LibrarySong<_>> = songs.take(number_songs).collect();
+        if options.explain_filtering {
+            filter_counts.explain();
+        }
+        if options.reverse {
+            playlist.reverse();
+        }
+
+        check_playlist_distance(&playlist, &seeds, distance, options)?;
+        self.record_playlist(&seed_paths, &playlist, options)?;
+        if let Some(m3u_path) = &options.output_m3u {
+            write_m3u(&playlist, m3u_path)?;
+        }
+        if options.dry_run {
+            print_playlist(&playlist, &seeds, distance, options)?;
+        }
+        if !options.modifies_queue() {
+            return Ok(());
+        }
+
+        let mpd_songs = playlist
+            .iter()
+            .map(|s| self.bliss_song_to_mpd(s))
+            .collect::<Result<Vec<_>>>()?;
+        self.record_queued(paths[0], &playlist)?;
+        let mut mpd_conn = self.mpd_conn()?;
+        Self::with_random_mode(&mut mpd_conn, options, |mpd_conn| {
+            if options.publish_stickers {
+                self.publish_stickers(mpd_conn, &playlist, &seeds, distance)?;
+            }
+            self.change_queue(mpd_conn, |mpd_conn| {
+                self.add_to_queue(mpd_conn, &mpd_songs, None)?;
+                if let Some(max_queue_length) = options.max_queue_length {
+                    Self::trim_queue(mpd_conn, max_queue_length)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Make a playlist from an audio file that is not necessarily in the
+    /// library, like a song someone sent you, and queue it at the end of the
+    /// current queue.
+    ///
+    /// The file is analyzed on the fly without being stored, and the playlist
+    /// is only made of songs of the library.
+    ///
+    /// # Parameters
+    ///
+    /// - `file`: The path to the audio file to make the playlist from.
+    /// - `number_songs`, `distance`, `sort_by` and `options`: see
+    ///   [queue_from_current_playlist](Self::queue_from_current_playlist).
+    fn queue_from_file<'a, F, I>(
+        &self,
+        file: &Path,
+        number_songs: usize,
+        distance: &'a dyn DistanceMetricBuilder,
+        sort_by: F,
+        options: &PlaylistOptions,
+    ) -> Result<()>
+    where
+        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
+        I: Iterator<Item = LibrarySong<()>> + 'a,
+    {
+        let seed = LibrarySong {
+            bliss_song: Decoder::song_from_path(file)
+                .with_context(|| format!("while analyzing {}", file.display()))?,
+            extra_info: (),
+        };
+        let queued_files: HashSet<String> = if options.skip_queued {
+            self.mpd_conn()?
+                .queue()?
+                .into_iter()
+                .map(|s| s.file)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let seeds = vec![seed];
+        let songs = self.candidate_songs(options)?;
+        // The file might be in the library as well, in which case it goes
+        // through the filters as the song the playlist is made from, and is
+        // left out afterwards.
+        let seed_paths = [seeds[0].bliss_song.path.to_owned()];
+        let (songs, filter_counts) = self.filter_playlist(
+            tie_break_playlist(
+                sort_by(&seeds, &songs, distance),
+                &seeds,
+                distance,
+                options.tie_break,
+                options.tie_break_epsilon,
+            ),
+            options,
+            FilterContext {
+                seed_paths: &seed_paths,
+                seeds: &seeds,
+                queued_files: &queued_files,
+                number_songs,
+            },
+        );
+        let mut playlist: Vec<LibrarySong<_>> = songs
+            .filter(|s| s.bliss_song.path != seed_paths[0])
+            .take(number_songs)
+            .collect();
+        if options.explain_filtering {
+            filter_counts.explain();
+        }
+        if options.reverse {
+            playlist.reverse();
+        }
+
+        check_playlist_distance(&playlist, &seeds, distance, options)?;
+        self.record_playlist(&seed_paths, &playlist, options)?;
+        if let Some(m3u_path) = &options.output_m3u {
+            write_m3u(&playlist, m3u_path)?;
+        }
+        if options.dry_run {
+            print_playlist(&playlist, &seeds, distance, options)?;
+        }
+        if !options.modifies_queue() {
+            return Ok(());
+        }
+
+        let mpd_songs = playlist
+            .iter()
+            .map(|s| self.bliss_song_to_mpd(s))
+            .collect::<Result<Vec<_>>>()?;
+        // Skips are not recorded, since they are only used for seeds that
+        // are in the library.
+        let mut mpd_conn = self.mpd_conn()?;
+        Self::with_random_mode(&mut mpd_conn, options, |mpd_conn| {
+            if options.publish_stickers {
+                self.publish_stickers(mpd_conn, &playlist, &seeds, distance)?;
+            }
+            self.change_queue(mpd_conn, |mpd_conn| {
+                self.add_to_queue(mpd_conn, &mpd_songs, None)?;
+                if let Some(max_queue_length) = options.max_queue_length {
+                    Self::trim_queue(mpd_conn, max_queue_length)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Make a playlist composed of songs similar to the song that's currently playing,
+    /// and queue them.
+    ///
+    /// # Parameters
+    ///
+    /// - `song_path`: The path to the song to make a playlist from. Can be either an absolute
+    ///   path, i.e. `/home/user/Music/album/song.flac`, or a path relative to
+    ///   (mpd_base_path)[Config::mpd_base_path], like `album/song.flac`. If not specified,
+    ///   defaults to the currently playing song.
+    /// - `number_songs`: The number of songs to queue.
+    /// - `distance`: The distance metric used to compute distances between songs, see the
+    ///   [bliss_audio::playlist] for details on distance metrics.
+    /// - `sort_by`: A closure that does the actual sorting of the playlist in place, based on
+    ///   the `distance` metric chosen, see [bliss_audio::playlist::closest_to_songs] for instance
+    ///   for details on sorting algorithms.
+    /// - `options`: The deduplication, dry-run and queue-keeping options, see
+    ///   [PlaylistOptions].
+    // TODO do we want a flag to keep / exclude the current song from the playlist?
+    // TODO maybe we don't have to collect? But the magic at the end makes it very convenient
+    fn queue_from_song<'a, F, I>(
+        &self,
+        song_path: Option<&str>,
+        number_songs: usize,
+        distance: &'a dyn DistanceMetricBuilder,
+        sort_by: F,
+        options: &PlaylistOptions,
+    ) -> Result<()>
+    where
+        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
+        I: Iterator<Item = LibrarySong<()>> + 'a,
+    {
+        // The current song is only needed if the playlist starts from it, or
+        // if the queue is modified, so offline dry runs can start from `song_path`.
+        let mpd_song = if song_path.is_none() || options.modifies_queue() {
+            match self.mpd_conn()?.currentsong()? {
+                Some(s) => Some(s),
+                None => bail!("No song is currently playing. Add a song to start the playlist from, and try again."),
+            }
+        } else {
+            None
+        };
+        let path = match (song_path, &mpd_song) {
+            (Some(path), _) => self.resolve_song_path(path)?,
+            (None, Some(mpd_song)) => self.mpd_to_bliss_path(mpd_song)?,
+            (None, None) => unreachable!(),
+        };
+
+        // If we specified a song path on the CLI, chances are the song is not already
+        // in the queue (nor anywhere else).
+        // If we didn't, we're using the current_song, and chances are that the song is
+        // already in the queue, so we want to get an extra song there, since the current
+        // song doesn't count.
+        let number_songs = if song_path.is_some() {
+            number_songs
+        } else {
+            number_songs + 1
+        };
+        // The songs that will still be in the queue once the playlist is queued,
+        // save for the current song if it is the one the playlist starts from.
+        let queued_files: HashSet<String> = if options.skip_queued {
+            let queue = if options.keep_queue {
+                self.mpd_conn()?.queue()?
+            } else {
+                mpd_song.iter().cloned().collect()
+            };
+            let current_file = mpd_song.as_ref().map(|s| &s.file);
+            queue
+                .into_iter()
+                .map(|s| s.file)
+                .filter(|f| song_path.is_some() || Some(f) != current_file)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let seeds = if options.show_distance
+            || options.publish_stickers
+            || options.tie_break.is_some()
+            || options.max_average_distance.is_some()
+            || options.avoid_seed_album.is_some()
+        {
+            vec![self.library.song_from_path(&path.to_string_lossy())?]
+        } else {
+            vec![]
+        };
+        let seed_paths = [path.to_owned()];
+        let (songs, filter_counts) = self.filter_playlist(
+            tie_break_playlist(
+                self.playlist_from_seeds(
+                    &[&path.to_string_lossy().clone()],
+                    distance,
+                    sort_by,
+                    options,
+                )?,
+                &seeds,
+                distance,
+                options.tie_break,
+                options.tie_break_epsilon,
+            ),
+            options,
+            FilterContext {
+                seed_paths: &seed_paths,
+                seeds: &seeds,
+                queued_files: &queued_files,
+                number_songs,
+            },
+        );
+        let mut playlist: Vec<LibrarySong<_>> = songs.take(number_songs).collect();
+        if options.explain_filtering {
+            filter_counts.explain();
+        }
+        if options.reverse {
+            // The current song is already playing, so only the songs after it
+            // are reversed; a song from `song_path` ends the playlist instead.
+            if song_path.is_some() {
+                playlist.reverse();
+            } else if !playlist.is_empty() {
+                playlist[1..].reverse();
+            }
+        }
+
+        check_playlist_distance(&playlist, &seeds, distance, options)?;
+        self.record_playlist(&seed_paths, &playlist, options)?;
+        if let Some(m3u_path) = &options.output_m3u {
+            write_m3u(&playlist, m3u_path)?;
+        }
+        if options.dry_run {
+            print_playlist(&playlist, &seeds, distance, options)?;
+        }
+        let mpd_song = match mpd_song {
+            Some(mpd_song) if options.modifies_queue() => mpd_song,
+            _ => return Ok(()),
+        };
+
+        // If we're starting from a song specified in song_path, the
+        // playlist is pushed straight at the end, else the current song is
+        // already in the queue.
+        let queued = if song_path.is_some() {
+            &playlist[..]
+        } else {
+            &playlist[1..]
+        };
+        let mpd_songs = queued
+            .iter()
+            .map(|s| self.bliss_song_to_mpd(s))
+            .collect::<Result<Vec<_>>>()?;
+        self.record_queued(&path.to_string_lossy(), &playlist)?;
+        let mut mpd_conn = self.mpd_conn()?;
+        Self::with_random_mode(&mut mpd_conn, options, |mpd_conn| {
+            if options.publish_stickers {
+                self.publish_stickers(mpd_conn, &playlist, &seeds, distance)?;
+            }
+            self.change_queue(mpd_conn, |mpd_conn| {
+                let mut current_pos = Self::queue_position(mpd_conn, &mpd_song)?;
+                // Delete everything except the current song and the pinned songs
+                // if we don't want to keep the queue.
+                if !options.keep_queue {
+                    current_pos = self.clear_queue_except(mpd_conn, current_pos)?;
+                }
+
+                if song_path.is_some() {
+                    self.add_to_queue(mpd_conn, &mpd_songs, None)?;
+                } else {
+                    // Else, do some magic to preserve the queue depending on the
+                    // --keep-current-queue argument.
+                    self.add_to_queue(mpd_conn, &mpd_songs, Some(current_pos))?;
+                    let new_pos = current_pos + mpd_songs.len() as u32;
+                    mpd_conn.shift(new_pos..new_pos + 1, current_pos.try_into()?)?;
+                }
+                if let Some(max_queue_length) = options.max_queue_length {
+                    Self::trim_queue(mpd_conn, max_queue_length)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Get the song's paths from the MPD database.
+    ///
+    /// Instead of returning one filename per CUE track (file.cue/track0001,
+    /// file2.cue/track0002, etc), returns the CUE sheet itself (file.cue)
+    ///
+    /// Note: this uses [mpd_base_path](Config::mpd_base_path) because MPD
+    /// returns paths without including MPD_BASE_PATH.
+    fn get_songs_paths(&self) -> BlissResult<Vec<String>> {
+        self.get_songs_paths_in(None)
+    }
+
+    /// Same as [get_songs_paths](Self::get_songs_paths), but only returns the
+    /// songs inside `directory`, relative to MPD's base path, if specified.
+    fn get_songs_paths_in(&self, directory: Option<&str>) -> BlissResult<Vec<String>> {
+        let mut mpd_conn = self
+            .mpd_conn()
+            .map_err(|e| BlissError::ProviderError(e.to_string()))?;
+
+        let mut query = Query::new();
+        let query = match directory {
+            Some(directory) => query.and(Term::Base, directory),
+            None => query.and(Term::File, ""),
+        };
+        let to_paths = |songs: Vec<MPDSong>| {
+            songs
+                .into_iter()
+                .map(|s| s.file.to_owned())
+                .map(|s| match cue::split_cue_track(&s) {
+                    Some((file, _)) => file.to_owned(),
+                    None => s,
+                })
+                .map(|s| {
+                    String::from(
+                        self.library
+                            .config
+                            .resolve_mpd_path(Path::new(&s))
+                            .to_str()
+                            .unwrap(),
+                    )
+                })
+                .collect::<Vec<String>>()
+        };
+        // The other connections are opened on their own, so that they can be
+        // used at the same time as this one.
+        let mut connections = vec![];
+        let address = self
+            .mpd_address()
+            .map_err(|e| BlissError::ProviderError(e.to_string()))?;
+        for _ in 1..self.library.config.mpd_search_connections.get() {
+            match Self::get_mpd_conn(&address) {
+                Ok(connection) => connections.push(connection),
+                Err(e) => {
+                    warn!(
+                        "Could not open another connection to MPD ({}), listing the songs with fewer.",
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        let clients = std::iter::once(&mut *mpd_conn)
+            .chain(connections.iter_mut())
+            .collect();
+        let mut files =
+            match search_windows(clients, query, self.library.config.mpd_search_chunk_size) {
+                Ok(songs) => to_paths(songs),
+                // Some (older or slower) servers don't handle windowed searches
+                // well, so fall back to listing everything at once.
+                Err(e) => {
+                    warn!(
+                        "Could not search MPD's database ({}), listing all songs instead.",
+                        e
+                    );
+                    let songs = mpd_conn
+                        .listall()
+                        .map_err(|e| BlissError::ProviderError(e.to_string()))?
+                        .into_iter()
+                        .filter(|s| match directory {
+                            Some(directory) => s
+                                .file
+                                .starts_with(&format!("{}/", directory.trim_end_matches('/'))),
+                            None => true,
+                        })
+                        .collect();
+                    to_paths(songs)
+                }
+            };
+        files.sort();
+        files.dedup();
+
+        Ok(self.remove_aliases(files))
+    }
+
+    /// Pick a random song to start an interactive playlist from, among the
+    /// songs of `genre` if specified, in a dense region of the library so
+    /// there are enough close songs to choose from.
+    fn random_start_song(&self, genre: Option<&str>) -> Result<LibrarySong<()>> {
+        let mut songs = self.library.songs_from_library()?;
+        if let Some(genre) = genre {
+            let paths = self
+                .songs_with_genre(genre)?
+                .into_iter()
+                .collect::<HashSet<_>>();
+            songs.retain(|s| paths.contains(&*s.bliss_song.path.to_string_lossy()));
+        }
+        match random_dense_song(&songs, RANDOM_START_NEIGHBOURS, &mut rand::thread_rng()) {
+            Some(song) => Ok(song.to_owned()),
+            None => match genre {
+                Some(genre) => bail!("No analyzed song has the genre '{}'.", genre),
+                None => bail!("No song is analyzed yet. Run `blissify update` and try again."),
+            },
+        }
+    }
+
+    /// Make a playlist interactively, prompting `number_choices` close songs
+    /// each time.
+    ///
+    /// If `target_duration` is set, suggest finishing the session once the
+    /// playlist is at least that long. If `random_start` is set, replace the
+    /// queue with a random song from a dense region of the library, among the
+    /// songs of `genre` if specified, and start from it.
+    ///
+    /// If `listen` is set, the choices are served over HTTP on that address
+    /// instead of being prompted on the terminal, see [remote].
+    pub fn make_interactive_playlist(
+        &mut self,
+        continue_playlist: bool,
+        number_choices: usize,
+        target_duration: Option<Duration>,
+        random_start: bool,
+        genre: Option<&str>,
+        listen: Option<&str>,
+    ) -> Result<()> {
+        let listener = listen
+            .map(|address| {
+                TcpListener::bind(address)
+                    .with_context(|| format!("while listening for choices on {}", address))
+            })
+            .transpose()?;
+        let mut mpd_conn = self.mpd_conn()?;
+        mpd_conn.random(false)?;
+        if random_start {
+            let song = self.random_start_song(genre)?;
+            mpd_conn.clear()?;
+            self.add_to_queue(&mut mpd_conn, &[self.bliss_song_to_mpd(&song)?], None)?;
+            mpd_conn.play()?;
+        }
+        let mpd_song = if !continue_playlist {
+            match mpd_conn.currentsong()? {
+                Some(s) => s,
+                None => bail!(
+                    "No song is currently playing. Add a song to start \
+                    the playlist from, and try again.",
+                ),
+            }
+        } else {
+            match mpd_conn.queue()?.last() {
+                Some(s) => s.to_owned(),
+                None => bail!(
+                    "The current playlist is empty. Add at least a song \
+                    to start the playlist from, and try again.",
+                ),
+            }
+        };
+
+        let mut current_song = self.mpd_to_bliss_song(&mpd_song)?.with_context(|| {
+            "The current song is not in bliss' database. Run `blissify \
+            update /path/to/mpd` and try again."
+        })?;
+        println!(
+            "The playlist will start from: '{} - {}'.",
+            current_song
+                .bliss_song
+                .artist
+                .as_deref()
+                .unwrap_or("<No artist>"),
+            current_song
+                .bliss_song
+                .title
+                .as_deref()
+                .unwrap_or("<No title>"),
+        );
+
+        // Remove all songs from the playlist except the first one.
+        if !continue_playlist {
+            let current_pos = mpd_song.place.unwrap().pos;
+            mpd_conn.delete(0..current_pos)?;
+            if mpd_conn.queue()?.len() > 1 {
+                mpd_conn.delete(1..)?;
+            }
+        }
+        let mut songs = self.library.songs_from_library()?;
+
+        let mut playlist = mpd_conn
+            .queue()?
+            .iter()
+            .map(|s| self.mpd_to_bliss_song(s))
+            .collect::<Result<Option<Vec<LibrarySong<()>>>>>()?
+            .with_context(|| {
+                "No song is currently playing. Add a song to start the \
+                playlist from, and try again."
+            })?;
+        let excluded_songs = self.excluded_songs()?;
+        songs.retain(|s| !playlist.contains(s) && !excluded_songs.contains(&s.bliss_song.path));
+        match listen {
+            Some(address) => println!(
+                "Open http://{}/ to choose the songs, and 'Finish' there \
+                when you're done.",
+                address
+            ),
+            None => println!(
+                "The three closest songs will be displayed. Input '1' or 'Enter' \
+                to queue the first one, '2' to queue the second one, and '3' \
+                for the third one. 'q' or ctrl + c quits the session when you're \
+                done.",
+            ),
+        }
+        while songs.len() > number_choices {
+            if !playlist.is_empty() {
+                let duration = playlist.iter().map(|s| s.bliss_song.duration).sum();
+                let length = match target_duration {
+                    Some(target) => format!(
+                        "{} / {}",
+                        format_duration(duration),
+                        format_duration(target)
+                    ),
+                    None => format_duration(duration),
+                };
+                println!(
+                    "Current playlist ({}):\n{}\n",
+                    length,
+                    playlist
+                        .iter()
+                        .map(|s| format!(
+                            "\t{} - {}'",
+                            s.to_owned()
+                                .bliss_song
+                                .artist
+                                .unwrap_or_else(|| String::from("No artist")),
+                            s.to_owned()
+                                .bliss_song
+                                .title
+                                .unwrap_or_else(|| String::from("No title"))
+                        ))
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                );
+                if target_duration.is_some_and(|target| duration >= target) {
+                    println!(
+                        "The playlist reached its target duration. Input 'q' to \
+                        finish the session, or keep adding songs.\n"
+                    );
+                }
+            }
+            songs.sort_by_cached_key(|song| {
+                n32(euclidean_distance(
+                    &current_song.bliss_song.analysis.as_arr1(),
+                    &song.bliss_song.analysis.as_arr1(),
+                ))
+            });
+            // TODO put a proper dedup here
+            //dedup_playlist(&mut songs, None);
+            for (i, song) in songs[1..number_choices + 1].iter().enumerate() {
+                println!(
+                    "{}: '{} - {}'",
+                    i + 1,
+                    song.bliss_song
+                        .artist
+                        .as_ref()
+                        .unwrap_or(&String::from("<No artist>")),
+                    song.bliss_song
+                        .title
+                        .as_ref()
+                        .unwrap_or(&String::from("<No title>")),
+                );
+            }
+
+            let choice = match &listener {
+                Some(listener) => remote::wait_for_choice(
+                    listener,
+                    &remote::SessionState::new(
+                        &current_song,
+                        &playlist,
+                        &songs[1..number_choices + 1],
+                        target_duration,
+                    ),
+                )?,
+                None => Self::read_choice(number_choices)?,
+            };
+            let Some(choice) = choice else {
+                break;
+            };
+            let mpd_song = self.bliss_song_to_mpd(&songs[choice])?;
+            self.add_to_queue(&mut mpd_conn, &[mpd_song], None)?;
+            let song = songs.remove(choice);
+            playlist.push(song.to_owned());
+            current_song = song;
+        }
+        Ok(())
+    }
+
+    /// Read which of the `number_choices` choices the user picks on the
+    /// terminal, starting from 1, or `None` if they quit the session.
+    fn read_choice(number_choices: usize) -> Result<Option<usize>> {
+        let mut stdout = io::stdout().into_raw_mode()?;
+        let number_choices_digit = char::from_digit(number_choices as u32, 10).unwrap();
+        for key in io::stdin().keys() {
+            let choice = match key {
+                Ok(termion::event::Key::Char('1')) | Ok(termion::event::Key::Char('\n')) => 1,
+                Ok(termion::event::Key::Char(c @ '2'..='9')) if c <= number_choices_digit => {
+                    char::to_digit(c, 10).unwrap() as usize
+                }
+                Ok(termion::event::Key::Char('q')) | Ok(termion::event::Key::Ctrl('c')) => {
+                    return Ok(None)
+                }
+                _ => continue,
+            };
+            write!(stdout, "{}", termion::clear::All)?;
+            return Ok(Some(choice));
+        }
+        Ok(None)
+    }
+}
+
+/// The default path of the database, following the XDG spec, i.e.
+/// "XDG_DATA_HOME/bliss-rs/songs.db".
+///
+/// Existing configuration files keep pointing to the database they were
+/// created with, see [migrate_database] to move it there.
+fn default_database_path() -> Result<PathBuf> {
+    let data_dir = dirs::data_dir()
+        .with_context(|| "No suitable data directory found, please specify a database path.")?;
+    Ok(data_dir.join("bliss-rs").join("songs.db"))
+}
+
+/// Move the database of the configuration at `config_path` to `destination`,
+/// or to [default_database_path] if not specified, and update the
+/// configuration accordingly.
+///
+/// Returns the new path of the database.
+fn migrate_database(config_path: Option<PathBuf>, destination: Option<PathBuf>) -> Result<PathBuf> {
+    let library = MPDLibrary::from_config_path_offline(config_path)?;
+    let mut config = library.library.config.to_owned();
+    // Close the database before moving it.
+    drop(library);
+
+    let destination = match destination {
+        Some(d) => d,
+        None => default_database_path()?,
+    };
+    let source = config.base_config.database_path.to_owned();
+    if source == destination {
+        bail!("The database is already in {}.", destination.display());
+    }
+    if destination.exists() {
+        bail!(
+            "{} already exists, please move it away and try again.",
+            destination.display()
+        );
+    }
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Renaming doesn't work across filesystems.
+    if std::fs::rename(&source, &destination).is_err() {
+        std::fs::copy(&source, &destination)
+            .with_context(|| format!("while copying the database to {}", destination.display()))?;
+        std::fs::remove_file(&source)?;
+    }
+    config.base_config.database_path = destination.to_owned();
+    config.write()?;
+    Ok(destination)
+}
+
+/// Parse the value of `--max-queue-length`.
+fn parse_max_queue_length(max_queue_length: &str) -> Result<usize> {
+    match max_queue_length.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("The maximum queue length must be a strictly positive number of songs."),
+    }
+}
+
+/// The options of the extended isolation forest distance.
+// TODO let users customize options?
+fn forest_options() -> ForestOptions {
+    ForestOptions {
+        n_trees: 1000,
+        sample_size: 200,
+        max_tree_depth: None,
+        extension_level: 10,
+    }
+}
+
+/// The options of the `playlist` subcommand, from its arguments in `sub_m`
+/// and from `defaults`, falling back on `defaults` alone for the arguments
+/// `sub_m` lacks, e.g. with empty matches.
+fn playlist_options(
+    library: &MPDLibrary,
+    sub_m: &ArgMatches,
+    defaults: &PlaylistDefaults,
+    format: OutputFormat,
+) -> Result<PlaylistOptions> {
+    let dedup_distance = match sub_m.value_of("dedup-distance") {
+        Some(d) => match d.parse::<f32>() {
+            Ok(d) if d >= 0. => d,
+            _ => bail!("The deduplication distance must be a positive number."),
+        },
+        None => DEFAULT_DEDUP_DISTANCE,
+    };
+    let options = PlaylistOptions {
+        dedup: if sub_m.is_present("no-dedup") || !defaults.deduplication {
+            None
+        } else {
+            Some(sub_m.value_of("dedup-by").unwrap_or("features").parse()?)
+        },
+        dedup_distance,
+        dry_run: sub_m.is_present("dry-run"),
+        keep_queue: sub_m.is_present("keep-queue") || defaults.keep_current_queue,
+        skip_queued: sub_m.is_present("skip-queued"),
+        disable_random: sub_m.is_present("disable-random") || library.library.config.disable_random,
+        restore_random: sub_m.is_present("restore-random"),
+        reverse: sub_m.is_present("reverse"),
+        offset: match sub_m.value_of("offset").unwrap_or("0").parse::<usize>() {
+            Ok(o) => o,
+            Err(_) => bail!("The offset must be a positive number."),
+        },
+        show_distance: sub_m.is_present("show-distance"),
+        output_m3u: sub_m.value_of("output-m3u").map(PathBuf::from),
+        format,
+        publish_stickers: sub_m.is_present("publish-stickers"),
+        tie_break: sub_m.value_of("tie-break").map(str::parse).transpose()?,
+        tie_break_epsilon: match sub_m.value_of("tie-break-epsilon") {
+            Some(e) => match e.parse::<f32>() {
+                Ok(e) if e >= 0. => e,
+                _ => bail!("The tie-breaking distance must be a positive number."),
+            },
+            None => DEFAULT_TIE_BREAK_EPSILON,
+        },
+        max_average_distance: match sub_m.value_of("max-average-distance") {
+            Some(d) => match d.parse::<f32>() {
+                Ok(d) if d >= 0. => Some(d),
+                _ => bail!("The maximum average distance must be a positive number."),
+            },
+            None => defaults.max_average_distance,
+        },
+        refuse_distant: defaults.refuse_distant_playlists && !sub_m.is_present("force"),
+        after_current: sub_m.value_of("after-current").unwrap_or("album").parse()?,
+        restart_current_album: sub_m.is_present("restart-current-album"),
+        max_queue_length: match sub_m.value_of("max-queue-length") {
+            Some(n) => Some(parse_max_queue_length(n)?),
+            None => defaults.max_queue_length,
+        },
+        exclude_titles: if sub_m.is_present("include-versions") {
+            vec![]
+        } else {
+            exclude_title_patterns(defaults)?
+        },
+        excluded_songs: library.excluded_songs()?,
+        max_familiar: sub_m
+            .value_of("max-familiar")
+            .map(parse_max_familiar)
+            .transpose()?,
+        familiar_artists: if sub_m.is_present("max-familiar") {
+            library.familiar_artists()?
+        } else {
+            HashSet::new()
+        },
+        avoid_seed_album: match sub_m.value_of("seed-album-gap") {
+            Some(gap) => Some(gap.parse::<usize>().with_context(|| {
+                format!(
+                    "The seed album gap must be a number of songs, got '{}'.",
+                    gap
+                )
+            })?),
+            None if sub_m.is_present("avoid-seed-album") => Some(usize::MAX),
+            None => None,
+        },
+        filters: filters::PlaylistFilters {
+            max_loudness_jump: match defaults.filters.max_loudness_jump {
+                None if sub_m.is_present("consistent-loudness") => {
+                    Some(filters::DEFAULT_MAX_LOUDNESS_JUMP)
+                }
+                max_jump => max_jump,
+            },
+            ..match sub_m.value_of("bpm") {
+                Some(range) => {
+                    let (min_bpm, max_bpm) = parse_bpm_range(range)?;
+                    filters::PlaylistFilters {
+                        min_bpm,
+                        max_bpm,
+                        ..defaults.filters.to_owned()
+                    }
+                }
+                None => defaults.filters.to_owned(),
+            }
+        },
+        explain_filtering: sub_m.is_present("explain-filtering"),
+        guest_songs: vec![],
+        history: Some(playlist_history::PlaylistParameters {
+            // Album playlists use their own distance.
+            distance: sub_m
+                .value_of("distance")
+                .or(defaults.distance.as_deref())
+                .filter(|_| !sub_m.is_present("album") && !sub_m.is_present("one-album"))
+                .map(String::from),
+            command: env::args().skip(1).collect(),
+        }),
+    };
+    if options.restart_current_album && options.after_current == AfterCurrent::Track {
+        bail!("--restart-current-album queues the current album again, which --after-current track leaves out; please choose one.");
+    }
+    if options.restore_random && !options.disable_random {
+        bail!("--restore-random turns random mode back on after blissify turned it off, so it needs --disable-random, or \"disable_random\" set to true in the configuration file.");
+    }
+    Ok(options)
+}
+
+/// Sorts the songs of a playlist, given the songs it is made from, the
+/// candidates and the distance to sort them with.
+type SortSongs<'a> = dyn Fn(
+        &[LibrarySong<()>],
+        &[LibrarySong<()>],
+        &'a dyn DistanceMetricBuilder,
+    ) -> Box<dyn Iterator<Item = LibrarySong<()>> + 'a>
+    + 'a;
+
+/// The distances and the sort of song playlists, see [with_playlist_ranking].
+struct PlaylistRanking<'a> {
+    /// The distance for playlists made from one song.
+    distance: &'a dyn DistanceMetricBuilder,
+    /// The distance for playlists made from several songs, the extended
+    /// isolation forest unless a distance was chosen.
+    multiple_songs_distance: &'a dyn DistanceMetricBuilder,
+    sort: &'a SortSongs<'a>,
+}
+
+/// Call `f` with the distances and the sort of the `playlist` subcommand,
+/// from its arguments in `sub_m` and from `defaults`, as
+/// [playlist_options] does for its options. `timings` times them, for
+/// `--time`.
+fn with_playlist_ranking<T>(
+    library: &MPDLibrary,
+    sub_m: &ArgMatches,
+    defaults: &PlaylistDefaults,
+    timings: &timing::Timings,
+    f: impl FnOnce(PlaylistRanking) -> Result<T>,
+) -> Result<T> {
+    let forest_distance: &dyn DistanceMetricBuilder = &forest_options();
+
+    let distance_name = sub_m.value_of("distance").or(defaults.distance.as_deref());
+    let mahalanobis_distance = mahalanobis_distance_builder(library.metric_matrix().to_owned());
+    let distance_metric = library.distance_metric(distance_name.unwrap_or("euclidean"))?;
+    let distance_metric = &*distance_metric;
+    let feedback_weight = match sub_m.value_of("feedback-weight") {
+        Some(w) => match w.parse::<f32>() {
+            Ok(w) if w >= 0. => w,
+            _ => bail!("The feedback weight must be a positive number."),
+        },
+        None => library.feedback_weight(),
+    };
+    let feedback = library.feedback(feedback_weight)?;
+    let play_counts = library.play_counts(
+        sub_m
+            .value_of("weight-by-playcount")
+            .map(str::parse)
+            .transpose()?,
+    )?;
+    // Seeded playlists are faster with distances threads can share,
+    // which excludes the forest, feedback and play counts.
+    let shared_distance: Option<seed::SharedDistance> = match distance_name {
+        _ if !feedback.is_empty() || !play_counts.is_empty() => None,
+        None | Some("euclidean") => Some(&euclidean_distance),
+        Some("cosine") => Some(&cosine_distance),
+        Some("mahalanobis") => Some(&mahalanobis_distance),
+        Some(_) => None,
+    };
+    let distance_metric = &feedback.distance(distance_metric);
+    let forest_distance = &feedback.distance(forest_distance);
+    let distance_metric = &play_counts.distance(distance_metric);
+    let forest_distance = &play_counts.distance(forest_distance);
+    let distance_metric = &timings.distance(distance_metric);
+    let forest_distance = &timings.distance(forest_distance);
+
+    let algorithm = Algorithm::from_options(
+        sub_m.value_of("algorithm"),
+        sub_m.is_present("seed"),
+        defaults,
+    )?;
+    let number_cores = library.library.config.base_config.number_cores;
+    let sort = |x: &[LibrarySong<()>],
+                y: &[LibrarySong<()>],
+                z|
+     -> Box<dyn Iterator<Item = LibrarySong<()>>> {
+        Box::new(
+            timings.rank(|| -> Box<dyn Iterator<Item = LibrarySong<()>>> {
+                match algorithm {
+                    Algorithm::Closest => Box::new(closest_to_songs(x, y, z)),
+                    Algorithm::Chained => {
+                        // Playlists made from several songs can use the
+                        // forest instead of `distance_metric`.
+                        let shared_distance =
+                            shared_distance.filter(|_| std::ptr::addr_eq(z, distance_metric));
+                        Box::new(seed::song_to_song(x, y, z, shared_distance, number_cores))
+                    }
+                }
+            }),
+        )
+    };
+    f(PlaylistRanking {
+        distance: distance_metric,
+        multiple_songs_distance: if distance_name.is_some() {
+            distance_metric
+        } else {
+            forest_distance
+        },
+        sort: &sort,
+    })
+}
+
+/// The queue positions given to `pin` or `unpin`, starting from 0.
+fn queue_positions(sub_m: &ArgMatches) -> Result<Vec<u32>> {
+    sub_m
+        .values_of("POSITION")
+        .unwrap()
+        .map(|p| match p.parse::<u32>() {
+            Ok(position) if position > 0 => Ok(position - 1),
+            _ => bail!("Queue positions start from 1, got '{}'.", p),
+        })
+        .collect()
+}
+
+/// The `exclude_titles` patterns of `defaults`, matching titles ignoring case.
+fn exclude_title_patterns(defaults: &PlaylistDefaults) -> Result<Vec<Regex>> {
+    defaults
+        .exclude_titles
+        .iter()
+        .map(|p| {
+            RegexBuilder::new(p)
+                .case_insensitive(true)
+                .build()
+                .with_context(|| format!("while reading the exclude_titles pattern '{}'", p))
+        })
+        .collect()
+}
+
+/// Parse the value of `--max-familiar`, a percentage, into a share of the
+/// playlist.
+fn parse_max_familiar(max_familiar: &str) -> Result<f32> {
+    match max_familiar.trim_end_matches('%').parse::<f32>() {
+        Ok(p) if (0. ..=100.).contains(&p) => Ok(p / 100.),
+        _ => {
+            bail!("The maximum share of familiar artists must be a percentage, between 0 and 100.")
+        }
+    }
+}
+
+/// Parse a range of beats per minute like "120-132", "120-" or "-132", for
+/// `--bpm`.
+fn parse_bpm_range(range: &str) -> Result<(Option<f32>, Option<f32>)> {
+    let invalid = || {
+        anyhow!(
+            "Invalid tempo range '{}', expected something like '120-132', '120-' or '-132'.",
+            range
+        )
+    };
+    let (min, max) = range.split_once('-').ok_or_else(invalid)?;
+    let bound = |bound: &str| match bound.trim() {
+        "" => Ok(None),
+        bound => match bound.parse::<f32>() {
+            Ok(bpm) if bpm.is_finite() && bpm >= 0. => Ok(Some(bpm)),
+            _ => Err(invalid()),
+        },
+    };
+    match (bound(min)?, bound(max)?) {
+        (None, None) => Err(invalid()),
+        (Some(min), Some(max)) if min > max => Err(invalid()),
+        bounds => Ok(bounds),
+    }
+}
+
+/// Parse a duration like "1h", "45m", "1h30m" or "90s", or, for longer ones,
+/// "30d", "6w" or "1y" (365 days).
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let invalid = || {
+        anyhow!(
+            "Invalid duration '{}', expected something like '1h', '45m' or '1h30m'.",
+            duration
+        )
+    };
+    let (mut total, mut number) = (0, String::new());
+    for c in duration.trim().chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
+            }
+            'y' => 365 * 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        total += number.parse::<u64>().map_err(|_| invalid())? * unit;
+        number.clear();
+    }
+    if !number.is_empty() || total == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// Format a duration as "m:ss", or "h:mm:ss" if it is longer than an hour.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 60 * 60 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+fn parse_number_cores(matches: &ArgMatches) -> Result<Option<NonZeroUsize>, BlissError> {
+    matches
+        .value_of("number-cores")
+        .map(|x| x.parse::<NonZeroUsize>())
+        .map_or(Ok(None), |r| r.map(Some))
+        .map_err(|_| BlissError::ProviderError(String::from("Number of cores must be positive")))
+}
+
+fn main() -> Result<()> {
+    env_logger::init_from_env(env_logger::Env::default().filter_or("RUST_LOG", "warn"));
+    let config_argument = Arg::with_name("config-path")
+            .short("c")
+            .long("config-path")
+            .help(
+                "Optional argument specifying the configuration path, for both loading and initializing blissify. Example: \"/path/to/config.json\". If not specified, defaults to \"XDG_CONFIG_HOME/bliss-rs/config.json\", e.g. \"/home/user/.config/bliss-rs/config.json\".",
+            )
+            .required(false)
+            .takes_value(true);
+
+    let isolate_argument = Arg::with_name("isolate")
+        .long("isolate")
+        .help(
+            "Decode songs in worker processes, so that a decoder crashing on a corrupt file only fails that file instead of stopping the whole analysis. Slightly slower. Can be enabled for good with \"isolate_analysis\": true in the configuration file.",
+        );
+
+    let max_file_size_argument = Arg::with_name("max-file-size")
+        .long("max-file-size")
+        .value_name("SIZE")
+        .help(
+            "Don't analyze the files larger than SIZE, a number of bytes optionally followed by K, M or G, like \"500M\". They are recorded as skipped rather than as errors. Overrides \"max_file_size\" in the configuration file.",
+        )
+        .takes_value(true);
+
+    let matches = App::new("blissify")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author("Polochon_street")
+        .about("Analyze and make smart playlists from an MPD music database.")
+        .arg(config_argument.clone().hidden(true))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .global(true)
+            .possible_values(&["plain", "json"])
+            .help(
+                "Choose how results are printed on stdout: 'plain' text (the default) or 'json', which is meant for other programs to parse, and stays stable between releases. Applies to list-db and to dry runs."
+            )
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("backup-before-migrate")
+            .long("backup-before-migrate")
+            .global(true)
+            .help(
+                "If the database needs to be migrated to a newer schema, which happens automatically after upgrading blissify, copy it next to itself first."
+            )
+            .takes_value(false)
+        )
+        .arg(Arg::with_name("user")
+            .long("user")
+            .value_name("NAME")
+            .global(true)
+            .help(
+                "Use the likes, dislikes and \"profiles.NAME\" settings of the configuration file of user NAME, instead of the default ones. Applies to playlist, like, dislike and metric."
+            )
+            .takes_value(true)
+        )
+        .subcommand(
+            SubCommand::with_name("list-db")
+            .about("Print songs that have been analyzed and are in blissify's database.")
+            .arg(Arg::with_name("detailed").long("detailed")
+                .takes_value(false)
+                .help("Display analyzed song paths, as well as the corresponding analysis.")
+            )
+            .arg(Arg::with_name("genre").long("genre")
+                .value_name("genre")
+                .help(
+                    "Only list songs having this genre. Multi-value genre tags, like \"Electronic; Ambient\", match each of their genres."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("artist").long("artist")
+                .value_name("artist")
+                .help("Only list songs whose artist or album artist is this one, ignoring case.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("album").long("album")
+                .value_name("album")
+                .help("Only list songs of this album, ignoring case.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("path-prefix").long("path-prefix")
+                .value_name("prefix")
+                .help(
+                    "Only list songs whose path starts with this, either relative to the MPD base path, like \"Albums/\", or absolute."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("analyzed-only").long("analyzed-only")
+                .help("Only list songs that were analyzed. This is the default.")
+                .conflicts_with("pending-only")
+            )
+            .arg(Arg::with_name("pending-only").long("pending-only")
+                .help(
+                    "Only list songs that are in the database but not analyzed, e.g. because their analysis failed. With --detailed, also print why."
+                )
+            )
+            .arg(Arg::with_name("sort").long("sort")
+                .value_name("order")
+                .help("Sort songs by 'path' (the default), 'artist', 'album' or 'duration'.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("missing-files").long("missing-files")
+                .help(
+                    "Only list songs whose file doesn't exist on disk anymore, analyzed or not, regardless of what MPD's database says."
+                )
+                .conflicts_with_all(&["analyzed-only", "pending-only"])
+            )
+            .arg(Arg::with_name("remove").long("remove")
+                .help("Remove the songs listed with --missing-files from blissify's database.")
+                .requires("missing-files")
+            )
+            .arg(config_argument.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+            .about("Print how many songs are analyzed, failed to be analyzed, or are pending, i.e. in MPD's database but not analyzed yet. Pending songs are only counted if MPD can be reached.")
+            .arg(Arg::with_name("by-directory").long("by-directory")
+                .takes_value(false)
+                .help("Print the number of songs of each top-level directory of the MPD base path, to find directories that were never analyzed.")
+            )
+            .arg(Arg::with_name("listening").long("listening")
+                .takes_value(false)
+                .conflicts_with("by-directory")
+                .help("Report on the songs played while \"blissify daemon\" was running instead: how often the songs blissify queued were skipped compared to the others, how far they were from the song played before them, and the most and least played genres.")
+            )
+            .arg(config_argument.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+            .about(
+                "Initialize blissify on an MPD library.\n\
+                By default, it creates the configuration folder \"bliss-rs\" \
+                in XDG_CONFIG_HOME, most likely `/home/user/.config/bliss-rs`, \
+                and analyzes the songs in the given MPD library.\n\
+                It can take some time."
+            )
+            .arg(Arg::with_name("MPD_BASE_PATH")
+                .help("MPD base path. The value of `music_directory` in your mpd.conf.")
+                .required_unless("interactive")
+            )
+            .arg(Arg::with_name("interactive")
+                .short("i")
+                .long("interactive")
+                .help("Find MPD and its music directory, ask for confirmation and the analysis settings, and check that MPD's songs are in the music directory before analyzing them.")
+                .conflicts_with("MPD_BASE_PATH")
+                .takes_value(false)
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("database-path")
+                .short("d")
+                .long("database-path")
+                .help(
+                    "Optional argument specifying where to store the database containing analyzed songs. Example: \"/path/to/bliss.db\". If not specified, defaults to \"XDG_DATA_HOME/bliss-rs/songs.db\", e.g. \"/home/user/.local/share/bliss-rs/songs.db\"."
+                )
+                .required(false)
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("number-cores")
+                .long("number-cores")
+                .help(
+                    "Number of CPU cores the analysis should use (defaults to the number of cores the CPU has).
+Useful to avoid a too heavy load on a machine.")
+                .required(false)
+                .takes_value(true)
+            )
+            .arg(isolate_argument.clone())
+            .arg(max_file_size_argument.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("migrate-database")
+            .about(
+                "Move the database to \"XDG_DATA_HOME/bliss-rs/songs.db\" (or to --to), where \
+                blissify now puts new databases, and update the configuration file accordingly."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("path")
+                .help("Where to move the database, instead of \"XDG_DATA_HOME/bliss-rs/songs.db\".")
+                .takes_value(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("import-db")
+            .about(
+                "Import the analyses of the songs of MPD's library that are not analyzed yet from \
+                the database of another bliss-based tool, instead of analyzing them again. Songs are \
+                matched by path only, after --replace-prefix, not by MusicBrainz id."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("DATABASE")
+                .help("The other tool's database, with bliss' \"song\" and \"feature\" tables.")
+                .required(true)
+            )
+            .arg(Arg::with_name("replace-prefix")
+                .long("replace-prefix")
+                .value_names(&["OLD", "NEW"])
+                .help(
+                    "Replace OLD at the beginning of the paths of the other database with NEW, if \
+                    the music was in another directory there, e.g. \"/mnt/music/ /home/user/Music/\"."
+                )
+                .takes_value(true)
+                .number_of_values(2)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("import-play-counts")
+            .about(
+                "Import how many times songs were played from a Last.fm export or an mpdscribble \
+                journal, for playlist --weight-by-playcount. Replaces the play counts imported before."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("FILE")
+                .help(
+                    "A Last.fm export, as CSV lines of \"artist,album,title,date\", or an \
+                    mpdscribble journal. Plays are matched to songs by artist and title."
+                )
+                .required(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("rescan")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("number-cores")
+                .long("number-cores")
+                .help(
+                    "Number of CPU cores the analysis should use (defaults to the number of cores the CPU has).
+Useful to avoid a too heavy load on a machine.")
+                .required(false)
+                .takes_value(true)
+            )
+            .arg(isolate_argument.clone())
+            .arg(max_file_size_argument.clone())
+            .arg(Arg::with_name("only-errors")
+                .long("only-errors")
+                .help("Only analyze again the songs that failed to be analyzed.")
+                .required(false)
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("only-outdated")
+                .long("only-outdated")
+                .help("Only analyze again the songs analyzed with another version of bliss' features. Can be combined with --only-errors.")
+                .required(false)
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("path")
+                .long("path")
+                .value_name("PATH")
+                .help("Only rescan the songs in PATH, a directory relative to MPD's base path, e.g. \"Artist/Album\".")
+                .required(false)
+                .takes_value(true)
+            )
+            .about("(Re)scan completely an MPD library, or only some of its songs")
+        )
+        .subcommand(
+            SubCommand::with_name("update")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("number-cores")
+                .long("number-cores")
+                .help(
+                    "Number of CPU cores the analysis should use (defaults to the number of cores the CPU has).
+Useful to avoid a too heavy load on a machine.")
+                .required(false)
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("path")
+                .long("path")
+                .value_name("directory")
+                .help(
+                    "Only update the songs in this directory, relative to MPD's base path, e.g. \"Albums/NewStuff/\", instead of listing the whole library."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("song")
+                .long("song")
+                .value_name("path")
+                .conflicts_with("path")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Analyze this song again, even if it is already analyzed, and only update its entry in the database. Can be a single track of a CUE sheet, like \"album/file.cue/track0003\", leaving the other tracks untouched. Can be given several times."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("older-than")
+                .long("older-than")
+                .value_name("age")
+                .conflicts_with_all(&["path", "song"])
+                .help(
+                    "Analyze the songs that were analyzed more than this long ago again, like \"1y\", \"6w\" or \"30d\", e.g. after a decoder bug was fixed. Songs analyzed before blissify recorded when are analyzed again too."
+                )
+                .takes_value(true)
+            )
+            .arg(isolate_argument.clone().conflicts_with_all(&["song", "older-than"]))
+            .arg(max_file_size_argument.clone().conflicts_with_all(&["song", "older-than"]))
+            .about("Scan new songs that were added to the MPD library since last scan.")
+        )
+        .subcommand(
+            SubCommand::with_name("analyze-worker")
+            .setting(AppSettings::Hidden)
+            .about("Analyze the songs whose paths are read from stdin, for --isolate.")
+        )
+        .subcommand(
+            SubCommand::with_name("playlist")
+            .about("Make a playlist from the currently playing song, clearing the queue and queuing NUMBER_SONGS songs similar to the currently playing song. See the other flags if you want to e.g. preserve the queue.")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("NUMBER_SONGS")
+                .help("Number of items to queue, including the first song. Can be omitted if \"number_songs\" is set in the \"playlist\" section of the configuration file.")
+                .required(false)
+            )
+            .arg(Arg::with_name("distance")
+                .long("distance")
+                .value_name("distance metric")
+                .help(
+                    "Choose the distance metric used to make the playlist. Default is 'euclidean' for playlists from a single song, and 'extended_isolation_forest' for playlists from multiple songs, unless \"distance\" is set in the \"playlist\" section of the configuration file. Run \"blissify list-metrics\" to see the other metrics, e.g. 'cosine' or 'mahalanobis'. It can also be a weighted sum of metrics, which can include 'genre_bonus' or 'artist_bonus' to bring songs sharing a genre or an artist with the song(s) the playlist is made from closer, like '0.8*euclidean + 0.2*genre_bonus'. By default, the mahalanobis distance is the same as the euclidean distance. You can tailor this distance to your tastes by running metric learning e.g. using https://github.com/Polochon-street/bliss-metric-learning. The extended_isolation_forest works better for playlists from multiple songs."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("from-song")
+                .long("from-song")
+                .value_name("song path")
+                .help("Instead of making a playlist from the current playing song, make a playlist from 'song path', and add the corresponding songs to the queue. This will also add the song in 'song path' to the playlist.")
+            )
+            .arg(Arg::with_name("from-queue-pos")
+                .long("from-queue-pos")
+                .value_name("position")
+                .conflicts_with_all(&["from-song", "from-song-id"])
+                .help("Same as --from-song, with the song at this position of MPD's queue, starting from 0. Handy for frontends, and for CUE tracks, whose paths are hard to give.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("from-song-id")
+                .long("from-song-id")
+                .value_name("id")
+                .conflicts_with_all(&["from-song", "from-queue-pos"])
+                .help("Same as --from-song, with the song of MPD's queue that has this MPD song id.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("guest-db")
+                .long("guest-db")
+                .value_name("database")
+                .help("Also make the playlist out of the analyzed songs of this other bliss database, e.g. a friend's blissify database, which is only read from. Dry runs show whether each song comes from the 'local' or the 'guest' library. To be queued, the guest's songs must be in MPD's music directory. Doesn't apply to album playlists, station mode and interactive playlists.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("guest-replace-prefix")
+                .long("guest-replace-prefix")
+                .value_names(&["OLD", "NEW"])
+                .requires("guest-db")
+                .help(
+                    "Replace OLD at the beginning of the paths of the guest database with NEW, where its music is here, e.g. \"/home/friend/Music/ /home/user/Music/friend/\"."
+                )
+                .takes_value(true)
+                .number_of_values(2)
+            )
+            .arg(Arg::with_name("analyze-missing-seed")
+                .long("analyze-missing-seed")
+                .requires("from-song")
+                .help("If the song given with --from-song is not analyzed yet, e.g. because it was added to MPD since the last update, analyze it first instead of failing, and make the playlist from it.")
+            )
+            .arg(Arg::with_name("max-average-distance")
+                .long("max-average-distance")
+                .value_name("distance")
+                .help(
+                    "Warn if the songs of the playlist are further than this from the song(s) it is made from, on average, which happens with small libraries or unusual songs. The scale depends on the distance metric. Defaults to \"max_average_distance\" in the \"playlist\" section of the configuration file, if set."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help(
+                    "Queue the playlist even if it is above the maximum average distance, when \"refuse_distant_playlists\" is set in the \"playlist\" section of the configuration file."
+                )
+            )
+            .arg(Arg::with_name("from-file")
+                .long("from-file")
+                .value_name("file")
+                .conflicts_with_all(&["from-song", "from-queue-pos", "from-song-id", "entire", "album"])
+                .help("Instead of making a playlist from the current playing song, make a playlist from an audio file that doesn't have to be in MPD's library nor in blissify's database, like a song someone sent you. The file is analyzed on the fly, without being stored, and the corresponding songs of the library are added at the end of the queue.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("from-stdin")
+                .long("from-stdin")
+                .conflicts_with_all(&["from-song", "from-queue-pos", "from-song-id", "from-file", "entire", "album"])
+                .help("Instead of making a playlist from the current playing song, make a playlist from the songs read on stdin, one path per line (either absolute, or relative to the MPD base path), and add the corresponding songs at the end of the queue. Defaults to using the distance metric extended_isolation_forest, as for --from-entire-playlist.")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("from-log")
+                .long("from-log")
+                .value_name("time range")
+                .conflicts_with_all(&["from-song", "from-queue-pos", "from-song-id", "from-file", "from-stdin", "entire", "album"])
+                .help("Instead of making a playlist from the current playing song, make a playlist from the songs MPD played during a time range, like \"yesterday 20:00..23:00\", \"2024-10-12 22:00..03:00\" or \"18:00..19:00\" (today), as found in MPD's log. Defaults to using the distance metric extended_isolation_forest, as for --from-entire-playlist.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("mpd-log")
+                .long("mpd-log")
+                .value_name("path")
+                .requires("from-log")
+                .help("MPD's log file, to use with --from-log. Defaults to \"mpd_log_path\" in the configuration file.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("algorithm")
+                .long("algorithm")
+                .possible_values(&["closest", "chained"])
+                .help(
+                    "How the songs of the playlist are ordered: 'closest' (the default) queues the songs closest to the current song first, 'chained' queues the closest song to the first song, then the closest to the second song, etc. Defaults to \"algorithm\" in the \"playlist\" section of the configuration file, if set."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("seed")
+                .long("seed-song")
+                .conflicts_with("algorithm")
+                .help(
+                    "Same as --algorithm chained: instead of making a playlist of only the closest song to the current song, make a playlist that queues the closest song to the first song, then the closest to the second song, etc. Can take some time to build."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("no-dedup")
+                .long("no-deduplication")
+                .help(
+                    "Do not deduplicate songs based both on the title / artist and their sheer proximity."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("dedup-distance")
+                .long("dedup-distance")
+                .value_name("distance")
+                .help(
+                    "When deduplicating by features, the distance under which two songs are considered duplicates of each other. Raise it to collapse near-identical versions (remasters, live takes) more aggressively. Defaults to 0.05."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("tie-break")
+                .long("tie-break")
+                .value_name("metadata")
+                .possible_values(&["genre", "artist"])
+                .conflicts_with("album")
+                .help(
+                    "Among songs at about the same distance from the song(s) the playlist is made from, prefer the ones sharing a genre or an artist with them. Songs are still mostly ordered by how they sound."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("tie-break-epsilon")
+                .long("tie-break-epsilon")
+                .value_name("distance")
+                .requires("tie-break")
+                .help(
+                    "How close the distances of songs must be for --tie-break to reorder them. Defaults to 0.1."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("dedup-by")
+                .long("dedup-by")
+                .value_name("strategy")
+                .possible_values(&["title", "features", "album", "release"])
+                .help(
+                    "How songs are considered duplicates: 'title' only looks at the title and artist, 'features' also collapses songs that are closer than --dedup-distance, 'album' keeps only one song per album, and 'release' also collapses the releases of a same recording, like remasters or deluxe editions, using MusicBrainz ids when MPD has them, or the title, artist and duration."
+                )
+                .default_value("features")
+            )
+            .arg(Arg::with_name("skip-queued")
+                .long("skip-queued")
+                .help(
+                    "Do not queue songs that are already present anywhere in the current queue. Mostly useful with --keep-current-queue."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("disable-random")
+                .long("disable-random")
+                .help(
+                    "Turn MPD's random mode off before queueing the playlist, instead of just warning about it. Can also be enabled by default by setting \"disable_random\" to true in the configuration file."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("restore-random")
+                .long("restore-random")
+                .help(
+                    "Turn MPD's random mode back on once the playlist has been queued, if it was turned off by --disable-random or the \"disable_random\" setting."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("keep-queue")
+                .long("keep-current-queue")
+                .help(
+                    "Instead of removing the rest of the queue and only keeping the selecting song, queuing songs similiar to the selected song, keep the queue the same, and add similar songs right after the selected song, preserving the rest of the queue."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("target-server")
+                .long("target-server")
+                .value_name("NAME")
+                .help("Read the current song from, and queue the playlist on, the MPD server NAME of the \"servers\" section of the configuration file, instead of the one given by MPD_HOST and MPD_PORT.")
+                .conflicts_with("offline")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("time")
+                .long("time")
+                .help("Print how long loading the candidate songs, setting up the distance metric (e.g. fitting the forest), ranking the songs and queueing the playlist took, on stderr.")
+                .conflicts_with("station")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("announce")
+                .long("announce")
+                .help("After queueing the playlist, send a JSON message describing how it was made (what it was made from, the distance metric, the algorithm...) on MPD's \"blissify\" client-to-client channel, for the MPD clients subscribed to it to display.")
+                .conflicts_with("station")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("use-priorities")
+                .long("use-priorities")
+                .help(
+                    "Instead of changing the queue, give the songs already in it that are the most similar to the current song MPD priorities, so that MPD plays them next in random mode."
+                )
+                .conflicts_with_all(&["album", "one-album", "entire", "from-stdin", "from-file", "from-log", "from-song", "from-queue-pos", "from-song-id", "station", "keep-queue", "output-m3u"])
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("offset")
+                .long("offset")
+                .value_name("N")
+                .conflicts_with("album")
+                .help(
+                    "Skip the N songs closest to the starting song(s) before queueing songs, to get the \"second ring\" of similar songs when the closest ones have been heard a hundred times."
+                )
+                .default_value("0")
+            )
+            .arg(Arg::with_name("reverse")
+                .long("reverse")
+                .conflicts_with("album")
+                .help(
+                    "Queue the playlist from the least similar song to the most similar one, ending on the song the playlist was made from (or right after it, if it is the currently playing song). Useful for warm-up sets."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help(
+                    "Doesn't actually make any changes to the playlist, but just print songs that would have been added on stdout."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("show-distance")
+                .long("show-distance")
+                .requires("dry-run")
+                .conflicts_with("album")
+                .help(
+                    "With --dry-run, also print the song(s) the playlist was made from, and prefix each song with its distance to them. Useful to compare distance metrics and deduplication thresholds."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("explain-filtering")
+                .long("explain-filtering")
+                .conflicts_with_all(&["album", "one-album"])
+                .help(
+                    "Print how many of the candidates looked at each filter left out of the playlist: the genre, duration and artist filters of the \"filters\" setting of the \"playlist\" section of the configuration file, deduplication, --skip-queued, excluded songs, --offset, --max-familiar, --avoid-seed-album and --consistent-loudness."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("output-m3u")
+                .long("output-m3u")
+                .value_name("file")
+                .help(
+                    "Instead of queueing the playlist, write it to 'file' as an M3U playlist. Combined with --offline, it makes it possible to generate playlists on a machine that doesn't run MPD."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("publish-stickers")
+                .long("publish-stickers")
+                .conflicts_with("album")
+                .help(
+                    "Store the distance of each queued song to the song(s) the playlist was made from in the \"bliss_distance\" MPD sticker, so MPD clients can display why a song was chosen. Use `blissify clear-stickers` to remove them."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("weight-by-playcount")
+                .long("weight-by-playcount")
+                .value_name("weighting")
+                .possible_values(&["inverse", "direct"])
+                .help(
+                    "Among songs that sound about as close, prefer the ones played less often (inverse) or more often (direct), according to the play counts imported with `blissify import-play-counts`."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("feedback-weight")
+                .long("feedback-weight")
+                .value_name("weight")
+                .help(
+                    "How much songs liked and disliked with `blissify like` and `blissify dislike` bias the playlist towards or away from similar songs. Defaults to the \"feedback_weight\" of the configuration file, or 1. 0 disables it."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("offline")
+                .long("offline")
+                .help(
+                    "Do not connect to MPD, and make the playlist purely from blissify's database. Only works with --dry-run or --output-m3u, and with --from-song, --from-file or --from-stdin. If MPD cannot be reached, dry runs and --output-m3u fall back to offline mode automatically."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("album")
+                .long("album-playlist")
+                .help("Make a playlist of similar albums from the current album.")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("after-current")
+                .long("after-current")
+                .value_name("album|disc|track")
+                .help("With --album-playlist, what to play from the current album before the next albums: the rest of the 'album' (the default), the rest of the current 'disc' only, or nothing, moving on to the next albums right after the current 'track'.")
+                .requires("album")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("restart-current-album")
+                .long("restart-current-album")
+                .help("With --album-playlist, queue the current album again from its first track (or the current disc from its first track, with --after-current disc) after the current song, instead of only the tracks after it, before the next albums.")
+                .requires("album")
+                .conflicts_with("keep-queue")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("max-queue-length")
+                .long("max-queue-length")
+                .value_name("number of songs")
+                .help("After queueing the playlist, remove already played songs from the top of the queue, then songs from its end if needed, so the queue never has more than this many songs. Defaults to \"max_queue_length\" in the \"playlist\" section of the configuration file, if set.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("max-familiar")
+                .long("max-familiar")
+                .value_name("PERCENT")
+                .help("Take at most this percentage of the playlist from artists played in the last month, as recorded by \"blissify daemon\", filling the rest with similar songs from artists played less recently, or never.")
+                .conflicts_with_all(&["album", "one-album"])
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("consistent-loudness")
+                .long("consistent-loudness")
+                .help("Leave out the songs much quieter or louder than the song queued before them, to avoid jumps in volume between quiet acoustic songs and loud masters. The largest jump allowed is 6 dB, or the \"max_loudness_jump\" filter of the \"playlist\" section of the configuration file, which also applies without this flag.")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("bpm")
+                .long("bpm")
+                .value_name("MIN-MAX")
+                .help("Only queue songs whose estimated tempo is in this range of beats per minute, like '120-132', '120-' or '-132', e.g. for workout playlists. The estimate is rough, and can be off by a factor of two. Overrides the \"min_bpm\" and \"max_bpm\" filters of the \"playlist\" section of the configuration file.")
+                .conflicts_with_all(&["album", "one-album"])
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("avoid-seed-album")
+                .long("avoid-seed-album")
+                .help("Leave the other songs of the album of the song(s) the playlist is made from out of the playlist, since they are usually the closest ones.")
+                .conflicts_with_all(&["album", "one-album"])
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("seed-album-gap")
+                .long("seed-album-gap")
+                .value_name("number of songs")
+                .help("Like --avoid-seed-album, but only keep the other songs of the album of the song(s) the playlist is made from out of this many first songs of the playlist.")
+                .conflicts_with_all(&["album", "one-album", "avoid-seed-album"])
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("station")
+                .long("station")
+                .help("After queueing the playlist, keep running in the foreground, queueing a few more songs close to the songs listened to since then whenever the queue is about to run out, until interrupted with Ctrl-C.")
+                .conflicts_with_all(&["dry-run", "output-m3u", "offline", "album", "one-album", "entire", "from-stdin", "from-file", "from-log"])
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("include-versions")
+                .long("include-versions")
+                .help("Do not leave out the songs whose title matches one of the \"exclude_titles\" patterns of the \"playlist\" section of the configuration file, like live versions or remixes.")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("update-first")
+                .long("update-first")
+                .help("Before making the playlist, analyze the songs that were added to MPD since its database last changed, so freshly added albums can be part of it. Does nothing if MPD's database didn't change. Defaults to \"update_first\" in the \"playlist\" section of the configuration file.")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("one-album")
+                .long("one-album")
+                .help("Instead of a mix of songs, queue the single album that fits the current song (or the one given with --from-song) best, in order, right after it.")
+                .conflicts_with_all(&["album", "entire", "from-stdin", "from-file"])
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("entire")
+                .long("from-entire-playlist")
+                .help("Make a playlist of songs similar to all the playlist's songs, \
+                    instead of just the first one. Defaults to using the distance metric \
+                    extended_isolation_forest, which gives the best results.")
+                .takes_value(false)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("ctl")
+            .about(
+                "Control a running `blissify daemon`: 'pause' finishes analyzing the songs in flight, \
+                stores them, and waits until 'resume' is sent. 'status' tells whether the analysis is \
+                paused, and how the analysis of new songs is going. 'run-profile PROFILE' queues a playlist from the current song with the \
+                playlist settings of PROFILE."
+            )
+            .arg(Arg::with_name("COMMAND")
+                .possible_values(&["pause", "resume", "status", "run-profile"])
+                .required(true)
+            )
+            .arg(Arg::with_name("PROFILE")
+                .help("The profile whose playlist 'run-profile' queues.")
+                .required_if("COMMAND", "run-profile")
+            )
+            .arg(Arg::with_name("control-socket")
+                .long("control-socket")
+                .value_name("path")
+                .help("The daemon's control socket. Defaults to \"XDG_RUNTIME_DIR/blissify.sock\", or to \"blissify-UID.sock\" in the temporary directory without XDG_RUNTIME_DIR.")
+                .takes_value(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("similar-album")
+            .about(
+                "Print the albums that sound the closest to an album, using the same album distance as `playlist --album-playlist`, without queueing anything."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("ALBUM")
+                .help("The name of the album, as in its tags.")
+                .required(true)
+            )
+            .arg(Arg::with_name("NUMBER_ALBUMS")
+                .help("The number of albums to print.")
+                .default_value("10")
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("analyze-file")
+            .about(
+                "Analyze audio files and tell whether they could be decoded, without storing them in the database. Doesn't need MPD nor a configuration file, which makes it handy to debug decoding errors."
+            )
+            .arg(Arg::with_name("print")
+                .long("print")
+                .help(
+                    "Print each file's tags and features as a line of JSON, e.g. to compare decoders or to feed the features to other tools."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("decoder")
+                .long("decoder")
+                .value_name("decoder")
+                .help("The decoder to analyze the files with. symphonia needs blissify to be built with `--features symphonia`.")
+                .possible_values(&["ffmpeg", "symphonia"])
+                .default_value("ffmpeg")
+            )
+            .arg(Arg::with_name("FILE")
+                .help("The audio files to analyze.")
+                .required(true)
+                .multiple(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("compare")
+            .about(
+                "Print the distance between two songs, or between two albums with --album."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("album")
+                .long("album")
+                .help(
+                    "Compare albums, using the same album distance as `playlist --album-playlist`. Also prints each track's distance to the other album, from the furthest to the closest, to see which tracks drive the distance."
+                )
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("FIRST")
+                .help("The first song, as a path relative to the MPD base path or an absolute one, or the first album's name with --album.")
+                .required(true)
+            )
+            .arg(Arg::with_name("SECOND")
+                .help("The second song or album.")
+                .required(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("top")
+            .about(
+                "Print the songs most representative of the library, i.e. the closest ones to the mean of all the songs' analyses, along with their distance to it. Handy to pick seeds for guests."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("NUMBER_SONGS")
+                .help("How many songs to print. Defaults to 20.")
+                .required(false)
+            )
+            .arg(Arg::with_name("outliers")
+                .long("outliers")
+                .help("Print the least representative songs instead, from the furthest to the closest.")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("distance")
+                .long("distance")
+                .value_name("distance metric")
+                .help("The distance metric used, as in `playlist --distance`. Defaults to 'euclidean'.")
+                .takes_value(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+            .about(
+                "Check that MPD can be reached, that the configuration and the database can be \
+                read, that all songs were analyzed with the current version of bliss' features, \
+                and that songs can be decoded.\n\
+                Exits with 2 if the configuration is wrong, 3 for the database, 4 for the \
+                features version, 5 for the decoder and 6 for MPD, e.g. to be used as \
+                systemd's ExecStartPre."
+            )
+            .arg(config_argument.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("describe")
+            .about(
+                "Describe a song in human terms: its tempo, loudness, brightness, and whether it \
+                sounds tonal or noisy, from its analysis. Songs that are not in blissify's \
+                database are analyzed on the fly."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("PATH")
+                .help("The song to describe, either relative to MPD's base path, like \"album/song.flac\", or an absolute path.")
+                .required(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("export-graph")
+            .about(
+                "Write each analyzed song, along with its nearest songs and their distance to it, \
+                as JSON, one song per line, for other tools to walk the library with."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("k")
+                .long("k")
+                .value_name("K")
+                .help("How many nearest songs to write for each song. Defaults to 10.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .help("Write the graph to FILE instead of stdout.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("distance")
+                .long("distance")
+                .value_name("distance metric")
+                .help("The distance metric used, as in `playlist --distance`. Defaults to 'euclidean'.")
+                .takes_value(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("clear-stickers")
+            .about("Remove the \"bliss_distance\" stickers written by `blissify playlist --publish-stickers` from all songs.")
+            .arg(config_argument.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("like")
+            .about("Like a song, so that playlists lean towards songs similar to it. See also --feedback-weight.")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("PATH")
+                .help(
+                    "The song to like, either relative to the MPD base path, like \"album/song.flac\" or \"album/file.cue/track0001\", or absolute. Defaults to the current song."
+                )
+            )
+            .arg(Arg::with_name("remove")
+                .long("remove")
+                .help("Forget about the feedback given on the song instead.")
+                .takes_value(false)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("dislike")
+            .about("Dislike a song, so that playlists stay away from songs similar to it. See also --feedback-weight.")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("PATH")
+                .help(
+                    "The song to dislike, either relative to the MPD base path, like \"album/song.flac\" or \"album/file.cue/track0001\", or absolute. Defaults to the current song."
+                )
+            )
+            .arg(Arg::with_name("remove")
+                .long("remove")
+                .help("Forget about the feedback given on the song instead.")
+                .takes_value(false)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("exclude")
+            .about("Never suggest songs in playlists, e.g. interludes or skits. They stay analyzed, and playlists can still be made from them.")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("PATH")
+                .help(
+                    "The songs to exclude, either relative to the MPD base path or absolute. Can be a directory, or a glob like \"album/*interlude*\"."
+                )
+                .required_unless_one(&["artist", "list"])
+                .multiple(true)
+            )
+            .arg(Arg::with_name("artist")
+                .long("artist")
+                .value_name("ARTIST")
+                .help("Exclude all the songs of this artist, ignoring case, including the ones analyzed later. Can be given several times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+            )
+            .arg(Arg::with_name("list")
+                .long("list")
+                .help("List the excluded artists and songs instead.")
+                .takes_value(false)
+                .conflicts_with_all(&["PATH", "artist"])
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("pin")
+            .about("Pin songs of the queue, so that playlists made without --keep-current-queue don't remove them.")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("POSITION")
+                .help("The positions of the songs to pin in the queue, starting from 1.")
+                .required_unless("list")
+                .multiple(true)
+            )
+            .arg(Arg::with_name("list")
+                .long("list")
+                .help("List the pinned songs instead.")
+                .takes_value(false)
+                .conflicts_with("POSITION")
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("unpin")
+            .about("Unpin songs pinned with \"blissify pin\", so that playlists can remove them again.")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("POSITION")
+                .help("The positions of the songs to unpin in the queue, starting from 1.")
+                .required_unless("all")
+                .multiple(true)
+            )
+            .arg(Arg::with_name("all")
+                .long("all")
+                .help("Unpin all the pinned songs instead.")
+                .takes_value(false)
+                .conflicts_with("POSITION")
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("include")
+            .about("Suggest songs excluded with \"blissify exclude\" in playlists again.")
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("PATH")
+                .help(
+                    "The songs to include again, either relative to the MPD base path or absolute. Can be a directory, or a glob like \"album/*interlude*\"."
+                )
+                .required_unless("artist")
+                .multiple(true)
+            )
+            .arg(Arg::with_name("artist")
+                .long("artist")
+                .value_name("ARTIST")
+                .help("Include the songs of an artist excluded with \"blissify exclude --artist\" again. Can be given several times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+            .about("Change blissify's configuration file.")
+            .subcommand(
+                SubCommand::with_name("set")
+                .about("Set a setting of the configuration file, e.g. \"blissify config set playlist.number_songs 30\".")
+                .arg(config_argument.clone())
+                .arg(Arg::with_name("KEY")
+                    .help("The setting's name in the configuration file, nested settings being separated by dots.")
+                    .required(true)
+                )
+                .arg(Arg::with_name("VALUE")
+                    .help("The setting's new value, as JSON or as a plain string.")
+                    .required(true)
+                )
+                .arg(Arg::with_name("rewrite-db")
+                    .long("rewrite-db")
+                    .help(
+                        "When setting mpd_base_path after moving the music library, also move the paths of \
+                        the songs already analyzed to the new base path, instead of having to analyze them \
+                        again."
+                    )
+                    .takes_value(false)
+                )
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("metric")
+            .about("Share the metric learned for the mahalanobis distance, see the \"Metric learning\" section of the README.")
+            .subcommand(
+                SubCommand::with_name("export")
+                .about("Write the learned metric, of the user given with --user if any, to a JSON file.")
+                .arg(config_argument.clone())
+                .arg(Arg::with_name("FILE")
+                    .help("The JSON file to write the metric to.")
+                    .required(true)
+                )
+            )
+            .subcommand(
+                SubCommand::with_name("import")
+                .about("Use the metric of a JSON file written by \"blissify metric export\", for the user given with --user if any, or for everyone.")
+                .arg(config_argument.clone())
+                .arg(Arg::with_name("FILE")
+                    .help("The JSON file to read the metric from.")
+                    .required(true)
+                )
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+            .about("Record the analyzed songs, to see later what e.g. an update changed.")
+            .subcommand(
+                SubCommand::with_name("save")
+                .about("Record the analyzed songs and their analysis as the snapshot NAME, replacing any snapshot of that name.")
+                .arg(config_argument.clone())
+                .arg(Arg::with_name("NAME")
+                    .help("The name of the snapshot.")
+                    .required(true)
+                )
+            )
+            .subcommand(
+                SubCommand::with_name("diff")
+                .about("Print the songs added (+), removed (-) or analyzed differently (~) since the snapshot NAME, or between NAME and OTHER.")
+                .arg(config_argument.clone())
+                .arg(Arg::with_name("NAME")
+                    .help("The snapshot to compare from.")
+                    .required(true)
+                )
+                .arg(Arg::with_name("OTHER")
+                    .help("The snapshot to compare to, instead of the current library.")
+                    .required(false)
+                )
+            )
+            .subcommand(
+                SubCommand::with_name("list")
+                .about("List the snapshots, with when they were saved and their number of songs.")
+                .arg(config_argument.clone())
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+            .about("Browse the playlists blissify made, and queue them again.")
+            .subcommand(
+                SubCommand::with_name("list")
+                .about("List the playlists made, from the oldest to the newest, with when and how they were made.")
+                .arg(config_argument.clone())
+            )
+            .subcommand(
+                SubCommand::with_name("show")
+                .about("Print how the playlist N was made, the songs it was made from, and its songs.")
+                .arg(config_argument.clone())
+                .arg(Arg::with_name("N")
+                    .help("The number of the playlist, as printed by \"blissify history list\".")
+                    .required(true)
+                )
+            )
+            .subcommand(
+                SubCommand::with_name("replay")
+                .about("Queue the songs of the playlist N again, as they were, after the current song.")
+                .arg(config_argument.clone())
+                .arg(Arg::with_name("N")
+                    .help("The number of the playlist, as printed by \"blissify history list\".")
+                    .required(true)
+                )
+                .arg(Arg::with_name("keep-queue")
+                    .long("keep-current-queue")
+                    .help("Keep the rest of the queue, instead of removing it save for the pinned songs.")
+                )
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("list-metrics")
+            .about("List the distance metrics \"playlist --distance\" can use, with what each of them does.")
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+            .about(
+                "Keep running, analyzing new songs as they are added to the MPD database. \
+                Meant to be run as a systemd service of Type=notify: it reloads its \
+                configuration on SIGHUP, and stops cleanly on SIGTERM or SIGINT, after \
+                the songs being analyzed have been stored."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("poll-interval")
+                .long("poll-interval")
+                .value_name("seconds")
+                .help("How often MPD is checked for database updates.")
+                .default_value("60")
+            )
+            .arg(Arg::with_name("max-queue-length")
+                .long("max-queue-length")
+                .value_name("number of songs")
+                .help("Remove already played songs from the top of the queue, then songs from its end if needed, whenever it has more than this many songs. Defaults to \"max_queue_length\" in the \"playlist\" section of the configuration file, if set.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("control-socket")
+                .long("control-socket")
+                .value_name("path")
+                .help(
+                    "Where to create the socket `blissify ctl` uses to control the daemon. Defaults to \"XDG_RUNTIME_DIR/blissify.sock\", or to \"blissify-UID.sock\" in the temporary directory without XDG_RUNTIME_DIR."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("metrics-address")
+                .long("metrics-address")
+                .value_name("address")
+                .help(
+                    "Expose Prometheus metrics on http://address/metrics, e.g. \"127.0.0.1:9747\". Metrics include the number of songs analyzed, analysis errors, and the size of the library."
+                )
+                .takes_value(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+            .about(
+                "Preview playlists from a browser: http://ADDRESS/playlist.html shows the playlist a dry run would make from the current song, with a button to play each song next, and http://ADDRESS/playlist gives the same as JSON. Add ?song=PATH to start from another song, and &number=N to change its length."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("ADDRESS")
+                .help("The address to listen on, e.g. \"127.0.0.1:9749\".")
+                .required(true)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("interactive-playlist")
+            .about(
+                "Make a playlist, prompting a set of close songs, and asking which one will be the most appropriate."
+            )
+            .arg(config_argument.clone())
+            .arg(Arg::with_name("continue")
+                .long("continue")
+                .help(
+                    "Take the current playlist's last song as a starting point, instead of removing the current playlist and starting from the first song."
+                )
+            )
+            .arg(Arg::with_name("choices")
+                .long("number-choices")
+                .value_name("choices")
+                .help(
+                    "Choose the number of proposed items you get each time.
+Defaults to 3, cannot be more than 9."
+                )
+                .default_value("3")
+            )
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDRESS")
+                .help(
+                    "Serve the choices on http://ADDRESS/ instead of prompting them on the terminal, e.g. \"0.0.0.0:9748\" to choose the songs from a phone on the same network. The page has a button for each choice, and http://ADDRESS/session gives the same as JSON."
+                )
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("random-start")
+                .long("random-start")
+                .help(
+                    "Replace the queue with a random song from a dense region of the library, i.e. with many similar songs, and start from it, instead of starting from the current song."
+                )
+                .conflicts_with("continue")
+            )
+            .arg(Arg::with_name("genre")
+                .long("genre")
+                .value_name("genre")
+                .help(
+                    "With --random-start, only pick a starting song having this genre."
+                )
+                .requires("random-start")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("target-duration")
+                .long("target-duration")
+                .value_name("duration")
+                .help(
+                    "Suggest finishing the session once the playlist is at least this long, e.g. \"1h\", \"45m\" or \"1h30m\"."
+                )
+                .takes_value(true)
+            )
+        )
+        .get_matches();
+
+    let mut config_path = match matches.subcommand() {
+        (_, Some(sub_m)) => sub_m.value_of("config-path").map(PathBuf::from),
+        _ => None,
+    };
+    if config_path.is_none() {
+        config_path = matches.value_of("config-path").map(PathBuf::from);
+    }
+    let format: OutputFormat = match matches.subcommand() {
+        (_, Some(sub_m)) => sub_m.value_of("format"),
+        _ => None,
+    }
+    .or_else(|| matches.value_of("format"))
+    .unwrap_or("plain")
+    .parse()?;
+    let user = match matches.subcommand() {
+        (_, Some(sub_m)) => sub_m.value_of("user"),
+        _ => None,
+    }
+    .or_else(|| matches.value_of("user"))
+    .map(String::from);
+    schema::set_backup_before_migrate(
+        matches.is_present("backup-before-migrate")
+            || matches
+                .subcommand()
+                .1
+                .is_some_and(|sub_m| sub_m.is_present("backup-before-migrate")),
+    );
+    if let Some(sub_m) = matches.subcommand_matches("list-db") {
+        let mut library = MPDLibrary::from_config_path(config_path)?;
+        let config = &library.library.config;
+        let filter = list::ListFilter {
+            artist: sub_m.value_of("artist").map(String::from),
+            album: sub_m.value_of("album").map(String::from),
+            genre: sub_m.value_of("genre").map(String::from),
+            // Relative to MPD's base path, unless already absolute.
+            path_prefix: sub_m.value_of("path-prefix").map(|p| {
+                if config
+                    .base_paths()
+                    .any(|b| p.starts_with(b.to_string_lossy().as_ref()))
+                {
+                    p.to_owned()
+                } else {
+                    config
+                        .resolve_mpd_path(Path::new(p))
+                        .to_string_lossy()
+                        .to_string()
+                }
+            }),
+            pending: sub_m.is_present("pending-only"),
+            missing_files: sub_m.is_present("missing-files"),
+            sort: sub_m.value_of("sort").unwrap_or("path").parse()?,
+        };
+        let detailed = sub_m.is_present("detailed");
+        if filter.pending {
+            library.classify_errors()?;
+        }
+        let songs = library.list_songs(&filter, detailed)?;
+        let removed = if sub_m.is_present("remove") {
+            Some(
+                library
+                    .library
+                    .delete_paths(songs.iter().map(|s| PathBuf::from(&s.path)))?,
+            )
+        } else {
+            None
+        };
+        if format == OutputFormat::Json {
+            let songs = songs
+                .into_iter()
+                .map(|song| SongOutput {
+                    path: song.path,
+                    artist: song.artist,
+                    title: song.title,
+                    album: song.album,
+                    bpm: song.analysis.as_ref().map(describe::tempo_bpm),
+                    distance: None,
+                    analysis: song.analysis.map(|a| a.as_vec()),
+                    error: song.error,
+                    error_kind: song.error_kind,
+                    analyzed_at: song.analyzed_at.filter(|_| detailed),
+                    source: None,
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string(&songs)?);
+            return Ok(());
+        }
+        for song in songs {
+            match (detailed, song.analysis, song.error) {
+                (true, Some(analysis), _) => {
+                    let bpm = describe::tempo_bpm(&analysis);
+                    match song.analyzed_at {
+                        Some(analyzed_at) => println!(
+                            "{} (~{:.0} BPM, analyzed {}): {:?}",
+                            song.path, bpm, analyzed_at, analysis
+                        ),
+                        None => println!("{} (~{:.0} BPM): {:?}", song.path, bpm, analysis),
+                    }
+                }
+                (true, None, Some(error)) => println!(
+                    "{}: [{}] {}",
+                    song.path,
+                    song.error_kind.as_deref().unwrap_or("other"),
+                    error
+                ),
+                _ => println!("{}", song.path),
+            }
+        }
+        if let Some(removed) = removed {
+            println!("Removed {} song(s) from the database.", removed);
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("init") {
+        let database_path = match sub_m.value_of("database-path") {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let path = default_database_path()?;
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                path
+            }
+        };
+        let number_cores = parse_number_cores(sub_m)?;
+        if sub_m.is_present("interactive") {
+            let stdin = io::stdin();
+            let mut stdout = io::stdout();
+            let mut prompt = wizard::Prompt {
+                input: &mut stdin.lock(),
+                output: &mut stdout,
+            };
+            return wizard::run(
+                &mut prompt,
+                config_path,
+                database_path,
+                number_cores,
+                sub_m.is_present("isolate"),
+            );
+        }
+        let base_path = sub_m.value_of("MPD_BASE_PATH").unwrap();
+        let mut library = MPDLibrary::new(
+            PathBuf::from(base_path),
+            config_path,
+            Some(database_path),
+            number_cores,
+        )?;
+
+        library.library.config.isolate_analysis |= sub_m.is_present("isolate");
+        if let Some(size) = sub_m.value_of("max-file-size") {
+            library.library.config.max_file_size = Some(size.parse()?);
+        }
+        library.full_rescan()?;
+        library.print_error_summary()?;
+    } else if matches.subcommand_matches("analyze-worker").is_some() {
+        isolation::run_worker()?;
+    } else if let Some(sub_m) = matches.subcommand_matches("migrate-database") {
+        let destination = migrate_database(config_path, sub_m.value_of("to").map(PathBuf::from))?;
+        println!("The database is now in {}.", destination.display());
+    } else if let Some(sub_m) = matches.subcommand_matches("import-db") {
+        let mut library = MPDLibrary::from_config_path(config_path)?;
+        let replace_prefix = sub_m
+            .values_of("replace-prefix")
+            .map(|v| v.collect::<Vec<_>>())
+            .map(|v| (v[0], v[1]));
+        let summary = library.import_database(
+            Path::new(sub_m.value_of("DATABASE").unwrap()),
+            replace_prefix,
+        )?;
+        println!(
+            "Imported {} song(s), {} were already analyzed, {} are not in MPD's library, and {} \
+            were not fully analyzed or with another version of bliss' features.",
+            summary.imported, summary.already_analyzed, summary.not_in_library, summary.unusable,
+        );
+    } else if let Some(sub_m) = matches.subcommand_matches("import-play-counts") {
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        let (matched, unmatched) =
+            library.import_play_counts(Path::new(sub_m.value_of("FILE").unwrap()))?;
+        println!(
+            "Imported {} play(s), {} did not match any analyzed song.",
+            matched, unmatched
+        );
+    } else if let Some(sub_m) = matches.subcommand_matches("rescan") {
+        let mut library = MPDLibrary::from_config_path(config_path)?;
+        let number_cores = parse_number_cores(sub_m)?;
+        if let Some(cores) = number_cores {
+            library.library.config.set_number_cores(cores)?;
+        };
+        library.library.config.isolate_analysis |= sub_m.is_present("isolate");
+        if let Some(size) = sub_m.value_of("max-file-size") {
+            library.library.config.max_file_size = Some(size.parse()?);
+        }
+        let selection = RescanSelection {
+            only_errors: sub_m.is_present("only-errors"),
+            only_outdated: sub_m.is_present("only-outdated"),
+            directory: sub_m.value_of("path").map(String::from),
+        };
+        let failed = library.rescan(&selection)?;
+        if failed > 0 {
+            bail!("{} song(s) failed to be analyzed.", failed);
+        }
+        library.print_error_summary()?;
+    } else if let Some(sub_m) = matches.subcommand_matches("update") {
+        let mut library = MPDLibrary::from_config_path(config_path)?;
+        let number_cores = parse_number_cores(sub_m)?;
+
+        if let Some(cores) = number_cores {
+            library.library.config.set_number_cores(cores)?;
+        };
+        library.library.config.isolate_analysis |= sub_m.is_present("isolate");
+        if let Some(size) = sub_m.value_of("max-file-size") {
+            library.library.config.max_file_size = Some(size.parse()?);
+        }
+        if let Some(songs) = sub_m.values_of("song") {
+            let paths = songs
+                .map(|s| library.resolve_song_path(s))
+                .collect::<Result<Vec<_>>>()?;
+            let failed = library.reanalyze(&paths)?;
+            if failed > 0 {
+                bail!("{} song(s) failed to be analyzed.", failed);
+            }
+        } else if let Some(age) = sub_m.value_of("older-than") {
+            let paths = library.songs_analyzed_before(parse_duration(age)?)?;
+            info!("Analyzing {} song(s) again.", paths.len());
+            let failed = library.reanalyze(&paths)?;
+            if failed > 0 {
+                bail!("{} song(s) failed to be analyzed.", failed);
+            }
+        } else if let Some(directory) = sub_m.value_of("path") {
+            library.update_directory(directory)?;
+            library.print_error_summary()?;
+        } else {
+            let paths = library.get_songs_paths()?;
+            library.remove_changed_songs(&paths)?;
+            library.analyze_embedded_cues(&paths)?;
+            library.update_library_paths(paths, true, true)?;
+            library.retry_failed_songs()?;
+            library.mark_degenerate_songs()?;
+            library.sync_genres()?;
+            library.print_error_summary()?;
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("playlist") {
+        let mut library = MPDLibrary::from_config_path_offline(config_path)?;
+        library.user = user;
+        let defaults = library.playlist_defaults().clone();
+        let number_songs = match sub_m.value_of("NUMBER_SONGS") {
+            Some(n) => match n.parse::<usize>() {
+                Err(_) => {
+                    bail!("Playlist number must be a valid number.");
+                }
+                Ok(n) => n,
+            },
+            None => defaults.number_songs.with_context(|| {
+                "Please specify the number of songs to queue, or set a default \
+                \"number_songs\" in the \"playlist\" section of the configuration file."
+            })?,
+        };
+        let mut options = playlist_options(&library, sub_m, &defaults, format)?;
+        if let Some(name) = sub_m.value_of("target-server") {
+            library.use_server(name)?;
+        }
+        if sub_m.is_present("offline") {
+            if options.modifies_queue() {
+                bail!("Offline mode cannot modify MPD's queue, use it with --dry-run or --output-m3u.");
+            }
+        } else if let Err(e) = library.connect() {
+            if options.modifies_queue() {
+                return Err(e);
+            }
+            warn!(
+                "Could not connect to MPD ({}), making the playlist from blissify's database only.",
+                e
+            );
+        } else if sub_m.is_present("update-first") || defaults.update_first {
+            if library.server.is_some() {
+                warn!("Not analyzing new songs first, since the songs of another server can't be analyzed; run `blissify update` against the local server instead.");
+            } else {
+                library.update_new_songs()?;
+            }
+        }
+
+        let announcement = if sub_m.is_present("announce") && options.modifies_queue() {
+            Some(announce::PlaylistAnnouncement::from_matches(
+                sub_m,
+                &defaults,
+                number_songs,
+                library.user.to_owned(),
+            )?)
+        } else {
+            None
+        };
+        if let Some(guest_db) = sub_m.value_of("guest-db") {
+            let replace_prefix = sub_m
+                .values_of("guest-replace-prefix")
+                .map(|v| v.collect::<Vec<_>>())
+                .map(|v| (v[0], v[1]));
+            options.guest_songs = library.guest_songs(Path::new(guest_db), replace_prefix)?;
+        }
+        if let Some(song_path) = sub_m.value_of("from-song") {
+            library.ensure_seed_analyzed(song_path, sub_m.is_present("analyze-missing-seed"))?;
+        }
+        let from_song = match queue_song::QueueSong::from_args(sub_m)? {
+            Some(song) => Some(library.queue_song_path(song)?),
+            None => sub_m.value_of("from-song").map(String::from),
+        };
+        let timings = timing::Timings::start();
+        if sub_m.is_present("album") {
+            library.queue_from_current_album(number_songs, &options)?;
+        } else if sub_m.is_present("one-album") {
+            library.queue_one_album(from_song.as_deref(), &options)?;
+        } else {
+            with_playlist_ranking(&library, sub_m, &defaults, &timings, |ranking| {
+                if let Some(file) = sub_m.value_of("from-file") {
+                    library.queue_from_file(
+                        Path::new(file),
+                        number_songs,
+                        ranking.distance,
+                        ranking.sort,
+                        &options,
+                    )?;
+                } else if sub_m.is_present("from-stdin") {
+                    let paths = io::stdin()
+                        .lock()
+                        .lines()
+                        .collect::<Result<Vec<String>, _>>()?
+                        .iter()
+                        .map(|l| l.trim())
+                        .filter(|l| !l.is_empty())
+                        .map(|l| {
+                            library
+                                .resolve_song_path(l)
+                                .map(|p| p.to_string_lossy().to_string())
+                        })
+                        .collect::<Result<Vec<String>>>()?;
+                    library.queue_from_paths(
+                        &paths,
+                        number_songs,
+                        ranking.multiple_songs_distance,
+                        ranking.sort,
+                        &options,
+                    )?;
+                } else if let Some(range) = sub_m.value_of("from-log") {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+                    let range = mpd_log::parse_time_range(range, now)?;
+                    let log_path = match sub_m.value_of("mpd-log") {
+                        Some(path) => PathBuf::from(path),
+                        None => library
+                            .library
+                            .config
+                            .mpd_log_path
+                            .to_owned()
+                            .with_context(|| {
+                                "Please give the path of MPD's log with --mpd-log, or set \
+                            \"mpd_log_path\" in the configuration file."
+                            })?,
+                    };
+                    let paths = library.songs_played_during(&log_path, range)?;
+                    if paths.is_empty() {
+                        bail!("No analyzed song was played then, according to MPD's log.");
+                    }
+                    library.queue_from_paths(
+                        &paths,
+                        number_songs,
+                        ranking.multiple_songs_distance,
+                        ranking.sort,
+                        &options,
+                    )?;
+                } else if sub_m.is_present("entire") {
+                    library.queue_from_current_playlist(
+                        number_songs,
+                        ranking.multiple_songs_distance,
+                        ranking.sort,
+                        &options,
+                    )?;
+                } else if sub_m.is_present("use-priorities") {
+                    library.prioritize_queue(
+                        number_songs,
+                        ranking.distance,
+                        ranking.sort,
+                        &options,
+                    )?;
+                } else if sub_m.is_present("station") {
+                    library.run_station(
+                        from_song.as_deref(),
+                        number_songs,
+                        ranking.distance,
+                        ranking.sort,
+                        &options,
+                    )?;
+                } else {
+                    library.queue_from_song(
+                        from_song.as_deref(),
+                        number_songs,
+                        ranking.distance,
+                        ranking.sort,
+                        &options,
+                    )?;
+                }
+                Ok(())
+            })?;
+        }
+        if let Some(announcement) = announcement {
+            library.announce_playlist(announcement)?;
+        }
+        if sub_m.is_present("time") {
+            timings.print();
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("daemon") {
+        let poll_interval = match sub_m
+            .value_of("poll-interval")
+            .unwrap_or("60")
+            .parse::<u64>()
+        {
+            Ok(i) if i > 0 => Duration::from_secs(i),
+            _ => bail!("The poll interval must be a positive number of seconds."),
+        };
+        let max_queue_length = sub_m
+            .value_of("max-queue-length")
+            .map(parse_max_queue_length)
+            .transpose()?;
+        daemon::run(daemon::DaemonOptions {
+            config_path,
+            poll_interval,
+            max_queue_length,
+            metrics_address: sub_m.value_of("metrics-address").map(String::from),
+            control_socket: sub_m
+                .value_of("control-socket")
+                .map(PathBuf::from)
+                .unwrap_or_else(control::default_socket_path),
+        })?;
+    } else if let Some(sub_m) = matches.subcommand_matches("ctl") {
+        let control_socket = sub_m
+            .value_of("control-socket")
+            .map(PathBuf::from)
+            .unwrap_or_else(control::default_socket_path);
+        let command = match sub_m.value_of("PROFILE") {
+            Some(profile) => format!("{} {}", sub_m.value_of("COMMAND").unwrap(), profile),
+            None => sub_m.value_of("COMMAND").unwrap().to_owned(),
+        };
+        let response = control::send(&control_socket, &command)?;
+        println!("{}", response);
+    } else if let Some(sub_m) = matches.subcommand_matches("similar-album") {
+        let number_albums = match sub_m.value_of("NUMBER_ALBUMS").unwrap_or("10").parse() {
+            Ok(n) => n,
+            Err(_) => bail!("The number of albums must be a valid number."),
+        };
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        let albums = library.similar_albums(sub_m.value_of("ALBUM").unwrap(), number_albums)?;
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string(&albums)?);
+        } else {
+            for album in albums {
+                match album.artist {
+                    Some(artist) => println!("{} - {}", artist, album.album),
+                    None => println!("{}", album.album),
+                }
+            }
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("stats") {
+        let mut library = MPDLibrary::from_config_path_offline(config_path)?;
+        if sub_m.is_present("listening") {
+            let listening = library.listening()?;
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::to_string(&listening)?);
+            } else {
+                stats::print_listening(&listening);
+            }
+            return Ok(());
+        }
+        let mpd_paths = match library.connect() {
+            Ok(()) => Some(library.get_songs_paths()?),
+            Err(e) => {
+                warn!(
+                    "Could not connect to MPD ({}), leaving the pending songs out.",
+                    e
+                );
+                None
+            }
+        };
+        let directories = library.coverage_by_directory(mpd_paths.as_deref())?;
+        let total = stats::total_coverage(&directories, mpd_paths.is_some());
+        if format == OutputFormat::Json {
+            if sub_m.is_present("by-directory") {
+                println!("{}", serde_json::to_string(&directories)?);
+            } else {
+                println!("{}", serde_json::to_string(&total)?);
+            }
+        } else {
+            println!(
+                "{:<30}\t{:>8}\t{:>8}\t{:>8}",
+                "directory", "analyzed", "failed", "pending"
+            );
+            if sub_m.is_present("by-directory") {
+                for coverage in &directories {
+                    stats::print_coverage(coverage.directory.as_deref().unwrap(), coverage);
+                }
+            }
+            stats::print_coverage("total", &total);
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("analyze-file") {
+        let decoder = sub_m.value_of("decoder").unwrap();
+        let mut failed = 0;
+        for file in sub_m.values_of("FILE").unwrap() {
+            let output = analyze_file(Path::new(file), decoder)?;
+            if output.error.is_some() {
+                failed += 1;
+            }
+            if sub_m.is_present("print") {
+                println!("{}", serde_json::to_string(&output)?);
+            } else if let (Some(error), Some(kind)) = (&output.error, &output.error_kind) {
+                println!("{}: {} ({})", output.path, error, kind);
+            } else {
+                println!("{}: analyzed", output.path);
+            }
+        }
+        if failed > 0 {
+            bail!("{} file(s) could not be analyzed with {}.", failed, decoder);
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("compare") {
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        let (first, second) = (
+            sub_m.value_of("FIRST").unwrap(),
+            sub_m.value_of("SECOND").unwrap(),
+        );
+        if sub_m.is_present("album") {
+            let comparison = library.compare_albums(first, second)?;
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::to_string(&comparison)?);
+            } else {
+                println!("Distance: {:.6}", comparison.distance);
+                for track in comparison.tracks {
+                    println!("{:.6}\t{}", track.distance.unwrap_or_default(), track.path);
+                }
+            }
+        } else {
+            let song = |path| {
+                let path = library.resolve_song_path(path)?;
+                library
+                    .library
+                    .song_from_path::<()>(&path.to_string_lossy())
+                    .with_context(|| {
+                        format!("while looking for {} in the database", path.display())
+                    })
+            };
+            let (first, second) = (song(first)?, song(second)?);
+            let distance = euclidean_distance(
+                &first.bliss_song.analysis.as_arr1(),
+                &second.bliss_song.analysis.as_arr1(),
+            );
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::json!({ "distance": distance }));
+            } else {
+                println!("Distance: {:.6}", distance);
+            }
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("top") {
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        let number_songs = match sub_m.value_of("NUMBER_SONGS") {
+            Some(n) => match n.parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => bail!("The number of songs must be a valid number."),
+            },
+            None => 20,
+        };
+        let distance =
+            library.distance_metric(sub_m.value_of("distance").unwrap_or("euclidean"))?;
+        let songs = library.top_songs(number_songs, sub_m.is_present("outliers"), &*distance)?;
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string(&songs)?);
+        } else {
+            for song in songs {
+                println!("{:.6}\t{}", song.distance.unwrap_or_default(), song.path);
+            }
+        }
+    } else if matches.subcommand_matches("check").is_some() {
+        if let Some(code) = check::report(&check::run_checks(config_path)) {
+            std::process::exit(code);
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("describe") {
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        let path = library.resolve_song_path(sub_m.value_of("PATH").unwrap())?;
+        let song = match library
+            .library
+            .song_from_path::<()>(&path.to_string_lossy())
+        {
+            Ok(song) => song.bliss_song,
+            // Songs that are not analyzed, or not in the library at all.
+            Err(_) => Decoder::song_from_path(&path)
+                .with_context(|| format!("while analyzing {}", path.display()))?,
+        };
+        let mut description = describe::Description::new(&song);
+        description.analyzed_at = library.analyzed_at(&path.to_string_lossy())?;
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string(&description)?);
+        } else {
+            println!("{}", description);
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("export-graph") {
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        let k = match sub_m.value_of("k").unwrap_or("10").parse::<usize>() {
+            Ok(k) if k > 0 => k,
+            _ => bail!("The number of nearest songs must be a positive number."),
+        };
+        let distance =
+            library.distance_metric(sub_m.value_of("distance").unwrap_or("euclidean"))?;
+        let mut output: Box<dyn Write> = match sub_m.value_of("output") {
+            Some(path) => Box::new(io::BufWriter::new(
+                std::fs::File::create(path).with_context(|| format!("while creating {}", path))?,
+            )),
+            None => Box::new(io::stdout().lock()),
+        };
+        let number_songs = library.export_graph(k, &*distance, &mut output)?;
+        if sub_m.is_present("output") {
+            println!("Wrote the nearest songs of {} song(s).", number_songs);
+        }
+    } else if matches.subcommand_matches("clear-stickers").is_some() {
+        let library = MPDLibrary::from_config_path(config_path)?;
+        let number_songs = library.clear_stickers()?;
+        println!("Removed the stickers of {} song(s).", number_songs);
+    } else if let Some((sub_m, rating)) = matches
+        .subcommand_matches("like")
+        .map(|m| (m, Rating::Like))
+        .or_else(|| {
+            matches
+                .subcommand_matches("dislike")
+                .map(|m| (m, Rating::Dislike))
+        })
+    {
+        let mut library = MPDLibrary::from_config_path_offline(config_path)?;
+        library.user = user;
+        let path = match sub_m.value_of("PATH") {
+            Some(p) => library.resolve_song_path(p)?,
+            None => {
+                library.connect()?;
+                let mpd_song = match library.mpd_conn()?.currentsong()? {
+                    Some(s) => s,
+                    None => bail!("No song is currently playing, please specify a song to rate."),
+                };
+                library.mpd_to_bliss_path(&mpd_song)?
+            }
+        };
+        let path = path.to_string_lossy();
+        if sub_m.is_present("remove") {
+            library.set_rating(&path, None)?;
+            println!("Removed the feedback given on {}.", path);
+        } else {
+            library.set_rating(&path, Some(rating))?;
+            println!(
+                "{} {}.",
+                if rating == Rating::Like {
+                    "Liked"
+                } else {
+                    "Disliked"
+                },
+                path
+            );
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("exclude") {
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        if sub_m.is_present("list") {
+            for artist in library.excluded_artists()? {
+                println!("artist: {}", artist);
+            }
+            let mut paths = library.excluded_songs()?.into_iter().collect::<Vec<_>>();
+            paths.sort();
+            for path in paths {
+                println!("{}", path.display());
+            }
+        }
+        for artist in sub_m.values_of("artist").into_iter().flatten() {
+            match library.exclude_artist(artist)? {
+                true => println!("Excluded the songs of {}.", artist),
+                false => println!("The songs of {} were already excluded.", artist),
+            }
+        }
+        for path in sub_m.values_of("PATH").into_iter().flatten() {
+            let pattern = library.resolve_song_path(path)?;
+            let pattern = pattern.to_string_lossy();
+            match library.exclude_songs(&pattern)? {
+                0 => println!("No analyzed song left to exclude matches {}.", pattern),
+                n => println!("Excluded {} song(s) matching {}.", n, pattern),
+            }
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("include") {
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        for artist in sub_m.values_of("artist").into_iter().flatten() {
+            match library.include_artist(artist)? {
+                true => println!("Included the songs of {} again.", artist),
+                false => println!("{} was not excluded.", artist),
+            }
+        }
+        for path in sub_m.values_of("PATH").into_iter().flatten() {
+            let pattern = library.resolve_song_path(path)?;
+            let pattern = pattern.to_string_lossy();
+            match library.include_songs(&pattern)? {
+                0 => println!("No excluded song matches {}.", pattern),
+                n => println!("Included {} song(s) matching {} again.", n, pattern),
+            }
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("pin") {
+        let library = MPDLibrary::from_config_path(config_path)?;
+        if sub_m.is_present("list") {
+            let pinned = library.pinned_songs(&mut *library.mpd_conn()?)?;
+            if pinned.is_empty() {
+                println!("No song of the queue is pinned.");
+            }
+            for (position, song) in pinned {
+                println!("{}\t{}", position + 1, song.file);
+            }
+        } else {
+            let positions = queue_positions(sub_m)?;
+            for song in library.pin_songs(&positions)? {
+                println!("Pinned {}.", song.file);
+            }
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("unpin") {
+        let library = MPDLibrary::from_config_path(config_path)?;
+        let positions = match sub_m.is_present("all") {
+            true => None,
+            false => Some(queue_positions(sub_m)?),
+        };
+        let unpinned = library.unpin_songs(positions.as_deref())?;
+        println!("Unpinned {} song(s).", unpinned);
+    } else if let Some(sub_m) = matches
+        .subcommand_matches("config")
+        .and_then(|sub_m| sub_m.subcommand_matches("set"))
+    {
+        let config_path = sub_m
+            .value_of("config-path")
+            .map(PathBuf::from)
+            .or(config_path);
+        let mut library = MPDLibrary::from_config_path_offline(config_path)?;
+        let key = sub_m.value_of("KEY").unwrap();
+        let config = settings::set_setting(
+            &library.library.config,
+            key,
+            sub_m.value_of("VALUE").unwrap(),
+        )?;
+        if sub_m.is_present("rewrite-db") {
+            if key != "mpd_base_path" {
+                bail!("--rewrite-db only applies to mpd_base_path.");
+            }
+            let old_base_path = library.library.config.mpd_base_path.to_owned();
+            let moved = library.rewrite_base_path(&config.mpd_base_path)?;
+            println!(
+                "Moved {} song(s) from {} to {}.",
+                moved,
+                old_base_path.display(),
+                config.mpd_base_path.display(),
+            );
+        } else {
+            if key == "mpd_base_path"
+                && config.mpd_base_path != library.library.config.mpd_base_path
+            {
+                warn!(
+                    "The songs already analyzed are still stored under {}; pass --rewrite-db to move them to the new base path.",
+                    library.library.config.mpd_base_path.display(),
+                );
+            }
+            config.write()?;
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("metric") {
+        let (sub_m, export) = match sub_m.subcommand() {
+            ("export", Some(sub_m)) => (sub_m, true),
+            ("import", Some(sub_m)) => (sub_m, false),
+            _ => bail!(
+                "Please choose between \"blissify metric export\" and \"blissify metric import\"."
+            ),
+        };
+        let config_path = sub_m
+            .value_of("config-path")
+            .map(PathBuf::from)
+            .or(config_path);
+        let mut library = MPDLibrary::from_config_path_offline(config_path)?;
+        library.user = sub_m.value_of("user").map(String::from).or(user);
+        let path = Path::new(sub_m.value_of("FILE").unwrap());
+        if export {
+            library.export_metric(path)?;
+        } else {
+            library.import_metric(path)?;
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("snapshot") {
+        let (command, sub_m) = match sub_m.subcommand() {
+            (command @ ("save" | "diff" | "list"), Some(sub_m)) => (command, sub_m),
+            _ => bail!(
+                "Please choose between \"blissify snapshot save\", \"blissify snapshot diff\" and \"blissify snapshot list\"."
+            ),
+        };
+        let config_path = sub_m
+            .value_of("config-path")
+            .map(PathBuf::from)
+            .or(config_path);
+        let library = MPDLibrary::from_config_path_offline(config_path)?;
+        match command {
+            "save" => {
+                let name = sub_m.value_of("NAME").unwrap();
+                let songs = library.save_snapshot(name)?;
+                println!("Saved {} song(s) as the snapshot '{}'.", songs, name);
+            }
+            "diff" => {
+                let diff = library
+                    .diff_snapshot(sub_m.value_of("NAME").unwrap(), sub_m.value_of("OTHER"))?;
+                if format == OutputFormat::Json {
+                    println!("{}", serde_json::to_string(&diff)?);
+                } else {
+                    println!("{}", diff);
+                }
+            }
+            _ => {
+                for (name, created_at, songs) in library.snapshots()? {
+                    println!("{}: {} song(s), saved {}", name, songs, created_at);
+                }
+            }
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("history") {
+        let (command, sub_m) = match sub_m.subcommand() {
+            (command @ ("list" | "show" | "replay"), Some(sub_m)) => (command, sub_m),
+            _ => bail!(
+                "Please choose between \"blissify history list\", \"blissify history show\" and \"blissify history replay\"."
+            ),
+        };
+        let config_path = sub_m
+            .value_of("config-path")
+            .map(PathBuf::from)
+            .or(config_path);
+        let id = sub_m
+            .value_of("N")
+            .map(|n| {
+                n.parse::<i64>().with_context(|| {
+                    format!("The playlist must be given by its number, got '{}'.", n)
+                })
+            })
+            .transpose()?;
+        match (command, id) {
+            ("show", Some(id)) => {
+                let library = MPDLibrary::from_config_path_offline(config_path)?;
+                let playlist = library.generated_playlist(id)?;
+                if format == OutputFormat::Json {
+                    println!("{}", serde_json::to_string(&playlist)?);
+                } else {
+                    print!("{}", playlist);
+                }
+            }
+            ("replay", Some(id)) => {
+                let library = MPDLibrary::from_config_path(config_path)?;
+                let queued = library.replay_playlist(id, sub_m.is_present("keep-queue"))?;
+                println!("Queued {} song(s) from playlist {}.", queued, id);
+            }
+            _ => {
+                let library = MPDLibrary::from_config_path_offline(config_path)?;
+                let playlists = library.generated_playlists()?;
+                if format == OutputFormat::Json {
+                    println!("{}", serde_json::to_string(&playlists)?);
+                } else {
+                    for playlist in playlists {
+                        println!("{}", playlist.summary());
+                    }
+                }
+            }
+        }
+    } else if matches.subcommand_matches("list-metrics").is_some() {
+        for (name, description) in hybrid::metrics() {
+            println!("{}: {}", name, description);
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("interactive-playlist") {
+        let number_choices: usize = sub_m.value_of("choices").unwrap_or("3").parse()?;
+        let target_duration = sub_m
+            .value_of("target-duration")
+            .map(parse_duration)
+            .transpose()?;
+        let mut library = MPDLibrary::from_config_path(config_path)?;
+        library.make_interactive_playlist(
+            sub_m.is_present("continue"),
+            number_choices,
+            target_duration,
+            sub_m.is_present("random-start"),
+            sub_m.value_of("genre"),
+            sub_m.value_of("listen"),
+        )?;
+    } else if let Some(sub_m) = matches.subcommand_matches("serve") {
+        let library = MPDLibrary::from_config_path(config_path)?;
+        let address = sub_m.value_of("ADDRESS").unwrap();
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("while listening on {}", address))?;
+        println!(
+            "Open http://{}/playlist.html to preview playlists.",
+            address
+        );
+        serve::serve(&library, &listener)?;
+    }
+
+    Ok(())
+}
+
+// TODO test the playlist length thingy
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bliss_audio::Analysis;
+    use mpd::error::Result;
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use mpd::Status;
+    use pretty_assertions::assert_eq;
+    use std::ops;
+    use tempdir::TempDir;
+
+    impl MockMPDClient {
+        pub fn connect(address: &str) -> Result<Self> {
+            assert_eq!(address, "127.0.0.1:6600");
+            Ok(Self {
+                mpd_queue: vec![],
+                search_window: 0,
+                random: false,
+                stickers: Default::default(),
+                fail_search: false,
+                consume: false,
+                messages: vec![],
+            })
+        }
+
+        pub fn sendmessage(&mut self, channel: mpd::Channel, message: &str) -> Result<()> {
+            self.messages.push((channel, message.to_owned()));
+            Ok(())
+        }
+
+        pub fn currentsong(&mut self) -> Result<Option<MPDSong>> {
+            // The first song whose place is its actual position, or the first
+            // song of the queue.
+            let current = self
+                .mpd_queue
+                .iter()
+                .enumerate()
+                .find(|(i, s)| s.place.is_some_and(|p| p.pos as usize == *i))
+                .map(|(_, s)| s)
+                .or(self.mpd_queue.first());
+            Ok(current.cloned())
+        }
+
+        pub fn songs(&mut self, pos: std::ops::RangeFrom<u32>) -> Result<Vec<MPDSong>> {
+            let range = std::ops::RangeFrom {
+                start: pos.start as usize,
+            };
+            Ok(self.mpd_queue[range].to_vec())
+        }
+
+        pub fn search(&mut self, _: &Query, _: Window) -> Result<Vec<MPDSong>> {
+            if self.fail_search {
+                return Err(mpd::error::Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out",
+                )));
+            }
+            if self.search_window >= 1 {
+                return Ok(vec![]);
+            }
+            self.search_window += 1;
+            Ok(vec![
+                MPDSong {
+                    file: String::from("s16_mono_22_5kHz.flac"),
+                    ..Default::default()
+                },
+                MPDSong {
+                    file: String::from("s16_stereo_22_5kHz.flac"),
+                    ..Default::default()
+                },
+                MPDSong {
+                    file: String::from("foo"),
+                    ..Default::default()
+                },
+            ])
+        }
+
+        pub fn listall(&mut self) -> Result<Vec<MPDSong>> {
+            Ok(vec![
+                MPDSong {
+                    file: String::from("Albums/first.flac"),
+                    ..Default::default()
+                },
+                MPDSong {
+                    file: String::from("Albums/album.cue/track0001"),
+                    ..Default::default()
+                },
+                MPDSong {
+                    file: String::from("Albums/album.cue/track0002"),
+                    ..Default::default()
+                },
+                MPDSong {
+                    file: String::from("Albums/embedded.flac/track0001"),
+                    ..Default::default()
+                },
+                MPDSong {
+                    file: String::from("Albums/embedded.flac/track0002"),
+                    ..Default::default()
+                },
+                MPDSong {
+                    file: String::from("Other/second.flac"),
+                    ..Default::default()
+                },
+            ])
+        }
+
+        pub fn insert(&mut self, song: MPDSong, pos: usize) -> Result<usize> {
+            self.mpd_queue.insert(pos, song);
+            Ok(pos)
+        }
+
+        pub fn shift(&mut self, from: std::ops::Range<u32>, to: usize) -> Result<()> {
+            let moved = self
+                .mpd_queue
+                .drain(from.start as usize..from.end as usize)
+                .collect::<Vec<_>>();
+            for (i, song) in moved.into_iter().enumerate() {
+                self.mpd_queue.insert(to + i, song);
+            }
+            Ok(())
+        }
+
+        pub fn priority(&mut self, id: Id, prio: u8) -> Result<()> {
+            for place in self.mpd_queue.iter_mut().filter_map(|s| s.place.as_mut()) {
+                if place.id == id {
+                    place.prio = prio;
+                }
+            }
+            Ok(())
+        }
+
+        pub fn queue(&mut self) -> Result<Vec<MPDSong>> {
+            Ok(self.mpd_queue.to_owned())
+        }
+
+        pub fn delete<T>(&mut self, range: T) -> Result<()>
+        where
+            T: ops::RangeBounds<u32> + Iterator<Item = u32>,
+        {
+            // poor man's range, removing from the end so indices stay valid
+            let len = self.mpd_queue.len() as u32;
+            let indices = range.take_while(|i| *i < len).collect::<Vec<_>>();
+            for i in indices.into_iter().rev() {
+                self.mpd_queue.remove(i as usize);
+            }
+            Ok(())
+        }
+
+        pub fn find(&mut self, _: &Query, _: (u32, u32)) -> Result<Vec<MPDSong>> {
+            // Queries can't be looked into, so return all the songs of the
+            // queue, to be filtered by the caller.
+            Ok(self.mpd_queue.to_owned())
+        }
+
+        pub fn playlistid(&mut self, id: Id) -> Result<Option<MPDSong>> {
+            // Places are not updated when the queue changes, unlike MPD's.
+            let position = self
+                .mpd_queue
+                .iter()
+                .position(|s| s.place.is_some_and(|p| p.id == id));
+            Ok(position.map(|pos| {
+                let mut song = self.mpd_queue[pos].to_owned();
+                song.place = Some(QueuePlace {
+                    pos: pos as u32,
+                    ..song.place.unwrap()
+                });
+                song
+            }))
+        }
+
+        pub fn clear(&mut self) -> Result<()> {
+            self.mpd_queue.clear();
+            Ok(())
+        }
+
+        pub fn play(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn push(&mut self, song: MPDSong) -> Result<()> {
+            self.mpd_queue.push(song);
+            Ok(())
+        }
+
+        pub fn random(&mut self, state: bool) -> Result<()> {
+            self.random = state;
+            Ok(())
+        }
+
+        pub fn status(&mut self) -> Result<Status> {
+            Ok(Status {
+                random: self.random,
+                consume: self.consume,
+                queue_len: self.mpd_queue.len() as u32,
+                song: self.currentsong()?.and_then(|s| s.place),
+                ..Default::default()
+            })
+        }
+
+        pub fn stats(&mut self) -> Result<mpd::Stats> {
+            Ok(mpd::Stats::default())
+        }
+
+        pub fn set_sticker(&mut self, typ: &str, uri: &str, name: &str, value: &str) -> Result<()> {
+            assert_eq!(typ, "song");
+            self.stickers
+                .insert((uri.to_owned(), name.to_owned()), value.to_owned());
+            Ok(())
+        }
+
+        pub fn find_sticker(
+            &mut self,
+            typ: &str,
+            uri: &str,
+            name: &str,
+        ) -> Result<Vec<(String, String)>> {
+            assert_eq!(typ, "song");
+            Ok(self
+                .stickers
+                .iter()
+                .filter(|((u, n), _)| u.starts_with(uri) && n == name)
+                .map(|((u, _), v)| (u.to_owned(), v.to_owned()))
+                .collect())
+        }
+
+        pub fn delete_sticker(&mut self, typ: &str, uri: &str, name: &str) -> Result<()> {
+            assert_eq!(typ, "song");
+            self.stickers.remove(&(uri.to_owned(), name.to_owned()));
+            Ok(())
+        }
+    }
+
+    impl MPDLibrary {
+        pub fn get_mpd_conn(_address: &MPDAddress) -> Result<MockMPDClient> {
+            Ok(MockMPDClient::connect("127.0.0.1:6600").unwrap())
+        }
+    }
+
+    pub(crate) fn setup_library() -> (MPDLibrary, TempDir) {
+        let config_dir = TempDir::new("coucou").unwrap();
+        let config_file = config_dir.path().join("config.json");
+        let database_file = config_dir.path().join("bliss.db");
+        let library = MPDLibrary::new(
+            "path".into(),
+            Some(config_file),
+            Some(database_file),
+            Some(NonZeroUsize::new(1).unwrap()),
         )
-        .subcommand(
-            SubCommand::with_name("playlist")
-            .about("Make a playlist from the currently playing song, clearing the queue and queuing NUMBER_SONGS songs similar to the currently playing song. See the other flags if you want to e.g. preserve the queue.")
-            .arg(config_argument.clone())
-            .arg(Arg::with_name("NUMBER_SONGS")
-                .help("Number of items to queue, including the first song.")
-                .required(true)
-            )
-            .arg(Arg::with_name("distance")
-                .long("distance")
-                .value_name("distance metric")
-                .help(
-                    "Choose the distance metric used to make the playlist. Default is 'euclidean' for playlists from a single song, and 'extended_isolation_forest' for playlists from multiple songs. Other options are 'cosine', 'mahalanobis', and 'extended_isolation_forest'. By default, the mahalanobis distance is the same as the euclidean distance. You can tailor this distance to your tastes by running metric learning e.g. using https://github.com/Polochon-street/bliss-metric-learning. The extended_isolation_forest works better for playlists from multiple songs."
-                )
-                .default_value("euclidean")
-            )
-            .arg(Arg::with_name("from-song")
-                .long("from-song")
-                .value_name("song path")
-                .help("Instead of making a playlist from the current playing song, make a playlist from 'song path', and add the corresponding songs to the queue. This will also add the song in 'song path' to the playlist.")
-            )
-            .arg(Arg::with_name("seed")
-                .long("seed-song")
-                .help(
-                    "Instead of making a playlist of only the closest song to the current song, make a playlist that queues the closest song to the first song, then the closest to the second song, etc. Can take some time to build."
-                )
-                .takes_value(false)
-            )
-            .arg(Arg::with_name("no-dedup")
-                .long("no-deduplication")
-                .help(
-                    "Do not deduplicate songs based both on the title / artist and their sheer proximity."
-                )
-                .takes_value(false)
-            )
-            .arg(Arg::with_name("keep-queue")
-                .long("keep-current-queue")
-                .help(
-                    "Instead of removing the rest of the queue and only keeping the selecting song, queuing songs similiar to the selected song, keep the queue the same, and add similar songs right after the selected song, preserving the rest of the queue."
+        .unwrap();
+        (library, config_dir)
+    }
+
+    #[test]
+    fn test_mpd_to_bliss_song() {
+        let (library, _tempdir) = setup_library();
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            sqlite_conn
+                .execute(
+                    "
+                insert into song (id, path, title, artist, album, genre, analyzed, version, duration, extra_info) values
+                    (1,'path/first_song.flac', 'First Song', 'Art Ist', 'Al Bum', 'Techno', true, 2, 50, null);
+                ",
+                    [],
                 )
-                .takes_value(false)
-            )
-            .arg(Arg::with_name("dry-run")
-                .long("dry-run")
-                .help(
-                    "Doesn't actually make any changes to the playlist, but just print songs that would have been added on stdout."
+                .unwrap();
+
+            sqlite_conn
+                .execute(
+                    "
+                insert into feature (song_id, feature, feature_index) values
+                    (1, 0., 1),
+                    (1, 0., 2),
+                    (1, 0., 3),
+                    (1, 0., 4),
+                    (1, 0., 5),
+                    (1, 0., 6),
+                    (1, 0., 7),
+                    (1, 0., 8),
+                    (1, 0., 9),
+                    (1, 0., 10),
+                    (1, 0., 11),
+                    (1, 0., 12),
+                    (1, 0., 13),
+                    (1, 0., 14),
+                    (1, 0., 15),
+                    (1, 0., 16),
+                    (1, 0., 17),
+                    (1, 0., 18),
+                    (1, 0., 19),
+                    (1, 0.3, 20);
+                 ",
+                    [],
                 )
-                .takes_value(false)
-            )
-            .arg(Arg::with_name("album")
-                .long("album-playlist")
-                .help("Make a playlist of similar albums from the current album.")
-                .takes_value(false)
-            )
-            .arg(Arg::with_name("entire")
-                .long("from-entire-playlist")
-                .help("Make a playlist of songs similar to all the playlist's songs, \
-                    instead of just the first one. Defaults to using the distance metric \
-                    extended_isolation_forest, which gives the best results.")
-                .takes_value(false)
-            )
-        )
-        .subcommand(
-            SubCommand::with_name("interactive-playlist")
-            .about(
-                "Make a playlist, prompting a set of close songs, and asking which one will be the most appropriate."
+                .unwrap();
+        }
+        let mpd_song = MPDSong {
+            file: String::from("first_song.flac"),
+            name: Some(String::from("First Song")),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 50,
+                prio: 0,
+            }),
+            ..Default::default()
+        };
+        let analysis = Analysis::new([
+            0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0.3,
+        ]);
+        let song = library.mpd_to_bliss_song(&mpd_song).unwrap().unwrap();
+        let expected_song = LibrarySong {
+            extra_info: (),
+            bliss_song: Song {
+                path: PathBuf::from("path/first_song.flac"),
+                title: Some(String::from("First Song")),
+                artist: Some(String::from("Art Ist")),
+                album: Some(String::from("Al Bum")),
+                genre: Some(String::from("Techno")),
+                analysis,
+                features_version: 2,
+                duration: Duration::from_secs(50),
+                ..Default::default()
+            },
+        };
+        assert_eq!(song, expected_song);
+    }
+
+    #[test]
+    fn test_dedup_playlist() {
+        let song = |title: &str, album: &str, feature: f32| LibrarySong {
+            extra_info: (),
+            bliss_song: Song {
+                path: PathBuf::from(format!("path/{}.flac", title.trim())),
+                title: Some(title.to_string()),
+                artist: Some(String::from("Art Ist")),
+                album: Some(album.to_string()),
+                analysis: Analysis::new([feature; bliss_audio::NUMBER_FEATURES]),
+                ..Default::default()
+            },
+        };
+        let songs = vec![
+            song("First", "Al Bum", 0.),
+            song("first ", "Other Album", 5.),
+            song("Second", "Al Bum", 0.01),
+            song("Third", "Other Album", 1.),
+        ];
+        let titles = |strategy| {
+            dedup_playlist(
+                songs.clone().into_iter(),
+                strategy,
+                DEFAULT_DEDUP_DISTANCE,
+                |_| None,
             )
-            .arg(config_argument.clone())
-            .arg(Arg::with_name("continue")
-                .long("continue")
-                .help(
-                    "Take the current playlist's last song as a starting point, instead of removing the current playlist and starting from the first song."
-                )
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            titles(None),
+            vec![
+                "path/First.flac",
+                "path/first.flac",
+                "path/Second.flac",
+                "path/Third.flac"
+            ],
+        );
+        assert_eq!(
+            titles(Some(DedupStrategy::Title)),
+            vec!["path/First.flac", "path/Second.flac", "path/Third.flac"],
+        );
+        assert_eq!(
+            titles(Some(DedupStrategy::Features)),
+            vec!["path/First.flac", "path/Third.flac"],
+        );
+        assert_eq!(
+            titles(Some(DedupStrategy::Album)),
+            vec!["path/First.flac", "path/first.flac"],
+        );
+    }
+
+    #[test]
+    fn test_tie_break_playlist() {
+        let song = |title: &str, genre: &str, artist: &str, feature: f32| LibrarySong {
+            extra_info: (),
+            bliss_song: Song {
+                path: PathBuf::from(format!("path/{}.flac", title)),
+                genre: Some(genre.to_string()),
+                artist: Some(artist.to_string()),
+                analysis: Analysis::new([feature; bliss_audio::NUMBER_FEATURES]),
+                ..Default::default()
+            },
+        };
+        let songs = vec![
+            song("seed", "Rock", "Art Ist", 0.),
+            song("jazz", "Jazz", "Other Artist", 0.01),
+            song("rock", "Indie; rock ", "Other Artist", 0.015),
+            song("far_jazz", "Jazz", "Art Ist", 1.),
+            song("far_rock", "Rock", "Other Artist", 1.005),
+        ];
+        let titles = |tie_break| {
+            tie_break_playlist(
+                songs.clone().into_iter(),
+                &songs[..1],
+                &euclidean_distance,
+                tie_break,
+                DEFAULT_TIE_BREAK_EPSILON,
             )
-            .arg(Arg::with_name("choices")
-                .long("number-choices")
-                .value_name("choices")
-                .help(
-                    "Choose the number of proposed items you get each time.
-Defaults to 3, cannot be more than 9."
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            titles(None),
+            vec![
+                "path/seed.flac",
+                "path/jazz.flac",
+                "path/rock.flac",
+                "path/far_jazz.flac",
+                "path/far_rock.flac"
+            ],
+        );
+        assert_eq!(
+            titles(Some(TieBreak::Genre)),
+            vec![
+                "path/seed.flac",
+                "path/rock.flac",
+                "path/jazz.flac",
+                "path/far_rock.flac",
+                "path/far_jazz.flac"
+            ],
+        );
+        assert_eq!(
+            titles(Some(TieBreak::Artist)),
+            vec![
+                "path/seed.flac",
+                "path/jazz.flac",
+                "path/rock.flac",
+                "path/far_jazz.flac",
+                "path/far_rock.flac"
+            ],
+        );
+    }
+
+    #[test]
+    fn test_similar_albums() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", Some("Album"), 0.),
+                (2, "path/second_song.flac", Some("Album"), 0.1),
+                (3, "path/far_song.flac", Some("Far Album"), 5.),
+                (4, "path/close_song.flac", Some("Close Album"), 0.5),
+                (5, "path/other_close_song.flac", Some("Close Album"), 0.6),
+                (6, "path/further_song.flac", Some("Further Album"), 2.),
+                (7, "path/no_album.flac", None, 0.),
+            ],
+        );
+        let albums = |n| {
+            library
+                .similar_albums("Album", n)
+                .unwrap()
+                .into_iter()
+                .map(|a| (a.album, a.number_songs))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            albums(2),
+            vec![
+                (String::from("Close Album"), 2),
+                (String::from("Further Album"), 1)
+            ],
+        );
+        assert_eq!(albums(10).len(), 3);
+        assert!(library.similar_albums("Unknown Album", 2).is_err());
+    }
+
+    #[test]
+    fn test_compare_albums() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", Some("Album"), 0.),
+                (2, "path/second_song.flac", Some("Album"), 0.2),
+                (3, "path/other_song.flac", Some("Other Album"), 1.),
+                (4, "path/outlier.flac", Some("Other Album"), 3.),
+            ],
+        );
+        let comparison = library.compare_albums("Album", "Other Album").unwrap();
+        let n = (bliss_audio::NUMBER_FEATURES as f32).sqrt();
+        assert!((comparison.distance - 1.9 * n).abs() < 1e-4);
+        assert_eq!(
+            comparison
+                .tracks
+                .iter()
+                .map(|t| t.path.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "path/outlier.flac",
+                "path/first_song.flac",
+                "path/second_song.flac",
+                "path/other_song.flac"
+            ],
+        );
+        assert!(library.compare_albums("Album", "Unknown Album").is_err());
+    }
+
+    #[test]
+    fn test_album_groups() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", Some("Album"), 0.),
+                (2, "path/second_song.flac", Some("Album"), 0.2),
+                (3, "path/other_song.flac", Some("Other Album"), 1.),
+                (4, "path/outlier.flac", Some("Other Album"), 3.),
+            ],
+        );
+        let album = library.library.songs_from_album::<()>("Album").unwrap();
+        let playlist = library
+            .library
+            .album_playlist_from::<()>(String::from("Album"), 2)
+            .unwrap();
+        let groups = album_groups(&playlist[1..], &album_mean(album.iter()));
+        let n = (bliss_audio::NUMBER_FEATURES as f32).sqrt();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| (
+                    g.album.as_deref().unwrap(),
+                    g.songs.iter().map(|s| s.path.as_str()).collect::<Vec<_>>()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Album", vec!["path/second_song.flac"]),
+                (
+                    "Other Album",
+                    vec!["path/other_song.flac", "path/outlier.flac"]
+                ),
+            ],
+        );
+        assert!((groups[0].distance - 0.1 * n).abs() < 1e-4);
+        assert!((groups[1].distance - 1.9 * n).abs() < 1e-4);
+        assert!(album_groups(&[], &album_mean(album.iter())).is_empty());
+    }
+
+    #[test]
+    fn test_parse_bpm_range() {
+        assert_eq!(
+            parse_bpm_range("120-132").unwrap(),
+            (Some(120.), Some(132.))
+        );
+        assert_eq!(parse_bpm_range("120-").unwrap(), (Some(120.), None));
+        assert_eq!(parse_bpm_range(" - 132.5").unwrap(), (None, Some(132.5)));
+        assert!(parse_bpm_range("132-120").is_err());
+        assert!(parse_bpm_range("-").is_err());
+        assert!(parse_bpm_range("120").is_err());
+        assert!(parse_bpm_range("fast-").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("45m").unwrap(), Duration::from_secs(2700));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_duration("1y2w").unwrap(),
+            Duration::from_secs((365 + 14) * 24 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration("30d").unwrap(),
+            Duration::from_secs(2_592_000)
+        );
+        assert!(parse_duration("45").is_err());
+        assert!(parse_duration("1x").is_err());
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn test_random_dense_song() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/cluster1.flac", None, 0.),
+                (2, "path/cluster2.flac", None, 0.01),
+                (3, "path/cluster3.flac", None, 0.02),
+                (4, "path/cluster4.flac", None, 0.03),
+                (5, "path/cluster5.flac", None, 0.04),
+                (6, "path/cluster6.flac", None, 0.05),
+                (7, "path/outlier1.flac", None, 0.5),
+                (8, "path/outlier2.flac", None, 0.8),
+                (9, "path/outlier3.flac", None, -0.7),
+            ],
+        );
+        let songs = library.library.songs_from_library().unwrap();
+        for seed in 0..20 {
+            let song = random_dense_song(&songs, 2, &mut StdRng::seed_from_u64(seed)).unwrap();
+            assert!(song
+                .bliss_song
+                .path
+                .to_string_lossy()
+                .starts_with("path/cluster"));
+        }
+        assert!(random_dense_song(&[], 2, &mut StdRng::seed_from_u64(0)).is_none());
+    }
+
+    #[test]
+    fn test_playlist_transitions() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/second.flac", None, 0.5),
+                (3, "path/third.flac", None, -0.25),
+            ],
+        );
+        let mut songs: Vec<LibrarySong<()>> = library.library.songs_from_library().unwrap();
+        songs.sort_by_key(|s| s.bliss_song.path.to_owned());
+        assert_eq!(
+            playlist_transitions(&songs),
+            vec![
+                TransitionOutput {
+                    tempo_delta: 0.5,
+                    loudness_delta: 0.5,
+                },
+                TransitionOutput {
+                    tempo_delta: -0.75,
+                    loudness_delta: -0.75,
+                },
+            ],
+        );
+        assert!(playlist_transitions(&songs[..1]).is_empty());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(75)), "1:15");
+        assert_eq!(format_duration(Duration::from_secs(3599)), "59:59");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1:02:05");
+    }
+
+    #[test]
+    fn test_cue_sheet_path() {
+        assert_eq!(
+            cue_sheet_path(Path::new("path/album.cue/CUE_TRACK003")),
+            Some(Path::new("path/album.cue"))
+        );
+        assert_eq!(cue_sheet_path(Path::new("path/song.flac")), None);
+        assert_eq!(cue_sheet_path(Path::new("path/CUE_TRACK_notes.flac")), None);
+    }
+
+    #[test]
+    fn test_reanalyze() {
+        let (mut library, tempdir) = setup_library();
+        let song_path = tempdir.path().join("song.flac");
+        std::fs::copy("data/s16_mono_22_5kHz.flac", &song_path).unwrap();
+        let song_path = song_path.to_string_lossy().to_string();
+        let broken_path = tempdir.path().join("broken.flac");
+        std::fs::write(&broken_path, b"").unwrap();
+        let broken_path = broken_path.to_string_lossy().to_string();
+        insert_songs(
+            &library,
+            &[
+                (1, &song_path, None, 0.5),
+                (2, "path/other.flac", None, 0.5),
+                (3, &broken_path, None, 0.5),
+            ],
+        );
+
+        let failed = library
+            .reanalyze(&[
+                PathBuf::from(&song_path),
+                PathBuf::from("path/missing.flac"),
+                PathBuf::from(&broken_path),
+            ])
+            .unwrap();
+        assert_eq!(failed, 2);
+        let song = library.library.song_from_path::<()>(&song_path).unwrap();
+        assert_ne!(
+            song.bliss_song.analysis,
+            Analysis::new([0.5; bliss_audio::NUMBER_FEATURES])
+        );
+        assert!(library
+            .library
+            .song_from_path::<()>("path/missing.flac")
+            .is_err());
+        // The broken song is kept, as failed.
+        assert!(library.library.song_from_path::<()>(&broken_path).is_err());
+        assert_eq!(library.count_songs().unwrap(), (2, 2));
+    }
+
+    #[test]
+    fn test_song_output_json() {
+        let song = LibrarySong {
+            extra_info: (),
+            bliss_song: Song {
+                path: PathBuf::from("path/first_song.flac"),
+                title: Some(String::from("First Song")),
+                artist: Some(String::from("Art Ist")),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&SongOutput::new(&song)).unwrap(),
+            r#"{"path":"path/first_song.flac","artist":"Art Ist","title":"First Song","album":null,"bpm":103.0}"#,
+        );
+        assert_eq!(
+            serde_json::to_string(&SongOutput {
+                distance: Some(0.5),
+                ..SongOutput::new(&song)
+            })
+            .unwrap(),
+            r#"{"path":"path/first_song.flac","artist":"Art Ist","title":"First Song","album":null,"bpm":103.0,"distance":0.5}"#,
+        );
+    }
+
+    #[test]
+    fn test_analyze_file() {
+        let tempdir = TempDir::new("test").unwrap();
+        let missing = tempdir.path().join("missing.flac");
+        let output = analyze_file(&missing, "ffmpeg").unwrap();
+        assert_eq!(output.analysis, None);
+        assert_eq!(output.error_kind.as_deref(), Some("missing_file"));
+
+        let existing = tempdir.path().join("existing.flac");
+        std::fs::copy("data/s16_mono_22_5kHz.flac", &existing).unwrap();
+        let output = analyze_file(&existing, "ffmpeg").unwrap();
+        assert_eq!(output.path, existing.to_string_lossy());
+        assert_eq!(output.error, None);
+        assert_eq!(output.analysis.unwrap().len(), bliss_audio::NUMBER_FEATURES);
+
+        #[cfg(not(feature = "symphonia"))]
+        assert!(analyze_file(&existing, "symphonia").is_err());
+    }
+
+    #[test]
+    fn test_mpd_address() {
+        assert_eq!(
+            MPDAddress::new("secret@localhost", 6600),
+            MPDAddress {
+                host: String::from("localhost"),
+                port: 6600,
+                password: Some(String::from("secret")),
+            }
+        );
+        // Abstract sockets start with '@', and have no password.
+        assert_eq!(MPDAddress::new("@mpd", 6600).host, "@mpd");
+        assert_eq!(MPDAddress::new("@mpd", 6600).password, None);
+        assert_eq!(
+            MPDAddress::new("/run/mpd/socket", 6600),
+            MPDAddress {
+                host: String::from("/run/mpd/socket"),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_handle_random_mode() {
+        let mut mpd_conn = MPDLibrary::get_mpd_conn(&MPDAddress::default()).unwrap();
+        mpd_conn.random = true;
+
+        // Dry runs leave the random mode alone.
+        let options = PlaylistOptions {
+            disable_random: true,
+            restore_random: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        assert!(MPDLibrary::handle_random_mode(&mut mpd_conn, &options).unwrap());
+        assert!(mpd_conn.random);
+
+        let options = PlaylistOptions {
+            dry_run: false,
+            ..options
+        };
+        let was_random = MPDLibrary::handle_random_mode(&mut mpd_conn, &options).unwrap();
+        assert!(was_random);
+        assert!(!mpd_conn.random);
+        MPDLibrary::restore_random_mode(&mut mpd_conn, was_random, &options).unwrap();
+        assert!(mpd_conn.random);
+
+        // Random mode is restored even if queueing fails.
+        let result = MPDLibrary::with_random_mode(&mut mpd_conn, &options, |mpd_conn| {
+            assert!(!mpd_conn.random);
+            bail!("queueing failed");
+        });
+        assert!(result.is_err());
+        assert!(mpd_conn.random);
+    }
+
+    #[test]
+    fn test_queue_position() {
+        let mut mpd_conn = MPDLibrary::get_mpd_conn(&MPDAddress::default()).unwrap();
+        let song = |file: &str, id| MPDSong {
+            file: String::from(file),
+            place: Some(QueuePlace {
+                id: Id(id),
+                pos: id,
+                prio: 0,
+            }),
+            ..Default::default()
+        };
+        mpd_conn.mpd_queue = vec![song("first.flac", 0), song("current.flac", 1)];
+        let current = mpd_conn.mpd_queue[1].to_owned();
+        assert_eq!(
+            MPDLibrary::queue_position(&mut mpd_conn, &current).unwrap(),
+            1
+        );
+
+        // The first song was consumed in the meantime.
+        mpd_conn.delete(0..1).unwrap();
+        assert_eq!(
+            MPDLibrary::queue_position(&mut mpd_conn, &current).unwrap(),
+            0
+        );
+
+        mpd_conn.clear().unwrap();
+        assert!(MPDLibrary::queue_position(&mut mpd_conn, &current).is_err());
+    }
+
+    #[test]
+    fn test_trim_queue() {
+        let mut mpd_conn = MPDLibrary::get_mpd_conn(&MPDAddress::default()).unwrap();
+        let song = |file: &str, pos: Option<u32>| MPDSong {
+            file: String::from(file),
+            place: pos.map(|pos| QueuePlace {
+                id: Id(pos),
+                pos,
+                prio: 0,
+            }),
+            ..Default::default()
+        };
+        mpd_conn.mpd_queue = vec![
+            song("played1.flac", None),
+            song("played2.flac", None),
+            song("current.flac", Some(2)),
+            song("next1.flac", None),
+            song("next2.flac", None),
+        ];
+        let files = |mpd_conn: &mut MPDClient| {
+            mpd_conn
+                .queue()
+                .unwrap()
+                .into_iter()
+                .map(|s| s.file)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(MPDLibrary::trim_queue(&mut mpd_conn, 5).unwrap(), 0);
+        assert_eq!(files(&mut mpd_conn).len(), 5);
+
+        // Played songs go first.
+        assert_eq!(MPDLibrary::trim_queue(&mut mpd_conn, 4).unwrap(), 1);
+        assert_eq!(
+            files(&mut mpd_conn),
+            vec!["played2.flac", "current.flac", "next1.flac", "next2.flac"],
+        );
+
+        // Then the end of the queue.
+        mpd_conn.mpd_queue[1].place.as_mut().unwrap().pos = 1;
+        assert_eq!(MPDLibrary::trim_queue(&mut mpd_conn, 2).unwrap(), 2);
+        assert_eq!(files(&mut mpd_conn), vec!["current.flac", "next1.flac"]);
+    }
+
+    /// Insert analyzed songs in the library, each song having all of its
+    /// features set to the same value.
+    /// The analysis [insert_songs] stores for songs with `feature`.
+    pub(crate) fn test_analysis(feature: f32) -> bliss_audio::Analysis {
+        let mut features = [feature; bliss_audio::NUMBER_FEATURES];
+        features[0] += 0.5;
+        bliss_audio::Analysis::new(features)
+    }
+
+    pub(crate) fn insert_songs(library: &MPDLibrary, songs: &[(u32, &str, Option<&str>, f32)]) {
+        let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+        for (id, path, album, feature) in songs {
+            let album = album.map_or(String::from("null"), |a| format!("'{}'", a));
+            sqlite_conn
+                .execute(
+                    &format!(
+                        "insert into song (id, path, album, analyzed, duration, version)
+                        values ({}, '{}', {}, true, 10, 1)",
+                        id, path, album,
+                    ),
+                    [],
                 )
-                .default_value("3")
-            )
-        )
-        .get_matches();
+                .unwrap();
+            // The first feature is offset, so that analyses aren't degenerate.
+            let features = (0..bliss_audio::NUMBER_FEATURES)
+                .map(|i| {
+                    let feature = if i == 0 { feature + 0.5 } else { *feature };
+                    format!("({}, {}, {})", id, feature, i)
+                })
+                .collect::<Vec<String>>()
+                .join(",\n");
+            sqlite_conn
+                .execute(
+                    &format!(
+                        "insert into feature (song_id, feature, feature_index) values {}",
+                        features
+                    ),
+                    [],
+                )
+                .unwrap();
+        }
+    }
 
-    let mut config_path = match matches.subcommand() {
-        (_, Some(sub_m)) => sub_m.value_of("config-path").map(PathBuf::from),
-        _ => None,
-    };
-    if config_path.is_none() {
-        config_path = matches.value_of("config-path").map(PathBuf::from);
+    #[test]
+    fn test_playlist_offset() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first_song.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", None, 0.),
+                (2, "path/second_song.flac", None, 0.1),
+                (3, "path/last_song.flac", None, 10.),
+            ],
+        );
+        let options = PlaylistOptions {
+            dedup: None,
+            offset: 1,
+            ..Default::default()
+        };
+        library
+            .queue_from_song(None, 20, &euclidean_distance, closest_to_songs, &options)
+            .unwrap();
+
+        let playlist = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|x| x.file.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            playlist,
+            vec![
+                String::from("first_song.flac"),
+                String::from("last_song.flac"),
+            ],
+        );
     }
-    if let Some(sub_m) = matches.subcommand_matches("list-db") {
-        let library = MPDLibrary::from_config_path(config_path)?;
-        let mut songs: Vec<LibrarySong<()>> = library.library.songs_from_library()?;
-        songs.sort_by_key(
-            |x: &LibrarySong<_>| match x.bliss_song.path.to_str().as_ref() {
-                Some(a) => a.to_string(),
-                None => String::from(""),
-            },
+
+    #[test]
+    fn test_playlist_exclude_titles() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first_song.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", None, 0.),
+                (2, "path/second_song.flac", None, 0.1),
+                (3, "path/third_song.flac", None, 0.2),
+                (4, "path/last_song.flac", None, 10.),
+            ],
         );
-        for song in songs {
-            if sub_m.is_present("detailed") {
-                println!(
-                    "{}: {:?}",
-                    song.bliss_song.path.display(),
-                    song.bliss_song.analysis
-                );
-            } else {
-                println!("{}", song.bliss_song.path.display());
-            }
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            sqlite_conn
+                .execute_batch(
+                    "update song set title = 'Song (Live)' where id = 1;
+                    update song set title = 'Song (LIVE at Wembley)' where id = 2;
+                    update song set title = 'Song (Club Remix)' where id = 3;
+                    update song set title = 'Song' where id = 4;",
+                )
+                .unwrap();
         }
-    } else if let Some(sub_m) = matches.subcommand_matches("init") {
-        let database_path = sub_m.value_of("database-path").map(PathBuf::from);
-        let number_cores = parse_number_cores(sub_m)?;
-        let base_path = sub_m.value_of("MPD_BASE_PATH").unwrap();
-        let mut library = MPDLibrary::new(
-            PathBuf::from(base_path),
-            config_path,
-            database_path,
-            number_cores,
-        )?;
-
-        library.full_rescan()?;
-    } else if let Some(sub_m) = matches.subcommand_matches("rescan") {
-        let mut library = MPDLibrary::from_config_path(config_path)?;
-        let number_cores = parse_number_cores(sub_m)?;
-        if let Some(cores) = number_cores {
-            library.library.config.set_number_cores(cores)?;
+        let options = PlaylistOptions {
+            dedup: None,
+            exclude_titles: vec![
+                RegexBuilder::new(r"\(live")
+                    .case_insensitive(true)
+                    .build()
+                    .unwrap(),
+                RegexBuilder::new("remix")
+                    .case_insensitive(true)
+                    .build()
+                    .unwrap(),
+            ],
+            ..Default::default()
         };
-        library.full_rescan()?;
-    } else if let Some(sub_m) = matches.subcommand_matches("update") {
-        let mut library = MPDLibrary::from_config_path(config_path)?;
-        let number_cores = parse_number_cores(sub_m)?;
+        library
+            .queue_from_song(None, 20, &euclidean_distance, closest_to_songs, &options)
+            .unwrap();
 
-        if let Some(cores) = number_cores {
-            library.library.config.set_number_cores(cores)?;
-        };
-        let paths = library.get_songs_paths()?;
-        library.library.update_library(paths, true, true)?;
-    } else if let Some(sub_m) = matches.subcommand_matches("playlist") {
-        let number_songs = match sub_m.value_of("NUMBER_SONGS").unwrap().parse::<usize>() {
-            Err(_) => {
-                bail!("Playlist number must be a valid number.");
-            }
-            Ok(n) => n,
-        };
+        // The song the playlist is made from is kept.
+        let playlist = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|x| x.file.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            playlist,
+            vec![
+                String::from("first_song.flac"),
+                String::from("last_song.flac"),
+            ],
+        );
+    }
 
-        let library = MPDLibrary::from_config_path(config_path)?;
-        let dry_run = sub_m.is_present("dry-run");
-        let no_dedup = sub_m.is_present("no-dedup");
-        let keep_queue = sub_m.is_present("keep-queue");
+    #[test]
+    fn test_check_playlist_distance() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/seed.flac", None, 0.),
+                (2, "path/close.flac", None, 0.1),
+                (3, "path/far.flac", None, 0.9),
+            ],
+        );
+        let song = |path: &str| library.library.song_from_path(path).unwrap();
+        let seeds = vec![song("path/seed.flac")];
+        let close = vec![song("path/seed.flac"), song("path/close.flac")];
+        let far = vec![song("path/close.flac"), song("path/far.flac")];
 
-        if sub_m.is_present("album") {
-            library.queue_from_current_album(number_songs, dry_run, keep_queue)?;
-        } else {
-            // TODO let users customize options?
-            let forest_distance: &dyn DistanceMetricBuilder = &ForestOptions {
-                n_trees: 1000,
-                sample_size: 200,
-                max_tree_depth: None,
-                extension_level: 10,
-            };
+        let mut options = PlaylistOptions::default();
+        check_playlist_distance(&far, &seeds, &euclidean_distance, &options).unwrap();
 
-            let sort = |x: &[LibrarySong<()>],
-                        y: &[LibrarySong<()>],
-                        z|
-             -> Box<dyn Iterator<Item = LibrarySong<()>>> {
-                match sub_m.is_present("seed") {
-                    false => Box::new(closest_to_songs(x, y, z)),
-                    true => Box::new(song_to_song(x, y, z)),
-                }
-            };
-            let distance_metric: &dyn DistanceMetricBuilder = if let Some(m) =
-                sub_m.value_of("distance")
-            {
-                match m {
-                    "euclidean" => &euclidean_distance,
-                    "cosine" => &cosine_distance,
-                    "mahalanobis" => {
-                        &mahalanobis_distance_builder(library.library.config.base_config.m.to_owned())
-                    }
-                    "extended_isolation_forest" => forest_distance,
-                    _ => bail!("Please choose a distance name, between 'euclidean', 'cosine', 'mahalanobis' and 'extended_isolation_forest'."),
-                }
-            } else {
-                &euclidean_distance
-            };
+        options.max_average_distance = Some(1.);
+        options.refuse_distant = true;
+        check_playlist_distance(&close, &seeds, &euclidean_distance, &options).unwrap();
+        let error = check_playlist_distance(&far, &seeds, &euclidean_distance, &options)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Use --force"), "{}", error);
 
-            if sub_m.is_present("entire") {
-                library.queue_from_current_playlist(
-                    number_songs,
-                    // Defaults to the extended_isolation_forest for multiple songs playlist
-                    if sub_m.value_of("distance").is_some() {
-                        distance_metric
-                    } else {
-                        forest_distance
-                    },
-                    sort,
-                    !no_dedup,
-                    dry_run,
-                )?;
-            } else {
-                library.queue_from_song(
-                    sub_m.value_of("from-song"),
-                    number_songs,
-                    distance_metric,
-                    sort,
-                    !no_dedup,
-                    dry_run,
-                    keep_queue,
-                )?;
-            }
-        }
-    } else if let Some(sub_m) = matches.subcommand_matches("interactive-playlist") {
-        let number_choices: usize = sub_m.value_of("choices").unwrap_or("3").parse()?;
-        let mut library = MPDLibrary::from_config_path(config_path)?;
-        if sub_m.is_present("continue") {
-            library.make_interactive_playlist(true, number_choices)?;
-        } else {
-            library.make_interactive_playlist(false, number_choices)?;
-        }
+        options.refuse_distant = false;
+        check_playlist_distance(&far, &seeds, &euclidean_distance, &options).unwrap();
     }
 
-    Ok(())
-}
-
-// TODO test the playlist length thingy
-#[cfg(test)]
-mod test {
-    use super::*;
-    use bliss_audio::{Analysis, Song};
-    use mpd::error::Result;
-    use mpd::song::{Id, QueuePlace, Song as MPDSong};
-    use mpd::Status;
-    use pretty_assertions::assert_eq;
-    use std::ops;
-    use std::time::Duration;
-    use tempdir::TempDir;
-
-    impl MockMPDClient {
-        pub fn connect(address: &str) -> Result<Self> {
-            assert_eq!(address, "127.0.0.1:6600");
-            Ok(Self {
-                mpd_queue: vec![],
-                search_window: 0,
-            })
-        }
+    #[test]
+    fn test_queue_one_album() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/current.flac", Some("Current"), 0.),
+                (2, "path/current2.flac", Some("Current"), 0.),
+                (3, "path/close1.flac", Some("Close"), 0.1),
+                (4, "path/close2.flac", Some("Close"), 0.3),
+                (5, "path/far1.flac", Some("Far"), 0.),
+                (6, "path/far2.flac", Some("Far"), 0.9),
+            ],
+        );
+        let songs = library.library.songs_from_library().unwrap();
+        let current = library.library.song_from_path("path/current.flac").unwrap();
+        // "Far" has the closest song, but "Close" is closer on average.
+        assert_eq!(closest_album(&current, &songs), Some(String::from("Close")));
+        assert_eq!(closest_album(&current, &songs[..2]), None);
 
-        pub fn currentsong(&mut self) -> Result<Option<MPDSong>> {
-            match self.mpd_queue.first() {
-                Some(s) => Ok(Some(s.to_owned())),
-                None => Ok(None),
-            }
-        }
+        library.mpd_conn().unwrap().mpd_queue = vec![
+            MPDSong {
+                file: String::from("current.flac"),
+                place: Some(QueuePlace {
+                    id: Id(1),
+                    pos: 0,
+                    prio: 0,
+                }),
+                ..Default::default()
+            },
+            MPDSong {
+                file: String::from("other.flac"),
+                ..Default::default()
+            },
+        ];
+        library
+            .queue_one_album(None, &PlaylistOptions::default())
+            .unwrap();
+        let queue = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|s| s.file.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(queue, vec!["current.flac", "close1.flac", "close2.flac"]);
+    }
 
-        pub fn songs(&mut self, pos: std::ops::RangeFrom<u32>) -> Result<Vec<MPDSong>> {
-            let range = std::ops::RangeFrom {
-                start: pos.start as usize,
-            };
-            Ok(self.mpd_queue[range].to_vec())
-        }
+    #[test]
+    fn test_playlist_update_first() {
+        let (mut library, _tempdir) = setup_library();
+        assert_eq!(library.last_mpd_update().unwrap(), None);
+        library
+            .set_last_mpd_update(Duration::from_secs(12))
+            .unwrap();
+        library
+            .set_last_mpd_update(Duration::from_secs(42))
+            .unwrap();
+        assert_eq!(
+            library.last_mpd_update().unwrap(),
+            Some(Duration::from_secs(42))
+        );
 
-        pub fn search(&mut self, _: &Query, _: Window) -> Result<Vec<MPDSong>> {
-            if self.search_window >= 1 {
-                return Ok(vec![]);
-            }
-            self.search_window += 1;
-            Ok(vec![
-                MPDSong {
-                    file: String::from("s16_mono_22_5kHz.flac"),
-                    ..Default::default()
-                },
-                MPDSong {
-                    file: String::from("s16_stereo_22_5kHz.flac"),
-                    ..Default::default()
-                },
-                MPDSong {
-                    file: String::from("foo"),
-                    ..Default::default()
-                },
-            ])
-        }
+        // MPD's database didn't change since the last update.
+        let db_update = library.mpd_conn().unwrap().stats().unwrap().db_update;
+        library.set_last_mpd_update(db_update).unwrap();
+        assert_eq!(library.update_new_songs().unwrap(), 0);
+        assert_eq!(library.count_songs().unwrap(), (0, 0));
+    }
 
-        pub fn insert(&mut self, song: MPDSong, pos: usize) -> Result<usize> {
-            self.mpd_queue.insert(pos, song);
-            Ok(pos)
-        }
+    #[test]
+    fn test_queue_from_file() {
+        let (library, tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", None, 0.),
+                (2, "path/second_song.flac", None, 0.1),
+                (3, "path/last_song.flac", None, 10.),
+            ],
+        );
+        // Not in the library.
+        let file = tempdir.path().join("song_from_a_friend.flac");
+        std::fs::copy("data/s16_mono_22_5kHz.flac", &file).unwrap();
+        let options = PlaylistOptions {
+            dedup: None,
+            ..Default::default()
+        };
+        library
+            .queue_from_file(&file, 2, &euclidean_distance, closest_to_songs, &options)
+            .unwrap();
+        let playlist = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|s| s.file.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(playlist, vec!["first_song.flac", "second_song.flac"]);
 
-        pub fn shift(&mut self, from: std::ops::Range<u32>, to: usize) -> Result<()> {
-            let value = self.mpd_queue.remove(from.start as usize);
-            self.mpd_queue.insert(to, value);
-            Ok(())
-        }
+        assert!(library
+            .queue_from_file(
+                &tempdir.path().join("missing.mp3"),
+                2,
+                &euclidean_distance,
+                closest_to_songs,
+                &options,
+            )
+            .is_err());
+    }
 
-        pub fn queue(&mut self) -> Result<Vec<MPDSong>> {
-            Ok(self.mpd_queue.to_owned())
-        }
+    #[test]
+    fn test_playlist_offline_m3u() {
+        let (mut library, tempdir) = setup_library();
+        library.mpd_conn = None;
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", None, 0.),
+                (2, "path/second_song.flac", None, 0.1),
+                (3, "path/last_song.flac", None, 10.),
+            ],
+        );
+        let m3u_path = tempdir.path().join("playlist.m3u");
+        let options = PlaylistOptions {
+            dedup: None,
+            output_m3u: Some(m3u_path.to_owned()),
+            ..Default::default()
+        };
+        library
+            .queue_from_song(
+                Some("second_song.flac"),
+                2,
+                &euclidean_distance,
+                closest_to_songs,
+                &options,
+            )
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&m3u_path).unwrap(),
+            "#EXTM3U\n\
+            #EXTINF:10,<No artist> - <No title>\n\
+            path/second_song.flac\n\
+            #EXTINF:10,<No artist> - <No title>\n\
+            path/first_song.flac\n",
+        );
 
-        pub fn delete<T>(&mut self, range: T) -> Result<()>
-        where
-            T: ops::RangeBounds<u32> + Iterator<Item = u32>,
-        {
-            // poor man's range
-            for i in range {
-                if i > self.mpd_queue.len() as u32 {
-                    break;
-                }
-                self.mpd_queue.remove(i as usize);
-            }
-            Ok(())
-        }
+        // Queueing songs needs MPD.
+        assert!(library
+            .queue_from_song(
+                Some("second_song.flac"),
+                2,
+                &euclidean_distance,
+                closest_to_songs,
+                &PlaylistOptions::default(),
+            )
+            .is_err());
+    }
 
-        pub fn push(&mut self, song: MPDSong) -> Result<()> {
-            self.mpd_queue.push(song);
-            Ok(())
-        }
+    #[test]
+    fn test_publish_stickers() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first_song.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", None, 0.),
+                (2, "path/second_song.flac", None, 0.5),
+            ],
+        );
+        let options = PlaylistOptions {
+            dedup: None,
+            publish_stickers: true,
+            ..Default::default()
+        };
+        library
+            .queue_from_song(None, 20, &euclidean_distance, closest_to_songs, &options)
+            .unwrap();
 
-        pub fn random(&mut self, state: bool) -> Result<()> {
-            assert!(!state);
-            Ok(())
-        }
+        let mut stickers = library
+            .mpd_conn()
+            .unwrap()
+            .find_sticker("song", "", DISTANCE_STICKER)
+            .unwrap();
+        stickers.sort();
+        let expected_distance = (0.25 * bliss_audio::NUMBER_FEATURES as f32).sqrt();
+        assert_eq!(
+            stickers,
+            vec![
+                (String::from("first_song.flac"), String::from("0.000000")),
+                (
+                    String::from("second_song.flac"),
+                    format!("{:.6}", expected_distance)
+                ),
+            ],
+        );
 
-        pub fn status(&mut self) -> Result<Status> {
-            Ok(Status {
-                random: false,
-                ..Default::default()
-            })
-        }
+        assert_eq!(library.clear_stickers().unwrap(), 2);
+        assert!(library.mpd_conn().unwrap().stickers.is_empty());
     }
 
-    impl MPDLibrary {
-        pub fn get_mpd_conn() -> Result<MockMPDClient> {
-            Ok(MockMPDClient::connect("127.0.0.1:6600").unwrap())
-        }
+    #[test]
+    fn test_migrate_database() {
+        let (library, tempdir) = setup_library();
+        insert_songs(&library, &[(1, "path/first_song.flac", None, 0.)]);
+        drop(library);
+        let config_path = tempdir.path().join("config.json");
+        let destination = tempdir.path().join("data").join("songs.db");
+
+        assert_eq!(
+            migrate_database(Some(config_path.to_owned()), Some(destination.to_owned())).unwrap(),
+            destination,
+        );
+        assert!(!tempdir.path().join("bliss.db").exists());
+        let library = MPDLibrary::from_config_path_offline(Some(config_path.to_owned())).unwrap();
+        assert_eq!(
+            library.library.config.base_config.database_path,
+            destination
+        );
+        assert_eq!(library.library.songs_from_library::<()>().unwrap().len(), 1);
+        drop(library);
+
+        assert!(migrate_database(Some(config_path), Some(destination)).is_err());
     }
 
-    fn setup_library() -> (MPDLibrary, TempDir) {
+    #[test]
+    fn test_toml_config() {
         let config_dir = TempDir::new("coucou").unwrap();
-        let config_file = config_dir.path().join("config.json");
-        let database_file = config_dir.path().join("bliss.db");
-        let library = MPDLibrary::new(
+        let config_file = config_dir.path().join("config.toml");
+        let mut config = Config::new(
             "path".into(),
-            Some(config_file),
-            Some(database_file),
+            Some(config_file.to_owned()),
+            Some(config_dir.path().join("bliss.db")),
             Some(NonZeroUsize::new(1).unwrap()),
         )
         .unwrap();
-        (library, config_dir)
+        config.playlist.distance = Some(String::from("cosine"));
+        config.write().unwrap();
+
+        let serialized = std::fs::read_to_string(&config_file).unwrap();
+        assert!(serialized.contains("mpd_base_path = \"path\"\n"));
+        assert!(serialized.contains("[playlist]\n"));
+        assert_eq!(
+            Config::from_path(&config_file.to_string_lossy())
+                .unwrap()
+                .playlist,
+            config.playlist,
+        );
     }
 
     #[test]
-    fn test_mpd_to_bliss_song() {
-        let (library, _tempdir) = setup_library();
-        {
-            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
-            sqlite_conn
-                .execute(
-                    "
-                insert into song (id, path, title, artist, album, genre, analyzed, version, duration, extra_info) values
-                    (1,'path/first_song.flac', 'First Song', 'Art Ist', 'Al Bum', 'Techno', true, 2, 50, null);
-                ",
-                    [],
-                )
-                .unwrap();
+    fn test_algorithm_from_options() {
+        let mut defaults = PlaylistDefaults::default();
+        let algorithm = |algorithm, seed_song, defaults: &PlaylistDefaults| {
+            Algorithm::from_options(algorithm, seed_song, defaults).unwrap()
+        };
+        assert_eq!(algorithm(None, false, &defaults), Algorithm::Closest);
+        assert_eq!(algorithm(None, true, &defaults), Algorithm::Chained);
+        assert_eq!(
+            algorithm(Some("chained"), false, &defaults),
+            Algorithm::Chained
+        );
 
-            sqlite_conn
-                .execute(
-                    "
-                insert into feature (song_id, feature, feature_index) values
-                    (1, 0., 1),
-                    (1, 0., 2),
-                    (1, 0., 3),
-                    (1, 0., 4),
-                    (1, 0., 5),
-                    (1, 0., 6),
-                    (1, 0., 7),
-                    (1, 0., 8),
-                    (1, 0., 9),
-                    (1, 0., 10),
-                    (1, 0., 11),
-                    (1, 0., 12),
-                    (1, 0., 13),
-                    (1, 0., 14),
-                    (1, 0., 15),
-                    (1, 0., 16),
-                    (1, 0., 17),
-                    (1, 0., 18),
-                    (1, 0., 19),
-                    (1, 0.3, 20);
-                 ",
-                    [],
-                )
-                .unwrap();
-        }
-        let mpd_song = MPDSong {
-            file: String::from("first_song.flac"),
-            name: Some(String::from("First Song")),
-            place: Some(QueuePlace {
-                id: Id(1),
-                pos: 50,
-                prio: 0,
-            }),
+        defaults.seed_song = true;
+        assert_eq!(algorithm(None, false, &defaults), Algorithm::Chained);
+        assert_eq!(
+            algorithm(Some("closest"), false, &defaults),
+            Algorithm::Closest
+        );
+        defaults.algorithm = Some(String::from("closest"));
+        assert_eq!(algorithm(None, false, &defaults), Algorithm::Closest);
+
+        defaults.algorithm = Some(String::from("bridge"));
+        assert!(Algorithm::from_options(None, false, &defaults)
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown playlist algorithm 'bridge'"));
+    }
+
+    #[test]
+    fn test_extra_base_paths() {
+        let (mut library, tempdir) = setup_library();
+        let drive = tempdir.path().join("drive");
+        std::fs::create_dir_all(drive.join("album")).unwrap();
+        std::fs::write(drive.join("album/song.flac"), b"").unwrap();
+        std::fs::write(drive.join("album/sheet.cue"), b"").unwrap();
+        library.library.config.extra_base_paths = vec![drive.to_owned()];
+
+        let mpd_song = |file: &str| MPDSong {
+            file: String::from(file),
             ..Default::default()
         };
-        let analysis = Analysis::new([
-            0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0.3,
-        ]);
-        let song = library.mpd_to_bliss_song(&mpd_song).unwrap().unwrap();
-        let expected_song = LibrarySong {
+        let path = library
+            .mpd_to_bliss_path(&mpd_song("album/song.flac"))
+            .unwrap();
+        assert_eq!(path, drive.join("album/song.flac"));
+        assert_eq!(
+            library
+                .mpd_to_bliss_path(&mpd_song("album/sheet.cue/track0002"))
+                .unwrap(),
+            drive.join("album/sheet.cue/CUE_TRACK002"),
+        );
+        // Paths found nowhere are in the MPD base path.
+        assert_eq!(
+            library
+                .mpd_to_bliss_path(&mpd_song("album/missing.flac"))
+                .unwrap(),
+            PathBuf::from("path/album/missing.flac"),
+        );
+
+        let song = |path: PathBuf| LibrarySong {
+            bliss_song: Song {
+                path,
+                ..Default::default()
+            },
             extra_info: (),
+        };
+        assert_eq!(
+            library.bliss_song_to_mpd(&song(path)).unwrap().file,
+            "album/song.flac"
+        );
+        assert_eq!(
+            library
+                .bliss_song_to_mpd(&song(PathBuf::from("path/other.flac")))
+                .unwrap()
+                .file,
+            "other.flac"
+        );
+        assert!(library
+            .bliss_song_to_mpd(&song(PathBuf::from("/elsewhere/song.flac")))
+            .is_err());
+    }
+
+    #[test]
+    fn test_mounts() {
+        let (mut library, _tempdir) = setup_library();
+        library.library.config.mounts = BTreeMap::from([
+            (String::from("nas"), PathBuf::from("/mnt/nas")),
+            (String::from("nas/usb"), PathBuf::from("/media/usb")),
+        ]);
+
+        let mpd_song = |file: &str| MPDSong {
+            file: String::from(file),
+            ..Default::default()
+        };
+        for (file, path) in [
+            ("nas/album/song.flac", "/mnt/nas/album/song.flac"),
+            ("nas/usb/song.flac", "/media/usb/song.flac"),
+            ("nas/sheet.cue/track0002", "/mnt/nas/sheet.cue/CUE_TRACK002"),
+            ("nasty/song.flac", "path/nasty/song.flac"),
+            ("album/song.flac", "path/album/song.flac"),
+        ] {
+            assert_eq!(
+                library.mpd_to_bliss_path(&mpd_song(file)).unwrap(),
+                PathBuf::from(path),
+            );
+        }
+
+        let song = |path: &str| LibrarySong {
             bliss_song: Song {
-                path: PathBuf::from("path/first_song.flac"),
-                title: Some(String::from("First Song")),
-                artist: Some(String::from("Art Ist")),
-                album: Some(String::from("Al Bum")),
-                genre: Some(String::from("Techno")),
-                analysis,
-                features_version: 2,
-                duration: Duration::from_secs(50),
+                path: PathBuf::from(path),
                 ..Default::default()
             },
+            extra_info: (),
         };
-        assert_eq!(song, expected_song);
+        for (path, file) in [
+            ("/mnt/nas/album/song.flac", "nas/album/song.flac"),
+            ("/media/usb/song.flac", "nas/usb/song.flac"),
+            ("path/album/song.flac", "album/song.flac"),
+        ] {
+            assert_eq!(library.bliss_song_to_mpd(&song(path)).unwrap().file, file);
+        }
+        assert!(library
+            .bliss_song_to_mpd(&song("/mnt/nasty/song.flac"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let (mut library, _tempdir) = setup_library();
+        library.library.config.playlist.number_songs = Some(20);
+        library.library.config.profiles.insert(
+            String::from("alice"),
+            Profile {
+                playlist: Some(PlaylistDefaults {
+                    number_songs: Some(50),
+                    ..Default::default()
+                }),
+                feedback_weight: Some(2.),
+                m: None,
+                schedule: None,
+            },
+        );
+        library.library.config.profiles.insert(
+            String::from("bob"),
+            Profile {
+                m: Some(Array2::eye(bliss_audio::NUMBER_FEATURES) * 2.),
+                ..Default::default()
+            },
+        );
+        library.library.config.write().unwrap();
+        let config = Config::from_path(
+            &library
+                .library
+                .config
+                .base_config
+                .config_path
+                .to_string_lossy(),
+        )
+        .unwrap();
+        assert_eq!(config.profiles, library.library.config.profiles);
+
+        assert_eq!(library.playlist_defaults().number_songs, Some(20));
+        assert_eq!(library.feedback_weight(), 1.);
+        library.user = Some(String::from("alice"));
+        assert_eq!(library.playlist_defaults().number_songs, Some(50));
+        assert_eq!(library.feedback_weight(), 2.);
+        assert_eq!(
+            library.metric_matrix(),
+            &library.library.config.base_config.m
+        );
+        library.user = Some(String::from("bob"));
+        assert_eq!(library.playlist_defaults().number_songs, Some(20));
+        assert_eq!(library.metric_matrix()[[0, 0]], 2.);
+        // Users without a profile get the default settings.
+        library.user = Some(String::from("carol"));
+        assert_eq!(library.playlist_defaults().number_songs, Some(20));
+    }
+
+    #[test]
+    fn test_get_songs_paths_fallback() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().fail_search = true;
+        assert_eq!(
+            library.get_songs_paths().unwrap(),
+            vec![
+                "path/Albums/album.cue",
+                "path/Albums/embedded.flac",
+                "path/Albums/first.flac",
+                "path/Other/second.flac",
+            ],
+        );
+        assert_eq!(
+            library.get_songs_paths_in(Some("Albums/")).unwrap(),
+            vec![
+                "path/Albums/album.cue",
+                "path/Albums/embedded.flac",
+                "path/Albums/first.flac",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_search_windows() {
+        let mut first = MockMPDClient::connect("127.0.0.1:6600").unwrap();
+        let mut second = MockMPDClient::connect("127.0.0.1:6600").unwrap();
+        // Each mock client has the same single window of songs to give, and
+        // which clients get to give it depends on the threads.
+        let mut paths = search_windows(vec![&mut first, &mut second], &Query::new(), 2)
+            .unwrap()
+            .into_iter()
+            .map(|song| song.file)
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        let mut expected = MockMPDClient::connect("127.0.0.1:6600")
+            .unwrap()
+            .search(&Query::new(), Window::from((0, 2)))
+            .unwrap()
+            .into_iter()
+            .map(|song| song.file)
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(paths, expected);
+
+        let mut failing = MockMPDClient::connect("127.0.0.1:6600").unwrap();
+        failing.fail_search = true;
+        assert!(search_windows(vec![&mut failing], &Query::new(), 2).is_err());
+    }
+
+    #[test]
+    fn test_delete_stale_songs() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/Albums/NewStuff/kept.flac", None, 0.),
+                (2, "path/Albums/NewStuff/deleted.flac", None, 0.),
+                (3, "path/Albums/NewStuffies/other.flac", None, 0.),
+                (4, "path/Other/deleted.flac", None, 0.),
+            ],
+        );
+        let deleted = library
+            .delete_stale_songs(
+                Path::new("path/Albums/NewStuff"),
+                &[String::from("path/Albums/NewStuff/kept.flac")],
+            )
+            .unwrap();
+        assert_eq!(deleted, 1);
+
+        let mut paths = library
+            .library
+            .songs_from_library::<()>()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "path/Albums/NewStuff/kept.flac",
+                "path/Albums/NewStuffies/other.flac",
+                "path/Other/deleted.flac",
+            ],
+        );
     }
 
     #[test]
@@ -1333,7 +7257,7 @@ mod test {
                 .unwrap();
         }
         assert_eq!(
-            library.queue_from_song(None, 20, &euclidean_distance, closest_to_songs, true, false, false).unwrap_err().to_string(),
+            library.queue_from_song(None, 20, &euclidean_distance, closest_to_songs, &PlaylistOptions::default()).unwrap_err().to_string(),
             String::from("No song is currently playing. Add a song to start the playlist from, and try again."),
         );
     }
@@ -1341,7 +7265,7 @@ mod test {
     #[test]
     fn test_playlist_song_not_in_db() {
         let (library, _tempdir) = setup_library();
-        library.mpd_conn.lock().unwrap().mpd_queue = vec![MPDSong {
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
             file: String::from("not-existing.flac"),
             name: Some(String::from("Coucou")),
             place: Some(QueuePlace {
@@ -1375,9 +7299,7 @@ mod test {
                     20,
                     &euclidean_distance,
                     closest_to_songs,
-                    true,
-                    false,
-                    false,
+                    &PlaylistOptions::default(),
                 )
                 .unwrap_err()
                 .to_string(),
@@ -1390,7 +7312,7 @@ mod test {
     #[test]
     fn test_playlist() {
         let (library, _tempdir) = setup_library();
-        library.mpd_conn.lock().unwrap().mpd_queue = vec![
+        library.mpd_conn().unwrap().mpd_queue = vec![
             MPDSong {
                 file: String::from("first_song.flac"),
                 name: Some(String::from("Coucou")),
@@ -1433,7 +7355,7 @@ mod test {
                 .execute(
                     "
                 insert into feature (song_id, feature, feature_index) values
-                    (1, 0., 1),
+                    (1, 0.5, 1),
                     (1, 0., 2),
                     (1, 0., 3),
                     (1, 0., 4),
@@ -1453,7 +7375,7 @@ mod test {
                     (1, 0., 18),
                     (1, 0., 19),
                     (1, 0., 20),
-                    (2, 0.1, 1),
+                    (2, 0.6, 1),
                     (2, 0.1, 2),
                     (2, 0.1, 3),
                     (2, 0.1, 4),
@@ -1473,7 +7395,7 @@ mod test {
                     (2, 0.1, 18),
                     (2, 0.1, 19),
                     (2, 0.1, 20),
-                    (3, 10, 1),
+                    (3, 10.5, 1),
                     (3, 10, 2),
                     (3, 10, 3),
                     (3, 10, 4),
@@ -1504,15 +7426,15 @@ mod test {
                 20,
                 &euclidean_distance,
                 closest_to_songs,
-                false,
-                false,
-                false,
+                &PlaylistOptions {
+                    dedup: None,
+                    ..Default::default()
+                },
             )
             .unwrap();
 
         let playlist = library
-            .mpd_conn
-            .lock()
+            .mpd_conn()
             .unwrap()
             .mpd_queue
             .iter()
@@ -1528,7 +7450,7 @@ mod test {
             ],
         );
 
-        library.mpd_conn.lock().unwrap().mpd_queue = vec![
+        library.mpd_conn().unwrap().mpd_queue = vec![
             MPDSong {
                 file: String::from("first_song.flac"),
                 name: Some(String::from("Coucou")),
@@ -1551,11 +7473,12 @@ mod test {
             },
         ];
 
-        library.queue_from_current_album(20, false, false).unwrap();
+        library
+            .queue_from_current_album(20, &PlaylistOptions::default())
+            .unwrap();
 
         let playlist = library
-            .mpd_conn
-            .lock()
+            .mpd_conn()
             .unwrap()
             .mpd_queue
             .iter()
@@ -1572,6 +7495,175 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_queue_from_current_album_after_current() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/current11.flac", Some("Current"), 0.),
+                (2, "path/current12.flac", Some("Current"), 0.),
+                (3, "path/current21.flac", Some("Current"), 0.),
+                (4, "path/next1.flac", Some("Next"), 0.1),
+                (5, "path/next2.flac", Some("Next"), 0.1),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "update song set disc_number = 1, track_number = 1 where id = 1;
+                update song set disc_number = 1, track_number = 2 where id = 2;
+                update song set disc_number = 2, track_number = 1 where id = 3;
+                update song set track_number = 1 where id = 4;
+                update song set track_number = 2 where id = 5;",
+            )
+            .unwrap();
+        let mpd_song = |file: &str, disc: &str, pos: u32| MPDSong {
+            file: String::from(file),
+            tags: vec![
+                (String::from("Album"), String::from("Current")),
+                (String::from("Disc"), String::from(disc)),
+            ],
+            place: Some(QueuePlace {
+                id: Id(pos),
+                pos,
+                prio: 0,
+            }),
+            ..Default::default()
+        };
+        let queue = vec![
+            mpd_song("current11.flac", "1/2", 0),
+            mpd_song("current12.flac", "1/2", 1),
+            mpd_song("current21.flac", "2/2", 2),
+            MPDSong {
+                file: String::from("other.flac"),
+                ..Default::default()
+            },
+        ];
+
+        for (keep_queue, after_current, expected) in [
+            (
+                false,
+                AfterCurrent::Album,
+                vec!["current11", "current12", "current21", "next1", "next2"],
+            ),
+            (
+                false,
+                AfterCurrent::Disc,
+                vec!["current11", "current12", "next1", "next2"],
+            ),
+            (
+                false,
+                AfterCurrent::Track,
+                vec!["current11", "next1", "next2"],
+            ),
+            (
+                true,
+                AfterCurrent::Album,
+                vec![
+                    "current11",
+                    "current12",
+                    "current21",
+                    "next1",
+                    "next2",
+                    "other",
+                ],
+            ),
+            (
+                true,
+                AfterCurrent::Disc,
+                vec![
+                    "current11",
+                    "current12",
+                    "next1",
+                    "next2",
+                    "current21",
+                    "other",
+                ],
+            ),
+            (
+                true,
+                AfterCurrent::Track,
+                vec![
+                    "current11",
+                    "next1",
+                    "next2",
+                    "current12",
+                    "current21",
+                    "other",
+                ],
+            ),
+        ] {
+            library.mpd_conn().unwrap().mpd_queue = queue.to_owned();
+            library
+                .queue_from_current_album(
+                    20,
+                    &PlaylistOptions {
+                        keep_queue,
+                        after_current,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let playlist = library
+                .mpd_conn()
+                .unwrap()
+                .mpd_queue
+                .iter()
+                .map(|x| x.file.trim_end_matches(".flac").to_owned())
+                .collect::<Vec<String>>();
+            assert_eq!(
+                playlist, expected,
+                "keep_queue: {}, after_current: {:?}",
+                keep_queue, after_current
+            );
+        }
+
+        // Starting from the second track, the album is queued again from the
+        // first one.
+        for (after_current, expected) in [
+            (
+                AfterCurrent::Album,
+                vec![
+                    "current12",
+                    "current11",
+                    "current12",
+                    "current21",
+                    "next1",
+                    "next2",
+                ],
+            ),
+            (
+                AfterCurrent::Disc,
+                vec!["current12", "current11", "current12", "next1", "next2"],
+            ),
+        ] {
+            library.mpd_conn().unwrap().mpd_queue =
+                vec![mpd_song("current12.flac", "1/2", 0), queue[3].to_owned()];
+            library
+                .queue_from_current_album(
+                    20,
+                    &PlaylistOptions {
+                        after_current,
+                        restart_current_album: true,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let playlist = library
+                .mpd_conn()
+                .unwrap()
+                .mpd_queue
+                .iter()
+                .map(|x| x.file.trim_end_matches(".flac").to_owned())
+                .collect::<Vec<String>>();
+            assert_eq!(playlist, expected, "after_current: {:?}", after_current);
+        }
+    }
+
     #[test]
     fn test_update() {
         let (mut library, _tempdir) = setup_library();
@@ -0,0 +1,146 @@
+//! Sharing learned metrics, see `blissify metric export` and
+//! `blissify metric import`.
+//!
+//! The matrix used by the mahalanobis distance, learned e.g. with
+//! [bliss-metric-learning](https://github.com/Polochon-street/bliss-metric-learning),
+//! is written to a JSON file of its own, along with the version of the
+//! features it was learned on, so that it can be version-controlled or given
+//! to someone else. Importing a matrix checks that it fits blissify's
+//! features before storing it in the configuration file.
+use crate::MPDLibrary;
+use anyhow::{bail, Context, Result};
+use bliss_audio::library::AppConfigTrait;
+use bliss_audio::{FEATURES_VERSION, NUMBER_FEATURES};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// The contents of an exported metric file.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MetricFile {
+    /// The version of bliss' features the metric was learned on.
+    pub features_version: u16,
+    /// The learned matrix, see [BaseConfig::m](bliss_audio::library::BaseConfig::m).
+    pub m: Array2<f32>,
+}
+
+impl MetricFile {
+    /// Fail if the metric can't be used with blissify's features.
+    fn validate(&self) -> Result<()> {
+        if self.features_version != FEATURES_VERSION {
+            bail!(
+                "The metric was learned on version {} of the features, but blissify uses version {}; it needs to be learned again.",
+                self.features_version,
+                FEATURES_VERSION,
+            );
+        }
+        if self.m.dim() != (NUMBER_FEATURES, NUMBER_FEATURES) {
+            bail!(
+                "The metric is a {}x{} matrix, but it should be {}x{}, one row and one column per feature.",
+                self.m.nrows(),
+                self.m.ncols(),
+                NUMBER_FEATURES,
+                NUMBER_FEATURES,
+            );
+        }
+        if self.m.iter().any(|x| !x.is_finite()) {
+            bail!("The metric contains values that are not finite numbers.");
+        }
+        Ok(())
+    }
+}
+
+impl MPDLibrary {
+    /// Write the current user's learned metric to `path`.
+    pub(crate) fn export_metric(&self, path: &Path) -> Result<()> {
+        let metric = MetricFile {
+            features_version: FEATURES_VERSION,
+            m: self.metric_matrix().to_owned(),
+        };
+        fs::write(path, serde_json::to_string_pretty(&metric)?)
+            .with_context(|| format!("while writing the metric to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Read the metric at `path` and make it the current user's, in the
+    /// profile of [user](Self::user) if set, or for everyone otherwise.
+    pub(crate) fn import_metric(&mut self, path: &Path) -> Result<()> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("while reading the metric from {}", path.display()))?;
+        let metric: MetricFile = serde_json::from_str(&contents)
+            .with_context(|| format!("{} is not a metric exported by blissify", path.display()))?;
+        metric.validate()?;
+        let mut config = self.library.config.to_owned();
+        match &self.user {
+            Some(user) => config.profiles.entry(user.to_owned()).or_default().m = Some(metric.m),
+            None => config.base_config.m = metric.m,
+        }
+        config.write()?;
+        self.library.config = config;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_export_import_metric() {
+        let (mut library, tempdir) = setup_library();
+        let path = tempdir.path().join("metric.json");
+        let m = Array2::from_shape_fn((NUMBER_FEATURES, NUMBER_FEATURES), |(i, j)| {
+            (i * NUMBER_FEATURES + j) as f32
+        });
+        fs::write(
+            &path,
+            serde_json::to_string(&MetricFile {
+                features_version: FEATURES_VERSION,
+                m: m.to_owned(),
+            })
+            .unwrap(),
+        )
+        .unwrap();
+
+        library.user = Some(String::from("alice"));
+        library.import_metric(&path).unwrap();
+        assert_eq!(library.metric_matrix(), &m);
+        library.user = None;
+        assert_eq!(
+            library.metric_matrix(),
+            &Array2::<f32>::eye(NUMBER_FEATURES)
+        );
+        library.import_metric(&path).unwrap();
+        assert_eq!(library.metric_matrix(), &m);
+
+        let exported = tempdir.path().join("exported.json");
+        library.export_metric(&exported).unwrap();
+        let exported: MetricFile =
+            serde_json::from_str(&fs::read_to_string(&exported).unwrap()).unwrap();
+        assert_eq!(exported.m, m);
+
+        for invalid in [
+            MetricFile {
+                features_version: FEATURES_VERSION,
+                m: Array2::eye(3),
+            },
+            MetricFile {
+                features_version: FEATURES_VERSION + 1,
+                m: Array2::eye(NUMBER_FEATURES),
+            },
+            MetricFile {
+                features_version: FEATURES_VERSION,
+                m: Array2::from_elem((NUMBER_FEATURES, NUMBER_FEATURES), f32::NAN),
+            },
+        ] {
+            fs::write(&path, serde_json::to_string(&invalid).unwrap()).unwrap();
+            assert!(library.import_metric(&path).is_err());
+        }
+        fs::write(&path, "not a metric").unwrap();
+        assert!(library.import_metric(&path).is_err());
+        assert_eq!(library.metric_matrix(), &m);
+    }
+}
@@ -0,0 +1,170 @@
+//! Prometheus metrics exposed by the daemon on `/metrics`.
+use anyhow::{Context, Result};
+use log::warn;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Counters and gauges updated by the daemon, and read by the HTTP server.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    /// Number of songs analyzed successfully since the daemon started.
+    pub songs_analyzed: AtomicU64,
+    /// Number of songs that failed to be analyzed since the daemon started.
+    pub analysis_errors: AtomicU64,
+    /// Number of playlists made by the daemon since it started.
+    pub playlist_generations: AtomicU64,
+    /// Number of analyzed songs in blissify's database.
+    pub library_size: AtomicU64,
+    /// Number of songs in MPD's queue.
+    pub queue_length: AtomicU64,
+    /// Number of new songs waiting to be analyzed.
+    pub analysis_pending: AtomicU64,
+    /// Number of songs being analyzed.
+    pub analysis_in_flight: AtomicU64,
+}
+
+impl Metrics {
+    /// Render the metrics in Prometheus' text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "songs_analyzed_total",
+                "counter",
+                "Number of songs analyzed since the daemon started.",
+                &self.songs_analyzed,
+            ),
+            (
+                "analysis_errors_total",
+                "counter",
+                "Number of songs that failed to be analyzed since the daemon started.",
+                &self.analysis_errors,
+            ),
+            (
+                "playlist_generations_total",
+                "counter",
+                "Number of playlists made since the daemon started.",
+                &self.playlist_generations,
+            ),
+            (
+                "library_size",
+                "gauge",
+                "Number of analyzed songs in the database.",
+                &self.library_size,
+            ),
+            (
+                "queue_length",
+                "gauge",
+                "Number of songs in MPD's queue.",
+                &self.queue_length,
+            ),
+            (
+                "analysis_pending",
+                "gauge",
+                "Number of new songs waiting to be analyzed.",
+                &self.analysis_pending,
+            ),
+            (
+                "analysis_in_flight",
+                "gauge",
+                "Number of songs being analyzed.",
+                &self.analysis_in_flight,
+            ),
+        ];
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            output.push_str(&format!(
+                "# HELP blissify_{name} {help}\n# TYPE blissify_{name} {kind}\nblissify_{name} {}\n",
+                value.load(Ordering::Relaxed),
+            ));
+        }
+        output
+    }
+}
+
+fn handle_connection(stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, the request line is all we need.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut stream = &stream;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if request_line.starts_with("GET ") && path == "/metrics" {
+        let body = metrics.render();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+    } else {
+        write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?;
+    }
+    Ok(())
+}
+
+/// Serve `metrics` on `http://address/metrics` from a background thread.
+pub fn serve(address: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("while binding the metrics endpoint to {}", address))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|s| handle_connection(s, &metrics));
+            if let Err(e) = result {
+                warn!("Could not serve metrics: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+
+    #[test]
+    fn test_serve_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.songs_analyzed.store(12, Ordering::Relaxed);
+        metrics.library_size.store(42, Ordering::Relaxed);
+        assert!(metrics.render().contains(
+            "# TYPE blissify_songs_analyzed_total counter\nblissify_songs_analyzed_total 12\n"
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        serve(&address, metrics.clone()).unwrap();
+
+        let mut stream = TcpStream::connect(&address).unwrap();
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&metrics.render()));
+        assert!(response.contains("blissify_library_size 42\n"));
+
+        let mut stream = TcpStream::connect(&address).unwrap();
+        write!(stream, "GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Making playlists from songs that are not analyzed yet, see
+//! `--analyze-missing-seed`.
+//!
+//! Songs added to MPD since the last `update` can't be the start of a
+//! playlist. Instead of updating the whole library first, only the song the
+//! playlist is made from is analyzed, right before making the playlist.
+use crate::{cue_sheet_path, MPDLibrary};
+use anyhow::{bail, Result};
+use log::info;
+
+impl MPDLibrary {
+    /// Make sure the song at `song_path`, given as for `--from-song`, is in
+    /// blissify's database. If it isn't, analyze it if `analyze` is set, or
+    /// fail with a hint about `--analyze-missing-seed` otherwise.
+    pub(crate) fn ensure_seed_analyzed(&mut self, song_path: &str, analyze: bool) -> Result<()> {
+        let path = self.resolve_song_path(song_path)?;
+        if self
+            .library
+            .song_from_path::<()>(&path.to_string_lossy())
+            .is_ok()
+        {
+            return Ok(());
+        }
+        if !analyze {
+            bail!(
+                "{} has not been analyzed yet. Run `blissify update` first, or add --analyze-missing-seed to analyze it now.",
+                path.display()
+            );
+        }
+        if self.server.is_some() {
+            bail!(
+                "{} has not been analyzed yet, and the songs of another server can't be analyzed; run `blissify update` against the local server instead.",
+                path.display()
+            );
+        }
+        // Don't store a failed analysis for a mistyped path.
+        if !cue_sheet_path(&path).unwrap_or(&path).exists() {
+            bail!(
+                "{} does not exist. Please check the path, and try again.",
+                path.display()
+            );
+        }
+        info!("Analyzing {} before making the playlist.", path.display());
+        if self.reanalyze(&[path.to_owned()])? > 0 {
+            bail!(
+                "{} could not be analyzed; `blissify list-db --pending-only` shows why.",
+                path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::setup_library;
+
+    #[test]
+    fn test_ensure_seed_analyzed() {
+        let (mut library, tempdir) = setup_library();
+        let song_path = tempdir.path().join("song.flac");
+        std::fs::copy("data/s16_mono_22_5kHz.flac", &song_path).unwrap();
+        let song_path = song_path.to_string_lossy().to_string();
+        let missing_path = tempdir.path().join("missing.flac");
+        let missing_path = missing_path.to_string_lossy().to_string();
+
+        let error = library
+            .ensure_seed_analyzed(&song_path, false)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("--analyze-missing-seed"), "{}", error);
+        assert!(library.ensure_seed_analyzed(&missing_path, true).is_err());
+        assert_eq!(library.count_songs().unwrap(), (0, 0));
+
+        library.ensure_seed_analyzed(&song_path, true).unwrap();
+        assert!(library.library.song_from_path::<()>(&song_path).is_ok());
+        library.ensure_seed_analyzed(&song_path, false).unwrap();
+    }
+}
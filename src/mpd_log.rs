@@ -0,0 +1,383 @@
+//! Finding the songs MPD played at some point from its log, see
+//! `playlist --from-log`.
+//!
+//! MPD logs a `player: played "file"` line whenever it starts playing a song,
+//! prefixed by the local time, either as "Oct 17 20:15 : " (the default,
+//! without the year), or as "2024-10-17T20:15:03 " with a custom format.
+//! Local times are converted with the C library, so the time zone is the
+//! system's.
+use crate::MPDLibrary;
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+use mpd::song::Song as MPDSong;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+/// What MPD logs before the file of a song it starts playing.
+const PLAYED: &str = "player: played \"";
+const DAY: i64 = 24 * 60 * 60;
+
+/// A local date and time. Fields out of range, like the 0th day of a month,
+/// are normalized when converted to a timestamp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct LocalTime {
+    pub year: i32,
+    /// From 1 to 12.
+    pub month: i32,
+    pub day: i32,
+    pub hour: i32,
+    pub minute: i32,
+    pub second: i32,
+}
+
+/// The broken-down local time at `timestamp`, in seconds since the epoch.
+fn local_tm(timestamp: i64) -> Result<libc::tm> {
+    let time = timestamp as libc::time_t;
+    // SAFETY: `tm` is plain data, filled in by `localtime_r`.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        bail!("Could not get the local time.");
+    }
+    Ok(tm)
+}
+
+impl LocalTime {
+    /// The local time at `timestamp`, in seconds since the epoch.
+    pub(crate) fn from_timestamp(timestamp: i64) -> Result<Self> {
+        let tm = local_tm(timestamp)?;
+        Ok(Self {
+            year: tm.tm_year + 1900,
+            month: tm.tm_mon + 1,
+            day: tm.tm_mday,
+            hour: tm.tm_hour,
+            minute: tm.tm_min,
+            second: tm.tm_sec,
+        })
+    }
+
+    /// The day of the week of this local time, from 0 for Sunday to 6 for
+    /// Saturday.
+    pub(crate) fn weekday(&self) -> Result<i32> {
+        Ok(local_tm(self.timestamp()?)?.tm_wday)
+    }
+
+    /// This local time, in seconds since the epoch.
+    // `time_t` is not 64 bits everywhere, e.g. on older Raspberry Pi systems.
+    #[allow(clippy::unnecessary_cast)]
+    pub(crate) fn timestamp(&self) -> Result<i64> {
+        // SAFETY: `tm` is plain data, read and normalized by `mktime`.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = self.year - 1900;
+        tm.tm_mon = self.month - 1;
+        tm.tm_mday = self.day;
+        tm.tm_hour = self.hour;
+        tm.tm_min = self.minute;
+        tm.tm_sec = self.second;
+        // Let the C library find out whether daylight saving time applies.
+        tm.tm_isdst = -1;
+        match unsafe { libc::mktime(&mut tm) } {
+            -1 => bail!("Invalid date or time."),
+            t => Ok(t as i64),
+        }
+    }
+}
+
+/// A time window, in seconds since the epoch, the end excluded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    pub start: i64,
+    pub end: i64,
+}
+
+/// Parse a day, "today", "yesterday" or "YYYY-MM-DD", relative to `today`.
+fn parse_day(day: &str, today: LocalTime) -> Option<LocalTime> {
+    match day {
+        "today" => Some(today),
+        "yesterday" => Some(LocalTime {
+            day: today.day - 1,
+            ..today
+        }),
+        _ => {
+            let mut parts = day.splitn(3, '-').map(|p| p.parse::<i32>().ok());
+            Some(LocalTime {
+                year: parts.next()??,
+                month: parts.next()??,
+                day: parts.next()??,
+                ..today
+            })
+        }
+    }
+}
+
+/// Parse "HH:MM" into hours and minutes.
+fn parse_hour(hour: &str) -> Option<(i32, i32)> {
+    let (hour, minute) = hour.split_once(':')?;
+    let (hour, minute) = (hour.parse().ok()?, minute.parse().ok()?);
+    ((0..24).contains(&hour) && (0..60).contains(&minute)).then_some((hour, minute))
+}
+
+/// Parse "[DAY ]HH:MM", on `default_day` if there is no day.
+fn parse_moment(moment: &str, today: LocalTime, default_day: LocalTime) -> Option<LocalTime> {
+    let (day, hour) = match moment.trim().rsplit_once(' ') {
+        Some((day, hour)) => (parse_day(day.trim(), today)?, hour),
+        None => (default_day, moment.trim()),
+    };
+    let (hour, minute) = parse_hour(hour)?;
+    Some(LocalTime {
+        hour,
+        minute,
+        second: 0,
+        ..day
+    })
+}
+
+/// Parse a time range like "yesterday 20:00..23:00", "2024-10-12 22:00..03:00"
+/// or "20:00..2024-10-13 01:00", relative to the time `now`.
+///
+/// Days are "today", the default, "yesterday" or "YYYY-MM-DD". The end is on
+/// the start's day if it has no day, or on the next day if it would be
+/// before the start otherwise.
+pub fn parse_time_range(range: &str, now: i64) -> Result<TimeRange> {
+    let invalid = || {
+        anyhow!(
+            "Invalid time range '{}', expected something like \"yesterday 20:00..23:00\".",
+            range
+        )
+    };
+    let today = LocalTime::from_timestamp(now)?;
+    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+    let start = parse_moment(start, today, today).ok_or_else(invalid)?;
+    let end_has_day = end.trim().contains(' ');
+    let mut end = parse_moment(end, today, start).ok_or_else(invalid)?;
+    if !end_has_day && end.timestamp()? <= start.timestamp()? {
+        end.day += 1;
+    }
+    let (start, end) = (start.timestamp()?, end.timestamp()?);
+    if end <= start {
+        bail!("The time range '{}' ends before it starts.", range);
+    }
+    Ok(TimeRange { start, end })
+}
+
+/// Parse the time at the start of a line of MPD's log, either "Oct 17 20:15"
+/// (assuming the most recent such date up to `now`) or "2024-10-17T20:15:03",
+/// and return it with the rest of the line.
+fn parse_log_time(line: &str, now: i64) -> Option<(i64, &str)> {
+    if let Some((date, rest)) = line.split_once('T') {
+        if let Some(day) = parse_day(date, LocalTime::from_timestamp(now).ok()?) {
+            let time = rest.get(..8)?;
+            let mut parts = time.split(':').map(|p| p.parse::<i32>().ok());
+            let time = LocalTime {
+                hour: parts.next()??,
+                minute: parts.next()??,
+                second: parts.next()??,
+                ..day
+            };
+            return Some((time.timestamp().ok()?, &rest[8..]));
+        }
+    }
+    let month = MONTHS.iter().position(|m| line.get(..3) == Some(*m))? as i32 + 1;
+    // Days are padded with a space, e.g. "Oct  7".
+    let line = line[3..].trim_start();
+    let (day, line) = line.split_once(' ')?;
+    let (time, rest) = line.split_once(' ')?;
+    let mut parts = time.split(':').map(|p| p.parse::<i32>().ok());
+    let mut time = LocalTime {
+        year: LocalTime::from_timestamp(now).ok()?.year,
+        month,
+        day: day.parse().ok()?,
+        hour: parts.next()??,
+        minute: parts.next()??,
+        second: parts.next().flatten().unwrap_or(0),
+    };
+    // The log has no years, so dates after now are from last year.
+    if time.timestamp().ok()? > now + DAY {
+        time.year -= 1;
+    }
+    Some((time.timestamp().ok()?, rest))
+}
+
+/// The file MPD started playing at `line`, if it logged one, and when.
+fn parse_played(line: &str, now: i64) -> Option<(i64, String)> {
+    let (time, rest) = parse_log_time(line, now)?;
+    let file = rest.split_once(PLAYED)?.1.strip_suffix('"')?;
+    Some((time, file.to_owned()))
+}
+
+impl MPDLibrary {
+    /// The songs MPD played during `range`, according to its log at
+    /// `log_path`, as bliss paths, in the order they were first played.
+    ///
+    /// Songs that were not analyzed are left out.
+    pub(crate) fn songs_played_during(
+        &self,
+        log_path: &Path,
+        range: TimeRange,
+    ) -> Result<Vec<String>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let log = File::open(log_path)
+            .with_context(|| format!("while opening MPD's log at {}", log_path.display()))?;
+        let mut seen = HashSet::new();
+        let mut paths = vec![];
+        let mut left_out = 0;
+        for line in BufReader::new(log).split(b'\n') {
+            let line = String::from_utf8_lossy(&line?).to_string();
+            let file = match parse_played(line.trim_end(), now) {
+                Some((time, file)) if range.start <= time && time < range.end => file,
+                _ => continue,
+            };
+            if !seen.insert(file.to_owned()) {
+                continue;
+            }
+            let path = self.mpd_to_bliss_path(&MPDSong {
+                file,
+                ..Default::default()
+            })?;
+            let path = path.to_string_lossy().to_string();
+            if self.library.song_from_path::<()>(&path).is_ok() {
+                paths.push(path);
+            } else {
+                left_out += 1;
+            }
+        }
+        if left_out > 0 {
+            warn!(
+                "Left {} song(s) played then out, since they were not analyzed.",
+                left_out
+            );
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+
+    fn timestamp(year: i32, month: i32, day: i32, hour: i32, minute: i32) -> i64 {
+        LocalTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second: 0,
+        }
+        .timestamp()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_time_range() {
+        let now = timestamp(2024, 10, 17, 12, 0);
+        let range = |start, end| TimeRange { start, end };
+        assert_eq!(
+            parse_time_range("yesterday 20:00..23:00", now).unwrap(),
+            range(
+                timestamp(2024, 10, 16, 20, 0),
+                timestamp(2024, 10, 16, 23, 0)
+            ),
+        );
+        assert_eq!(
+            parse_time_range("08:30..9:00", now).unwrap(),
+            range(
+                timestamp(2024, 10, 17, 8, 30),
+                timestamp(2024, 10, 17, 9, 0)
+            ),
+        );
+        // Parties go on after midnight.
+        assert_eq!(
+            parse_time_range("2024-10-12 22:00..03:00", now).unwrap(),
+            range(
+                timestamp(2024, 10, 12, 22, 0),
+                timestamp(2024, 10, 13, 3, 0)
+            ),
+        );
+        assert_eq!(
+            parse_time_range("2024-10-01 22:00 .. 2024-10-03 01:00", now).unwrap(),
+            range(timestamp(2024, 10, 1, 22, 0), timestamp(2024, 10, 3, 1, 0)),
+        );
+        assert!(parse_time_range("yesterday 20:00", now).is_err());
+        assert!(parse_time_range("tomorrow 20:00..21:00", now).is_err());
+        assert!(parse_time_range("25:00..26:00", now).is_err());
+        assert!(parse_time_range("today 20:00..yesterday 21:00", now).is_err());
+    }
+
+    #[test]
+    fn test_parse_played() {
+        let now = timestamp(2024, 1, 2, 12, 0);
+        assert_eq!(
+            parse_played("Jan 01 20:15 : player: played \"album/song.flac\"", now),
+            Some((
+                timestamp(2024, 1, 1, 20, 15),
+                String::from("album/song.flac")
+            )),
+        );
+        // From last year.
+        assert_eq!(
+            parse_played(
+                "Dec 31 23:59 : player: played \"a \"quoted\" song.flac\"",
+                now
+            ),
+            Some((
+                timestamp(2023, 12, 31, 23, 59),
+                String::from("a \"quoted\" song.flac")
+            )),
+        );
+        assert_eq!(
+            parse_played("Jan  2 08:00 : player: played \"song.flac\"", now),
+            Some((timestamp(2024, 1, 2, 8, 0), String::from("song.flac"))),
+        );
+        assert_eq!(
+            parse_played("2024-01-01T20:15:00 player: played \"song.flac\"", now),
+            Some((timestamp(2024, 1, 1, 20, 15), String::from("song.flac"))),
+        );
+        assert_eq!(
+            parse_played("Jan 01 20:15 : update: added album/song.flac", now),
+            None
+        );
+        assert_eq!(parse_played("exception: some error", now), None);
+    }
+
+    #[test]
+    fn test_songs_played_during() {
+        let (library, tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/second.flac", None, 0.),
+                (3, "path/later.flac", None, 0.),
+            ],
+        );
+        let log_path = tempdir.path().join("mpd.log");
+        let mut log = File::create(&log_path).unwrap();
+        writeln!(
+            log,
+            "2024-10-16T19:59:00 player: played \"later.flac\"\n\
+            2024-10-16T20:10:00 player: played \"first.flac\"\n\
+            2024-10-16T20:12:00 player: played \"not_analyzed.flac\"\n\
+            2024-10-16T20:15:00 player: played \"second.flac\"\n\
+            2024-10-16T20:20:00 player: played \"first.flac\"\n\
+            2024-10-16T23:10:00 player: played \"later.flac\"",
+        )
+        .unwrap();
+        let range = TimeRange {
+            start: timestamp(2024, 10, 16, 20, 0),
+            end: timestamp(2024, 10, 16, 23, 0),
+        };
+        assert_eq!(
+            library.songs_played_during(&log_path, range).unwrap(),
+            vec!["path/first.flac", "path/second.flac"],
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Queue entries that playlists never remove, see `blissify pin`.
+//!
+//! Unless `--keep-current-queue` is given, queueing a playlist removes
+//! everything but the current song from the queue. Pinned entries are left
+//! where they are instead: the playlist is queued right after the current
+//! song, so the entries pinned after it end up after the playlist.
+//!
+//! Entries are told apart by their MPD song id, which MPD keeps for as long as
+//! the entry stays in the queue, and their file, since ids are reused after
+//! MPD restarts. Pins of entries that left the queue are forgotten.
+use crate::{MPDClient, MPDLibrary};
+use anyhow::{bail, Result};
+use mpd::song::Song as MPDSong;
+use std::collections::HashSet;
+
+impl MPDLibrary {
+    /// Create the table storing the pinned queue entries, if it doesn't
+    /// exist yet.
+    pub(crate) fn create_pinned_song_table(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "create table if not exists pinned_song (
+                id integer primary key,
+                file text not null
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// The queue entries at `positions`, starting from 0.
+    fn queue_entries(mpd_conn: &mut MPDClient, positions: &[u32]) -> Result<Vec<MPDSong>> {
+        let queue = mpd_conn.queue()?;
+        positions
+            .iter()
+            .map(|&position| match queue.get(position as usize) {
+                Some(song) if song.place.is_some() => Ok(song.to_owned()),
+                _ => bail!(
+                    "There is no song at position {} of the queue, which has {} song(s).",
+                    position + 1,
+                    queue.len()
+                ),
+            })
+            .collect()
+    }
+
+    /// Pin the queue entries at `positions`, starting from 0, so that
+    /// playlists don't remove them.
+    ///
+    /// Returns the entries pinned.
+    pub(crate) fn pin_songs(&self, positions: &[u32]) -> Result<Vec<MPDSong>> {
+        let songs = Self::queue_entries(&mut *self.mpd_conn()?, positions)?;
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        for song in &songs {
+            sqlite_conn.execute(
+                "insert or replace into pinned_song (id, file) values (?1, ?2)",
+                (song.place.unwrap().id.0, &song.file),
+            )?;
+        }
+        Ok(songs)
+    }
+
+    /// Unpin the queue entries at `positions`, starting from 0, or all of
+    /// them if `positions` is `None`.
+    ///
+    /// Returns the number of entries that were pinned.
+    pub(crate) fn unpin_songs(&self, positions: Option<&[u32]>) -> Result<usize> {
+        let songs = match positions {
+            Some(positions) => Self::queue_entries(&mut *self.mpd_conn()?, positions)?,
+            None => {
+                let unpinned = self
+                    .library
+                    .sqlite_conn
+                    .lock()
+                    .unwrap()
+                    .execute("delete from pinned_song", [])?;
+                return Ok(unpinned);
+            }
+        };
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut unpinned = 0;
+        for song in &songs {
+            unpinned += sqlite_conn.execute(
+                "delete from pinned_song where id = ?1 and file = ?2",
+                (song.place.unwrap().id.0, &song.file),
+            )?;
+        }
+        Ok(unpinned)
+    }
+
+    /// The positions of the pinned entries of the queue, starting from 0,
+    /// with the entries. Forgets the pins of the entries that left it.
+    pub(crate) fn pinned_songs(&self, mpd_conn: &mut MPDClient) -> Result<Vec<(u32, MPDSong)>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let pins = {
+            let mut query = sqlite_conn.prepare("select id, file from pinned_song")?;
+            let pins = query
+                .query_map([], |row| {
+                    Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<HashSet<_>, _>>()?;
+            pins
+        };
+        if pins.is_empty() {
+            return Ok(vec![]);
+        }
+        let pinned = mpd_conn
+            .queue()?
+            .into_iter()
+            .enumerate()
+            .filter(|(_, song)| {
+                song.place
+                    .is_some_and(|p| pins.contains(&(p.id.0, song.file.to_owned())))
+            })
+            .map(|(position, song)| (position as u32, song))
+            .collect::<Vec<_>>();
+        if pinned.len() < pins.len() {
+            let mut query = sqlite_conn.prepare("delete from pinned_song where id = ?1")?;
+            for (id, _) in pins.iter().filter(|(id, file)| {
+                !pinned
+                    .iter()
+                    .any(|(_, s)| s.place.unwrap().id.0 == *id && s.file == *file)
+            }) {
+                query.execute([id])?;
+            }
+        }
+        Ok(pinned)
+    }
+
+    /// Remove everything from the queue but the song at `current_pos` and the
+    /// pinned entries.
+    ///
+    /// Returns the new position of the song that was at `current_pos`.
+    pub(crate) fn clear_queue_except(
+        &self,
+        mpd_conn: &mut MPDClient,
+        current_pos: u32,
+    ) -> Result<u32> {
+        let mut kept = self
+            .pinned_songs(mpd_conn)?
+            .into_iter()
+            .map(|(position, _)| position)
+            .collect::<Vec<_>>();
+        kept.push(current_pos);
+        kept.sort_unstable();
+        kept.dedup();
+        let queue_len = mpd_conn.queue()?.len() as u32;
+        // Delete the songs between the kept ones from the end, so that the
+        // positions of the songs before them don't change.
+        let ends = kept[1..].iter().chain([&queue_len]);
+        for (&start, &end) in kept.iter().zip(ends).collect::<Vec<_>>().into_iter().rev() {
+            if end > start + 1 {
+                mpd_conn.delete(start + 1..end)?;
+            }
+        }
+        if kept[0] > 0 {
+            mpd_conn.delete(0..kept[0])?;
+        }
+        Ok(kept.iter().position(|&p| p == current_pos).unwrap() as u32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::{insert_songs, setup_library};
+    use crate::PlaylistOptions;
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_pinned_songs() {
+        let (library, _tempdir) = setup_library();
+        // Only the current song's place is its position, see currentsong.
+        let mpd_song = |file: &str, id: u32, pos: u32| MPDSong {
+            file: String::from(file),
+            place: Some(QueuePlace {
+                id: Id(id),
+                pos,
+                prio: 0,
+            }),
+            ..Default::default()
+        };
+        library.mpd_conn().unwrap().mpd_queue = vec![
+            mpd_song("pinned_before.flac", 1, 10),
+            mpd_song("played.flac", 2, 10),
+            mpd_song("first_song.flac", 3, 2),
+            mpd_song("queued.flac", 4, 10),
+            mpd_song("pinned_after.flac", 5, 10),
+            mpd_song("queued_too.flac", 6, 10),
+        ];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", None, 0.),
+                (2, "path/second_song.flac", None, 0.1),
+                (3, "path/third_song.flac", None, 0.2),
+            ],
+        );
+        let pinned = library.pin_songs(&[0, 4]).unwrap();
+        assert_eq!(pinned[1].file, "pinned_after.flac");
+        assert!(library.pin_songs(&[6]).is_err());
+
+        let options = PlaylistOptions {
+            dedup: None,
+            ..Default::default()
+        };
+        library
+            .queue_from_song(None, 20, &euclidean_distance, closest_to_songs, &options)
+            .unwrap();
+        let queue = || {
+            library
+                .mpd_conn()
+                .unwrap()
+                .mpd_queue
+                .iter()
+                .map(|x| x.file.to_owned())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            queue(),
+            vec![
+                "pinned_before.flac",
+                "first_song.flac",
+                "second_song.flac",
+                "third_song.flac",
+                "pinned_after.flac",
+            ],
+        );
+
+        // The pins of songs that left the queue are forgotten, even if their
+        // id comes back with another song.
+        library.mpd_conn().unwrap().mpd_queue[0].file = String::from("other.flac");
+        let pinned = library
+            .pinned_songs(&mut library.mpd_conn().unwrap())
+            .unwrap();
+        assert_eq!(
+            pinned
+                .into_iter()
+                .map(|(p, s)| (p, s.file))
+                .collect::<Vec<_>>(),
+            vec![(4, String::from("pinned_after.flac"))],
+        );
+        library.mpd_conn().unwrap().mpd_queue[0].file = String::from("pinned_before.flac");
+        assert_eq!(
+            library
+                .pinned_songs(&mut library.mpd_conn().unwrap())
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert_eq!(library.unpin_songs(Some(&[4])).unwrap(), 1);
+        assert_eq!(library.unpin_songs(None).unwrap(), 0);
+        library.pin_songs(&[0]).unwrap();
+        assert_eq!(library.unpin_songs(None).unwrap(), 1);
+    }
+}
@@ -0,0 +1,451 @@
+//! Play counts imported from Last.fm or mpdscribble, see
+//! `blissify import-play-counts` and `playlist --weight-by-playcount`.
+//!
+//! Unlike the history the daemon records, see [crate::history], these cover
+//! years of listening. Plays are matched to songs by artist and title,
+//! ignoring case, and the counts are kept in a `play_count` table, replaced
+//! by each import since exports hold the whole history.
+//!
+//! `--weight-by-playcount` scales the distance of each candidate by
+//! `exp(±PLAYCOUNT_WEIGHT * c)`, `c` being its play count on a logarithmic
+//! scale from 0 (never played) to 1 (the most played song), so that
+//! neglected (`inverse`) or beloved (`direct`) songs come first among songs
+//! that sound about as close.
+use crate::MPDLibrary;
+use anyhow::{bail, Context, Result};
+use bliss_audio::playlist::{DistanceMetric, DistanceMetricBuilder};
+use bliss_audio::BlissError;
+use ndarray::Array1;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// How much play counts weigh on the distance.
+const PLAYCOUNT_WEIGHT: f32 = 0.5;
+
+/// Which songs `--weight-by-playcount` favors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaycountWeight {
+    /// Songs played less often.
+    Inverse,
+    /// Songs played more often.
+    Direct,
+}
+
+impl std::str::FromStr for PlaycountWeight {
+    type Err = BlissError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inverse" => Ok(PlaycountWeight::Inverse),
+            "direct" => Ok(PlaycountWeight::Direct),
+            _ => Err(BlissError::ProviderError(format!(
+                "Unknown play count weighting '{}', please choose between 'inverse' and 'direct'.",
+                s
+            ))),
+        }
+    }
+}
+
+/// A play read from an export, before it is matched to a song.
+#[derive(Debug, PartialEq)]
+struct Play {
+    artist: String,
+    title: String,
+}
+
+/// Split a line of CSV into its fields, handling quoted fields.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Read the plays of a Last.fm export, as CSV lines of
+/// "artist,album,title,date", after an optional header line.
+fn lastfm_plays(contents: &str) -> Vec<Play> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(i, line)| {
+            let line = line.to_lowercase();
+            *i > 0
+                || !(line.starts_with("artist,album,title")
+                    || line.starts_with("artist,album,track"))
+        })
+        .map(|(_, line)| csv_fields(line))
+        .filter(|fields| fields.len() >= 3)
+        .map(|fields| Play {
+            artist: fields[0].to_owned(),
+            title: fields[2].to_owned(),
+        })
+        .collect()
+}
+
+/// Read the plays of an mpdscribble journal, made of blank line separated
+/// records of "key = value" lines, "a" being the artist and "t" the title.
+fn mpdscribble_plays(contents: &str) -> Vec<Play> {
+    contents
+        .split("\n\n")
+        .filter_map(|record| {
+            let value = |key: &str| {
+                record.lines().find_map(|line| {
+                    let (k, v) = line.split_once('=')?;
+                    (k.trim() == key).then(|| v.trim().to_owned())
+                })
+            };
+            Some(Play {
+                artist: value("a")?,
+                title: value("t")?,
+            })
+        })
+        .collect()
+}
+
+/// The plays of the export in `contents`, either an mpdscribble journal or a
+/// Last.fm CSV export.
+fn read_plays(contents: &str) -> Vec<Play> {
+    let contents = contents.replace("\r\n", "\n");
+    if contents.lines().any(|line| line.starts_with("a = ")) {
+        mpdscribble_plays(&contents)
+    } else {
+        lastfm_plays(&contents)
+    }
+}
+
+/// The analyses of the songs that were played, with their play counts, used
+/// to bias playlists with a [PlaycountDistance].
+#[derive(Debug, Default)]
+pub struct PlayCounts {
+    /// The play counts, from 0 to 1, by analysis, see [analysis_key].
+    counts: HashMap<Vec<u32>, f32>,
+    weight: Option<PlaycountWeight>,
+}
+
+/// A key to look analyses up by, since floats can't be hashed.
+fn analysis_key(analysis: &Array1<f32>) -> Vec<u32> {
+    analysis.iter().map(|f| f.to_bits()).collect()
+}
+
+impl PlayCounts {
+    pub fn is_empty(&self) -> bool {
+        self.weight.is_none() || self.counts.is_empty()
+    }
+
+    /// Wrap `inner` so that the distances it computes take play counts into
+    /// account.
+    pub fn distance<'a>(&'a self, inner: &'a dyn DistanceMetricBuilder) -> PlaycountDistance<'a> {
+        PlaycountDistance {
+            inner,
+            play_counts: self,
+        }
+    }
+}
+
+/// A distance metric taking play counts into account, see the
+/// [module](self) documentation.
+pub struct PlaycountDistance<'a> {
+    inner: &'a dyn DistanceMetricBuilder,
+    play_counts: &'a PlayCounts,
+}
+
+impl DistanceMetricBuilder for PlaycountDistance<'_> {
+    fn build<'a>(&'a self, vectors: &[Array1<f32>]) -> Box<dyn DistanceMetric + 'a> {
+        let inner = self.inner.build(vectors);
+        let sign = match self.play_counts.weight {
+            _ if self.play_counts.is_empty() => return inner,
+            Some(PlaycountWeight::Inverse) => 1.,
+            Some(PlaycountWeight::Direct) => -1.,
+            None => return inner,
+        };
+        Box::new(PlaycountMetric {
+            inner,
+            counts: &self.play_counts.counts,
+            sign,
+        })
+    }
+}
+
+struct PlaycountMetric<'a> {
+    inner: Box<dyn DistanceMetric + 'a>,
+    counts: &'a HashMap<Vec<u32>, f32>,
+    /// 1 to push songs played often away, -1 to bring them closer.
+    sign: f32,
+}
+
+impl DistanceMetric for PlaycountMetric<'_> {
+    fn distance(&self, vector: &Array1<f32>) -> f32 {
+        let count = self
+            .counts
+            .get(&analysis_key(vector))
+            .copied()
+            .unwrap_or(0.);
+        self.inner.distance(vector) * (self.sign * PLAYCOUNT_WEIGHT * count).exp()
+    }
+}
+
+impl MPDLibrary {
+    /// Create the table storing the imported play counts, if it doesn't exist
+    /// yet.
+    pub(crate) fn create_play_count_table(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "create table if not exists play_count (
+                path text primary key,
+                count integer not null
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Replace the play counts with the plays of the Last.fm export or
+    /// mpdscribble journal at `path`.
+    ///
+    /// Returns the number of plays matched to a song of the library, and the
+    /// number of plays that were not.
+    pub(crate) fn import_play_counts(&self, path: &Path) -> Result<(usize, usize)> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("while reading the plays of {}", path.display()))?;
+        let plays = read_plays(&contents);
+        if plays.is_empty() {
+            bail!(
+                "No play found in {}, which should be a Last.fm CSV export or an mpdscribble journal.",
+                path.display()
+            );
+        }
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let tx = sqlite_conn.transaction()?;
+        // Lowercased here rather than with SQLite's lower(), which leaves
+        // non-ASCII letters alone.
+        let mut songs: HashMap<(String, String), Vec<String>> = HashMap::new();
+        {
+            let mut query = tx.prepare(
+                "select artist, title, path from song
+                where analyzed = true and artist is not null and title is not null",
+            )?;
+            let rows = query.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?;
+            for row in rows {
+                let (artist, title, path) = row?;
+                songs
+                    .entry((artist.to_lowercase(), title.to_lowercase()))
+                    .or_default()
+                    .push(path);
+            }
+        }
+        let mut counts: HashMap<&str, i64> = HashMap::new();
+        let mut unmatched = 0;
+        for play in &plays {
+            match songs.get(&(play.artist.to_lowercase(), play.title.to_lowercase())) {
+                Some(paths) => {
+                    for path in paths {
+                        *counts.entry(path).or_default() += 1;
+                    }
+                }
+                None => unmatched += 1,
+            }
+        }
+        tx.execute("delete from play_count", [])?;
+        {
+            let mut insert = tx.prepare("insert into play_count (path, count) values (?1, ?2)")?;
+            for (path, count) in &counts {
+                insert.execute((path, count))?;
+            }
+        }
+        tx.commit()?;
+        Ok((plays.len() - unmatched, unmatched))
+    }
+
+    /// The play counts of the analyzed songs, used to bias playlists towards
+    /// neglected or beloved songs depending on `weight`.
+    pub(crate) fn play_counts(&self, weight: Option<PlaycountWeight>) -> Result<PlayCounts> {
+        let mut play_counts = PlayCounts {
+            weight,
+            ..Default::default()
+        };
+        if weight.is_none() {
+            return Ok(play_counts);
+        }
+        let counts = {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut query = sqlite_conn.prepare("select path, count from play_count")?;
+            let counts = query
+                .query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            counts
+        };
+        let max = counts.iter().map(|(_, count)| *count).max().unwrap_or(0);
+        for (path, count) in counts {
+            if let Ok(song) = self.library.song_from_path::<()>(&path) {
+                let count = (count as f32).ln_1p() / (max as f32).ln_1p();
+                play_counts
+                    .counts
+                    .insert(analysis_key(&song.bliss_song.analysis.as_arr1()), count);
+            }
+        }
+        Ok(play_counts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::playlist::euclidean_distance;
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+
+    #[test]
+    fn test_read_plays() {
+        let play = |artist: &str, title: &str| Play {
+            artist: artist.to_owned(),
+            title: title.to_owned(),
+        };
+        assert_eq!(
+            read_plays(
+                "artist,album,title,date\r\n\
+                Aphex Twin,Drukqs,Avril 14th,01 Jan 2020 12:00\r\n\
+                \"Crosby, Stills & Nash\",CSN,\"Suite: Judy \"\"Blue\"\" Eyes\",01 Jan 2020 12:05\r\n"
+            ),
+            vec![
+                play("Aphex Twin", "Avril 14th"),
+                play("Crosby, Stills & Nash", "Suite: Judy \"Blue\" Eyes"),
+            ],
+        );
+        assert_eq!(
+            read_plays(
+                "a = Aphex Twin\nt = Avril 14th\nb = Drukqs\ni = 2020-01-01T12:00:00\nl = 125\no = P\n\n\
+                a = Boards of Canada\nt = Roygbiv\ni = 2020-01-01T12:03:00\n"
+            ),
+            vec![
+                play("Aphex Twin", "Avril 14th"),
+                play("Boards of Canada", "Roygbiv"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_play_counts() {
+        let (library, tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/seed.flac", None, 0.),
+                (2, "path/beloved.flac", None, 0.1),
+                (3, "path/neglected.flac", None, -0.1),
+            ],
+        );
+        {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            for (id, title) in [(1, "Seed"), (2, "Beloved"), (3, "Neglected")] {
+                sqlite_conn
+                    .execute(
+                        "update song set artist = 'Artist', title = ?1 where id = ?2",
+                        (title, id),
+                    )
+                    .unwrap();
+            }
+        }
+        let export = tempdir.path().join("scrobbles.csv");
+        let mut file = fs::File::create(&export).unwrap();
+        for _ in 0..10 {
+            writeln!(file, "ARTIST,Album,beloved,01 Jan 2020 12:00").unwrap();
+        }
+        writeln!(file, "Artist,Album,Neglected,01 Jan 2020 12:00").unwrap();
+        writeln!(file, "Someone else,Album,Unknown,01 Jan 2020 12:00").unwrap();
+        assert_eq!(library.import_play_counts(&export).unwrap(), (11, 1));
+        // Importing again replaces the counts.
+        assert_eq!(library.import_play_counts(&export).unwrap(), (11, 1));
+
+        let seed = library
+            .library
+            .song_from_path::<()>("path/seed.flac")
+            .unwrap()
+            .bliss_song
+            .analysis
+            .as_arr1();
+        let analysis = |path: &str| {
+            library
+                .library
+                .song_from_path::<()>(path)
+                .unwrap()
+                .bliss_song
+                .analysis
+                .as_arr1()
+        };
+        let distances = |weight| {
+            let play_counts = library.play_counts(weight).unwrap();
+            let distance = play_counts.distance(&euclidean_distance);
+            let metric = distance.build(std::slice::from_ref(&seed));
+            (
+                metric.distance(&analysis("path/beloved.flac")),
+                metric.distance(&analysis("path/neglected.flac")),
+            )
+        };
+        let (beloved, neglected) = distances(None);
+        assert!((beloved - neglected).abs() < 1e-6);
+        let (beloved, neglected) = distances(Some(PlaycountWeight::Inverse));
+        assert!(beloved > neglected);
+        let (beloved, neglected) = distances(Some(PlaycountWeight::Direct));
+        assert!(beloved < neglected);
+
+        let empty = tempdir.path().join("empty.csv");
+        fs::write(&empty, "").unwrap();
+        assert!(library.import_play_counts(&empty).is_err());
+    }
+
+    #[test]
+    fn test_import_play_counts_non_ascii() {
+        let (library, tempdir) = setup_library();
+        insert_songs(&library, &[(1, "path/vie-en-rose.flac", None, 0.)]);
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute(
+                "update song set artist = 'Édith Piaf', title = 'La Vie en rose' where id = 1",
+                [],
+            )
+            .unwrap();
+        let export = tempdir.path().join("scrobbles.csv");
+        fs::write(
+            &export,
+            "ÉDITH PIAF,Album,la vie en rose,01 Jan 2020 12:00\n\
+            édith piaf,Album,LA VIE EN ROSE,01 Jan 2020 12:00\n",
+        )
+        .unwrap();
+        assert_eq!(library.import_play_counts(&export).unwrap(), (2, 0));
+        let count: i64 = library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "select count from play_count where path = 'path/vie-en-rose.flac'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+}
@@ -0,0 +1,352 @@
+//! The playlists blissify made, see `blissify history`.
+//!
+//! Each playlist made by `playlist` is stored with the songs it was made
+//! from, the distance metric and the command line that made it, and its
+//! songs, so that `history replay` can queue it again as it was, even after
+//! the library changed and the same command would make another playlist.
+use crate::{MPDLibrary, PlaylistOptions};
+use anyhow::{bail, Result};
+use bliss_audio::library::LibrarySong;
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a playlist was made with, stored along with it.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PlaylistParameters {
+    /// The distance metric chosen with `--distance` or in the configuration
+    /// file, `None` for the default one.
+    pub distance: Option<String>,
+    /// The arguments blissify was run with.
+    pub command: Vec<String>,
+}
+
+/// A playlist of the history.
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct GeneratedPlaylist {
+    pub id: i64,
+    /// When the playlist was made, as a local date and time.
+    pub created_at: String,
+    pub distance: Option<String>,
+    pub command: Vec<String>,
+    /// The paths of the songs the playlist was made from.
+    pub seeds: Vec<String>,
+    pub songs: Vec<String>,
+}
+
+impl GeneratedPlaylist {
+    /// A line describing the playlist, as printed by `history list`.
+    pub fn summary(&self) -> String {
+        format!(
+            "{}: {}, {} song(s) from {}, made with `blissify {}`",
+            self.id,
+            self.created_at,
+            self.songs.len(),
+            match &self.seeds[..] {
+                [] => String::from("no song"),
+                [seed] => seed.to_owned(),
+                seeds => format!("{} songs", seeds.len()),
+            },
+            self.command.join(" "),
+        )
+    }
+}
+
+impl fmt::Display for GeneratedPlaylist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Made: {}", self.created_at)?;
+        writeln!(f, "Command: blissify {}", self.command.join(" "))?;
+        writeln!(
+            f,
+            "Distance: {}",
+            self.distance.as_deref().unwrap_or("default")
+        )?;
+        for seed in &self.seeds {
+            writeln!(f, "Seed: {}", seed)?;
+        }
+        for song in &self.songs {
+            writeln!(f, "{}", song)?;
+        }
+        Ok(())
+    }
+}
+
+impl MPDLibrary {
+    /// Create the tables storing the playlists made, if they don't exist
+    /// yet.
+    pub(crate) fn create_generated_playlist_tables(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute_batch(
+            "create table if not exists generated_playlist (
+                id integer primary key,
+                created_at integer not null,
+                distance text,
+                command text not null
+            );
+            create table if not exists generated_playlist_song (
+                playlist_id integer not null,
+                position integer not null,
+                path text not null,
+                is_seed boolean not null,
+                primary key (playlist_id, is_seed, position)
+            );",
+        )?;
+        Ok(())
+    }
+
+    /// Store `playlist`, made from the songs at `seeds`, in the history, if
+    /// `options` has the parameters it was made with.
+    pub(crate) fn record_playlist(
+        &self,
+        seeds: &[PathBuf],
+        playlist: &[LibrarySong<()>],
+        options: &PlaylistOptions,
+    ) -> Result<()> {
+        if let Some(parameters) = &options.history {
+            self.store_playlist(seeds, playlist, parameters)?;
+        }
+        Ok(())
+    }
+
+    /// Store `playlist`, made from the songs at `seeds` with `parameters`,
+    /// in the history. Returns its id.
+    fn store_playlist(
+        &self,
+        seeds: &[PathBuf],
+        playlist: &[LibrarySong<()>],
+        parameters: &PlaylistParameters,
+    ) -> Result<i64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let tx = sqlite_conn.transaction()?;
+        tx.execute(
+            "insert into generated_playlist (created_at, distance, command) values (?1, ?2, ?3)",
+            (
+                now,
+                &parameters.distance,
+                serde_json::to_string(&parameters.command)?,
+            ),
+        )?;
+        let id = tx.last_insert_rowid();
+        {
+            let mut insert = tx.prepare(
+                "insert into generated_playlist_song (playlist_id, position, path, is_seed)
+                values (?1, ?2, ?3, ?4)",
+            )?;
+            for (position, seed) in seeds.iter().enumerate() {
+                insert.execute((id, position, seed.to_string_lossy(), true))?;
+            }
+            for (position, song) in playlist.iter().enumerate() {
+                insert.execute((id, position, song.bliss_song.path.to_string_lossy(), false))?;
+            }
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// The playlists of the history, from the oldest to the newest, or only
+    /// the playlist `id` if set.
+    fn generated_playlists_where(&self, id: Option<i64>) -> Result<Vec<GeneratedPlaylist>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select id, datetime(created_at, 'unixepoch', 'localtime'), distance, command
+            from generated_playlist where ?1 is null or id = ?1 order by id",
+        )?;
+        let mut playlists = query
+            .query_map([id], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .map(|row| -> Result<GeneratedPlaylist> {
+                let (id, created_at, distance, command): (i64, String, _, String) = row?;
+                Ok(GeneratedPlaylist {
+                    id,
+                    created_at,
+                    distance,
+                    command: serde_json::from_str(&command)?,
+                    seeds: vec![],
+                    songs: vec![],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let indices: HashMap<i64, usize> = playlists
+            .iter()
+            .enumerate()
+            .map(|(i, playlist)| (playlist.id, i))
+            .collect();
+        let mut query = sqlite_conn.prepare(
+            "select playlist_id, path, is_seed from generated_playlist_song
+            where ?1 is null or playlist_id = ?1 order by playlist_id, position",
+        )?;
+        let songs = query.query_map([id], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, bool>(2)?,
+            ))
+        })?;
+        for song in songs {
+            let (playlist_id, path, is_seed) = song?;
+            if let Some(&i) = indices.get(&playlist_id) {
+                if is_seed {
+                    playlists[i].seeds.push(path);
+                } else {
+                    playlists[i].songs.push(path);
+                }
+            }
+        }
+        Ok(playlists)
+    }
+
+    /// The playlists of the history, from the oldest to the newest.
+    pub(crate) fn generated_playlists(&self) -> Result<Vec<GeneratedPlaylist>> {
+        self.generated_playlists_where(None)
+    }
+
+    /// The playlist `id` of the history.
+    pub(crate) fn generated_playlist(&self, id: i64) -> Result<GeneratedPlaylist> {
+        match self.generated_playlists_where(Some(id))?.pop() {
+            Some(playlist) => Ok(playlist),
+            None => bail!(
+                "There is no playlist {} in the history, run \"blissify history list\" to see them.",
+                id
+            ),
+        }
+    }
+
+    /// Queue the songs of the playlist `id` of the history again, after the
+    /// current song. Unless `keep_queue` is set, the rest of the queue is
+    /// removed first, save for the pinned songs. Songs that are not in
+    /// blissify's database anymore are left out. Returns the number of songs
+    /// queued.
+    pub(crate) fn replay_playlist(&self, id: i64, keep_queue: bool) -> Result<usize> {
+        let playlist = self.generated_playlist(id)?;
+        let mut mpd_songs = vec![];
+        for path in &playlist.songs {
+            match self.library.song_from_path::<()>(path) {
+                Ok(song) => mpd_songs.push(self.bliss_song_to_mpd(&song)?),
+                Err(_) => warn!(
+                    "{} is not in blissify's database anymore, leaving it out.",
+                    path
+                ),
+            }
+        }
+        let mut mpd_conn = self.mpd_conn()?;
+        let current_song = mpd_conn.currentsong()?;
+        self.change_queue(&mut mpd_conn, |mpd_conn| {
+            match &current_song {
+                Some(current_song) => {
+                    let mut current_pos = Self::queue_position(mpd_conn, current_song)?;
+                    if !keep_queue {
+                        current_pos = self.clear_queue_except(mpd_conn, current_pos)?;
+                    }
+                    self.add_to_queue(mpd_conn, &mpd_songs, Some(current_pos + 1))?;
+                }
+                None => {
+                    if !keep_queue {
+                        mpd_conn.clear()?;
+                    }
+                    self.add_to_queue(mpd_conn, &mpd_songs, None)?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(mpd_songs.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_playlist_history() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/second.flac", None, 0.1),
+                (3, "path/third.flac", None, 0.2),
+                (4, "path/removed.flac", None, 0.3),
+            ],
+        );
+        let songs = library.library.songs_from_library::<()>().unwrap();
+        let parameters = PlaylistParameters {
+            distance: Some(String::from("cosine")),
+            command: vec![
+                String::from("playlist"),
+                String::from("3"),
+                String::from("--distance"),
+                String::from("cosine"),
+            ],
+        };
+        let first = library
+            .store_playlist(
+                &[PathBuf::from("path/first.flac")],
+                &songs[..1],
+                &parameters,
+            )
+            .unwrap();
+        let second = library
+            .store_playlist(
+                &[PathBuf::from("path/first.flac")],
+                &songs[1..],
+                &parameters,
+            )
+            .unwrap();
+
+        let playlists = library.generated_playlists().unwrap();
+        assert_eq!(
+            playlists.iter().map(|p| p.id).collect::<Vec<_>>(),
+            vec![first, second],
+        );
+        let playlist = library.generated_playlist(second).unwrap();
+        assert_eq!(playlist.distance.as_deref(), Some("cosine"));
+        assert_eq!(playlist.command, parameters.command);
+        assert_eq!(playlist.seeds, vec!["path/first.flac"]);
+        assert_eq!(
+            playlist.songs,
+            vec!["path/second.flac", "path/third.flac", "path/removed.flac"],
+        );
+        assert!(playlist.summary().ends_with(
+            "3 song(s) from path/first.flac, made with `blissify playlist 3 --distance cosine`"
+        ));
+        assert!(library.generated_playlist(second + 1).is_err());
+
+        library.library.delete_paths(["path/removed.flac"]).unwrap();
+        library.mpd_conn().unwrap().mpd_queue = vec![
+            MPDSong {
+                file: String::from("first.flac"),
+                place: Some(QueuePlace {
+                    id: Id(1),
+                    pos: 0,
+                    prio: 0,
+                }),
+                ..Default::default()
+            },
+            MPDSong {
+                file: String::from("other.flac"),
+                place: Some(QueuePlace {
+                    id: Id(2),
+                    pos: 1,
+                    prio: 0,
+                }),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(library.replay_playlist(second, false).unwrap(), 2);
+        let queue = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|s| s.file.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(queue, vec!["first.flac", "second.flac", "third.flac"]);
+    }
+}
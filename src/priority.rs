@@ -0,0 +1,166 @@
+//! Making playlists out of the songs already in MPD's queue, see
+//! `--use-priorities`.
+//!
+//! Instead of clearing the queue and queueing new songs, the songs of the
+//! queue closest to the current one are given MPD priorities, the closest
+//! getting the highest. In random mode, MPD plays the songs with the highest
+//! priority first, so the queue stays as it is, but plays the most similar
+//! songs next.
+use crate::{dedup_playlist, print_playlist, MPDLibrary, PlaylistOptions};
+use anyhow::{bail, Result};
+use bliss_audio::library::LibrarySong;
+use bliss_audio::playlist::DistanceMetricBuilder;
+use log::warn;
+use std::collections::HashMap;
+
+impl MPDLibrary {
+    /// Give the `number_songs` songs of the queue closest to the current
+    /// song decreasing MPD priorities, from 255 down, sorted with `sort_by`
+    /// according to `distance`, and reset the priorities of the other songs.
+    ///
+    /// Songs of the queue that were not analyzed are left alone. With
+    /// `options.dry_run`, only print the songs that would be prioritized.
+    pub(crate) fn prioritize_queue<'a, F, I>(
+        &self,
+        number_songs: usize,
+        distance: &'a dyn DistanceMetricBuilder,
+        sort_by: F,
+        options: &PlaylistOptions,
+    ) -> Result<()>
+    where
+        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
+        I: Iterator<Item = LibrarySong<()>> + 'a,
+    {
+        let (current_song, queue) = {
+            let mut mpd_conn = self.mpd_conn()?;
+            match mpd_conn.currentsong()? {
+                Some(s) => (s, mpd_conn.queue()?),
+                None => bail!("No song is currently playing. Add a song to start the playlist from, and try again."),
+            }
+        };
+        let current_id = current_song.place.map(|p| p.id);
+        let seed = self
+            .library
+            .song_from_path(&self.mpd_to_bliss_path(&current_song)?.to_string_lossy())?;
+        // The analyzed songs of the queue, and their ids in it.
+        let mut ids = HashMap::new();
+        let mut candidates = vec![];
+        for mpd_song in &queue {
+            let place = match mpd_song.place {
+                Some(place) if Some(place.id) != current_id => place,
+                _ => continue,
+            };
+            let path = self.mpd_to_bliss_path(mpd_song)?;
+            if let Ok(song) = self.library.song_from_path(&path.to_string_lossy()) {
+                ids.entry(path).or_insert(place.id);
+                candidates.push(song);
+            }
+        }
+        let seeds = [seed];
+        // The current song goes first so that its duplicates are left out.
+        let playlist = dedup_playlist(
+            seeds
+                .iter()
+                .cloned()
+                .chain(sort_by(&seeds, &candidates, distance)),
+            options.dedup,
+            options.dedup_distance,
+            |s| self.recording_id(s),
+        )
+        .skip(1)
+        .filter(|s| !options.excludes(s))
+        .take(number_songs.min(255))
+        .collect::<Vec<_>>();
+
+        if options.dry_run {
+            return print_playlist(&playlist, &seeds, distance, options);
+        }
+        let mut mpd_conn = self.mpd_conn()?;
+        if !mpd_conn.status()?.random {
+            warn!("Random mode is disabled for MPD, so it will ignore the priorities and play the queue in order.");
+        }
+        // MPD ids don't implement Hash, so they are keyed by their number.
+        let mut prioritized = HashMap::new();
+        for (i, song) in playlist.iter().enumerate() {
+            if let Some(id) = ids.get(&song.bliss_song.path) {
+                prioritized.insert(id.0, 255 - i as u8);
+            }
+        }
+        for mpd_song in queue {
+            let place = match mpd_song.place {
+                Some(place) => place,
+                None => continue,
+            };
+            let prio = prioritized.get(&place.id.0).copied().unwrap_or(0);
+            if prio != place.prio {
+                mpd_conn.priority(place.id, prio)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::{insert_songs, setup_library};
+    use crate::PlaylistOptions;
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_prioritize_queue() {
+        let (library, _tempdir) = setup_library();
+        let queued = |file: &str, id, prio| MPDSong {
+            file: String::from(file),
+            place: Some(QueuePlace {
+                id: Id(id),
+                pos: id - 1,
+                prio,
+            }),
+            ..Default::default()
+        };
+        library.mpd_conn().unwrap().mpd_queue = vec![
+            queued("current.flac", 1, 0),
+            queued("far.flac", 2, 10),
+            queued("not_analyzed.flac", 3, 0),
+            queued("close.flac", 4, 0),
+            queued("closer.flac", 5, 0),
+        ];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/current.flac", None, 0.),
+                (2, "path/far.flac", None, 0.9),
+                (3, "path/close.flac", None, 0.2),
+                (4, "path/closer.flac", None, 0.1),
+                (5, "path/not_queued.flac", None, 0.),
+            ],
+        );
+
+        library
+            .prioritize_queue(
+                2,
+                &euclidean_distance,
+                closest_to_songs,
+                &PlaylistOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            library
+                .mpd_conn()
+                .unwrap()
+                .mpd_queue
+                .iter()
+                .map(|s| (s.file.as_str(), s.place.unwrap().prio))
+                .collect::<Vec<_>>(),
+            vec![
+                ("current.flac", 0),
+                ("far.flac", 0),
+                ("not_analyzed.flac", 0),
+                ("close.flac", 254),
+                ("closer.flac", 255),
+            ],
+        );
+    }
+}
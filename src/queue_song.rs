@@ -0,0 +1,105 @@
+//! Making playlists from a song of MPD's queue, see `--from-queue-pos` and
+//! `--from-song-id`.
+//!
+//! Frontends know the songs of the queue by their position or MPD id, and
+//! turning those back into paths for `--from-song` breaks for CUE tracks,
+//! or for paths in odd encodings. The song is instead looked up in the queue,
+//! and its path converted the same way as the current song's.
+use crate::MPDLibrary;
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use mpd::song::Id;
+
+/// A song of MPD's queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum QueueSong {
+    /// Its position in the queue, starting from 0, as MPD counts them.
+    Position(u32),
+    /// Its MPD id, which stays the same when the queue is reordered.
+    Id(u32),
+}
+
+impl QueueSong {
+    /// The song given with `--from-queue-pos` or `--from-song-id`, if any.
+    pub fn from_args(sub_m: &ArgMatches) -> Result<Option<Self>> {
+        if let Some(position) = sub_m.value_of("from-queue-pos") {
+            let position = position.parse().with_context(|| {
+                format!(
+                    "The queue position must be a number, starting from 0, got '{}'.",
+                    position
+                )
+            })?;
+            return Ok(Some(QueueSong::Position(position)));
+        }
+        if let Some(id) = sub_m.value_of("from-song-id") {
+            let id = id
+                .parse()
+                .with_context(|| format!("The song id must be a number, got '{}'.", id))?;
+            return Ok(Some(QueueSong::Id(id)));
+        }
+        Ok(None)
+    }
+}
+
+impl MPDLibrary {
+    /// The path of the song `song` of the queue, as stored in blissify's
+    /// database, which `--from-song` takes.
+    pub(crate) fn queue_song_path(&self, song: QueueSong) -> Result<String> {
+        let mut mpd_conn = self.mpd_conn()?;
+        let mpd_song = match song {
+            QueueSong::Position(position) => mpd_conn.queue()?.into_iter().nth(position as usize),
+            QueueSong::Id(id) => mpd_conn.playlistid(Id(id))?,
+        };
+        let Some(mpd_song) = mpd_song else {
+            match song {
+                QueueSong::Position(position) => bail!(
+                    "There is no song at position {} of the queue; positions start from 0.",
+                    position
+                ),
+                QueueSong::Id(id) => bail!("There is no song with id {} in the queue.", id),
+            }
+        };
+        Ok(self
+            .mpd_to_bliss_path(&mpd_song)?
+            .to_string_lossy()
+            .into_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use mpd::song::{QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_queue_song_path() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = ["first.flac", "album.cue/track0002"]
+            .iter()
+            .enumerate()
+            .map(|(pos, file)| MPDSong {
+                file: file.to_string(),
+                place: Some(QueuePlace {
+                    id: Id(pos as u32 + 10),
+                    pos: pos as u32,
+                    prio: 0,
+                }),
+                ..Default::default()
+            })
+            .collect();
+        let base_path = library.library.config.mpd_base_path.to_owned();
+
+        assert_eq!(
+            library.queue_song_path(QueueSong::Position(0)).unwrap(),
+            base_path.join("first.flac").to_string_lossy(),
+        );
+        assert_eq!(
+            library.queue_song_path(QueueSong::Id(11)).unwrap(),
+            base_path.join("album.cue/CUE_TRACK002").to_string_lossy(),
+        );
+        assert!(library.queue_song_path(QueueSong::Position(2)).is_err());
+        assert!(library.queue_song_path(QueueSong::Id(1)).is_err());
+    }
+}
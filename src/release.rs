@@ -0,0 +1,221 @@
+//! Telling apart the releases of a same recording, see `--dedup-by release`.
+//!
+//! Remasters, deluxe or anniversary editions of an album hold the same songs
+//! as the original release, but they can be mastered differently enough for
+//! their analyses to be far apart, so [DedupStrategy::Features] keeps them
+//! all. Songs are considered the same recording if MPD gives them the same
+//! MusicBrainz recording id, or, when one of them has none, if they have the
+//! same artist, the same title once edition suffixes like "(2011 Remaster)"
+//! are removed, and about the same duration. Only playlists are affected: all
+//! the releases stay in the database, and the first one the playlist comes
+//! across is kept.
+//!
+//! [DedupStrategy::Features]: crate::DedupStrategy::Features
+use crate::MPDLibrary;
+use bliss_audio::library::LibrarySong;
+use bliss_audio::Song;
+use mpd::search::{Query, Term};
+use std::time::Duration;
+
+/// The MPD tag holding MusicBrainz recording ids.
+const RECORDING_ID_TAG: &str = "MUSICBRAINZ_TRACKID";
+/// How far apart the durations of two releases of a recording can be, since
+/// remasters often trim or extend silences.
+const DURATION_TOLERANCE: Duration = Duration::from_secs(5);
+/// Words marking the parts of titles that only tell releases apart, like
+/// "(Remastered 2011)" or "- Deluxe Edition". Live versions, remixes and
+/// edits are different recordings, so they are not there.
+const EDITION_WORDS: &[&str] = &[
+    "remaster",
+    "deluxe",
+    "anniversary",
+    "edition",
+    "reissue",
+    "expanded",
+    "bonus",
+    "mono",
+    "stereo",
+    "album version",
+    "single version",
+];
+
+/// Whether `part` of a title only tells releases apart.
+fn is_edition(part: &str) -> bool {
+    let part = part.to_lowercase();
+    EDITION_WORDS.iter().any(|w| part.contains(w))
+}
+
+/// `title` without its edition suffixes, lowercased and without punctuation,
+/// e.g. "Song Title" for "Song Title (Remastered 2011) - Mono".
+pub fn normalized_title(title: &str) -> String {
+    let mut title = title.to_owned();
+    loop {
+        let trimmed = title.trim_end();
+        let stripped = match trimmed.chars().last() {
+            Some(close @ (')' | ']')) => {
+                let open = if close == ')' { '(' } else { '[' };
+                trimmed
+                    .rfind(open)
+                    .filter(|i| is_edition(&trimmed[*i..]))
+                    .map(|i| &trimmed[..i])
+            }
+            _ => trimmed
+                .rsplit_once(" - ")
+                .filter(|(_, suffix)| is_edition(suffix))
+                .map(|(title, _)| title),
+        };
+        match stripped {
+            Some(stripped) => title = stripped.to_owned(),
+            None => break,
+        }
+    }
+    title
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Whether `song1` and `song2`, with the recording ids `id1` and `id2`, are
+/// releases of the same recording.
+pub fn same_recording(song1: &Song, id1: Option<&str>, song2: &Song, id2: Option<&str>) -> bool {
+    if let (Some(id1), Some(id2)) = (id1, id2) {
+        return id1 == id2;
+    }
+    let artist = |s: &Song| s.artist.as_ref().map(|a| a.trim().to_lowercase());
+    // Songs without a duration can't be compared by duration.
+    let close_durations = song1.duration.is_zero()
+        || song2.duration.is_zero()
+        || song1.duration.abs_diff(song2.duration) <= DURATION_TOLERANCE;
+    match (&song1.title, &song2.title) {
+        (Some(t1), Some(t2)) => {
+            artist(song1) == artist(song2)
+                && close_durations
+                && normalized_title(t1) == normalized_title(t2)
+        }
+        _ => false,
+    }
+}
+
+impl MPDLibrary {
+    /// The MusicBrainz recording id MPD has for `song`, if any. Always
+    /// `None` offline.
+    pub(crate) fn recording_id(&self, song: &LibrarySong<()>) -> Option<String> {
+        let file = self.bliss_song_to_mpd(song).ok()?.file;
+        let mut query = Query::new();
+        query.and(Term::File, file.to_owned());
+        let songs = self.mpd_conn().ok()?.find(&query, (0, 1)).ok()?;
+        songs
+            .into_iter()
+            .find(|s| s.file == file)?
+            .tags
+            .into_iter()
+            .find(|(tag, _)| tag.eq_ignore_ascii_case(RECORDING_ID_TAG))
+            .map(|(_, id)| id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use mpd::song::Song as MPDSong;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_normalized_title() {
+        assert_eq!(normalized_title("Song Title"), "song title");
+        assert_eq!(
+            normalized_title("Song Title (Remastered 2011) - Mono"),
+            "song title"
+        );
+        assert_eq!(
+            normalized_title("Song Title - 2011 Remaster [Deluxe Edition]"),
+            "song title"
+        );
+        assert_eq!(normalized_title("Song, Title!"), "song title");
+        assert_eq!(
+            normalized_title("Song Title (Live at Wembley)"),
+            "song title live at wembley"
+        );
+        assert_eq!(
+            normalized_title("Song Title - Extended Mix"),
+            "song title extended mix"
+        );
+    }
+
+    #[test]
+    fn test_same_recording() {
+        let song = |title: &str, seconds| Song {
+            title: Some(title.to_string()),
+            artist: Some(String::from("Art Ist")),
+            duration: Duration::from_secs(seconds),
+            ..Default::default()
+        };
+        let original = song("Song Title", 200);
+        assert!(same_recording(
+            &original,
+            None,
+            &song("Song Title (2011 Remaster)", 203),
+            None
+        ));
+        assert!(!same_recording(
+            &original,
+            None,
+            &song("Song Title (2011 Remaster)", 260),
+            None
+        ));
+        assert!(!same_recording(
+            &original,
+            None,
+            &song("Song Title (Live)", 200),
+            None
+        ));
+        // Recording ids win over titles, both ways.
+        assert!(same_recording(
+            &original,
+            Some("id"),
+            &song("Another Title", 300),
+            Some("id")
+        ));
+        assert!(!same_recording(
+            &original,
+            Some("id"),
+            &song("Song Title", 200),
+            Some("other id")
+        ));
+    }
+
+    #[test]
+    fn test_recording_id() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/tagged.flac", None, 0.),
+                (2, "path/untagged.flac", None, 0.),
+            ],
+        );
+        library.mpd_conn().unwrap().mpd_queue = vec![
+            MPDSong {
+                file: String::from("tagged.flac"),
+                tags: vec![(String::from("MUSICBRAINZ_TRACKID"), String::from("id"))],
+                ..Default::default()
+            },
+            MPDSong {
+                file: String::from("untagged.flac"),
+                ..Default::default()
+            },
+        ];
+        let song = |path| library.library.song_from_path(path).unwrap();
+
+        assert_eq!(
+            library.recording_id(&song("path/tagged.flac")),
+            Some(String::from("id"))
+        );
+        assert_eq!(library.recording_id(&song("path/untagged.flac")), None);
+    }
+}
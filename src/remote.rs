@@ -0,0 +1,419 @@
+//! Making interactive playlists from another device, see
+//! `interactive-playlist --listen`.
+//!
+//! Instead of prompting on the terminal, the session is served over HTTP:
+//! `GET /` is a small page showing the choices, with a button for each, meant
+//! for a phone, and `GET /session` is the same as JSON for other clients.
+//! `POST /choose/N` picks the Nth choice, and `POST /quit` ends the session.
+//! blissify keeps managing MPD's queue as it does on the terminal.
+use crate::format_duration;
+use anyhow::{bail, Context, Result};
+use bliss_audio::library::LibrarySong;
+use log::warn;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// How long a client can take to send its request, or to read the answer,
+/// so that a client connecting and sending nothing, like a browser opening
+/// connections in advance, doesn't block the server.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(if cfg!(test) { 1 } else { 10 });
+/// The longest request line or header line accepted.
+const MAX_LINE_LENGTH: usize = 8 << 10;
+/// The largest number of headers accepted.
+const MAX_HEADERS: usize = 100;
+/// The largest body accepted, which is read but not needed.
+const MAX_BODY_SIZE: u64 = 64 << 10;
+
+/// A song, as shown to clients.
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct RemoteSong {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub path: String,
+}
+
+impl RemoteSong {
+    fn new(song: &LibrarySong<()>) -> Self {
+        Self {
+            artist: song.bliss_song.artist.to_owned(),
+            title: song.bliss_song.title.to_owned(),
+            path: song.bliss_song.path.to_string_lossy().to_string(),
+        }
+    }
+
+    fn name(&self) -> String {
+        format!(
+            "{} - {}",
+            self.artist.as_deref().unwrap_or("<No artist>"),
+            self.title.as_deref().unwrap_or("<No title>"),
+        )
+    }
+}
+
+/// Where an interactive session is at, as served on `GET /session`.
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct SessionState {
+    /// The song the choices are close to.
+    pub current: RemoteSong,
+    pub playlist: Vec<RemoteSong>,
+    /// The songs to choose from, the first one being choice 1.
+    pub choices: Vec<RemoteSong>,
+    /// The duration of the playlist, like "1:02:03".
+    pub duration: String,
+    /// The duration the playlist should reach, if any.
+    pub target_duration: Option<String>,
+}
+
+impl SessionState {
+    pub fn new(
+        current: &LibrarySong<()>,
+        playlist: &[LibrarySong<()>],
+        choices: &[LibrarySong<()>],
+        target_duration: Option<Duration>,
+    ) -> Self {
+        Self {
+            current: RemoteSong::new(current),
+            playlist: playlist.iter().map(RemoteSong::new).collect(),
+            choices: choices.iter().map(RemoteSong::new).collect(),
+            duration: format_duration(playlist.iter().map(|s| s.bliss_song.duration).sum()),
+            target_duration: target_duration.map(format_duration),
+        }
+    }
+
+    /// The page shown on `GET /`.
+    fn render_html(&self) -> String {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+            <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+            <title>blissify</title></head><body>\n",
+        );
+        html.push_str(&format!(
+            "<p>Playing from: {}</p>\n",
+            escape(&self.current.name())
+        ));
+        for (i, song) in self.choices.iter().enumerate() {
+            html.push_str(&format!(
+                "<form method=\"post\" action=\"/choose/{}\"><button>{}</button></form>\n",
+                i + 1,
+                escape(&song.name())
+            ));
+        }
+        html.push_str("<form method=\"post\" action=\"/quit\"><button>Finish</button></form>\n");
+        let duration = match &self.target_duration {
+            Some(target) => format!("{} / {}", self.duration, target),
+            None => self.duration.to_owned(),
+        };
+        html.push_str(&format!("<p>Playlist ({}):</p>\n<ol>\n", duration));
+        for song in &self.playlist {
+            html.push_str(&format!("<li>{}</li>\n", escape(&song.name())));
+        }
+        html.push_str("</ol>\n</body></html>\n");
+        html
+    }
+}
+
+/// Escape `text` to be put in HTML.
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// What a request asks for.
+#[derive(Debug, PartialEq)]
+enum Request {
+    Page,
+    Session,
+    Choose(usize),
+    Quit,
+    NotFound,
+}
+
+/// The parts of an HTTP request blissify looks at.
+#[derive(Debug, PartialEq)]
+pub(crate) struct HttpRequest {
+    pub method: String,
+    /// The requested path, with its query string.
+    pub target: String,
+    /// The host and port the client connected to, as it tells.
+    pub host: Option<String>,
+    /// The page the request was made from, if the client tells.
+    pub referer: Option<String>,
+    /// The site the request was made from, if the client tells.
+    pub origin: Option<String>,
+}
+
+/// A request refused before being read entirely, answered with `status`.
+#[derive(Debug)]
+pub(crate) struct RejectedRequest {
+    pub status: &'static str,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for RejectedRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({})", self.reason, self.status)
+    }
+}
+
+impl std::error::Error for RejectedRequest {}
+
+/// Read a line of at most [MAX_LINE_LENGTH] bytes into `line`.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<usize> {
+    let read = reader.take(MAX_LINE_LENGTH as u64).read_line(line)?;
+    if read == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        bail!(RejectedRequest {
+            status: "400 Bad Request",
+            reason: "a line of the request is too long",
+        });
+    }
+    Ok(read)
+}
+
+/// Read a request from `stream`, including its body, which is not needed.
+///
+/// Requests that are too large fail with a [RejectedRequest], which
+/// [reject] answers.
+pub(crate) fn read_http_request(stream: &TcpStream) -> Result<HttpRequest> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    read_line(&mut reader, &mut request_line)?;
+    let mut content_length = 0;
+    let mut host = None;
+    let mut referer = None;
+    let mut origin = None;
+    let mut headers = 0;
+    let mut line = String::new();
+    while read_line(&mut reader, &mut line)? > 2 {
+        headers += 1;
+        if headers > MAX_HEADERS {
+            bail!(RejectedRequest {
+                status: "400 Bad Request",
+                reason: "the request has too many headers",
+            });
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| RejectedRequest {
+                    status: "400 Bad Request",
+                    reason: "the content length is invalid",
+                })?;
+            } else if name.eq_ignore_ascii_case("host") {
+                host = Some(value.trim().to_owned());
+            } else if name.eq_ignore_ascii_case("referer") {
+                referer = Some(value.trim().to_owned());
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.trim().to_owned());
+            }
+        }
+        line.clear();
+    }
+    if content_length > MAX_BODY_SIZE {
+        bail!(RejectedRequest {
+            status: "413 Payload Too Large",
+            reason: "the request body is too large",
+        });
+    }
+    reader.take(content_length).read_to_end(&mut vec![])?;
+
+    let mut words = request_line.split_whitespace();
+    Ok(HttpRequest {
+        method: words.next().unwrap_or("").to_owned(),
+        target: words.next().unwrap_or("").to_owned(),
+        host,
+        referer,
+        origin,
+    })
+}
+
+/// Answer `error`, from [read_http_request], with its status if the request
+/// was rejected. Other errors, like timeouts, leave nobody to answer.
+pub(crate) fn reject(stream: &TcpStream, error: &anyhow::Error) {
+    if let Some(rejected) = error.downcast_ref::<RejectedRequest>() {
+        if let Err(e) = respond(stream, rejected.status, "text/plain", rejected.reason) {
+            warn!("Could not answer a request: {}", e);
+        }
+    }
+}
+
+/// Read a request of an interactive session from `stream`.
+fn read_request(stream: &TcpStream) -> Result<Request> {
+    let request = read_http_request(stream)?;
+    Ok(match (request.method.as_str(), request.target.as_str()) {
+        ("GET", "/") => Request::Page,
+        ("GET", "/session") => Request::Session,
+        ("POST", "/quit") => Request::Quit,
+        ("POST", path) => match path.strip_prefix("/choose/").map(str::parse) {
+            Some(Ok(choice)) => Request::Choose(choice),
+            _ => Request::NotFound,
+        },
+        _ => Request::NotFound,
+    })
+}
+
+pub(crate) fn respond(
+    mut stream: &TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// Answer the requests of `listener` with `state`, until a client picks one
+/// of the choices, returning its number starting from 1, or ends the
+/// session, returning `None`.
+pub(crate) fn wait_for_choice(
+    listener: &TcpListener,
+    state: &SessionState,
+) -> Result<Option<usize>> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let request = match read_request(&stream) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Could not read a request: {}", e);
+                reject(&stream, &e);
+                continue;
+            }
+        };
+        let answer = match request {
+            Request::Page => respond(
+                &stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                &state.render_html(),
+            ),
+            Request::Session => respond(
+                &stream,
+                "200 OK",
+                "application/json",
+                &serde_json::to_string(state)?,
+            ),
+            Request::Choose(choice) if (1..=state.choices.len()).contains(&choice) => {
+                // Back to the page, which shows the next choices by then.
+                write!(
+                    &stream,
+                    "HTTP/1.1 303 See Other\r\nLocation: /\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .context("while answering a choice")?;
+                return Ok(Some(choice));
+            }
+            Request::Quit => {
+                respond(
+                    &stream,
+                    "200 OK",
+                    "text/html; charset=utf-8",
+                    "<!DOCTYPE html>\n<html><body><p>The session is finished.</p></body></html>\n",
+                )?;
+                return Ok(None);
+            }
+            Request::Choose(_) | Request::NotFound => {
+                respond(&stream, "404 Not Found", "text/plain", "")
+            }
+        };
+        if let Err(e) = answer {
+            warn!("Could not answer a request: {}", e);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::thread;
+
+    fn request(address: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{}", request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_wait_for_choice() {
+        let song = |path: &str, title: &str| {
+            let mut song = LibrarySong {
+                bliss_song: Default::default(),
+                extra_info: (),
+            };
+            song.bliss_song.path = path.into();
+            song.bliss_song.title = Some(title.to_owned());
+            song.bliss_song.duration = Duration::from_secs(61);
+            song
+        };
+        let current = song("path/current.flac", "Current");
+        let state = SessionState::new(
+            &current,
+            std::slice::from_ref(&current),
+            &[
+                song("path/first.flac", "First"),
+                song("path/second.flac", "<Second>"),
+            ],
+            None,
+        );
+        assert_eq!(state.duration, "1:01");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let client = thread::spawn(move || {
+            let page = request(&address, "GET / HTTP/1.1\r\n\r\n");
+            let session = request(&address, "GET /session HTTP/1.1\r\n\r\n");
+            let out_of_range = request(
+                &address,
+                "POST /choose/3 HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+            );
+            let choice = request(
+                &address,
+                "POST /choose/2 HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody",
+            );
+            (page, session, out_of_range, choice)
+        });
+        assert_eq!(wait_for_choice(&listener, &state).unwrap(), Some(2));
+        let (page, session, out_of_range, choice) = client.join().unwrap();
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(page.contains(
+            "<form method=\"post\" action=\"/choose/2\"><button>&lt;No artist&gt; - &lt;Second&gt;</button></form>"
+        ));
+        assert!(session.ends_with(&serde_json::to_string(&state).unwrap()));
+        assert!(out_of_range.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(choice.starts_with("HTTP/1.1 303 See Other\r\n"));
+
+        let address = listener.local_addr().unwrap().to_string();
+        let client = thread::spawn(move || {
+            // Connecting without sending anything doesn't block the others.
+            let _idle = TcpStream::connect(&address).unwrap();
+            let too_large = request(
+                &address,
+                "POST /choose/1 HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n",
+            );
+            let too_many_headers = request(
+                &address,
+                &format!("GET / HTTP/1.1\r\n{}", "X: y\r\n".repeat(MAX_HEADERS + 1)),
+            );
+            let quit = request(&address, "POST /quit HTTP/1.1\r\n\r\n");
+            (too_large, too_many_headers, quit)
+        });
+        assert_eq!(wait_for_choice(&listener, &state).unwrap(), None);
+        let (too_large, too_many_headers, quit) = client.join().unwrap();
+        assert!(too_large.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(too_many_headers.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(quit.contains("The session is finished."));
+    }
+}
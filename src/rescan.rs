@@ -0,0 +1,172 @@
+//! Rescanning only part of the library, see `rescan --only-errors`,
+//! `--only-outdated` and `--path`.
+//!
+//! Rescanning a directory is a full rescan of the MPD songs in it, while
+//! rescanning the songs that failed or that have outdated features analyzes
+//! the songs already in the database again, replacing their rows.
+use crate::MPDLibrary;
+use anyhow::{bail, Result};
+use bliss_audio::FEATURES_VERSION;
+use log::info;
+use std::path::{Path, PathBuf};
+
+/// Which songs `rescan` analyzes again, if not all of them.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RescanSelection {
+    /// Songs that failed to be analyzed.
+    pub only_errors: bool,
+    /// Songs analyzed with another version of bliss' features.
+    pub only_outdated: bool,
+    /// Only the songs in this directory, relative to MPD's base path.
+    pub directory: Option<String>,
+}
+
+impl RescanSelection {
+    /// Whether all the songs of the library are rescanned.
+    pub fn is_everything(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl MPDLibrary {
+    /// The paths of the songs in the database selected by `selection`, among
+    /// the songs that failed or have outdated features. `directory` is the
+    /// local path of the selected directory, if any.
+    fn songs_to_rescan(
+        &self,
+        selection: &RescanSelection,
+        directory: Option<&Path>,
+    ) -> Result<Vec<PathBuf>> {
+        let directory = directory.map(|d| {
+            let mut directory = d.to_string_lossy().to_string();
+            if !directory.ends_with('/') {
+                directory.push('/');
+            }
+            directory
+        });
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select path from song
+            where ((?1 and analyzed = false) or (?2 and analyzed = true and version != ?3))
+            and (?4 is null or substr(path, 1, length(?4)) = ?4)
+            order by path",
+        )?;
+        let paths = query
+            .query_map(
+                (
+                    selection.only_errors,
+                    selection.only_outdated,
+                    FEATURES_VERSION,
+                    directory,
+                ),
+                |row| row.get::<_, String>(0),
+            )?
+            .map(|path| path.map(PathBuf::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(paths)
+    }
+
+    /// Analyze the songs selected by `selection` again, replacing their
+    /// rows. Returns the number of songs that failed to be analyzed, when
+    /// only songs with errors or outdated features are rescanned.
+    pub(crate) fn rescan(&mut self, selection: &RescanSelection) -> Result<usize> {
+        if selection.is_everything() {
+            self.full_rescan()?;
+            return Ok(0);
+        }
+        let directory = selection
+            .directory
+            .as_deref()
+            .map(|d| self.mpd_directory(d));
+        if !selection.only_errors && !selection.only_outdated {
+            // Only a directory, which is rescanned like the whole library.
+            let directory = directory.unwrap();
+            let paths = self.get_songs_paths_in(Some(&directory))?;
+            if paths.is_empty() {
+                bail!(
+                    "MPD doesn't know about any song in '{}'. Make sure it is relative to MPD's base path, and that MPD's database is up to date.",
+                    directory
+                );
+            }
+            let directory_path = self.library.config.resolve_mpd_path(Path::new(&directory));
+            self.delete_stale_songs(&directory_path, &[])?;
+            self.analyze_rescanned_paths(paths)?;
+            return Ok(0);
+        }
+        let directory_path = directory
+            .as_ref()
+            .map(|d| self.library.config.resolve_mpd_path(Path::new(d)));
+        let paths = self.songs_to_rescan(selection, directory_path.as_deref())?;
+        info!("Analyzing {} song(s) again.", paths.len());
+        self.reanalyze(&paths)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::BlissError;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_songs_to_rescan() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/Albums/up-to-date.flac", None, 0.),
+                (2, "path/Albums/outdated.flac", None, 0.1),
+                (3, "path/Other/outdated.flac", None, 0.2),
+            ],
+        );
+        library
+            .library
+            .store_failed_song(
+                "path/Albums/failed.flac",
+                BlissError::DecodingError(String::from("nope")),
+            )
+            .unwrap();
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute(
+                "update song set version = ?1 - (path like '%outdated.flac') where analyzed = true",
+                [FEATURES_VERSION],
+            )
+            .unwrap();
+
+        let songs = |only_errors, only_outdated, directory: Option<&str>| {
+            let selection = RescanSelection {
+                only_errors,
+                only_outdated,
+                directory: None,
+            };
+            library
+                .songs_to_rescan(&selection, directory.map(Path::new))
+                .unwrap()
+        };
+        assert_eq!(
+            songs(true, false, None),
+            vec![PathBuf::from("path/Albums/failed.flac")],
+        );
+        assert_eq!(
+            songs(false, true, None),
+            vec![
+                PathBuf::from("path/Albums/outdated.flac"),
+                PathBuf::from("path/Other/outdated.flac"),
+            ],
+        );
+        assert_eq!(
+            songs(true, true, Some("path/Albums")),
+            vec![
+                PathBuf::from("path/Albums/failed.flac"),
+                PathBuf::from("path/Albums/outdated.flac"),
+            ],
+        );
+        assert_eq!(songs(true, true, Some("path/Alb")), Vec::<PathBuf>::new());
+        assert!(RescanSelection::default().is_everything());
+    }
+}
@@ -0,0 +1,312 @@
+//! Queueing the playlists of profiles from the daemon, on demand or on a
+//! schedule.
+//!
+//! `blissify ctl run-profile NAME` makes the daemon queue a playlist from the
+//! current song with the playlist settings of the [Profile] NAME, as
+//! `blissify --user NAME playlist` would. Profiles with a `schedule`, like
+//! `"Fri 19:00"`, `"Mon,Wed 07:30"` or `"19:00"` for every day, are run by the
+//! daemon at that time, local time.
+use crate::mpd_log::LocalTime;
+use crate::timing::Timings;
+use crate::{
+    playlist_options, with_playlist_ranking, MPDLibrary, OutputFormat, PlaylistOptions, Profile,
+};
+use anyhow::{bail, Result};
+use bliss_audio::BlissError;
+use clap::ArgMatches;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The days of the week, as written in schedules, starting from Sunday like
+/// [LocalTime::weekday].
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+/// How late a schedule can still run, e.g. if the daemon was busy when it
+/// was due.
+const SCHEDULE_GRACE: i64 = 5 * 60;
+
+/// When to run a profile, like "Fri 19:00".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// The days of the week, see [WEEKDAYS]; every day if empty.
+    days: Vec<i32>,
+    hour: i32,
+    minute: i32,
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = BlissError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            BlissError::ProviderError(format!(
+                "Invalid schedule '{}', expected something like 'Fri 19:00', 'Mon,Wed 07:30' or '19:00'.",
+                s
+            ))
+        };
+        let (days, time) = match s.trim().rsplit_once(' ') {
+            Some((days, time)) => (Some(days), time),
+            None => (None, s.trim()),
+        };
+        let days = match days {
+            Some(days) => days
+                .split(',')
+                .map(|d| {
+                    WEEKDAYS
+                        .iter()
+                        .position(|w| w.eq_ignore_ascii_case(d.trim()))
+                        .map(|i| i as i32)
+                        .ok_or_else(invalid)
+                })
+                .collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+        let (hour, minute) = (
+            hour.parse().map_err(|_| invalid())?,
+            minute.parse().map_err(|_| invalid())?,
+        );
+        if !(0..24).contains(&hour) || !(0..60).contains(&minute) {
+            return Err(invalid());
+        }
+        Ok(Schedule { days, hour, minute })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.days.is_empty() {
+            let days = self
+                .days
+                .iter()
+                .map(|d| WEEKDAYS[*d as usize])
+                .collect::<Vec<_>>();
+            write!(f, "{} ", days.join(","))?;
+        }
+        write!(f, "{:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Schedule {
+    /// Whether the schedule is due at the minute starting at `timestamp`.
+    fn is_due_at(&self, timestamp: i64) -> Result<bool> {
+        let time = LocalTime::from_timestamp(timestamp)?;
+        Ok(time.hour == self.hour
+            && time.minute == self.minute
+            && (self.days.is_empty() || self.days.contains(&time.weekday()?)))
+    }
+}
+
+/// The names of the profiles whose schedule was due after `since` and until
+/// `now`, in seconds since the epoch, going back at most [SCHEDULE_GRACE].
+pub fn due_profiles(
+    profiles: &BTreeMap<String, Profile>,
+    since: i64,
+    now: i64,
+) -> Result<Vec<String>> {
+    let since = since.max(now - SCHEDULE_GRACE);
+    let mut due = vec![];
+    for (name, profile) in profiles {
+        let schedule = match &profile.schedule {
+            Some(schedule) => schedule,
+            None => continue,
+        };
+        let mut minute = since - since.rem_euclid(60) + 60;
+        while minute <= now {
+            if schedule.is_due_at(minute)? {
+                due.push(name.to_owned());
+                break;
+            }
+            minute += 60;
+        }
+    }
+    Ok(due)
+}
+
+impl MPDLibrary {
+    /// Queue a playlist from the current song with the playlist settings of
+    /// the profile `name`, as `blissify --user NAME playlist` would.
+    pub(crate) fn queue_profile(&mut self, name: &str) -> Result<()> {
+        if !self.library.config.profiles.contains_key(name) {
+            bail!("Unknown profile '{}'.", name);
+        }
+        let user = self.user.replace(name.to_owned());
+        let result = self.queue_playlist_defaults();
+        self.user = user;
+        result
+    }
+
+    /// Queue a playlist from the current song with the settings of
+    /// [playlist_defaults](Self::playlist_defaults).
+    fn queue_playlist_defaults(&self) -> Result<()> {
+        let defaults = self.playlist_defaults().clone();
+        let number_songs = match defaults.number_songs {
+            Some(n) => n,
+            None => bail!("The profile has no \"number_songs\" in its \"playlist\" section."),
+        };
+        let matches = ArgMatches::default();
+        let options = PlaylistOptions {
+            // Only `playlist` itself records the command-line it was run with.
+            history: None,
+            ..playlist_options(self, &matches, &defaults, OutputFormat::Plain)?
+        };
+        with_playlist_ranking(self, &matches, &defaults, &Timings::start(), |ranking| {
+            self.queue_from_song(None, number_songs, ranking.distance, ranking.sort, &options)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filters::PlaylistFilters;
+    use crate::mpd_log::LocalTime;
+    use crate::test::{insert_songs, setup_library};
+    use crate::PlaylistDefaults;
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_schedule() {
+        let schedule = "fri 19:00".parse::<Schedule>().unwrap();
+        assert_eq!(
+            schedule,
+            Schedule {
+                days: vec![5],
+                hour: 19,
+                minute: 0,
+            }
+        );
+        assert_eq!(schedule.to_string(), "Fri 19:00");
+        assert_eq!(
+            "Mon,Wed 7:30".parse::<Schedule>().unwrap().to_string(),
+            "Mon,Wed 07:30"
+        );
+        assert!("19:00".parse::<Schedule>().unwrap().days.is_empty());
+        assert!("Friday 19:00".parse::<Schedule>().is_err());
+        assert!("Fri 25:00".parse::<Schedule>().is_err());
+        assert!("Fri".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_due_profiles() {
+        let profile = |schedule: Option<&str>| Profile {
+            schedule: schedule.map(|s| s.parse().unwrap()),
+            ..Default::default()
+        };
+        let profiles = BTreeMap::from([
+            (String::from("dinner"), profile(Some("Fri 19:00"))),
+            (String::from("morning"), profile(Some("07:30"))),
+            (String::from("manual"), profile(None)),
+        ]);
+        // 2024-10-18 was a Friday.
+        let at = |day, hour, minute| {
+            LocalTime {
+                year: 2024,
+                month: 10,
+                day,
+                hour,
+                minute,
+                second: 0,
+            }
+            .timestamp()
+            .unwrap()
+        };
+
+        assert_eq!(
+            due_profiles(&profiles, at(18, 18, 59), at(18, 19, 0)).unwrap(),
+            vec!["dinner"]
+        );
+        // Already run.
+        assert!(due_profiles(&profiles, at(18, 19, 0), at(18, 19, 1))
+            .unwrap()
+            .is_empty());
+        // Too late.
+        assert!(due_profiles(&profiles, at(18, 18, 0), at(18, 19, 30))
+            .unwrap()
+            .is_empty());
+        // Not on Thursdays.
+        assert!(due_profiles(&profiles, at(17, 18, 59), at(17, 19, 0))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            due_profiles(&profiles, at(17, 7, 28), at(17, 7, 31)).unwrap(),
+            vec!["morning"]
+        );
+    }
+
+    #[test]
+    fn test_queue_profile() {
+        let (mut library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/second.flac", None, 0.1),
+                (3, "path/third.flac", None, 0.2),
+                (4, "path/fourth.flac", None, 0.3),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute(
+                "update song set duration = 600 where path = 'path/second.flac'",
+                [],
+            )
+            .unwrap();
+        library.library.config.profiles.insert(
+            String::from("dinner"),
+            Profile {
+                playlist: Some(PlaylistDefaults {
+                    number_songs: Some(2),
+                    filters: PlaylistFilters {
+                        max_duration: Some(300),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+
+        assert!(library.queue_profile("breakfast").is_err());
+        library.queue_profile("dinner").unwrap();
+        assert_eq!(library.user, None);
+        assert_eq!(
+            library
+                .mpd_conn()
+                .unwrap()
+                .mpd_queue
+                .iter()
+                .map(|s| s.file.to_owned())
+                .collect::<Vec<_>>(),
+            vec!["first.flac", "third.flac", "fourth.flac"],
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Versioning of the tables and columns blissify adds to bliss' database.
+//!
+//! The version of the schema is stored in a `schema_version` table, and the
+//! [MIGRATIONS] the database is missing run automatically when it is opened.
+//! The first migrations predate that table, so every migration must be safe
+//! to run on a database that already has its changes.
+use crate::MPDLibrary;
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A change to the database.
+type Migration = fn(&MPDLibrary) -> Result<()>;
+
+/// The migrations bringing the database to the latest schema, in order, with
+/// what they do. The database's schema version is the number of migrations
+/// that ran on it.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    (
+        "add the feedback tables",
+        MPDLibrary::create_feedback_tables,
+    ),
+    ("add the genre table", MPDLibrary::create_genre_table),
+    (
+        "add the MPD update table",
+        MPDLibrary::create_mpd_update_table,
+    ),
+    (
+        "add the error_kind column",
+        MPDLibrary::create_error_kind_column,
+    ),
+    ("add the decoder column", MPDLibrary::create_decoder_column),
+    (
+        "add the excluded song table",
+        MPDLibrary::create_excluded_song_table,
+    ),
+    ("add the play table", MPDLibrary::create_play_table),
+    (
+        "add the content_hash column",
+        MPDLibrary::create_content_hash_column,
+    ),
+    (
+        "add the pinned song table",
+        MPDLibrary::create_pinned_song_table,
+    ),
+    (
+        "add the analyzed_at column",
+        MPDLibrary::create_analyzed_at_column,
+    ),
+    (
+        "add the snapshot tables",
+        MPDLibrary::create_snapshot_tables,
+    ),
+    (
+        "add the excluded artist table",
+        MPDLibrary::create_excluded_artist_table,
+    ),
+    (
+        "add the play count table",
+        MPDLibrary::create_play_count_table,
+    ),
+    (
+        "add the generated playlist tables",
+        MPDLibrary::create_generated_playlist_tables,
+    ),
+    (
+        "add the skipped song table",
+        MPDLibrary::create_skipped_song_table,
+    ),
+];
+
+/// Whether to back the database up before migrating it, see
+/// `--backup-before-migrate`.
+static BACKUP_BEFORE_MIGRATE: AtomicBool = AtomicBool::new(false);
+
+/// Back the database up before migrating it from now on.
+pub fn set_backup_before_migrate(backup: bool) {
+    BACKUP_BEFORE_MIGRATE.store(backup, Ordering::SeqCst);
+}
+
+impl MPDLibrary {
+    /// The version of the database's schema, 0 for databases that were never
+    /// migrated.
+    pub(crate) fn schema_version(&self) -> Result<usize> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        sqlite_conn.execute(
+            "create table if not exists schema_version (
+                id integer primary key check (id = 0),
+                version integer not null
+            )",
+            [],
+        )?;
+        let mut query = sqlite_conn.prepare("select version from schema_version where id = 0")?;
+        let version = query
+            .query_map([], |row| row.get::<_, usize>(0))?
+            .next()
+            .transpose()?;
+        Ok(version.unwrap_or(0))
+    }
+
+    fn set_schema_version(&self, version: usize) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute(
+            "insert or replace into schema_version (id, version) values (0, ?1)",
+            [version],
+        )?;
+        Ok(())
+    }
+
+    /// Run the migrations the database is missing, backing it up first if
+    /// `--backup-before-migrate` was given.
+    pub(crate) fn migrate(&self) -> Result<()> {
+        let version = self.schema_version()?;
+        if version > MIGRATIONS.len() {
+            bail!(
+                "The database was used by a newer version of blissify (schema version {}, this one only knows about {}), please upgrade blissify.",
+                version,
+                MIGRATIONS.len(),
+            );
+        }
+        if version == MIGRATIONS.len() {
+            return Ok(());
+        }
+        if BACKUP_BEFORE_MIGRATE.load(Ordering::SeqCst) {
+            let backup = self.backup_database(version)?;
+            info!("Backed the database up to {}.", backup.display());
+        }
+        for (i, (name, migration)) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Migrating the database: {}.", name);
+            migration(self).with_context(|| format!("while migrating the database ({})", name))?;
+            self.set_schema_version(i + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the database next to itself, the copy's name saying which schema
+    /// version it has, and returns the copy's path.
+    fn backup_database(&self, version: usize) -> Result<PathBuf> {
+        let database_path = &self.library.config.base_config.database_path;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut backup = database_path.as_os_str().to_owned();
+        backup.push(format!(".v{}-{}.bak", version, now));
+        let backup = PathBuf::from(backup);
+        // Unlike copying the file, this works while the database is open.
+        self.library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute("vacuum into ?1", [backup.to_string_lossy()])
+            .with_context(|| format!("while backing the database up to {}", backup.display()))?;
+        Ok(backup)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::setup_library;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_migrate() {
+        let (library, tempdir) = setup_library();
+        assert_eq!(library.schema_version().unwrap(), MIGRATIONS.len());
+
+        // Databases from before the schema version was stored already have
+        // some of the migrations.
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute("delete from schema_version", [])
+            .unwrap();
+        assert_eq!(library.schema_version().unwrap(), 0);
+        library.migrate().unwrap();
+        assert_eq!(library.schema_version().unwrap(), MIGRATIONS.len());
+
+        library.set_schema_version(2).unwrap();
+        let backup = library.backup_database(2).unwrap();
+        assert!(backup.starts_with(tempdir.path()));
+        assert!(backup.to_string_lossy().contains("bliss.db.v2-"));
+        assert!(backup.exists());
+
+        library.set_schema_version(MIGRATIONS.len() + 1).unwrap();
+        assert!(library
+            .migrate()
+            .unwrap_err()
+            .to_string()
+            .contains("newer version of blissify"));
+    }
+}
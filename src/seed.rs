@@ -0,0 +1,180 @@
+//! Seeded playlists, see `--seed-song`.
+//!
+//! bliss' [song_to_song](bliss_audio::playlist::song_to_song) converts the
+//! analysis of every remaining candidate to an array again each time it picks
+//! the next song, which makes long seeded playlists slow on big libraries.
+//! This does the same, converting each analysis once, and splitting the
+//! candidates between threads when the distance metric can be shared by them.
+use bliss_audio::playlist::DistanceMetricBuilder;
+use bliss_audio::Song;
+use ndarray::Array1;
+use std::num::NonZeroUsize;
+use std::thread;
+
+/// Below this many candidates, computing the distances in a single thread is
+/// faster than spawning threads.
+const MIN_CANDIDATES_PER_THREAD: usize = 1024;
+
+/// A distance metric that threads can share, for the distance metrics that
+/// are plain functions.
+pub type SharedDistance<'a> = &'a (dyn Fn(&Array1<f32>, &Array1<f32>) -> f32 + Sync);
+
+/// Make a playlist starting from `initial_songs`, each song being the closest
+/// to the previous one, like bliss' `song_to_song`.
+///
+/// If `shared_distance` is set, it is used instead of `metric_builder`, and
+/// the distances are computed with up to `number_cores` threads.
+pub fn song_to_song<'a, T: AsRef<Song> + Clone + Send + Sync + 'a>(
+    initial_songs: &[T],
+    candidate_songs: &[T],
+    metric_builder: &'a dyn DistanceMetricBuilder,
+    shared_distance: Option<SharedDistance<'a>>,
+    number_cores: NonZeroUsize,
+) -> impl Iterator<Item = T> + 'a {
+    let mut vectors = initial_songs
+        .iter()
+        .map(|s| s.as_ref().analysis.as_arr1())
+        .collect::<Vec<_>>();
+    let mut candidates = candidate_songs
+        .iter()
+        .map(|s| (s.to_owned(), s.as_ref().analysis.as_arr1()))
+        .collect::<Vec<_>>();
+    std::iter::from_fn(move || {
+        if candidates.is_empty() {
+            return None;
+        }
+        let best = match shared_distance {
+            Some(distance) => closest_shared(&vectors, &candidates, distance, number_cores),
+            None => {
+                let metric = metric_builder.build(&vectors);
+                closest(&candidates, |v| metric.distance(v))
+            }
+        };
+        let (song, vector) = candidates.remove(best);
+        vectors = vec![vector];
+        Some(song)
+    })
+}
+
+/// The index of the first of `candidates` with the smallest `distance`.
+fn closest<T>(candidates: &[(T, Array1<f32>)], distance: impl Fn(&Array1<f32>) -> f32) -> usize {
+    let mut best = (0, f32::INFINITY);
+    for (i, (_, vector)) in candidates.iter().enumerate() {
+        let d = distance(vector);
+        if d < best.1 {
+            best = (i, d);
+        }
+    }
+    best.0
+}
+
+/// Same as [closest], computing the average `distance` to `vectors` of each
+/// candidate in parallel.
+fn closest_shared<T: Sync>(
+    vectors: &[Array1<f32>],
+    candidates: &[(T, Array1<f32>)],
+    distance: SharedDistance,
+    number_cores: NonZeroUsize,
+) -> usize {
+    let average = |v: &Array1<f32>| {
+        vectors.iter().map(|s| distance(s, v)).sum::<f32>() / vectors.len() as f32
+    };
+    let number_threads = number_cores
+        .get()
+        .min(candidates.len() / MIN_CANDIDATES_PER_THREAD)
+        .max(1);
+    if number_threads == 1 {
+        return closest(candidates, average);
+    }
+    let chunk_size = candidates.len().div_ceil(number_threads);
+    thread::scope(|s| {
+        let handles = candidates
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let average = &average;
+                s.spawn(move || {
+                    let best = closest(chunk, average);
+                    (i * chunk_size + best, average(&chunk[best].1))
+                })
+            })
+            .collect::<Vec<_>>();
+        // Chunks are joined in order, so ties go to the first candidate, as
+        // in a single thread.
+        let mut best = (0, f32::INFINITY);
+        for handle in handles {
+            let (i, d) = handle.join().unwrap();
+            if d < best.1 {
+                best = (i, d);
+            }
+        }
+        best.0
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::library::LibrarySong;
+    use bliss_audio::playlist::euclidean_distance;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_song_to_song() {
+        let (library, _tempdir) = setup_library();
+        // Squared, so that no two candidates are as close to a song.
+        let paths = (0..2100)
+            .map(|i| format!("path/{}.flac", i))
+            .collect::<Vec<_>>();
+        let songs = paths
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let feature = ((i * 7919) % 2100) as f32 / 2100.;
+                (i as u32 + 1, p.as_str(), None, feature * feature)
+            })
+            .collect::<Vec<_>>();
+        // In a single transaction, since there are enough songs to use
+        // several threads.
+        let execute = |sql| {
+            let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+            sqlite_conn.execute_batch(sql).unwrap();
+        };
+        execute("begin");
+        insert_songs(&library, &songs);
+        execute("commit");
+        let songs: Vec<LibrarySong<()>> = library.library.songs_from_library().unwrap();
+        let seed = &songs[..1];
+        let playlist_paths = |playlist: Box<dyn Iterator<Item = LibrarySong<()>>>| {
+            playlist
+                .take(20)
+                .map(|s| s.bliss_song.path)
+                .collect::<Vec<_>>()
+        };
+        let distance = euclidean_distance;
+        let expected = playlist_paths(Box::new(bliss_audio::playlist::song_to_song(
+            seed, &songs, &distance,
+        )));
+        assert_eq!(
+            playlist_paths(Box::new(song_to_song(
+                seed,
+                &songs,
+                &distance,
+                None,
+                NonZeroUsize::new(1).unwrap(),
+            ))),
+            expected,
+        );
+        assert_eq!(
+            playlist_paths(Box::new(song_to_song(
+                seed,
+                &songs,
+                &distance,
+                Some(&distance),
+                NonZeroUsize::new(4).unwrap(),
+            ))),
+            expected,
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Keeping the albums of the songs a playlist is made from out of its start,
+//! see `--avoid-seed-album`.
+//!
+//! Songs of a same album usually sound very much alike, so they take the
+//! first places of every playlist made from one of them. Songs of these
+//! albums are held back until the playlist has enough songs, or left out
+//! entirely.
+use bliss_audio::library::LibrarySong;
+use std::collections::{HashSet, VecDeque};
+
+/// An album, as its name and album artist, or artist if it has no album
+/// artist, or `None` if the song has no album.
+fn album_key(song: &LibrarySong<()>) -> Option<(String, Option<String>)> {
+    let song = &song.bliss_song;
+    let artist = song.album_artist.as_ref().or(song.artist.as_ref());
+    Some((song.album.to_owned()?, artist.cloned()))
+}
+
+/// The songs of an iterator, with the songs of the seeds' albums held back
+/// until `gap` songs came before them, see [avoid_seed_albums].
+pub struct SeedAlbumGap<I> {
+    songs: I,
+    seeds: Vec<LibrarySong<()>>,
+    albums: HashSet<(String, Option<String>)>,
+    gap: usize,
+    /// The number of songs returned so far.
+    returned: usize,
+    held_back: VecDeque<LibrarySong<()>>,
+}
+
+impl<I: Iterator<Item = LibrarySong<()>>> Iterator for SeedAlbumGap<I> {
+    type Item = LibrarySong<()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.returned >= self.gap {
+            if let Some(song) = self.held_back.pop_front() {
+                self.returned += 1;
+                return Some(song);
+            }
+        }
+        for song in self.songs.by_ref() {
+            let held_back = self.returned < self.gap
+                && !self.seeds.contains(&song)
+                && album_key(&song).is_some_and(|a| self.albums.contains(&a));
+            if held_back {
+                self.held_back.push_back(song);
+            } else {
+                self.returned += 1;
+                return Some(song);
+            }
+        }
+        // Songs held back when there aren't enough other songs are left out.
+        None
+    }
+}
+
+/// Hold the songs from the albums of `seeds` back until `gap` songs came
+/// before them in `songs`, or leave them out if `gap` is `usize::MAX`. The
+/// seeds themselves are kept where they are. Doesn't change `songs` if `gap`
+/// is `None`.
+pub fn avoid_seed_albums<I: Iterator<Item = LibrarySong<()>>>(
+    songs: I,
+    seeds: &[LibrarySong<()>],
+    gap: Option<usize>,
+) -> SeedAlbumGap<I> {
+    SeedAlbumGap {
+        songs,
+        seeds: seeds.to_vec(),
+        albums: match gap {
+            Some(_) => seeds.iter().filter_map(album_key).collect(),
+            None => HashSet::new(),
+        },
+        gap: gap.unwrap_or(0),
+        returned: 0,
+        held_back: VecDeque::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::{insert_songs, setup_library};
+    use crate::PlaylistOptions;
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_avoid_seed_album() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first_song.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first_song.flac", Some("Seed album"), 0.),
+                (2, "path/second_song.flac", Some("Seed album"), 0.1),
+                (3, "path/third_song.flac", Some("Other album"), 0.2),
+                (4, "path/fourth_song.flac", Some("Seed album"), 0.3),
+                (5, "path/fifth_song.flac", None, 0.4),
+            ],
+        );
+        let playlist = |gap: Option<usize>| {
+            let options = PlaylistOptions {
+                dedup: None,
+                avoid_seed_album: gap,
+                ..Default::default()
+            };
+            library
+                .queue_from_song(None, 20, &euclidean_distance, closest_to_songs, &options)
+                .unwrap();
+            library
+                .mpd_conn()
+                .unwrap()
+                .mpd_queue
+                .iter()
+                .map(|x| x.file.to_owned())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            playlist(None),
+            vec![
+                "first_song.flac",
+                "second_song.flac",
+                "third_song.flac",
+                "fourth_song.flac",
+                "fifth_song.flac",
+            ],
+        );
+        assert_eq!(
+            playlist(Some(usize::MAX)),
+            vec!["first_song.flac", "third_song.flac", "fifth_song.flac"],
+        );
+        // The seed and third songs come before the seed album's songs.
+        assert_eq!(
+            playlist(Some(2)),
+            vec![
+                "first_song.flac",
+                "third_song.flac",
+                "second_song.flac",
+                "fourth_song.flac",
+                "fifth_song.flac",
+            ],
+        );
+    }
+}
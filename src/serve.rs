@@ -0,0 +1,425 @@
+//! Previewing playlists from a browser, see `blissify serve`.
+//!
+//! `GET /playlist` returns the playlist `playlist --dry-run --format json`
+//! would print, made with the defaults of the configuration file from the
+//! song playing, or from the `song` query parameter, given as for
+//! `--from-song`. `number` sets its length. `GET /playlist.html` is the same
+//! playlist as a page, with a "Play next" button for each song, which
+//! queues it right after the song playing with `POST /play-next?path=PATH`.
+use crate::filters::FilterContext;
+use crate::remote::{escape, read_http_request, reject, respond, HttpRequest};
+use crate::timing::Timings;
+use crate::{
+    check_playlist_distance, playlist_distances, playlist_options, playlist_output,
+    tie_break_playlist, with_playlist_ranking, MPDLibrary, OutputFormat, PlaylistOptions,
+    PlaylistOutput,
+};
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+
+/// The number of songs of previews, if `number` is not given.
+const DEFAULT_PREVIEW_LENGTH: usize = 20;
+
+/// Decode a component of a query string, where spaces can be written `+`.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match text
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encode `text` to be put in a query string.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Split a request target like `/playlist?song=a.flac` into its path and
+/// its decoded query parameters.
+fn parse_target(target: &str) -> (&str, HashMap<String, String>) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let parameters = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (name, value) = p.split_once('=').unwrap_or((p, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect();
+    (path, parameters)
+}
+
+/// The page shown on `GET /playlist.html`.
+fn render_html(playlist: &PlaylistOutput) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+        <title>blissify</title></head><body>\n<ol>\n",
+    );
+    for song in &playlist.songs {
+        let mut name = format!(
+            "{} - {}",
+            song.artist.as_deref().unwrap_or("<No artist>"),
+            song.title.as_deref().unwrap_or("<No title>"),
+        );
+        if let Some(distance) = song.distance {
+            name.push_str(&format!(" ({:.3})", distance));
+        }
+        html.push_str(&format!(
+            "<li>{} <form method=\"post\" action=\"/play-next?path={}\" style=\"display:inline\">\
+            <button>Play next</button></form></li>\n",
+            escape(&name),
+            escape(&percent_encode(&song.path)),
+        ));
+    }
+    html.push_str("</ol>\n</body></html>\n");
+    html
+}
+
+impl MPDLibrary {
+    /// The playlist of `number` songs a dry run would make from the song at
+    /// `song_path`, given as for `--from-song`, or from the song playing,
+    /// with the defaults of the configuration file, and each song's distance
+    /// to the song it is made from.
+    pub(crate) fn preview_playlist(
+        &self,
+        song_path: Option<&str>,
+        number: usize,
+    ) -> Result<PlaylistOutput> {
+        let path = match song_path {
+            Some(path) => self.resolve_song_path(path)?,
+            None => match self.mpd_conn()?.currentsong()? {
+                Some(mpd_song) => self.mpd_to_bliss_path(&mpd_song)?,
+                None => bail!(
+                    "No song is currently playing. Add ?song=PATH to choose the song to start the playlist from."
+                ),
+            },
+        };
+        let seeds = vec![self
+            .library
+            .song_from_path(&path.to_string_lossy())
+            .with_context(|| format!("{} is not in blissify's database.", path.display()))?];
+        // The same playlist as `playlist --dry-run --show-distance` would
+        // make, with no argument but those.
+        let defaults = self.playlist_defaults();
+        let matches = ArgMatches::default();
+        let options = PlaylistOptions {
+            dry_run: true,
+            show_distance: true,
+            ..playlist_options(self, &matches, defaults, OutputFormat::Json)?
+        };
+        with_playlist_ranking(self, &matches, defaults, &Timings::start(), |ranking| {
+            let seed_paths = [path.to_owned()];
+            let queued_files = HashSet::new();
+            let (songs, _) = self.filter_playlist(
+                tie_break_playlist(
+                    self.playlist_from_seeds(
+                        &[&path.to_string_lossy()],
+                        ranking.distance,
+                        ranking.sort,
+                        &options,
+                    )?,
+                    &seeds,
+                    ranking.distance,
+                    options.tie_break,
+                    options.tie_break_epsilon,
+                ),
+                &options,
+                FilterContext {
+                    seed_paths: &seed_paths,
+                    seeds: &seeds,
+                    queued_files: &queued_files,
+                    number_songs: number,
+                },
+            );
+            let playlist = songs.take(number).collect::<Vec<_>>();
+            check_playlist_distance(&playlist, &seeds, ranking.distance, &options)?;
+            let distances = playlist_distances(&playlist, &seeds, ranking.distance)
+                .into_iter()
+                .map(Some)
+                .collect();
+            Ok(playlist_output(&playlist, &seeds, distances, &options))
+        })
+    }
+
+    /// Queue the song at `path`, as stored in blissify's database, right
+    /// after the song playing, or at the end of the queue if none is.
+    pub(crate) fn play_next(&self, path: &str) -> Result<()> {
+        let song = self
+            .library
+            .song_from_path::<()>(path)
+            .with_context(|| format!("{} is not in blissify's database.", path))?;
+        let mpd_song = self.bliss_song_to_mpd(&song)?;
+        let mut mpd_conn = self.mpd_conn()?;
+        let position = match mpd_conn.currentsong()? {
+            Some(current_song) => Some(Self::queue_position(&mut mpd_conn, &current_song)? + 1),
+            None => None,
+        };
+        self.change_queue(&mut mpd_conn, |mpd_conn| {
+            self.add_to_queue(mpd_conn, &[mpd_song], position)
+        })
+    }
+}
+
+/// Whether `request` comes from a page of this server, according to its
+/// `Origin`, or its `Referer` without one, so that other sites can't have
+/// browsers change the queue.
+fn is_same_origin(request: &HttpRequest) -> bool {
+    let own_origin = match &request.host {
+        Some(host) => format!("http://{}", host),
+        None => return false,
+    };
+    match (&request.origin, &request.referer) {
+        (Some(origin), _) => *origin == own_origin,
+        (None, Some(referer)) => referer
+            .strip_prefix(&own_origin)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+        (None, None) => false,
+    }
+}
+
+/// Answer the request of `stream`.
+fn answer(library: &MPDLibrary, mut stream: &TcpStream) -> Result<()> {
+    let request = match read_http_request(stream) {
+        Ok(request) => request,
+        Err(e) => {
+            reject(stream, &e);
+            return Err(e);
+        }
+    };
+    let (path, parameters) = parse_target(&request.target);
+    match (request.method.as_str(), path) {
+        ("GET", "/" | "/playlist.html" | "/playlist") => {
+            let number = match parameters.get("number").map(|n| n.parse::<usize>()) {
+                Some(Ok(number)) => number,
+                Some(Err(_)) => {
+                    return respond(
+                        stream,
+                        "400 Bad Request",
+                        "text/plain",
+                        "number must be a number of songs.",
+                    )
+                }
+                None => DEFAULT_PREVIEW_LENGTH,
+            };
+            let playlist = match library
+                .preview_playlist(parameters.get("song").map(String::as_str), number)
+            {
+                Ok(playlist) => playlist,
+                Err(e) => return respond(stream, "400 Bad Request", "text/plain", &e.to_string()),
+            };
+            if path == "/playlist" {
+                respond(
+                    stream,
+                    "200 OK",
+                    "application/json",
+                    &serde_json::to_string(&playlist)?,
+                )
+            } else {
+                respond(
+                    stream,
+                    "200 OK",
+                    "text/html; charset=utf-8",
+                    &render_html(&playlist),
+                )
+            }
+        }
+        ("POST", "/play-next") if !is_same_origin(&request) => respond(
+            stream,
+            "403 Forbidden",
+            "text/plain",
+            "Songs can only be queued from the pages of this server.",
+        ),
+        ("POST", "/play-next") => {
+            let result = match parameters.get("path") {
+                Some(path) => library.play_next(path),
+                None => Err(anyhow::anyhow!("path is missing.")),
+            };
+            match result {
+                // Back to the playlist.
+                Ok(()) => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 303 See Other\r\nLocation: /playlist.html\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )?;
+                    Ok(())
+                }
+                Err(e) => respond(stream, "400 Bad Request", "text/plain", &e.to_string()),
+            }
+        }
+        _ => respond(stream, "404 Not Found", "text/plain", ""),
+    }
+}
+
+/// Answer the requests of `listener`, see [serve](self).
+pub(crate) fn serve(library: &MPDLibrary, listener: &TcpListener) -> Result<()> {
+    for stream in listener.incoming() {
+        if let Err(e) = answer(library, &stream?) {
+            warn!("Could not answer a request: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+    use std::thread;
+
+    fn request(address: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{}", request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let (path, parameters) = parse_target("/playlist?song=a%20b+c.flac&number=5&flag");
+        assert_eq!(path, "/playlist");
+        assert_eq!(parameters["song"], "a b c.flac");
+        assert_eq!(parameters["number"], "5");
+        assert_eq!(parameters["flag"], "");
+        assert_eq!(
+            percent_encode("path/a b&é.flac"),
+            "path/a%20b%26%C3%A9.flac"
+        );
+        assert_eq!(
+            percent_decode(&percent_encode("path/a b&é.flac")),
+            "path/a b&é.flac"
+        );
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+
+    #[test]
+    fn test_is_same_origin() {
+        let request = |origin: Option<&str>, referer: Option<&str>| HttpRequest {
+            method: String::from("POST"),
+            target: String::from("/play-next"),
+            host: Some(String::from("localhost:8000")),
+            referer: referer.map(String::from),
+            origin: origin.map(String::from),
+        };
+        assert!(is_same_origin(&request(
+            Some("http://localhost:8000"),
+            None
+        )));
+        assert!(is_same_origin(&request(
+            None,
+            Some("http://localhost:8000/playlist.html")
+        )));
+        assert!(!is_same_origin(&request(Some("http://example.com"), None)));
+        assert!(!is_same_origin(&request(
+            None,
+            Some("http://localhost:8000.example.com/")
+        )));
+        assert!(!is_same_origin(&request(None, None)));
+    }
+
+    #[test]
+    fn test_serve() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/second.flac", None, 0.1),
+                (3, "path/third.flac", None, 0.2),
+            ],
+        );
+        library.mpd_conn().unwrap().mpd_queue = vec![MPDSong {
+            file: String::from("first.flac"),
+            place: Some(QueuePlace {
+                id: Id(1),
+                pos: 0,
+                prio: 0,
+            }),
+            ..Default::default()
+        }];
+
+        let playlist = library.preview_playlist(None, 2).unwrap();
+        assert_eq!(
+            playlist
+                .songs
+                .iter()
+                .map(|s| s.path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["path/first.flac", "path/second.flac"],
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let client = thread::spawn(move || {
+            let json = request(&address, "GET /playlist?number=2 HTTP/1.1\r\n\r\n");
+            let page = request(
+                &address,
+                "GET /playlist.html?song=path%2Fthird.flac HTTP/1.1\r\n\r\n",
+            );
+            let forged = request(
+                &address,
+                "POST /play-next?path=path%2Fsecond.flac HTTP/1.1\r\nHost: localhost:8000\r\n\
+                Origin: http://example.com\r\nContent-Length: 0\r\n\r\n",
+            );
+            let play_next = request(
+                &address,
+                "POST /play-next?path=path%2Fthird.flac HTTP/1.1\r\nHost: localhost:8000\r\n\
+                Origin: http://localhost:8000\r\nReferer: http://example.com/\r\n\
+                Content-Length: 0\r\n\r\n",
+            );
+            let unknown = request(&address, "GET /unknown HTTP/1.1\r\n\r\n");
+            (json, page, forged, play_next, unknown)
+        });
+        for stream in listener.incoming().take(5) {
+            answer(&library, &stream.unwrap()).unwrap();
+        }
+        let (json, page, forged, play_next, unknown) = client.join().unwrap();
+        assert!(json.ends_with(&serde_json::to_string(&playlist).unwrap()));
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(page.contains("action=\"/play-next?path=path/second.flac\""));
+        assert!(forged.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(play_next.starts_with("HTTP/1.1 303 See Other\r\n"));
+        assert!(play_next.contains("Location: /playlist.html\r\n"));
+        assert!(unknown.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let queue = library
+            .mpd_conn()
+            .unwrap()
+            .mpd_queue
+            .iter()
+            .map(|s| s.file.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(queue, vec!["first.flac", "third.flac"]);
+    }
+}
@@ -0,0 +1,280 @@
+//! Queueing playlists on other MPD servers, see `playlist --target-server`.
+//!
+//! Servers are listed in the `servers` section of the configuration file,
+//! under the name `--target-server` takes. Playlists are still made from
+//! blissify's database, but the current song is read from that server and
+//! the playlist is queued there, e.g. to compute playlists on a desktop and
+//! play them on a Raspberry Pi sharing the same music over the network.
+//!
+//! Each server can see the music under different paths than the MPD server
+//! blissify analyzed the library with: `path_prefixes` maps the beginnings
+//! of paths as MPD gives them locally to the ones the server uses, like
+//! `{"": "nas/"}` for a server that has the whole library in a `nas`
+//! directory.
+//!
+//! So that the configuration file can be shared, e.g. in a dotfiles
+//! repository, a server's password can be read from a file of its own with
+//! `password_file`, or from the output of a command, like a password manager,
+//! with `password_cmd`, rather than being part of `host`.
+use crate::{MPDAddress, MPDLibrary};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn default_port() -> u16 {
+    6600
+}
+
+/// An MPD server playlists can be queued on.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MPDServer {
+    /// The server's address, like MPD_HOST: a hostname or an IP address,
+    /// optionally preceded by "password@", or a socket path.
+    pub host: String,
+    /// The server's port, like MPD_PORT.
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Beginnings of MPD paths, as MPD gives them locally, mapped to the ones
+    /// the server uses instead. The longest matching one is used.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub path_prefixes: BTreeMap<String, String>,
+    /// A file containing the server's password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    /// A shell command printing the server's password, like
+    /// "pass show mpd".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_cmd: Option<String>,
+}
+
+impl MPDServer {
+    /// The server's password from [password_file](Self::password_file) or
+    /// [password_cmd](Self::password_cmd), if either is set.
+    fn password(&self) -> Result<Option<String>> {
+        let password = match (&self.password_file, &self.password_cmd) {
+            (Some(_), Some(_)) => {
+                bail!("Only one of \"password_file\" and \"password_cmd\" can be set.")
+            }
+            (Some(file), None) => fs::read_to_string(file)
+                .with_context(|| format!("while reading the password from {}", file.display()))?,
+            (None, Some(command)) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .with_context(|| format!("while running \"{}\"", command))?;
+                if !output.status.success() {
+                    bail!(
+                        "\"{}\" failed ({}): {}",
+                        command,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+                String::from_utf8(output.stdout)
+                    .with_context(|| format!("\"{}\" printed an invalid password", command))?
+            }
+            (None, None) => return Ok(None),
+        };
+        // Files and commands usually end with a newline.
+        Ok(Some(password.trim_end_matches(['\n', '\r']).to_owned()))
+    }
+
+    /// Where the server is, with its password if there is one.
+    ///
+    /// Passwords read from [password_file](Self::password_file) or
+    /// [password_cmd](Self::password_cmd) are kept apart from the host, so
+    /// they can contain '@'.
+    pub fn address(&self) -> Result<MPDAddress> {
+        match self.password()? {
+            // "@host" is a unix abstract socket, which can't have a password.
+            Some(_) if self.host.contains('@') => bail!(
+                "The host \"{}\" already has a password or is an abstract socket, \"password_file\" and \"password_cmd\" can't be used with it.",
+                self.host
+            ),
+            Some(password) => Ok(MPDAddress {
+                host: self.host.to_owned(),
+                port: self.port,
+                password: Some(password),
+            }),
+            None => Ok(MPDAddress::new(&self.host, self.port)),
+        }
+    }
+
+    /// Replace the beginning of `path` with the longest of `prefixes` it
+    /// starts with, if any.
+    fn replace_prefix<'a>(
+        path: &str,
+        prefixes: impl Iterator<Item = (&'a str, &'a str)>,
+    ) -> String {
+        match prefixes
+            .filter(|(from, _)| path.starts_with(from))
+            .max_by_key(|(from, _)| from.len())
+        {
+            Some((from, to)) => format!("{}{}", to, &path[from.len()..]),
+            None => path.to_owned(),
+        }
+    }
+
+    /// The path the server uses for the local MPD path `path`.
+    pub fn server_path(&self, path: &str) -> String {
+        Self::replace_prefix(
+            path,
+            self.path_prefixes
+                .iter()
+                .map(|(from, to)| (from.as_str(), to.as_str())),
+        )
+    }
+
+    /// The local MPD path of the path `path` the server uses.
+    pub fn local_path(&self, path: &str) -> String {
+        Self::replace_prefix(
+            path,
+            self.path_prefixes
+                .iter()
+                .map(|(from, to)| (to.as_str(), from.as_str())),
+        )
+    }
+}
+
+impl MPDLibrary {
+    /// Talk to the server `name` of the `servers` section of the
+    /// configuration file instead of the one given by MPD_HOST and MPD_PORT,
+    /// from the next connection on.
+    pub(crate) fn use_server(&mut self, name: &str) -> Result<()> {
+        let server = match self.library.config.servers.get(name) {
+            Some(server) => server.to_owned(),
+            None => bail!(
+                "Unknown server '{}', please add it to the \"servers\" section of the configuration file.",
+                name
+            ),
+        };
+        let address = server
+            .address()
+            .with_context(|| format!("while reading the password of the server '{}'", name))?;
+        self.server_address = Some(address);
+        self.server = Some(server);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use mpd::song::Song as MPDSong;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_server_paths() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(&library, &[(1, "path/Albums/song.flac", None, 0.)]);
+        let song = library
+            .library
+            .song_from_path("path/Albums/song.flac")
+            .unwrap();
+        assert!(library.use_server("kitchen").is_err());
+        library.library.config.servers = BTreeMap::from([(
+            String::from("kitchen"),
+            MPDServer {
+                host: String::from("secret@kitchen.local"),
+                port: 6601,
+                path_prefixes: BTreeMap::new(),
+                password_file: None,
+                password_cmd: None,
+            },
+        )]);
+        library.use_server("kitchen").unwrap();
+        assert_eq!(
+            library.mpd_address().unwrap(),
+            MPDAddress {
+                host: String::from("kitchen.local"),
+                port: 6601,
+                password: Some(String::from("secret")),
+            }
+        );
+
+        library.server = Some(MPDServer {
+            host: String::from("kitchen.local"),
+            port: 6600,
+            path_prefixes: BTreeMap::from([
+                (String::from(""), String::from("nas/")),
+                (String::from("Albums/"), String::from("music/albums/")),
+            ]),
+            password_file: None,
+            password_cmd: None,
+        });
+        let mpd_song = library.bliss_song_to_mpd(&song).unwrap();
+        assert_eq!(mpd_song.file, "music/albums/song.flac");
+        assert_eq!(
+            library.mpd_to_bliss_path(&mpd_song).unwrap(),
+            PathBuf::from("path/Albums/song.flac")
+        );
+        assert_eq!(
+            library
+                .mpd_to_bliss_path(&MPDSong {
+                    file: String::from("nas/Singles/other.flac"),
+                    ..Default::default()
+                })
+                .unwrap(),
+            PathBuf::from("path/Singles/other.flac")
+        );
+    }
+
+    #[test]
+    fn test_server_password() {
+        let tempdir = tempdir::TempDir::new("servers").unwrap();
+        let password_file = tempdir.path().join("mpd-password");
+        fs::write(&password_file, "secret\n").unwrap();
+        let server =
+            |host: &str, password_file: Option<PathBuf>, password_cmd: Option<&str>| MPDServer {
+                host: String::from(host),
+                port: 6600,
+                path_prefixes: BTreeMap::new(),
+                password_file,
+                password_cmd: password_cmd.map(String::from),
+            };
+
+        let address = |password: &str| MPDAddress {
+            host: String::from("kitchen.local"),
+            port: 6600,
+            password: Some(String::from(password)),
+        };
+
+        assert_eq!(
+            server("old@kitchen.local", None, None).address().unwrap(),
+            address("old")
+        );
+        assert_eq!(
+            server("kitchen.local", Some(password_file.to_owned()), None)
+                .address()
+                .unwrap(),
+            address("secret")
+        );
+        assert_eq!(
+            server("kitchen.local", None, Some("echo from-command"))
+                .address()
+                .unwrap(),
+            address("from-command")
+        );
+        // Passwords are not split on '@' like hosts.
+        assert_eq!(
+            server("kitchen.local", None, Some("echo 'p@ss@word'"))
+                .address()
+                .unwrap(),
+            address("p@ss@word")
+        );
+        for invalid in [
+            server("old@kitchen.local", None, Some("echo new")),
+            server("kitchen.local", Some(password_file), Some("echo both")),
+            server("kitchen.local", Some(tempdir.path().join("missing")), None),
+            server("kitchen.local", None, Some("echo oops >&2; exit 1")),
+        ] {
+            assert!(invalid.address().is_err());
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Changing the configuration file from the command-line, see
+//! `blissify config set`.
+//!
+//! Settings are named after their key in the configuration file, nested keys
+//! being separated by dots, e.g. `playlist.number_songs`. Values are parsed as
+//! JSON, falling back to plain strings, so that paths don't need quoting.
+//!
+//! Since bliss stores absolute paths, changing `mpd_base_path` after moving
+//! the music library leaves the database pointing to the old location;
+//! `--rewrite-db` moves the stored paths along instead of re-analyzing
+//! everything.
+use crate::{Config, MPDLibrary};
+use anyhow::{bail, Result};
+use bliss_audio::library::AppConfigTrait;
+use serde_json::Value;
+use std::path::Path;
+
+/// The columns holding absolute song paths, in bliss' tables and blissify's.
+const PATH_COLUMNS: &[(&str, &str)] = &[
+    ("song", "path"),
+    ("song", "cue_path"),
+    ("song", "audio_file_path"),
+    ("feedback", "path"),
+    ("queued_song", "path"),
+    ("queued_song", "seed"),
+    ("skip", "seed"),
+    ("skip", "path"),
+    ("excluded_song", "path"),
+    ("play", "path"),
+    ("play_count", "path"),
+    ("generated_playlist_song", "path"),
+    ("skipped_song", "path"),
+];
+
+/// A copy of `config` with the setting `key` set to `value`.
+pub fn set_setting(config: &Config, key: &str, value: &str) -> Result<Config> {
+    let parsed = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.into()));
+    let pointer = format!("/{}", key.replace('.', "/"));
+    let mut values = vec![parsed];
+    // Numbers or booleans given for string settings, e.g. a directory named
+    // "2024", are taken as is.
+    if !values[0].is_string() {
+        values.push(Value::String(value.into()));
+    }
+    for value in values {
+        let mut serialized = serde_json::to_value(config)?;
+        let (parent, name) = match pointer.rsplit_once('/') {
+            Some((parent, name)) => (parent, name),
+            None => bail!("Invalid setting name \"{}\".", key),
+        };
+        let object = match serialized
+            .pointer_mut(parent)
+            .and_then(Value::as_object_mut)
+        {
+            Some(object) => object,
+            None => bail!("Unknown setting \"{}\".", key),
+        };
+        object.insert(name.to_string(), value.to_owned());
+        if let Ok(new_config) = serde_json::from_value::<Config>(serialized) {
+            // Unknown keys are ignored when deserializing, so make sure the
+            // setting exists.
+            if serde_json::to_value(&new_config)?
+                .pointer(&pointer)
+                .is_some()
+            {
+                return Ok(new_config);
+            }
+        }
+    }
+    bail!(
+        "Unknown setting \"{}\", or invalid value \"{}\".",
+        key,
+        value
+    )
+}
+
+impl MPDLibrary {
+    /// Set `mpd_base_path` to `new_base_path`, and rewrite the song paths
+    /// stored under the old one to be under `new_base_path`, in a single
+    /// transaction that is only committed once the configuration file is
+    /// written.
+    ///
+    /// Returns the number of songs moved.
+    pub(crate) fn rewrite_base_path(&mut self, new_base_path: &Path) -> Result<usize> {
+        let old_base_path = self.library.config.mpd_base_path.to_owned();
+        // Paths are stored as `mpd_base_path` joined with MPD's paths, without
+        // trailing slashes.
+        let old_prefix = old_base_path.join("").to_string_lossy().to_string();
+        let new_prefix = new_base_path.join("").to_string_lossy().to_string();
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let transaction = sqlite_conn.transaction()?;
+        let mut moved = 0;
+        for (table, column) in PATH_COLUMNS {
+            let rewritten = transaction.execute(
+                &format!(
+                    "update {table} set {column} = ?2 || substr({column}, length(?1) + 1)
+                    where substr({column}, 1, length(?1)) = ?1",
+                    table = table,
+                    column = column,
+                ),
+                [&old_prefix, &new_prefix],
+            )?;
+            if (*table, *column) == ("song", "path") {
+                moved = rewritten;
+            }
+        }
+        let mut config = self.library.config.to_owned();
+        config.mpd_base_path = new_base_path.to_owned();
+        config.write()?;
+        transaction.commit()?;
+        self.library.config = config;
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_set_setting() {
+        let (library, _tempdir) = setup_library();
+        let config = &library.library.config;
+
+        let new_config = set_setting(config, "mpd_base_path", "/mnt/big/Music").unwrap();
+        assert_eq!(new_config.mpd_base_path, PathBuf::from("/mnt/big/Music"));
+        let new_config = set_setting(config, "mpd_base_path", "2024").unwrap();
+        assert_eq!(new_config.mpd_base_path, PathBuf::from("2024"));
+        let new_config = set_setting(config, "playlist.number_songs", "30").unwrap();
+        assert_eq!(new_config.playlist.number_songs, Some(30));
+        let new_config = set_setting(config, "feedback_weight", "0.3").unwrap();
+        assert_eq!(new_config.feedback_weight, 0.3);
+        let new_config = set_setting(config, "disable_random", "true").unwrap();
+        assert!(new_config.disable_random);
+
+        assert!(set_setting(config, "feedback_weight", "a lot").is_err());
+        assert!(set_setting(config, "no_such_setting", "1").is_err());
+        assert!(set_setting(config, "no.such.setting", "1").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_base_path() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/album/song.flac", None, 0.),
+                (2, "path/album/other.flac", None, 0.),
+                (3, "pathological/song.flac", None, 0.),
+            ],
+        );
+        library.exclude_songs("path/album/other.flac").unwrap();
+
+        assert_eq!(
+            library.rewrite_base_path(Path::new("/new/path")).unwrap(),
+            2
+        );
+        assert_eq!(
+            library.library.config.mpd_base_path,
+            PathBuf::from("/new/path")
+        );
+        let mut paths = library
+            .library
+            .songs_from_library::<()>()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.bliss_song.path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "/new/path/album/other.flac",
+                "/new/path/album/song.flac",
+                "pathological/song.flac",
+            ],
+        );
+        assert_eq!(
+            library.excluded_songs().unwrap(),
+            [PathBuf::from("/new/path/album/other.flac")].into(),
+        );
+
+        let config_path = library.library.config.base_config.config_path.to_owned();
+        let config = Config::from_path(&config_path.to_string_lossy()).unwrap();
+        assert_eq!(config.mpd_base_path, PathBuf::from("/new/path"));
+    }
+}
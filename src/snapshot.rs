@@ -0,0 +1,262 @@
+//! Snapshots of the analyzed songs, see `blissify snapshot`.
+//!
+//! A snapshot records the path of each analyzed song along with a hash of its
+//! analysis, so that diffing it with the library, or with another snapshot,
+//! shows which songs a big `update` or `list-db --remove` added, removed, or
+//! analyzed differently.
+use crate::MPDLibrary;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::Xxh3;
+
+/// The differences between two sets of analyzed songs, by path.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub(crate) struct SnapshotDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Songs whose analysis changed, e.g. because they were analyzed again
+    /// with another version of the features.
+    pub changed: Vec<String>,
+}
+
+impl SnapshotDiff {
+    fn new(old: &BTreeMap<String, i64>, new: &BTreeMap<String, i64>) -> Self {
+        let mut diff = SnapshotDiff::default();
+        for (path, hash) in new {
+            match old.get(path) {
+                None => diff.added.push(path.to_owned()),
+                Some(old_hash) if old_hash != hash => diff.changed.push(path.to_owned()),
+                Some(_) => (),
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|path| !new.contains_key(*path))
+            .cloned()
+            .collect();
+        diff
+    }
+}
+
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (sign, paths) in [
+            ("+", &self.added),
+            ("-", &self.removed),
+            ("~", &self.changed),
+        ] {
+            for path in paths {
+                writeln!(f, "{} {}", sign, path)?;
+            }
+        }
+        write!(
+            f,
+            "{} song(s) added, {} removed, {} changed.",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+}
+
+impl MPDLibrary {
+    /// Create the tables storing the snapshots, if they don't exist yet.
+    pub(crate) fn create_snapshot_tables(&self) -> Result<()> {
+        self.library.sqlite_conn.lock().unwrap().execute_batch(
+            "create table if not exists snapshot (
+                id integer primary key,
+                name text not null unique,
+                created_at integer not null
+            );
+            create table if not exists snapshot_song (
+                snapshot_id integer not null,
+                path text not null,
+                analysis_hash integer not null,
+                primary key (snapshot_id, path)
+            );",
+        )?;
+        Ok(())
+    }
+
+    /// The analyzed songs of the library, by path, with a hash of their
+    /// analysis and of the version of the features it has.
+    fn analysis_hashes(&self) -> Result<BTreeMap<String, i64>> {
+        Ok(self
+            .library
+            .songs_from_library::<()>()?
+            .into_iter()
+            .map(|song| {
+                let song = song.bliss_song;
+                let mut hasher = Xxh3::new();
+                hasher.update(&song.features_version.to_le_bytes());
+                for feature in song.analysis.as_vec() {
+                    hasher.update(&feature.to_le_bytes());
+                }
+                (
+                    song.path.to_string_lossy().to_string(),
+                    hasher.digest() as i64,
+                )
+            })
+            .collect())
+    }
+
+    /// Record the analyzed songs as the snapshot `name`, replacing the
+    /// snapshot of that name if there is one. Returns the number of songs
+    /// recorded.
+    pub(crate) fn save_snapshot(&self, name: &str) -> Result<usize> {
+        let hashes = self.analysis_hashes()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let tx = sqlite_conn.transaction()?;
+        tx.execute(
+            "delete from snapshot_song where snapshot_id in (
+                select id from snapshot where name = ?1
+            )",
+            [name],
+        )?;
+        tx.execute("delete from snapshot where name = ?1", [name])?;
+        tx.execute(
+            "insert into snapshot (name, created_at) values (?1, ?2)",
+            (name, now),
+        )?;
+        let id = tx.last_insert_rowid();
+        {
+            let mut insert = tx.prepare(
+                "insert into snapshot_song (snapshot_id, path, analysis_hash) values (?1, ?2, ?3)",
+            )?;
+            for (path, hash) in &hashes {
+                insert.execute((id, path, hash))?;
+            }
+        }
+        tx.commit()?;
+        Ok(hashes.len())
+    }
+
+    /// The names of the snapshots, from the oldest to the newest, with the
+    /// time they were saved, as a local date and time, and their number of
+    /// songs.
+    pub(crate) fn snapshots(&self) -> Result<Vec<(String, String, usize)>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn.prepare(
+            "select snapshot.name, datetime(snapshot.created_at, 'unixepoch', 'localtime'),
+            count(snapshot_song.path)
+            from snapshot left join snapshot_song on snapshot_song.snapshot_id = snapshot.id
+            group by snapshot.id order by snapshot.created_at, snapshot.id",
+        )?;
+        let snapshots = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(snapshots)
+    }
+
+    /// The songs of the snapshot `name`, by path, with the hash of their
+    /// analysis.
+    fn snapshot_songs(&self, name: &str) -> Result<BTreeMap<String, i64>> {
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let exists = sqlite_conn
+            .prepare("select 1 from snapshot where name = ?1")?
+            .exists([name])?;
+        if !exists {
+            bail!(
+                "There is no snapshot named '{}', run \"blissify snapshot list\" to see the saved ones.",
+                name
+            );
+        }
+        let mut query = sqlite_conn.prepare(
+            "select snapshot_song.path, snapshot_song.analysis_hash
+            from snapshot_song join snapshot on snapshot.id = snapshot_song.snapshot_id
+            where snapshot.name = ?1",
+        )?;
+        let songs = query
+            .query_map([name], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        Ok(songs)
+    }
+
+    /// What changed between the snapshot `name` and the snapshot `other`, or
+    /// the analyzed songs of the library if `other` is `None`.
+    pub(crate) fn diff_snapshot(&self, name: &str, other: Option<&str>) -> Result<SnapshotDiff> {
+        let old = self.snapshot_songs(name)?;
+        let new = match other {
+            Some(other) => self.snapshot_songs(other)?,
+            None => self.analysis_hashes()?,
+        };
+        Ok(SnapshotDiff::new(&old, &new))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_snapshots() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/kept.flac", None, 0.),
+                (2, "path/removed.flac", None, 0.1),
+                (3, "path/changed.flac", None, 0.2),
+            ],
+        );
+        assert_eq!(library.save_snapshot("before").unwrap(), 3);
+        assert_eq!(
+            library.diff_snapshot("before", None).unwrap(),
+            SnapshotDiff::default()
+        );
+
+        library.library.delete_paths(["path/removed.flac"]).unwrap();
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute(
+                "update feature set feature = 0.9 where song_id = 3 and feature_index = 1",
+                [],
+            )
+            .unwrap();
+        insert_songs(&library, &[(4, "path/added.flac", None, 0.3)]);
+        let diff = SnapshotDiff {
+            added: vec![String::from("path/added.flac")],
+            removed: vec![String::from("path/removed.flac")],
+            changed: vec![String::from("path/changed.flac")],
+        };
+        assert_eq!(library.diff_snapshot("before", None).unwrap(), diff);
+        assert_eq!(
+            diff.to_string(),
+            "+ path/added.flac\n\
+            - path/removed.flac\n\
+            ~ path/changed.flac\n\
+            1 song(s) added, 1 removed, 1 changed."
+        );
+
+        library.save_snapshot("after").unwrap();
+        assert_eq!(
+            library.diff_snapshot("before", Some("after")).unwrap(),
+            diff
+        );
+        // Saving a snapshot again replaces it.
+        library.save_snapshot("before").unwrap();
+        assert_eq!(
+            library.diff_snapshot("before", Some("after")).unwrap(),
+            SnapshotDiff::default()
+        );
+        assert_eq!(
+            library
+                .snapshots()
+                .unwrap()
+                .into_iter()
+                .map(|(name, _, songs)| (name, songs))
+                .collect::<Vec<_>>(),
+            vec![(String::from("after"), 3), (String::from("before"), 3)],
+        );
+        assert!(library.diff_snapshot("missing", None).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! `playlist --station`: keep the queue going from a seed.
+//!
+//! After queueing a playlist from the seed, blissify stays in the foreground,
+//! and whenever the queue is about to run out, queues a few more songs close
+//! to the songs listened to since the station started, until it is
+//! interrupted. Unlike the daemon, it is tied to a single seed and terminal
+//! session.
+use crate::{dedup_playlist, MPDLibrary, PlaylistOptions};
+use anyhow::Result;
+use bliss_audio::library::LibrarySong;
+use bliss_audio::playlist::DistanceMetricBuilder;
+use log::info;
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// How often MPD's queue is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Queue more songs when fewer than this many are left after the current one.
+const LOW_WATER: usize = 2;
+/// Same as [LOW_WATER] with MPD's consume mode on: since songs leave the
+/// queue once played, an empty queue would leave nothing to go on, and
+/// playback stops.
+const CONSUME_LOW_WATER: usize = 4;
+/// The number of songs queued each time the queue is topped up.
+const TOP_UP: usize = 5;
+/// How many of the last songs listened to the next songs are made from.
+const RECENT_SONGS: usize = 5;
+
+/// The songs of a station's session.
+#[derive(Debug, Default)]
+pub struct Station {
+    /// The songs listened to, as bliss paths, the most recent last.
+    recent: VecDeque<PathBuf>,
+    /// The songs queued since the station started, so they are not queued
+    /// twice.
+    queued: HashSet<PathBuf>,
+}
+
+impl MPDLibrary {
+    /// Queue a playlist from `song_path`, or the current song, then keep
+    /// topping the queue up with songs close to the ones listened to, see
+    /// the [module](self) documentation. Only returns on errors.
+    pub(crate) fn run_station<'a, F, I>(
+        &self,
+        song_path: Option<&str>,
+        number_songs: usize,
+        distance: &'a dyn DistanceMetricBuilder,
+        sort_by: F,
+        options: &PlaylistOptions,
+    ) -> Result<()>
+    where
+        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
+        I: Iterator<Item = LibrarySong<()>> + 'a,
+    {
+        self.queue_from_song(song_path, number_songs, distance, &sort_by, options)?;
+        println!("Station started, press Ctrl-C to stop it.");
+        let mut station = Station::default();
+        loop {
+            let queued = self.top_up_station(&mut station, distance, &sort_by, options)?;
+            if queued > 0 {
+                info!("Queued {} more song(s).", queued);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Record the current song as listened to, and queue [TOP_UP] songs
+    /// close to the last songs listened to if the queue is running out.
+    ///
+    /// Returns the number of songs queued.
+    pub(crate) fn top_up_station<'a, F, I>(
+        &self,
+        station: &mut Station,
+        distance: &'a dyn DistanceMetricBuilder,
+        sort_by: F,
+        options: &PlaylistOptions,
+    ) -> Result<usize>
+    where
+        F: Fn(&[LibrarySong<()>], &[LibrarySong<()>], &'a dyn DistanceMetricBuilder) -> I,
+        I: Iterator<Item = LibrarySong<()>> + 'a,
+    {
+        let (status, current, queue) = {
+            let mut mpd_conn = self.mpd_conn()?;
+            (
+                mpd_conn.status()?,
+                mpd_conn.currentsong()?,
+                mpd_conn.queue()?,
+            )
+        };
+        if let Some(current) = current {
+            let path = self.mpd_to_bliss_path(&current)?;
+            if station.recent.back() != Some(&path) {
+                station.recent.push_back(path);
+                if station.recent.len() > RECENT_SONGS {
+                    station.recent.pop_front();
+                }
+            }
+        }
+        let left = (status.queue_len as usize)
+            .saturating_sub(status.song.map_or(0, |s| s.pos as usize + 1));
+        let low_water = if status.consume {
+            CONSUME_LOW_WATER
+        } else {
+            LOW_WATER
+        };
+        let seed = match station.recent.back() {
+            Some(seed) if left < low_water => seed.to_string_lossy().to_string(),
+            _ => return Ok(0),
+        };
+
+        // Songs the user queued themselves shouldn't be queued again either.
+        station
+            .queued
+            .extend(queue.iter().filter_map(|s| self.mpd_to_bliss_path(s).ok()));
+        let (seeds, candidates): (Vec<_>, Vec<_>) = self
+            .library
+            .songs_from_library()?
+            .into_iter()
+            .partition(|s| station.recent.contains(&s.bliss_song.path));
+        let candidates = candidates
+            .into_iter()
+            .filter(|s| !station.queued.contains(&s.bliss_song.path) && !options.excludes(s))
+            .collect::<Vec<_>>();
+        let playlist: Vec<LibrarySong<()>> = dedup_playlist(
+            sort_by(&seeds, &candidates, distance),
+            options.dedup,
+            options.dedup_distance,
+            |s| self.recording_id(s),
+        )
+        .take(TOP_UP)
+        .collect();
+
+        self.record_queued(&seed, &playlist)?;
+        let mpd_songs = playlist
+            .iter()
+            .map(|s| self.bliss_song_to_mpd(s))
+            .collect::<Result<Vec<_>>>()?;
+        let mut mpd_conn = self.mpd_conn()?;
+        self.change_queue(&mut mpd_conn, |mpd_conn| {
+            self.add_to_queue(mpd_conn, &mpd_songs, None)?;
+            if let Some(max_queue_length) = options.max_queue_length {
+                Self::trim_queue(mpd_conn, max_queue_length)?;
+            }
+            Ok(())
+        })?;
+        station
+            .queued
+            .extend(playlist.iter().map(|s| s.bliss_song.path.to_owned()));
+        Ok(playlist.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::playlist::{closest_to_songs, euclidean_distance};
+    use mpd::song::{Id, QueuePlace, Song as MPDSong};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_top_up_station() {
+        let (library, _tempdir) = setup_library();
+        library.mpd_conn().unwrap().mpd_queue = vec![
+            MPDSong {
+                file: String::from("first.flac"),
+                place: Some(QueuePlace {
+                    id: Id(1),
+                    pos: 0,
+                    prio: 0,
+                }),
+                ..Default::default()
+            },
+            MPDSong {
+                file: String::from("queued.flac"),
+                ..Default::default()
+            },
+        ];
+        let paths = (1..=15)
+            .map(|i| format!("path/{}.flac", i))
+            .collect::<Vec<_>>();
+        let mut songs = paths
+            .iter()
+            .zip(1..)
+            .map(|(p, i)| (i, p.as_str(), None, i as f32 / 10.))
+            .collect::<Vec<_>>();
+        songs.push((16, "path/first.flac", None, 0.));
+        songs.push((17, "path/queued.flac", None, 0.));
+        insert_songs(&library, &songs);
+        let options = PlaylistOptions {
+            dedup: None,
+            ..Default::default()
+        };
+        let mut station = Station::default();
+        let top_up = |station: &mut Station| {
+            library
+                .top_up_station(station, &euclidean_distance, closest_to_songs, &options)
+                .unwrap()
+        };
+        let files = || {
+            library
+                .mpd_conn()
+                .unwrap()
+                .mpd_queue
+                .iter()
+                .map(|s| s.file.to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        // Only one song is left after the current one.
+        assert_eq!(top_up(&mut station), TOP_UP);
+        assert_eq!(
+            files(),
+            vec![
+                "first.flac",
+                "queued.flac",
+                "1.flac",
+                "2.flac",
+                "3.flac",
+                "4.flac",
+                "5.flac"
+            ],
+        );
+        // The queue is long enough now.
+        assert_eq!(top_up(&mut station), 0);
+
+        library.mpd_conn().unwrap().mpd_queue.truncate(2);
+        assert_eq!(top_up(&mut station), TOP_UP);
+        assert_eq!(
+            files(),
+            vec![
+                "first.flac",
+                "queued.flac",
+                "6.flac",
+                "7.flac",
+                "8.flac",
+                "9.flac",
+                "10.flac"
+            ],
+        );
+
+        // Songs leave the queue once played in consume mode, so it is topped
+        // up earlier.
+        library.mpd_conn().unwrap().mpd_queue.truncate(4);
+        assert_eq!(top_up(&mut station), 0);
+        library.mpd_conn().unwrap().consume = true;
+        assert_eq!(top_up(&mut station), TOP_UP);
+        assert_eq!(
+            files(),
+            vec![
+                "first.flac",
+                "queued.flac",
+                "6.flac",
+                "7.flac",
+                "11.flac",
+                "12.flac",
+                "13.flac",
+                "14.flac",
+                "15.flac"
+            ],
+        );
+    }
+}
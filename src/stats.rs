@@ -0,0 +1,402 @@
+//! How much of the library is analyzed, see `blissify stats`.
+//!
+//! Songs are either analyzed, failed to be analyzed, or pending, i.e. in
+//! MPD's database but not in blissify's yet. Counting pending songs needs
+//! MPD, so they are left out when it can't be reached.
+//!
+//! `--listening` reports on the songs played instead, from the history the
+//! daemon records, see [crate::history].
+use crate::MPDLibrary;
+use anyhow::Result;
+use ndarray::Array1;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+/// Two songs played further apart than this are not counted as consecutive.
+const SESSION_GAP: i64 = 60 * 60;
+/// The number of most and least played genres reported.
+const TOP_GENRES: usize = 5;
+
+/// The songs of a directory, or of the whole library, by analysis state.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Coverage {
+    /// The top-level directory, relative to MPD's base path, or "." for the
+    /// songs at its root. `None` for the whole library.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+    pub analyzed: u64,
+    pub failed: u64,
+    /// `None` if MPD could not be reached.
+    pub pending: Option<u64>,
+}
+
+/// What the songs played look like, see `blissify stats --listening`.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Listening {
+    /// The number of songs played, skipped or not.
+    pub plays: u64,
+    /// The share of the songs blissify queued that were skipped, `None` if
+    /// none were played.
+    pub blissify_skip_rate: Option<f32>,
+    /// Same as [blissify_skip_rate](Self::blissify_skip_rate), for the other
+    /// songs.
+    pub other_skip_rate: Option<f32>,
+    /// The average distance between the songs blissify queued and the song
+    /// played before them, `None` if there are none.
+    pub blissify_transition_distance: Option<f32>,
+    /// Same as
+    /// [blissify_transition_distance](Self::blissify_transition_distance),
+    /// for the other songs.
+    pub other_transition_distance: Option<f32>,
+    /// The genres played the most, with their number of plays.
+    pub most_played_genres: Vec<(String, u64)>,
+    /// The genres of the library played the least, with their number of
+    /// plays.
+    pub least_played_genres: Vec<(String, u64)>,
+}
+
+/// The average of `values`, `None` if there are none.
+fn average(values: &[f32]) -> Option<f32> {
+    (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32)
+}
+
+impl MPDLibrary {
+    /// The top-level directory `path` is in, relative to MPD's base path.
+    fn top_level_directory(&self, path: &str) -> String {
+        let path = Path::new(path);
+        let relative = self
+            .library
+            .config
+            .strip_base_path(path)
+            .unwrap_or_else(|| path.to_owned());
+        let mut components = relative.components();
+        match (components.next(), components.next()) {
+            (Some(directory), Some(_)) => directory.as_os_str().to_string_lossy().to_string(),
+            _ => String::from("."),
+        }
+    }
+
+    /// Count the analyzed, failed and pending songs of each top-level
+    /// directory, sorted by directory. Pending songs are only counted if
+    /// `mpd_paths`, the songs MPD knows about, is given.
+    ///
+    /// CUE sheets are counted once per track in blissify's database, but
+    /// once per sheet when pending.
+    pub(crate) fn coverage_by_directory(
+        &self,
+        mpd_paths: Option<&[String]>,
+    ) -> Result<Vec<Coverage>> {
+        let mut directories: BTreeMap<String, Coverage> = BTreeMap::new();
+        let mut known = HashSet::new();
+        {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut query =
+                sqlite_conn.prepare("select path, coalesce(cue_path, path), analyzed from song")?;
+            let songs = query
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, bool>(2)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (path, file, analyzed) in songs {
+                let coverage = directories
+                    .entry(self.top_level_directory(&path))
+                    .or_default();
+                if analyzed {
+                    coverage.analyzed += 1;
+                } else {
+                    coverage.failed += 1;
+                }
+                known.insert(file);
+            }
+        }
+        if let Some(mpd_paths) = mpd_paths {
+            for coverage in directories.values_mut() {
+                coverage.pending = Some(0);
+            }
+            for path in mpd_paths.iter().filter(|p| !known.contains(*p)) {
+                let coverage = directories
+                    .entry(self.top_level_directory(path))
+                    .or_insert_with(|| Coverage {
+                        pending: Some(0),
+                        ..Default::default()
+                    });
+                coverage.pending = coverage.pending.map(|p| p + 1);
+            }
+        }
+        Ok(directories
+            .into_iter()
+            .map(|(directory, coverage)| Coverage {
+                directory: Some(directory),
+                ..coverage
+            })
+            .collect())
+    }
+}
+
+impl MPDLibrary {
+    /// Report on the songs played, see [Listening]. Genres stand for the
+    /// regions of the library, since they are what users know them by.
+    pub(crate) fn listening(&self) -> Result<Listening> {
+        let (plays, genres) = {
+            let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+            let mut query = sqlite_conn.prepare(
+                "select path, played_at, skipped, queued_by_blissify from play order by id",
+            )?;
+            let plays = query
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, bool>(2)?,
+                        row.get::<_, bool>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut query = sqlite_conn.prepare(
+                "select song_genre.genre, count(play.id) from song_genre
+                inner join song on song.id = song_genre.song_id
+                left join play on play.path = song.path
+                group by song_genre.genre
+                order by count(play.id) desc, song_genre.genre",
+            )?;
+            let genres = query
+                .query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            (plays, genres)
+        };
+
+        let skip_rate = |from_blissify: bool| {
+            let plays = plays
+                .iter()
+                .filter(|(_, _, _, b)| *b == from_blissify)
+                .collect::<Vec<_>>();
+            let skipped = plays.iter().filter(|(_, _, s, _)| *s).count();
+            (!plays.is_empty()).then(|| skipped as f32 / plays.len() as f32)
+        };
+
+        let mut analyses: HashMap<&str, Option<Array1<f32>>> = HashMap::new();
+        for (path, _, _, _) in &plays {
+            analyses.entry(path).or_insert_with(|| {
+                self.library
+                    .song_from_path::<()>(path)
+                    .ok()
+                    .map(|s| s.bliss_song.analysis.as_arr1())
+            });
+        }
+        let (mut blissify_distances, mut other_distances) = (vec![], vec![]);
+        for pair in plays.windows(2) {
+            let ((previous, previous_at, _, _), (path, played_at, _, from_blissify)) =
+                (&pair[0], &pair[1]);
+            if played_at - previous_at > SESSION_GAP {
+                continue;
+            }
+            if let (Some(Some(a)), Some(Some(b))) =
+                (analyses.get(previous.as_str()), analyses.get(path.as_str()))
+            {
+                let distance = (a - b).mapv(|x| x * x).sum().sqrt();
+                if *from_blissify {
+                    blissify_distances.push(distance);
+                } else {
+                    other_distances.push(distance);
+                }
+            }
+        }
+
+        let least_played_genres = genres.iter().rev().take(TOP_GENRES).cloned().collect();
+        Ok(Listening {
+            plays: plays.len() as u64,
+            blissify_skip_rate: skip_rate(true),
+            other_skip_rate: skip_rate(false),
+            blissify_transition_distance: average(&blissify_distances),
+            other_transition_distance: average(&other_distances),
+            most_played_genres: genres
+                .into_iter()
+                .filter(|(_, plays)| *plays > 0)
+                .take(TOP_GENRES)
+                .collect(),
+            least_played_genres,
+        })
+    }
+}
+
+/// Print `listening` as plain text.
+pub fn print_listening(listening: &Listening) {
+    let percent =
+        |rate: Option<f32>| rate.map_or(String::from("-"), |r| format!("{:.0}%", r * 100.));
+    let distance = |d: Option<f32>| d.map_or(String::from("-"), |d| format!("{:.2}", d));
+    let genres = |genres: &[(String, u64)]| {
+        genres
+            .iter()
+            .map(|(genre, plays)| format!("{} ({})", genre, plays))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    println!("Songs played: {}", listening.plays);
+    println!(
+        "Skipped: {} of the songs blissify queued, {} of the others",
+        percent(listening.blissify_skip_rate),
+        percent(listening.other_skip_rate),
+    );
+    println!(
+        "Average distance to the previous song: {} for the songs blissify queued, {} for the others",
+        distance(listening.blissify_transition_distance),
+        distance(listening.other_transition_distance),
+    );
+    println!(
+        "Most played genres: {}",
+        genres(&listening.most_played_genres)
+    );
+    println!(
+        "Least played genres: {}",
+        genres(&listening.least_played_genres)
+    );
+}
+
+/// Add up the coverage of `directories`.
+pub fn total_coverage(directories: &[Coverage], with_pending: bool) -> Coverage {
+    Coverage {
+        directory: None,
+        analyzed: directories.iter().map(|d| d.analyzed).sum(),
+        failed: directories.iter().map(|d| d.failed).sum(),
+        pending: with_pending.then(|| directories.iter().filter_map(|d| d.pending).sum()),
+    }
+}
+
+/// Print `coverage` as a line of a plain-text table.
+pub fn print_coverage(name: &str, coverage: &Coverage) {
+    let pending = coverage
+        .pending
+        .map_or(String::from("?"), |p| p.to_string());
+    println!(
+        "{:<30}\t{:>8}\t{:>8}\t{:>8}",
+        name, coverage.analyzed, coverage.failed, pending
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_coverage_by_directory() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/Albums/album/song1.flac", None, 0.),
+                (2, "path/Albums/album/song2.flac", None, 0.),
+                (3, "path/Singles/song.flac", None, 0.),
+                (4, "path/loose.flac", None, 0.),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute("update song set analyzed = false where id = 2", [])
+            .unwrap();
+
+        let coverage = |directory: &str, analyzed, failed, pending| Coverage {
+            directory: Some(String::from(directory)),
+            analyzed,
+            failed,
+            pending,
+        };
+        assert_eq!(
+            library.coverage_by_directory(None).unwrap(),
+            vec![
+                coverage(".", 1, 0, None),
+                coverage("Albums", 1, 1, None),
+                coverage("Singles", 1, 0, None),
+            ],
+        );
+
+        let mpd_paths = [
+            "path/Albums/album/song1.flac",
+            "path/Albums/album/song2.flac",
+            "path/Albums/album/song3.flac",
+            "path/Unreadable/song.flac",
+            "path/loose.flac",
+        ]
+        .map(String::from);
+        let directories = library.coverage_by_directory(Some(&mpd_paths)).unwrap();
+        assert_eq!(
+            directories,
+            vec![
+                coverage(".", 1, 0, Some(0)),
+                coverage("Albums", 1, 1, Some(1)),
+                coverage("Singles", 1, 0, Some(0)),
+                coverage("Unreadable", 0, 0, Some(1)),
+            ],
+        );
+        assert_eq!(
+            total_coverage(&directories, true),
+            Coverage {
+                directory: None,
+                analyzed: 3,
+                failed: 1,
+                pending: Some(2),
+            },
+        );
+    }
+
+    #[test]
+    fn test_listening() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/first.flac", None, 0.),
+                (2, "path/second.flac", None, 0.1),
+                (3, "path/third.flac", None, 0.3),
+                (4, "path/unplayed.flac", None, 0.),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "insert into song_genre (song_id, genre) values
+                    (1, 'ambient'), (2, 'ambient'), (3, 'techno'), (4, 'jazz');
+                insert into play (path, played_at, skipped, queued_by_blissify) values
+                    ('path/first.flac', 0, false, false),
+                    ('path/second.flac', 100, false, true),
+                    ('path/third.flac', 200, true, true),
+                    ('path/first.flac', 100000, false, false);",
+            )
+            .unwrap();
+
+        let listening = library.listening().unwrap();
+        let step = (bliss_audio::NUMBER_FEATURES as f32).sqrt() / 10.;
+        assert_eq!(listening.plays, 4);
+        assert_eq!(listening.blissify_skip_rate, Some(0.5));
+        assert_eq!(listening.other_skip_rate, Some(0.));
+        // The last play is in another session.
+        assert!((listening.blissify_transition_distance.unwrap() - 1.5 * step).abs() < 1e-4);
+        assert_eq!(listening.other_transition_distance, None);
+        assert_eq!(
+            listening.most_played_genres,
+            vec![(String::from("ambient"), 3), (String::from("techno"), 1)],
+        );
+        assert_eq!(
+            listening.least_played_genres,
+            vec![
+                (String::from("jazz"), 0),
+                (String::from("techno"), 1),
+                (String::from("ambient"), 3)
+            ],
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Storing analyses so that an interrupted analysis can't leave half-stored
+//! songs behind.
+//!
+//! Each song is stored in its own transaction, with its previous row removed
+//! in the same transaction, so that a crash or a kill in the middle of an
+//! analysis either keeps the song as it was or stores it entirely. Songs
+//! stored by bliss itself, e.g. by `update_library`, are not, so songs marked
+//! as analyzed without all their features are removed when the database is
+//! opened, to be analyzed again by the next update.
+use crate::MPDLibrary;
+use anyhow::Result;
+use bliss_audio::{BlissError, Song, NUMBER_FEATURES};
+use std::path::Path;
+
+impl MPDLibrary {
+    /// Run `store` in a savepoint, rolled back if it fails.
+    fn atomically<T>(&mut self, store: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch("savepoint store_song")?;
+        let result = store(self);
+        let sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        match result {
+            Ok(_) => sqlite_conn.execute_batch("release store_song")?,
+            Err(_) => sqlite_conn.execute_batch("rollback to store_song; release store_song")?,
+        }
+        result
+    }
+
+    /// Replace the song at `song.path` with `song`, atomically.
+    ///
+    /// bliss' `store_song` opens its own transaction, which can't be nested
+    /// in another one, so the song is stored the same way here instead.
+    pub(crate) fn store_analyzed_song(&mut self, song: Song) -> Result<()> {
+        let path = song.path.to_string_lossy();
+        let (cue_path, audio_file_path) = match &song.cue_info {
+            Some(cue_info) => (
+                Some(cue_info.cue_path.to_string_lossy()),
+                Some(cue_info.audio_file_path.to_string_lossy()),
+            ),
+            None => (None, None),
+        };
+        let mut sqlite_conn = self.library.sqlite_conn.lock().unwrap();
+        let tx = sqlite_conn.transaction()?;
+        tx.execute("delete from song where path = ?1", [&path])?;
+        // The songs' extra info is (), serialized as null.
+        tx.execute(
+            "insert into song (
+                path, artist, title, album, album_artist, duration, track_number,
+                disc_number, genre, analyzed, version, extra_info, cue_path,
+                audio_file_path
+            )
+            values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, true, ?10, 'null', ?11, ?12)",
+            (
+                &path,
+                &song.artist,
+                &song.title,
+                &song.album,
+                &song.album_artist,
+                song.duration.as_secs_f64(),
+                song.track_number,
+                song.disc_number,
+                &song.genre,
+                song.features_version,
+                cue_path,
+                audio_file_path,
+            ),
+        )?;
+        let song_id = tx.last_insert_rowid();
+        {
+            let mut insert = tx.prepare(
+                "insert into feature (song_id, feature, feature_index) values (?1, ?2, ?3)",
+            )?;
+            for (index, feature) in song.analysis.as_vec().iter().enumerate() {
+                insert.execute((song_id, feature, index))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Replace the song at `path` with a song that failed to be analyzed
+    /// with `error`, atomically.
+    pub(crate) fn store_failed_analysis(&mut self, path: &Path, error: BlissError) -> Result<()> {
+        self.atomically(|library| {
+            library.library.delete_paths([path])?;
+            library.library.store_failed_song(path, error)?;
+            Ok(())
+        })
+    }
+
+    /// Remove the songs marked as analyzed without all their features,
+    /// returning the number of songs removed. Their features go along, since
+    /// they cascade.
+    pub(crate) fn remove_incomplete_songs(&self) -> Result<usize> {
+        let removed = self.library.sqlite_conn.lock().unwrap().execute(
+            "delete from song where analyzed and id not in (
+                select song_id from feature group by song_id having count(*) = ?1
+            )",
+            [NUMBER_FEATURES],
+        )?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::Analysis;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    fn features(library: &MPDLibrary) -> Vec<(String, bool, usize)> {
+        let sqlite_conn = library.library.sqlite_conn.lock().unwrap();
+        let mut query = sqlite_conn
+            .prepare(
+                "select path, analyzed, count(song_id) from song
+                left outer join feature on feature.song_id = song.id
+                group by song.id order by path",
+            )
+            .unwrap();
+        let features = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        features
+    }
+
+    #[test]
+    fn test_store_analyzed_song() {
+        let (mut library, _tempdir) = setup_library();
+        insert_songs(&library, &[(1, "path/song.flac", None, 0.)]);
+        library
+            .store_analyzed_song(Song {
+                path: PathBuf::from("path/song.flac"),
+                analysis: Analysis::new([0.5; NUMBER_FEATURES]),
+                ..Default::default()
+            })
+            .unwrap();
+        library
+            .store_failed_analysis(
+                Path::new("path/broken.flac"),
+                BlissError::DecodingError(String::from("broken")),
+            )
+            .unwrap();
+        assert_eq!(
+            features(&library),
+            vec![
+                (String::from("path/broken.flac"), false, 0),
+                (String::from("path/song.flac"), true, NUMBER_FEATURES),
+            ],
+        );
+
+        // A failed store leaves the previous song untouched.
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "create trigger fail_store before insert on feature
+                begin select raise(abort, 'disk full'); end;",
+            )
+            .unwrap();
+        assert!(library
+            .store_analyzed_song(Song {
+                path: PathBuf::from("path/song.flac"),
+                analysis: Analysis::new([0.; NUMBER_FEATURES]),
+                ..Default::default()
+            })
+            .is_err());
+        assert_eq!(
+            features(&library),
+            vec![
+                (String::from("path/broken.flac"), false, 0),
+                (String::from("path/song.flac"), true, NUMBER_FEATURES),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_remove_incomplete_songs() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/complete.flac", None, 0.),
+                (2, "path/incomplete.flac", None, 0.),
+            ],
+        );
+        library
+            .library
+            .sqlite_conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "delete from feature where song_id = 2 and feature_index > 3;
+                insert into song (id, path, analyzed) values (3, 'path/failed.flac', false);",
+            )
+            .unwrap();
+
+        assert_eq!(library.remove_incomplete_songs().unwrap(), 1);
+        assert_eq!(
+            features(&library),
+            vec![
+                (String::from("path/complete.flac"), true, NUMBER_FEATURES),
+                (String::from("path/failed.flac"), false, 0),
+            ],
+        );
+    }
+}
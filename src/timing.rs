@@ -0,0 +1,178 @@
+//! Timing the stages of making a playlist, see `playlist --time`.
+//!
+//! The stages are told apart from the outside, by wrapping the sorting
+//! closure and the distance metric: candidates are loaded until the songs
+//! start being sorted, the metric is set up (e.g. the forest fitted) whenever
+//! it is built, songs are ranked while the sorted songs are read, and the
+//! playlist is queued, or printed, from then on.
+use bliss_audio::playlist::{DistanceMetric, DistanceMetricBuilder};
+use ndarray::Array1;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct Stages {
+    /// When the songs started being sorted the first time.
+    sorting_started: Option<Instant>,
+    /// When the sorted songs were last done being read.
+    ranking_done: Option<Instant>,
+    metric_setup: Duration,
+    /// The time spent sorting songs and reading them, metric setup included.
+    ranking: Duration,
+}
+
+/// The time spent in each stage of making a playlist.
+pub struct Timings {
+    start: Instant,
+    stages: Arc<Mutex<Stages>>,
+}
+
+impl Timings {
+    /// Start timing a playlist, from loading the candidates on.
+    pub fn start() -> Self {
+        Timings {
+            start: Instant::now(),
+            stages: Default::default(),
+        }
+    }
+
+    /// `distance`, with the time spent building it counted as metric setup.
+    pub fn distance<'a>(&self, distance: &'a dyn DistanceMetricBuilder) -> TimedDistance<'a> {
+        TimedDistance {
+            inner: distance,
+            stages: self.stages.clone(),
+        }
+    }
+
+    /// Sort songs with `sort`, counting the time spent doing so, and reading
+    /// the sorted songs, as ranking.
+    pub fn rank<I>(&self, sort: impl FnOnce() -> I) -> TimedIterator<I> {
+        let started = Instant::now();
+        self.stages
+            .lock()
+            .unwrap()
+            .sorting_started
+            .get_or_insert(started);
+        let inner = sort();
+        self.stages.lock().unwrap().ranking += started.elapsed();
+        TimedIterator {
+            inner,
+            stages: self.stages.clone(),
+        }
+    }
+
+    /// The name and duration of each stage so far, leaving out the stages
+    /// that were skipped, e.g. ranking for album playlists.
+    pub fn stages(&self) -> Vec<(&'static str, Duration)> {
+        let now = Instant::now();
+        let stages = self.stages.lock().unwrap();
+        let sorting_started = match stages.sorting_started {
+            Some(sorting_started) => sorting_started,
+            None => return vec![("Total", now - self.start)],
+        };
+        vec![
+            ("Loading candidates", sorting_started - self.start),
+            ("Metric setup", stages.metric_setup),
+            (
+                "Ranking",
+                stages.ranking.saturating_sub(stages.metric_setup),
+            ),
+            (
+                "Queueing",
+                now - stages.ranking_done.unwrap_or(sorting_started),
+            ),
+            ("Total", now - self.start),
+        ]
+    }
+
+    /// Print [stages](Self::stages) on stderr, so that they don't mix with
+    /// the playlists printed by dry runs.
+    pub fn print(&self) {
+        for (stage, duration) in self.stages() {
+            eprintln!("{}: {:.3}s", stage, duration.as_secs_f64());
+        }
+    }
+}
+
+/// A distance metric counting the time spent building it, see
+/// [Timings::distance].
+pub struct TimedDistance<'a> {
+    inner: &'a dyn DistanceMetricBuilder,
+    stages: Arc<Mutex<Stages>>,
+}
+
+impl DistanceMetricBuilder for TimedDistance<'_> {
+    fn build<'a>(&'a self, vectors: &[Array1<f32>]) -> Box<dyn DistanceMetric + 'a> {
+        let started = Instant::now();
+        let metric = self.inner.build(vectors);
+        self.stages.lock().unwrap().metric_setup += started.elapsed();
+        metric
+    }
+}
+
+/// Sorted songs counting the time spent reading them, see [Timings::rank].
+pub struct TimedIterator<I> {
+    inner: I,
+    stages: Arc<Mutex<Stages>>,
+}
+
+impl<I: Iterator> Iterator for TimedIterator<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let started = Instant::now();
+        let item = self.inner.next();
+        self.stages.lock().unwrap().ranking += started.elapsed();
+        item
+    }
+}
+
+impl<I> Drop for TimedIterator<I> {
+    fn drop(&mut self) {
+        self.stages.lock().unwrap().ranking_done = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bliss_audio::playlist::euclidean_distance;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_timings() {
+        let timings = Timings::start();
+        assert_eq!(
+            timings.stages().iter().map(|s| s.0).collect::<Vec<_>>(),
+            vec!["Total"]
+        );
+
+        sleep(Duration::from_millis(10));
+        let distance = timings.distance(&euclidean_distance);
+        let songs = timings
+            .rank(|| {
+                distance.build(&[Array1::zeros(3)]);
+                sleep(Duration::from_millis(10));
+                0..2
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(songs.len(), 2);
+        sleep(Duration::from_millis(10));
+
+        let stages = timings.stages();
+        assert_eq!(
+            stages.iter().map(|s| s.0).collect::<Vec<_>>(),
+            vec![
+                "Loading candidates",
+                "Metric setup",
+                "Ranking",
+                "Queueing",
+                "Total"
+            ]
+        );
+        assert!(stages[0].1 >= Duration::from_millis(10));
+        assert!(stages[2].1 >= Duration::from_millis(10));
+        assert!(stages[3].1 >= Duration::from_millis(10));
+        assert!(stages[4].1 >= stages[0].1 + stages[2].1 + stages[3].1);
+    }
+}
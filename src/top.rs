@@ -0,0 +1,96 @@
+//! Ranking songs by how representative of the library they are, see
+//! `blissify top`.
+//!
+//! The most representative songs are the closest ones to the centroid of the
+//! library, i.e. the mean of all the analyses, which makes them good default
+//! seeds for people who don't know the library. `--outliers` ranks the
+//! furthest ones first instead, to find the odd songs out.
+use crate::{MPDLibrary, SongOutput};
+use anyhow::Result;
+use bliss_audio::playlist::DistanceMetricBuilder;
+
+impl MPDLibrary {
+    /// The `number_songs` songs closest to the centroid of the library
+    /// according to `distance`, or the furthest ones if `outliers` is set,
+    /// along with their distance to it. Excluded songs are left out.
+    pub(crate) fn top_songs(
+        &self,
+        number_songs: usize,
+        outliers: bool,
+        distance: &dyn DistanceMetricBuilder,
+    ) -> Result<Vec<SongOutput>> {
+        let excluded = self.excluded_songs()?;
+        let songs = self
+            .library
+            .songs_from_library::<()>()?
+            .into_iter()
+            .filter(|s| !excluded.contains(&s.bliss_song.path))
+            .collect::<Vec<_>>();
+        let centroid = match songs
+            .iter()
+            .map(|s| s.bliss_song.analysis.as_arr1())
+            .reduce(|a, b| a + b)
+        {
+            Some(sum) => sum / songs.len() as f32,
+            None => return Ok(vec![]),
+        };
+        let metric = distance.build(&[centroid]);
+        let mut ranked = songs
+            .iter()
+            .map(|song| SongOutput {
+                distance: Some(metric.distance(&song.bliss_song.analysis.as_arr1())),
+                ..SongOutput::new(song)
+            })
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| a.distance.unwrap().total_cmp(&b.distance.unwrap()));
+        if outliers {
+            ranked.reverse();
+        }
+        ranked.truncate(number_songs);
+        Ok(ranked)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::{insert_songs, setup_library};
+    use bliss_audio::playlist::euclidean_distance;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_top_songs() {
+        let (library, _tempdir) = setup_library();
+        insert_songs(
+            &library,
+            &[
+                (1, "path/quiet.flac", None, 0.),
+                (2, "path/typical.flac", None, 0.2),
+                (3, "path/almost_typical.flac", None, 0.3),
+                (4, "path/excluded.flac", None, 0.25),
+                (5, "path/odd.flac", None, 1.),
+            ],
+        );
+        library.exclude_songs("path/excluded.flac").unwrap();
+        let top = |outliers| {
+            library
+                .top_songs(3, outliers, &euclidean_distance)
+                .unwrap()
+                .into_iter()
+                .map(|s| s.path)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            top(false),
+            vec![
+                "path/almost_typical.flac",
+                "path/typical.flac",
+                "path/quiet.flac",
+            ],
+        );
+        assert_eq!(
+            top(true),
+            vec!["path/odd.flac", "path/quiet.flac", "path/typical.flac"],
+        );
+    }
+}
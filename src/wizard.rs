@@ -0,0 +1,364 @@
+//! The interactive setup of `blissify init --interactive`.
+//!
+//! It looks for MPD the way it is usually set up: through MPD_HOST and
+//! MPD_PORT, mpd.conf's `bind_to_address` and `port`, or the sockets
+//! distributions put MPD's in, and suggests mpd.conf's `music_directory` as
+//! the base path. Every guess can be overridden, and the base path is checked
+//! against the songs MPD lists before the library is analyzed, since a wrong
+//! base path is the most common setup mistake.
+use crate::MPDLibrary;
+use anyhow::{bail, Context, Result};
+use bliss_audio::library::AppConfigTrait;
+use std::env;
+use std::io::{BufRead, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+/// How many of the songs MPD lists are looked for in the base path.
+const SAMPLE_SIZE: usize = 20;
+
+/// The settings of an mpd.conf blissify cares about.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct MPDConf {
+    pub music_directory: Option<PathBuf>,
+    /// Every `bind_to_address`, which can be given several times.
+    pub bind_to_address: Vec<String>,
+    pub port: Option<u16>,
+    pub log_file: Option<PathBuf>,
+}
+
+/// `path`, with a leading "~" replaced by the home directory, as MPD does.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest.trim_start_matches('/')),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Whether the MPD host `host` is a socket rather than a hostname.
+fn is_socket(host: &str) -> bool {
+    host.starts_with('/') || host.starts_with('~') || host.starts_with('@')
+}
+
+/// Read the top-level settings of an mpd.conf, leaving the blocks like
+/// `audio_output { ... }` out.
+pub(crate) fn parse_mpd_conf(contents: &str) -> MPDConf {
+    let mut conf = MPDConf::default();
+    let mut depth = 0;
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if line.ends_with('{') {
+            depth += 1;
+            continue;
+        }
+        if line == "}" {
+            depth -= 1;
+            continue;
+        }
+        let (key, value) = match line.split_once(char::is_whitespace) {
+            Some((key, value)) if depth == 0 => (key, value.trim().trim_matches('"')),
+            _ => continue,
+        };
+        match key {
+            "music_directory" => conf.music_directory = Some(expand_home(value)),
+            "bind_to_address" => conf.bind_to_address.push(value.to_owned()),
+            "port" => conf.port = value.parse().ok(),
+            "log_file" => conf.log_file = Some(expand_home(value)),
+            _ => (),
+        }
+    }
+    conf
+}
+
+/// Find and read MPD's configuration file, from the places MPD looks for it
+/// in.
+fn find_mpd_conf() -> Option<(PathBuf, MPDConf)> {
+    let mut paths = vec![];
+    if let Some(config_dir) = dirs::config_dir() {
+        paths.push(config_dir.join("mpd/mpd.conf"));
+    }
+    if let Some(home) = dirs::home_dir() {
+        paths.push(home.join(".mpdconf"));
+        paths.push(home.join(".mpd/mpd.conf"));
+    }
+    paths.push(PathBuf::from("/etc/mpd.conf"));
+    paths.push(PathBuf::from("/usr/local/etc/mpd.conf"));
+    paths.into_iter().find_map(|path| {
+        let contents = std::fs::read_to_string(&path).ok()?;
+        Some((path, parse_mpd_conf(&contents)))
+    })
+}
+
+/// The sockets MPD usually listens on.
+fn common_sockets() -> Vec<PathBuf> {
+    let mut sockets = vec![];
+    if let Some(runtime_dir) = dirs::runtime_dir() {
+        sockets.push(runtime_dir.join("mpd/socket"));
+    }
+    sockets.push(PathBuf::from("/run/mpd/socket"));
+    sockets.push(PathBuf::from("/var/run/mpd/socket"));
+    if let Some(home) = dirs::home_dir() {
+        sockets.push(home.join(".mpd/socket"));
+        sockets.push(home.join(".config/mpd/socket"));
+    }
+    sockets
+}
+
+/// Guess MPD's host and port, and where the guess comes from.
+fn detect_mpd(conf: Option<&MPDConf>) -> (String, u16, &'static str) {
+    let conf_port = conf.and_then(|c| c.port).unwrap_or(6600);
+    if let Ok(host) = env::var("MPD_HOST") {
+        let port = env::var("MPD_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(6600);
+        return (host, port, "the MPD_HOST environment variable");
+    }
+    let addresses = conf.map(|c| c.bind_to_address.as_slice()).unwrap_or(&[]);
+    if let Some(socket) = addresses
+        .iter()
+        .find(|a| is_socket(a) && expand_home(a).exists())
+    {
+        let socket = expand_home(socket).to_string_lossy().to_string();
+        return (socket, conf_port, "mpd.conf");
+    }
+    if let Some(socket) = common_sockets().into_iter().find(|s| s.exists()) {
+        return (socket.to_string_lossy().to_string(), 6600, "a socket");
+    }
+    match addresses.iter().find(|a| !is_socket(a) && *a != "any") {
+        Some(address) => (address.to_owned(), conf_port, "mpd.conf"),
+        None => (String::from("localhost"), conf_port, "the default"),
+    }
+}
+
+/// Questions written to `output`, with their answers read from `input`.
+pub(crate) struct Prompt<'a> {
+    pub input: &'a mut dyn BufRead,
+    pub output: &'a mut dyn Write,
+}
+
+impl Prompt<'_> {
+    /// Ask `question`, showing `hint` next to it, and read the answer.
+    fn answer(&mut self, question: &str, hint: &str) -> Result<String> {
+        write!(self.output, "{} [{}]: ", question, hint)?;
+        self.output.flush()?;
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            bail!("The setup was interrupted.");
+        }
+        Ok(answer.trim().to_owned())
+    }
+
+    /// Ask `question`, returning `default` if nothing is answered.
+    pub fn ask(&mut self, question: &str, default: &str) -> Result<String> {
+        match self.answer(question, default)? {
+            answer if answer.is_empty() => Ok(default.to_owned()),
+            answer => Ok(answer),
+        }
+    }
+
+    /// Ask a yes / no `question` until it is answered, returning `default`
+    /// if nothing is answered.
+    pub fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            match self.answer(question, hint)?.to_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => writeln!(self.output, "Please answer y or n.")?,
+            }
+        }
+    }
+
+    /// Ask for a directory until an existing one is given.
+    fn ask_directory(&mut self, question: &str, default: &Path) -> Result<PathBuf> {
+        loop {
+            let directory = expand_home(&self.ask(question, &default.to_string_lossy())?);
+            if directory.is_dir() {
+                return Ok(directory);
+            }
+            writeln!(self.output, "{} is not a directory.", directory.display())?;
+        }
+    }
+}
+
+/// Set blissify up interactively, then offer to analyze the library.
+///
+/// `number_cores` and `isolate` are the defaults given on the command-line.
+pub(crate) fn run(
+    prompt: &mut Prompt,
+    config_path: Option<PathBuf>,
+    database_path: PathBuf,
+    number_cores: Option<NonZeroUsize>,
+    isolate: bool,
+) -> Result<()> {
+    let conf = find_mpd_conf();
+    if let Some((path, _)) = &conf {
+        writeln!(
+            prompt.output,
+            "Found MPD's configuration in {}.",
+            path.display()
+        )?;
+    }
+    let conf = conf.map(|(_, conf)| conf);
+
+    let (host, port, source) = detect_mpd(conf.as_ref());
+    writeln!(prompt.output, "Using MPD's address from {}.", source)?;
+    let host = prompt.ask(
+        "MPD host (a hostname, an IP address or a socket path, optionally preceded by \"password@\")",
+        &host,
+    )?;
+    let port = if is_socket(&host) {
+        port
+    } else {
+        prompt
+            .ask("MPD port", &port.to_string())?
+            .parse::<u16>()
+            .context("while reading MPD's port")?
+    };
+    let env_unchanged = env::var("MPD_HOST").ok().as_deref() == Some(host.as_str())
+        && env::var("MPD_PORT").map_or(port == 6600, |p| p == port.to_string());
+    env::set_var("MPD_HOST", &host);
+    env::set_var("MPD_PORT", port.to_string());
+
+    let default_directory = conf
+        .as_ref()
+        .and_then(|c| c.music_directory.to_owned())
+        .or_else(dirs::audio_dir)
+        .unwrap_or_default();
+    let music_directory = prompt.ask_directory(
+        "MPD's music directory, the `music_directory` of mpd.conf",
+        &default_directory,
+    )?;
+
+    let all_cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let number_cores = prompt
+        .ask(
+            "Number of CPU cores to analyze songs with",
+            &number_cores.map_or(all_cores, |n| n.get()).to_string(),
+        )?
+        .parse::<NonZeroUsize>()
+        .context("while reading the number of cores")?;
+    let isolate = prompt.confirm(
+        "Decode songs in worker processes, so that a decoder crashing on a corrupt file \
+        only fails that file instead of stopping the analysis? It is slightly slower",
+        isolate,
+    )?;
+
+    let mut library = MPDLibrary::new(
+        music_directory,
+        config_path,
+        Some(database_path),
+        Some(number_cores),
+    )
+    .with_context(|| format!("while connecting to MPD at {}", host))?;
+    let mut paths = library.get_songs_paths()?;
+    if paths.is_empty() {
+        writeln!(
+            prompt.output,
+            "MPD's library is empty, add songs to it and run `blissify update`."
+        )?;
+    }
+    while !paths.is_empty()
+        && !paths
+            .iter()
+            .take(SAMPLE_SIZE)
+            .any(|p| Path::new(p).exists())
+    {
+        let mpd_base_path = library.library.config.mpd_base_path.to_owned();
+        writeln!(
+            prompt.output,
+            "None of the songs MPD lists, like {}, are in {}.",
+            paths[0],
+            mpd_base_path.display(),
+        )?;
+        library.library.config.mpd_base_path =
+            prompt.ask_directory("MPD's music directory", &mpd_base_path)?;
+        paths = library.get_songs_paths()?;
+    }
+    library.library.config.isolate_analysis = isolate;
+    library.library.config.mpd_log_path = conf
+        .and_then(|c| c.log_file)
+        .filter(|log_file| log_file.is_file());
+    library.library.config.write()?;
+    writeln!(
+        prompt.output,
+        "Wrote the configuration to {}.",
+        library.library.config.base_config.config_path.display()
+    )?;
+    if !env_unchanged {
+        writeln!(
+            prompt.output,
+            "blissify finds MPD through the MPD_HOST and MPD_PORT environment variables, \
+            add `export MPD_HOST='{}' MPD_PORT={}` to your shell's profile.",
+            host, port
+        )?;
+    }
+
+    if paths.is_empty() {
+        return Ok(());
+    }
+    if prompt.confirm("Analyze the library now? It can take some time", true)? {
+        library.full_rescan()?;
+        library.print_error_summary()?;
+    } else {
+        writeln!(prompt.output, "Run `blissify update` to analyze it later.")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_mpd_conf() {
+        let conf = parse_mpd_conf(
+            r#"
+            # music_directory "/commented/out"
+            music_directory    "/srv/music"
+            bind_to_address "any"
+            bind_to_address	"/run/mpd/socket"
+            port "6601"
+            log_file "syslog"
+            audio_output {
+                type "pipewire"
+                port "1234"
+            }
+            "#,
+        );
+        assert_eq!(
+            conf,
+            MPDConf {
+                music_directory: Some(PathBuf::from("/srv/music")),
+                bind_to_address: vec![String::from("any"), String::from("/run/mpd/socket")],
+                port: Some(6601),
+                log_file: Some(PathBuf::from("syslog")),
+            }
+        );
+    }
+
+    #[test]
+    fn test_prompt() {
+        let mut input = "\n/custom\nmaybe\n\nN\n".as_bytes();
+        let mut output = vec![];
+        let mut prompt = Prompt {
+            input: &mut input,
+            output: &mut output,
+        };
+        assert_eq!(prompt.ask("Directory", "/default").unwrap(), "/default");
+        assert_eq!(prompt.ask("Directory", "/default").unwrap(), "/custom");
+        assert!(prompt.confirm("Analyze", true).unwrap());
+        assert!(!prompt.confirm("Analyze", true).unwrap());
+        assert!(prompt.ask("Directory", "/default").is_err());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Directory [/default]: Directory [/default]: Analyze [Y/n]: Please answer y or n.\n\
+            Analyze [Y/n]: Analyze [Y/n]: Directory [/default]: "
+        );
+    }
+}